@@ -0,0 +1,574 @@
+//! Full BIP-322 proofs. Instead of a witness, the signature is the complete, consensus encoded `to_sign`
+//! transaction, as emitted by wallets such as Sparrow. Its first input must spend the `to_spend` commitment to
+//! the message and is verified as a spend of the address.
+//!
+//! There is no script interpreter, so the spend must follow one of the standard templates: P2PKH, P2WPKH,
+//! P2SH-wrapped P2WPKH or P2WSH, P2WSH with a single key or a bare multisig witness script, and P2TR through the
+//! key path or a single key script leaf, optionally with an annex. Proofs of funds, i.e. additional inputs, are
+//! rejected because their outputs cannot be looked up.
+
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::Hash;
+use bitcoin::key::XOnlyPublicKey;
+use bitcoin::opcodes::all::{
+    OP_CHECKMULTISIG, OP_CHECKSIG, OP_PUSHNUM_1, OP_PUSHNUM_16, OP_RETURN,
+};
+use bitcoin::script::Instruction;
+use bitcoin::secp256k1::{Message, Secp256k1};
+use bitcoin::sighash::{Annex, Prevouts, SighashCache};
+use bitcoin::taproot::{ControlBlock, LeafVersion, TapLeafHash};
+use bitcoin::{Address, PublicKey, Script, ScriptBuf, Transaction, TxOut, Witness};
+
+use crate::error::BtcError;
+use crate::login::{bip0322_hash, bip0322_tx, LoginError};
+use crate::utils::{decode_base64_bounded, max_signature_bytes};
+
+const TAPROOT_ANNEX_PREFIX: u8 = 0x50;
+
+fn format_error(reason: &str) -> LoginError {
+    LoginError::BtcError(BtcError::SignatureFormatError(reason.to_string()))
+}
+
+/// Verifies that `signature`, a base64 encoded `to_sign` transaction, proves control of `address` for
+/// `message`.
+pub(crate) fn verify_bip322_full(
+    message: &str,
+    address: &Address,
+    signature: &str,
+) -> Result<(), LoginError> {
+    let data = decode_base64_bounded(signature, max_signature_bytes())?;
+    let to_sign: Transaction =
+        deserialize(&data).map_err(|_| format_error("Invalid BIP-322 transaction"))?;
+
+    let script_pubkey = address.script_pubkey();
+    let commitment =
+        bip0322_tx(&bip0322_hash(message), script_pubkey.clone()).input[0].previous_output;
+
+    if to_sign.version != 0 && to_sign.version != 2 {
+        return Err(format_error("BIP-322 transaction version must be 0 or 2"));
+    }
+    if to_sign.input.len() != 1 {
+        return Err(format_error("BIP-322 proofs of funds are not supported"));
+    }
+    if to_sign.output.len() != 1
+        || to_sign.output[0].value != 0
+        || to_sign.output[0].script_pubkey.as_bytes() != [OP_RETURN.to_u8()]
+    {
+        return Err(format_error(
+            "BIP-322 transaction must have a single empty OP_RETURN output",
+        ));
+    }
+    // The commitment is the only place the message enters the proof, so a proof for another message spends a
+    // different outpoint.
+    if to_sign.input[0].previous_output != commitment {
+        return Err(LoginError::AddressMismatch);
+    }
+
+    let spend = Spend {
+        tx: &to_sign,
+        prevout: TxOut {
+            value: 0,
+            script_pubkey: script_pubkey.clone(),
+        },
+    };
+    let input = &to_sign.input[0];
+    let verified = if script_pubkey.is_p2pkh() {
+        input.witness.is_empty() && spend.verify_p2pkh(&input.script_sig)
+    } else if script_pubkey.is_p2sh() {
+        match single_push(&input.script_sig) {
+            Some(redeem_script)
+                if ScriptBuf::new_p2sh(&redeem_script.script_hash()) == script_pubkey =>
+            {
+                spend.verify_witness_program(&redeem_script, &input.witness)?
+            }
+            _ => false,
+        }
+    } else if script_pubkey.is_v0_p2wpkh() || script_pubkey.is_v0_p2wsh() {
+        input.script_sig.is_empty()
+            && spend.verify_witness_program(&script_pubkey, &input.witness)?
+    } else if script_pubkey.is_v1_p2tr() {
+        input.script_sig.is_empty() && spend.verify_p2tr(&script_pubkey, &input.witness)?
+    } else {
+        return Err(LoginError::BtcError(BtcError::AddressTypeNotSupported));
+    };
+
+    if !verified {
+        return Err(LoginError::AddressMismatch);
+    }
+    Ok(())
+}
+
+/// Verifies `witness` as the spend of the version 0 witness program `script_pubkey` by the first input of
+/// `to_sign`, as in a BIP-322 Simple signature.
+pub(crate) fn verify_witness_spend(
+    to_sign: &Transaction,
+    script_pubkey: &Script,
+    witness: &Witness,
+) -> Result<bool, LoginError> {
+    let spend = Spend {
+        tx: to_sign,
+        prevout: TxOut {
+            value: 0,
+            script_pubkey: script_pubkey.to_owned(),
+        },
+    };
+    spend.verify_witness_program(script_pubkey, witness)
+}
+
+/// The data of a script that consists of exactly one push, such as the script sig of a P2SH-wrapped spend.
+fn single_push(script: &Script) -> Option<ScriptBuf> {
+    let mut instructions = script.instructions();
+    match (instructions.next(), instructions.next()) {
+        (Some(Ok(Instruction::PushBytes(data))), None) => {
+            Some(ScriptBuf::from_bytes(data.as_bytes().to_vec()))
+        }
+        _ => None,
+    }
+}
+
+/// The first input of `tx` spending `prevout`.
+struct Spend<'a> {
+    tx: &'a Transaction,
+    prevout: TxOut,
+}
+
+impl Spend<'_> {
+    fn verify_ecdsa(
+        &self,
+        signature: &[u8],
+        key: &PublicKey,
+        script_code: &Script,
+        segwit: bool,
+    ) -> bool {
+        let Ok(signature) = bitcoin::ecdsa::Signature::from_slice(signature) else {
+            return false;
+        };
+        let mut cache = SighashCache::new(self.tx);
+        let sighash = if segwit {
+            cache
+                .segwit_signature_hash(0, script_code, 0, signature.hash_ty)
+                .map(|h| h.to_byte_array())
+        } else {
+            cache
+                .legacy_signature_hash(0, script_code, signature.hash_ty.to_u32())
+                .map(|h| h.to_byte_array())
+        };
+        match sighash.map(|h| Message::from_slice(&h)) {
+            Ok(Ok(message)) => Secp256k1::verification_only()
+                .verify_ecdsa(&message, &signature.sig, &key.inner)
+                .is_ok(),
+            _ => false,
+        }
+    }
+
+    fn verify_p2pkh(&self, script_sig: &Script) -> bool {
+        let pushes: Option<Vec<&[u8]>> = script_sig
+            .instructions()
+            .map(|instruction| match instruction {
+                Ok(Instruction::PushBytes(data)) => Some(data.as_bytes()),
+                _ => None,
+            })
+            .collect();
+        let Some([signature, key]) = pushes.as_deref() else {
+            return false;
+        };
+        let Ok(key) = PublicKey::from_slice(key) else {
+            return false;
+        };
+        ScriptBuf::new_p2pkh(&key.pubkey_hash()) == self.prevout.script_pubkey
+            && self.verify_ecdsa(signature, &key, &self.prevout.script_pubkey, false)
+    }
+
+    /// Verifies the witness of a version 0 witness program, native or wrapped in P2SH.
+    fn verify_witness_program(
+        &self,
+        program: &Script,
+        witness: &Witness,
+    ) -> Result<bool, LoginError> {
+        let stack: Vec<&[u8]> = witness.iter().collect();
+        if program.is_v0_p2wpkh() {
+            let [signature, key] = stack.as_slice() else {
+                return Ok(false);
+            };
+            let Ok(key) = PublicKey::from_slice(key) else {
+                return Ok(false);
+            };
+            // The key must be the one the program commits to, which also rules out uncompressed keys. The
+            // script code of a P2WPKH spend is the P2PKH script of the key.
+            let script_code = ScriptBuf::new_p2pkh(&key.pubkey_hash());
+            return Ok(key
+                .wpubkey_hash()
+                .map(|hash| ScriptBuf::new_v0_p2wpkh(&hash))
+                == Some(program.to_owned())
+                && self.verify_ecdsa(signature, &key, &script_code, true));
+        }
+        if program.is_v0_p2wsh() {
+            let Some((witness_script, stack)) = stack.split_last() else {
+                return Ok(false);
+            };
+            let witness_script = Script::from_bytes(witness_script);
+            if ScriptBuf::new_v0_p2wsh(&witness_script.wscript_hash()).as_script() != program {
+                return Ok(false);
+            }
+            return self.verify_witness_script(witness_script, stack);
+        }
+        Err(LoginError::BtcError(BtcError::AddressTypeNotSupported))
+    }
+
+    /// Evaluates a single key or bare multisig witness script against the remaining witness stack.
+    fn verify_witness_script(
+        &self,
+        witness_script: &Script,
+        stack: &[&[u8]],
+    ) -> Result<bool, LoginError> {
+        let instructions: Option<Vec<Instruction>> =
+            witness_script.instructions().map(Result::ok).collect();
+        let Some(instructions) = instructions else {
+            return Ok(false);
+        };
+        match instructions.as_slice() {
+            [Instruction::PushBytes(key), Instruction::Op(OP_CHECKSIG)] => {
+                let [signature] = stack else {
+                    return Ok(false);
+                };
+                Ok(PublicKey::from_slice(key.as_bytes())
+                    .is_ok_and(|key| self.verify_ecdsa(signature, &key, witness_script, true)))
+            }
+            [Instruction::Op(required), keys @ .., Instruction::Op(total), Instruction::Op(OP_CHECKMULTISIG)] =>
+            {
+                let (Some(required), Some(total)) = (small_int(*required), small_int(*total))
+                else {
+                    return Ok(false);
+                };
+                let keys: Option<Vec<PublicKey>> = keys
+                    .iter()
+                    .map(|key| match key {
+                        Instruction::PushBytes(key) => PublicKey::from_slice(key.as_bytes()).ok(),
+                        Instruction::Op(_) => None,
+                    })
+                    .collect();
+                let Some(keys) = keys else {
+                    return Ok(false);
+                };
+                // OP_CHECKMULTISIG pops one element too many, which must be empty.
+                let Some((dummy, signatures)) = stack.split_first() else {
+                    return Ok(false);
+                };
+                if keys.len() != total
+                    || required > total
+                    || signatures.len() != required
+                    || !dummy.is_empty()
+                {
+                    return Ok(false);
+                }
+                // The signatures must appear in the order of their keys.
+                let mut keys = keys.iter();
+                Ok(signatures.iter().all(|signature| {
+                    keys.any(|key| self.verify_ecdsa(signature, key, witness_script, true))
+                }))
+            }
+            _ => Err(LoginError::BtcError(BtcError::AddressTypeNotSupported)),
+        }
+    }
+
+    fn verify_p2tr(&self, script_pubkey: &Script, witness: &Witness) -> Result<bool, LoginError> {
+        let Ok(output_key) = XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..]) else {
+            return Ok(false);
+        };
+        let mut stack: Vec<&[u8]> = witness.iter().collect();
+        let annex = match stack.last() {
+            Some(last) if stack.len() >= 2 && last.first() == Some(&TAPROOT_ANNEX_PREFIX) => {
+                stack.pop().and_then(|annex| Annex::new(annex).ok())
+            }
+            _ => None,
+        };
+
+        match stack.as_slice() {
+            // Key path spend.
+            [signature] => Ok(self.verify_schnorr(signature, &output_key, annex, None)),
+            // Script path spend of a single key leaf.
+            [rest @ .., leaf_script, control_block] => {
+                let leaf_script = Script::from_bytes(leaf_script);
+                let Ok(control_block) = ControlBlock::decode(control_block) else {
+                    return Ok(false);
+                };
+                if control_block.leaf_version != LeafVersion::TapScript
+                    || !control_block.verify_taproot_commitment(
+                        &Secp256k1::verification_only(),
+                        output_key,
+                        leaf_script,
+                    )
+                {
+                    return Ok(false);
+                }
+                let mut instructions = leaf_script.instructions();
+                let key = match (
+                    instructions.next(),
+                    instructions.next(),
+                    instructions.next(),
+                ) {
+                    (
+                        Some(Ok(Instruction::PushBytes(key))),
+                        Some(Ok(Instruction::Op(OP_CHECKSIG))),
+                        None,
+                    ) => XOnlyPublicKey::from_slice(key.as_bytes()),
+                    _ => return Err(LoginError::BtcError(BtcError::AddressTypeNotSupported)),
+                };
+                let (Ok(key), [signature]) = (key, rest) else {
+                    return Ok(false);
+                };
+                let leaf_hash = TapLeafHash::from_script(leaf_script, LeafVersion::TapScript);
+                Ok(self.verify_schnorr(signature, &key, annex, Some((leaf_hash, u32::MAX))))
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn verify_schnorr(
+        &self,
+        signature: &[u8],
+        key: &XOnlyPublicKey,
+        annex: Option<Annex>,
+        leaf: Option<(TapLeafHash, u32)>,
+    ) -> bool {
+        let Ok(signature) = bitcoin::taproot::Signature::from_slice(signature) else {
+            return false;
+        };
+        let prevouts = [&self.prevout];
+        let sighash = SighashCache::new(self.tx).taproot_signature_hash(
+            0,
+            &Prevouts::All(&prevouts),
+            annex,
+            leaf,
+            signature.hash_ty,
+        );
+        match sighash.map(|h| Message::from_slice(h.as_byte_array())) {
+            Ok(Ok(message)) => Secp256k1::verification_only()
+                .verify_schnorr(&signature.sig, &message, key)
+                .is_ok(),
+            _ => false,
+        }
+    }
+}
+
+/// The value of OP_1 through OP_16.
+fn small_int(op: bitcoin::opcodes::All) -> Option<usize> {
+    let op = op.to_u8();
+    (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8())
+        .contains(&op)
+        .then(|| (op - OP_PUSHNUM_1.to_u8() + 1) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose;
+    use base64::Engine;
+    use bitcoin::consensus::serialize;
+    use bitcoin::key::TapTweak;
+    use bitcoin::opcodes::all::{OP_PUSHNUM_2, OP_PUSHNUM_3};
+    use bitcoin::script::Builder;
+    use bitcoin::secp256k1::{KeyPair, SecretKey};
+    use bitcoin::sighash::{EcdsaSighashType, TapSighashType};
+    use bitcoin::taproot::TaprootBuilder;
+    use bitcoin::{Network, TxIn};
+
+    const MESSAGE: &str = "Hello World";
+
+    fn secret_key(byte: u8) -> SecretKey {
+        SecretKey::from_slice(&[byte; 32]).unwrap()
+    }
+
+    fn public_key(byte: u8) -> PublicKey {
+        PublicKey::new(secret_key(byte).public_key(&Secp256k1::new()))
+    }
+
+    fn to_sign(address: &Address) -> Transaction {
+        bip0322_tx(&bip0322_hash(MESSAGE), address.script_pubkey())
+    }
+
+    fn encode(tx: &Transaction) -> String {
+        general_purpose::STANDARD.encode(serialize(tx))
+    }
+
+    fn ecdsa_signature(tx: &Transaction, byte: u8, script_code: &Script, segwit: bool) -> Vec<u8> {
+        let mut cache = SighashCache::new(tx);
+        let sighash = if segwit {
+            cache
+                .segwit_signature_hash(0, script_code, 0, EcdsaSighashType::All)
+                .unwrap()
+                .to_byte_array()
+        } else {
+            cache
+                .legacy_signature_hash(0, script_code, EcdsaSighashType::All.to_u32())
+                .unwrap()
+                .to_byte_array()
+        };
+        let sig =
+            Secp256k1::new().sign_ecdsa(&Message::from_slice(&sighash).unwrap(), &secret_key(byte));
+        bitcoin::ecdsa::Signature {
+            sig,
+            hash_ty: EcdsaSighashType::All,
+        }
+        .to_vec()
+    }
+
+    fn schnorr_signature(
+        tx: &Transaction,
+        address: &Address,
+        key_pair: &KeyPair,
+        leaf: Option<(TapLeafHash, u32)>,
+    ) -> Vec<u8> {
+        let prevouts = [TxOut {
+            value: 0,
+            script_pubkey: address.script_pubkey(),
+        }];
+        let sighash = SighashCache::new(tx)
+            .taproot_signature_hash(
+                0,
+                &Prevouts::All(&prevouts),
+                None,
+                leaf,
+                TapSighashType::Default,
+            )
+            .unwrap();
+        let message = Message::from_slice(sighash.as_byte_array()).unwrap();
+        Secp256k1::new()
+            .sign_schnorr_no_aux_rand(&message, key_pair)
+            .as_ref()
+            .to_vec()
+    }
+
+    #[test]
+    fn test_p2wpkh_proof() {
+        let address = Address::p2wpkh(&public_key(1), Network::Bitcoin).unwrap();
+        let mut tx = to_sign(&address);
+        let script_code = ScriptBuf::new_p2pkh(&public_key(1).pubkey_hash());
+        let signature = ecdsa_signature(&tx, 1, &script_code, true);
+        tx.input[0].witness = Witness::from_slice(&[signature, public_key(1).to_bytes()]);
+
+        assert!(verify_bip322_full(MESSAGE, &address, &encode(&tx)).is_ok());
+        assert!(matches!(
+            verify_bip322_full("Hello World!", &address, &encode(&tx)),
+            Err(LoginError::AddressMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_p2sh_p2wpkh_proof() {
+        let address = Address::p2shwpkh(&public_key(1), Network::Bitcoin).unwrap();
+        let redeem_script = ScriptBuf::new_v0_p2wpkh(&public_key(1).wpubkey_hash().unwrap());
+        let mut tx = to_sign(&address);
+        let script_code = ScriptBuf::new_p2pkh(&public_key(1).pubkey_hash());
+        let signature = ecdsa_signature(&tx, 1, &script_code, true);
+        tx.input[0].script_sig = Builder::new()
+            .push_slice(<&bitcoin::script::PushBytes>::try_from(redeem_script.as_bytes()).unwrap())
+            .into_script();
+        tx.input[0].witness = Witness::from_slice(&[signature, public_key(1).to_bytes()]);
+
+        assert!(verify_bip322_full(MESSAGE, &address, &encode(&tx)).is_ok());
+    }
+
+    #[test]
+    fn test_p2wsh_multisig_proof() {
+        let witness_script = Builder::new()
+            .push_opcode(OP_PUSHNUM_2)
+            .push_key(&public_key(1))
+            .push_key(&public_key(2))
+            .push_key(&public_key(3))
+            .push_opcode(OP_PUSHNUM_3)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script();
+        let address = Address::p2wsh(&witness_script, Network::Bitcoin);
+        let tx = to_sign(&address);
+        let first = ecdsa_signature(&tx, 1, &witness_script, true);
+        let third = ecdsa_signature(&tx, 3, &witness_script, true);
+
+        let mut signed = tx.clone();
+        signed.input[0].witness = Witness::from_slice(&[
+            vec![],
+            first.clone(),
+            third.clone(),
+            witness_script.to_bytes(),
+        ]);
+        let signer = crate::login::verify_signature(
+            MESSAGE,
+            &address,
+            &encode(&signed),
+            "",
+            crate::login::SignMessageType::Bip322Full,
+        )
+        .unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(signer.address_type, "p2wsh");
+
+        // OP_CHECKMULTISIG expects the signatures in the order of the keys.
+        let mut reordered = tx;
+        reordered.input[0].witness =
+            Witness::from_slice(&[vec![], third, first, witness_script.to_bytes()]);
+        assert!(verify_bip322_full(MESSAGE, &address, &encode(&reordered)).is_err());
+    }
+
+    #[test]
+    fn test_p2tr_key_and_script_path_proofs() {
+        let secp = Secp256k1::new();
+        let internal = KeyPair::from_secret_key(&secp, &secret_key(1));
+
+        let address = Address::p2tr(
+            &secp,
+            internal.x_only_public_key().0,
+            None,
+            Network::Bitcoin,
+        );
+        let mut tx = to_sign(&address);
+        let tweaked = internal.tap_tweak(&secp, None).to_inner();
+        tx.input[0].witness =
+            Witness::from_slice(&[schnorr_signature(&tx, &address, &tweaked, None)]);
+        assert!(verify_bip322_full(MESSAGE, &address, &encode(&tx)).is_ok());
+
+        let leaf_key = KeyPair::from_secret_key(&secp, &secret_key(2));
+        let leaf_script = Builder::new()
+            .push_x_only_key(&leaf_key.x_only_public_key().0)
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        let spend_info = TaprootBuilder::new()
+            .add_leaf(0, leaf_script.clone())
+            .unwrap()
+            .finalize(&secp, internal.x_only_public_key().0)
+            .unwrap();
+        let address = Address::p2tr_tweaked(spend_info.output_key(), Network::Bitcoin);
+        let control_block = spend_info
+            .control_block(&(leaf_script.clone(), LeafVersion::TapScript))
+            .unwrap();
+        let leaf_hash = TapLeafHash::from_script(&leaf_script, LeafVersion::TapScript);
+        let mut tx = to_sign(&address);
+        let signature = schnorr_signature(&tx, &address, &leaf_key, Some((leaf_hash, u32::MAX)));
+        tx.input[0].witness =
+            Witness::from_slice(&[signature, leaf_script.to_bytes(), control_block.serialize()]);
+        assert!(verify_bip322_full(MESSAGE, &address, &encode(&tx)).is_ok());
+    }
+
+    #[test]
+    fn test_p2pkh_proof() {
+        let address = Address::p2pkh(&public_key(1), Network::Bitcoin);
+        let mut tx = to_sign(&address);
+        let signature = ecdsa_signature(&tx, 1, &address.script_pubkey(), false);
+        tx.input[0].script_sig = Builder::new()
+            .push_slice(<&bitcoin::script::PushBytes>::try_from(signature.as_slice()).unwrap())
+            .push_key(&public_key(1))
+            .into_script();
+
+        assert!(verify_bip322_full(MESSAGE, &address, &encode(&tx)).is_ok());
+    }
+
+    #[test]
+    fn test_proof_of_funds_is_rejected() {
+        let address = Address::p2wpkh(&public_key(1), Network::Bitcoin).unwrap();
+        let mut tx = to_sign(&address);
+        tx.input.push(TxIn::default());
+
+        assert!(matches!(
+            verify_bip322_full(MESSAGE, &address, &encode(&tx)),
+            Err(LoginError::BtcError(BtcError::SignatureFormatError(_)))
+        ));
+    }
+}
@@ -0,0 +1,155 @@
+use bitcoin::AddressType;
+use candid::{CandidType, Deserialize};
+
+use crate::login::SignMessageType;
+use crate::settings::Settings;
+use crate::with_settings;
+
+/// The address types the library can verify and the message signature types each of them accepts. ECDSA
+/// signatures for P2SH addresses are verified as P2SH-P2WPKH, and BIP-322 simple is only implemented for P2WPKH
+/// and key-path P2TR. Full BIP-322 proofs are limited to the templates listed in [`crate::bip322`].
+pub const SIGNATURE_MATRIX: &[(AddressType, &[SignMessageType])] = &[
+    (
+        AddressType::P2pkh,
+        &[SignMessageType::ECDSA, SignMessageType::Bip322Full],
+    ),
+    (
+        AddressType::P2sh,
+        &[SignMessageType::ECDSA, SignMessageType::Bip322Full],
+    ),
+    (
+        AddressType::P2wpkh,
+        &[
+            SignMessageType::ECDSA,
+            SignMessageType::Bip322Simple,
+            SignMessageType::Bip322Full,
+        ],
+    ),
+    (
+        AddressType::P2wsh,
+        &[SignMessageType::Bip322Simple, SignMessageType::Bip322Full],
+    ),
+    (
+        AddressType::P2tr,
+        &[
+            SignMessageType::ECDSA,
+            SignMessageType::Bip322Simple,
+            SignMessageType::Bip322Full,
+        ],
+    ),
+];
+
+/// An address type and the ways its owner can sign the SIWB message.
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct AddressTypeSupport {
+    /// The address type in the format of `VerifiedSigner::address_type`, e.g. "p2tr".
+    pub address_type: String,
+
+    pub sign_message_types: Vec<SignMessageType>,
+}
+
+/// What a deployment accepts, so that frontends can offer exactly the wallets and address types that will
+/// work instead of hardcoding assumptions.
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct SupportedCapabilities {
+    /// The network of accepted addresses in the format of `VerifiedSigner::network`, e.g. "testnet".
+    pub network: String,
+
+    /// The address types that can sign in, i.e. those of [`SIGNATURE_MATRIX`] allowed by
+    /// `Settings::allowed_address_types`.
+    pub address_types: Vec<AddressTypeSupport>,
+
+    /// The enabled runtime features, e.g. "IncludeUriInSeed".
+    pub runtime_features: Vec<String>,
+
+    /// Whether SIWB messages carry a random nonce, i.e. whether the `nonce` cargo feature is enabled.
+    pub nonce: bool,
+}
+
+/// Describes what the library accepts under the current settings.
+pub fn supported_capabilities() -> SupportedCapabilities {
+    with_settings!(|settings: &Settings| {
+        let address_types = SIGNATURE_MATRIX
+            .iter()
+            .filter(|(address_type, _)| {
+                settings
+                    .allowed_address_types
+                    .as_ref()
+                    .is_none_or(|allowed| allowed.contains(address_type))
+            })
+            .map(|(address_type, sign_message_types)| AddressTypeSupport {
+                address_type: address_type.to_string(),
+                sign_message_types: sign_message_types.to_vec(),
+            })
+            .collect();
+        SupportedCapabilities {
+            network: settings.network.to_string(),
+            address_types,
+            runtime_features: settings
+                .runtime_features
+                .iter()
+                .flatten()
+                .map(|feature| format!("{:?}", feature))
+                .collect(),
+            nonce: cfg!(feature = "nonce"),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::{RuntimeFeature, SettingsBuilder};
+    use crate::SETTINGS;
+    use bitcoin::Network;
+
+    #[test]
+    fn test_supported_capabilities_defaults() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .build()
+            .unwrap();
+        SETTINGS.set(Some(settings));
+
+        let capabilities = supported_capabilities();
+        assert_eq!(capabilities.network, "bitcoin");
+        let types: Vec<&str> = capabilities
+            .address_types
+            .iter()
+            .map(|a| a.address_type.as_str())
+            .collect();
+        assert_eq!(types, vec!["p2pkh", "p2sh", "p2wpkh", "p2wsh", "p2tr"]);
+        assert!(capabilities.runtime_features.is_empty());
+    }
+
+    #[test]
+    fn test_supported_capabilities_follow_settings() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .network(Network::Testnet)
+            .allowed_address_types(vec![AddressType::P2tr, AddressType::P2pkh])
+            .runtime_features(vec![RuntimeFeature::IncludeUriInSeed])
+            .build()
+            .unwrap();
+        SETTINGS.set(Some(settings));
+
+        let capabilities = supported_capabilities();
+        assert_eq!(capabilities.network, "testnet");
+        assert_eq!(
+            capabilities.address_types,
+            vec![
+                AddressTypeSupport {
+                    address_type: "p2pkh".to_string(),
+                    sign_message_types: vec![SignMessageType::ECDSA, SignMessageType::Bip322Full],
+                },
+                AddressTypeSupport {
+                    address_type: "p2tr".to_string(),
+                    sign_message_types: vec![
+                        SignMessageType::ECDSA,
+                        SignMessageType::Bip322Simple,
+                        SignMessageType::Bip322Full
+                    ],
+                },
+            ]
+        );
+        assert_eq!(capabilities.runtime_features, vec!["IncludeUriInSeed"]);
+    }
+}
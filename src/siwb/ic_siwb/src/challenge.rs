@@ -0,0 +1,227 @@
+use crate::time::get_current_time;
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum ChallengeError {
+    /// The nonce was never issued, or the challenge was consumed or pruned.
+    ChallengeNotFound,
+    /// The challenge existed but expired at `expired_at` (nanoseconds since the UNIX epoch).
+    ChallengeExpired { expired_at: u64 },
+    /// A challenge with this nonce is still outstanding.
+    NonceInUse,
+    /// The store holds `capacity` unexpired challenges.
+    StoreFull,
+}
+
+impl fmt::Display for ChallengeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChallengeError::ChallengeNotFound => write!(f, "Challenge not found"),
+            ChallengeError::ChallengeExpired { expired_at } => {
+                write!(f, "Challenge expired at {}", expired_at)
+            }
+            ChallengeError::NonceInUse => write!(f, "Nonce already in use"),
+            ChallengeError::StoreFull => write!(f, "Too many outstanding challenges"),
+        }
+    }
+}
+
+impl From<ChallengeError> for String {
+    fn from(error: ChallengeError) -> Self {
+        error.to_string()
+    }
+}
+
+/// An outstanding challenge and the data the flow attached to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Challenge<T> {
+    pub nonce: String,
+    pub metadata: T,
+    pub issued_at: u64,
+    pub expires_at: u64,
+}
+
+/// A bounded store of one-time challenges for signed-challenge flows other than login, e.g. linking an
+/// address or signing an attestation. Each nonce maps to flow-specific metadata and expires after the
+/// store's TTL; a challenge can be consumed once.
+///
+/// Expired challenges are pruned lazily, whenever a challenge is inserted.
+pub struct ChallengeStore<T> {
+    challenges: HashMap<String, Challenge<T>>,
+    expirations: BTreeSet<(u64, String)>,
+    ttl: u64,
+    capacity: usize,
+}
+
+impl<T: Clone> ChallengeStore<T> {
+    /// Creates a store whose challenges live for `ttl` nanoseconds, holding at most `capacity` of them.
+    pub fn new(ttl: u64, capacity: usize) -> ChallengeStore<T> {
+        ChallengeStore {
+            challenges: HashMap::new(),
+            expirations: BTreeSet::new(),
+            ttl,
+            capacity,
+        }
+    }
+
+    /// Stores a challenge for `nonce`. The nonce must be unpredictable, e.g. from `raw_rand`, and is the
+    /// only thing that identifies the challenge.
+    pub fn insert(&mut self, nonce: String, metadata: T) -> Result<Challenge<T>, ChallengeError> {
+        self.insert_at(nonce, metadata, get_current_time())
+    }
+
+    fn insert_at(
+        &mut self,
+        nonce: String,
+        metadata: T,
+        now: u64,
+    ) -> Result<Challenge<T>, ChallengeError> {
+        self.prune_expired_at(now);
+        if self.challenges.contains_key(&nonce) {
+            return Err(ChallengeError::NonceInUse);
+        }
+        if self.challenges.len() >= self.capacity {
+            return Err(ChallengeError::StoreFull);
+        }
+        let challenge = Challenge {
+            nonce: nonce.clone(),
+            metadata,
+            issued_at: now,
+            expires_at: now.saturating_add(self.ttl),
+        };
+        self.expirations
+            .insert((challenge.expires_at, nonce.clone()));
+        self.challenges.insert(nonce, challenge.clone());
+        Ok(challenge)
+    }
+
+    /// Returns the challenge for `nonce` without consuming it.
+    pub fn get(&self, nonce: &str) -> Result<Challenge<T>, ChallengeError> {
+        self.get_at(nonce, get_current_time())
+    }
+
+    fn get_at(&self, nonce: &str, now: u64) -> Result<Challenge<T>, ChallengeError> {
+        match self.challenges.get(nonce) {
+            Some(challenge) if now > challenge.expires_at => {
+                Err(ChallengeError::ChallengeExpired {
+                    expired_at: challenge.expires_at,
+                })
+            }
+            Some(challenge) => Ok(challenge.clone()),
+            None => Err(ChallengeError::ChallengeNotFound),
+        }
+    }
+
+    /// Removes and returns the challenge for `nonce`. Call this only after the response has been verified,
+    /// so a bad signature does not burn the challenge. An expired challenge is removed as well.
+    pub fn consume(&mut self, nonce: &str) -> Result<Challenge<T>, ChallengeError> {
+        self.consume_at(nonce, get_current_time())
+    }
+
+    fn consume_at(&mut self, nonce: &str, now: u64) -> Result<Challenge<T>, ChallengeError> {
+        let result = self.get_at(nonce, now);
+        if let Some(challenge) = self.challenges.remove(nonce) {
+            self.expirations
+                .remove(&(challenge.expires_at, challenge.nonce));
+        }
+        result
+    }
+
+    /// Removes challenges that have expired and returns how many were removed.
+    pub fn prune_expired(&mut self) -> usize {
+        self.prune_expired_at(get_current_time())
+    }
+
+    fn prune_expired_at(&mut self, now: u64) -> usize {
+        let mut pruned = 0;
+        while let Some((expires_at, _)) = self.expirations.first() {
+            if *expires_at >= now {
+                break;
+            }
+            if let Some((_, nonce)) = self.expirations.pop_first() {
+                self.challenges.remove(&nonce);
+                pruned += 1;
+            }
+        }
+        pruned
+    }
+
+    /// The number of stored challenges, including expired ones that have not been pruned yet.
+    pub fn len(&self) -> usize {
+        self.challenges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.challenges.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::challenge::{ChallengeError, ChallengeStore};
+
+    const TTL: u64 = 60;
+
+    #[test]
+    fn test_consume_once() {
+        let mut store = ChallengeStore::new(TTL, 10);
+        store.insert_at("n1".to_string(), "link", 100).unwrap();
+
+        let challenge = store.consume_at("n1", 120).unwrap();
+        assert_eq!(challenge.metadata, "link");
+        assert_eq!(challenge.expires_at, 160);
+        assert_eq!(
+            store.consume_at("n1", 120),
+            Err(ChallengeError::ChallengeNotFound)
+        );
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_expired_challenge() {
+        let mut store = ChallengeStore::new(TTL, 10);
+        store.insert_at("n1".to_string(), (), 100).unwrap();
+
+        assert!(store.get_at("n1", 160).is_ok());
+        assert_eq!(
+            store.consume_at("n1", 161),
+            Err(ChallengeError::ChallengeExpired { expired_at: 160 })
+        );
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_nonce_in_use() {
+        let mut store = ChallengeStore::new(TTL, 10);
+        store.insert_at("n1".to_string(), 1, 100).unwrap();
+        assert_eq!(
+            store.insert_at("n1".to_string(), 2, 110),
+            Err(ChallengeError::NonceInUse)
+        );
+
+        // Once expired, the nonce may be issued again
+        assert!(store.insert_at("n1".to_string(), 3, 200).is_ok());
+        assert_eq!(store.get_at("n1", 200).unwrap().metadata, 3);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut store = ChallengeStore::new(TTL, 2);
+        store.insert_at("n1".to_string(), (), 100).unwrap();
+        store.insert_at("n2".to_string(), (), 110).unwrap();
+        assert_eq!(
+            store.insert_at("n3".to_string(), (), 120),
+            Err(ChallengeError::StoreFull)
+        );
+
+        // Inserting prunes expired challenges first
+        assert!(store.insert_at("n3".to_string(), (), 165).is_ok());
+        assert_eq!(store.len(), 2);
+        assert_eq!(
+            store.get_at("n1", 165),
+            Err(ChallengeError::ChallengeNotFound)
+        );
+    }
+}
@@ -0,0 +1,345 @@
+//! OP_RETURN commitments that bind a Bitcoin transaction to a principal or to a Merkle root, for attestations
+//! and state snapshots anchored on chain.
+//!
+//! The payload is the ASCII tag, a colon and a 32-byte digest, e.g. `REPDAO:<sha256(principal)>`, pushed as
+//! the only data of an OP_RETURN output.
+
+use bitcoin::blockdata::opcodes::all::OP_RETURN;
+use bitcoin::blockdata::script::{Instruction, PushBytesBuf};
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::{Script, ScriptBuf, Transaction};
+use candid::Principal;
+use std::fmt;
+
+use crate::hash::hash_bytes;
+use ic_certified_map::Hash;
+use sha2::{Digest, Sha256};
+
+/// The largest OP_RETURN payload relayed by default (`-datacarriersize` is 83 bytes of script).
+pub const MAX_OP_RETURN_PAYLOAD: usize = 80;
+
+/// The longest tag accepted by [`principal_commitment`].
+pub const MAX_COMMITMENT_TAG_LEN: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum CommitmentError {
+    /// The tag is empty, too long, or contains a colon or a non-printable character.
+    InvalidTag,
+    PayloadTooLarge(usize),
+    TransactionDecodingError(String),
+    /// No output of the transaction commits to the principal.
+    CommitmentNotFound,
+}
+
+impl fmt::Display for CommitmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommitmentError::InvalidTag => write!(f, "Invalid commitment tag"),
+            CommitmentError::PayloadTooLarge(size) => write!(
+                f,
+                "OP_RETURN payload of {} bytes exceeds {} bytes",
+                size, MAX_OP_RETURN_PAYLOAD
+            ),
+            CommitmentError::TransactionDecodingError(e) => {
+                write!(f, "Transaction decoding error: {}", e)
+            }
+            CommitmentError::CommitmentNotFound => write!(f, "Commitment not found"),
+        }
+    }
+}
+
+impl From<CommitmentError> for String {
+    fn from(error: CommitmentError) -> Self {
+        error.to_string()
+    }
+}
+
+fn validate_tag(tag: &str) -> Result<(), CommitmentError> {
+    let valid = !tag.is_empty()
+        && tag.len() <= MAX_COMMITMENT_TAG_LEN
+        && tag.bytes().all(|b| b.is_ascii_graphic() && b != b':');
+    if valid {
+        Ok(())
+    } else {
+        Err(CommitmentError::InvalidTag)
+    }
+}
+
+/// The commitment payload for `digest` under `tag`: `<tag>:<digest>`.
+pub fn tagged_commitment(tag: &str, digest: &Hash) -> Result<Vec<u8>, CommitmentError> {
+    validate_tag(tag)?;
+    let mut payload = Vec::with_capacity(tag.len() + 33);
+    payload.extend_from_slice(tag.as_bytes());
+    payload.push(b':');
+    payload.extend_from_slice(digest);
+    Ok(payload)
+}
+
+/// The commitment payload for `principal` under `tag`: `<tag>:<sha256(principal)>`.
+pub fn principal_commitment(tag: &str, principal: &Principal) -> Result<Vec<u8>, CommitmentError> {
+    tagged_commitment(tag, &hash_bytes(principal.as_slice()))
+}
+
+/// Builds the OP_RETURN output script carrying `payload`.
+pub fn op_return_script(payload: &[u8]) -> Result<ScriptBuf, CommitmentError> {
+    if payload.len() > MAX_OP_RETURN_PAYLOAD {
+        return Err(CommitmentError::PayloadTooLarge(payload.len()));
+    }
+    let data = PushBytesBuf::try_from(payload.to_vec())
+        .map_err(|_| CommitmentError::PayloadTooLarge(payload.len()))?;
+    Ok(ScriptBuf::new_op_return(&data))
+}
+
+/// Returns the first output of `transaction` carrying `payload` in an OP_RETURN.
+pub fn find_commitment(transaction: &Transaction, payload: &[u8]) -> Option<usize> {
+    transaction
+        .output
+        .iter()
+        .position(|output| parse_op_return(&output.script_pubkey).as_deref() == Some(payload))
+}
+
+/// Returns the payload of an OP_RETURN script holding a single data push, or None for any other script.
+pub fn parse_op_return(script: &Script) -> Option<Vec<u8>> {
+    let mut instructions = script.instructions();
+    match instructions.next() {
+        Some(Ok(Instruction::Op(op))) if op == OP_RETURN => {}
+        _ => return None,
+    }
+    let payload = match instructions.next() {
+        Some(Ok(Instruction::PushBytes(data))) => data.as_bytes().to_vec(),
+        _ => return None,
+    };
+    match instructions.next() {
+        None => Some(payload),
+        Some(_) => None,
+    }
+}
+
+/// Decodes a consensus-serialized transaction, e.g. one fetched from a block explorer as raw bytes.
+pub fn decode_transaction(raw: &[u8]) -> Result<Transaction, CommitmentError> {
+    deserialize(raw).map_err(|e| CommitmentError::TransactionDecodingError(e.to_string()))
+}
+
+/// Returns the index of the first output of `transaction` that commits to `principal` under `tag`.
+///
+/// This only checks the transaction itself. Callers must establish separately that it was mined, e.g.
+/// through the Bitcoin canister, before treating the commitment as anchored.
+pub fn verify_principal_commitment(
+    transaction: &Transaction,
+    tag: &str,
+    principal: &Principal,
+) -> Result<usize, CommitmentError> {
+    let expected = principal_commitment(tag, principal)?;
+    find_commitment(transaction, &expected).ok_or(CommitmentError::CommitmentNotFound)
+}
+
+/// Hashes a Merkle leaf. Leaves and inner nodes use distinct prefixes, as in RFC 6962, so a leaf can never
+/// be passed off as an inner node.
+pub fn merkle_leaf_hash(data: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([0u8]);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+fn merkle_node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([1u8]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// One step of a [`merkle_proof`]: the sibling hash and whether it sits on the left.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MerkleStep {
+    pub sibling: Hash,
+    pub sibling_on_left: bool,
+}
+
+fn next_level(level: &[Hash]) -> Vec<Hash> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => merkle_node_hash(left, right),
+            // An odd node is promoted unchanged
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// The root of a binary Merkle tree over already hashed `leaves`; the hash of nothing for an empty tree.
+pub fn merkle_root(leaves: &[Hash]) -> Hash {
+    if leaves.is_empty() {
+        return hash_bytes([]);
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+/// The path from leaf `index` to the root, or None if `index` is out of range.
+pub fn merkle_proof(leaves: &[Hash], index: usize) -> Option<Vec<MerkleStep>> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let mut proof = Vec::new();
+    let mut level = leaves.to_vec();
+    let mut index = index;
+    while level.len() > 1 {
+        let sibling = index ^ 1;
+        if sibling < level.len() {
+            proof.push(MerkleStep {
+                sibling: level[sibling],
+                sibling_on_left: sibling < index,
+            });
+        }
+        level = next_level(&level);
+        index /= 2;
+    }
+    Some(proof)
+}
+
+/// Recomputes the root from `leaf` and `proof`.
+pub fn merkle_root_from_proof(leaf: &Hash, proof: &[MerkleStep]) -> Hash {
+    proof.iter().fold(*leaf, |node, step| {
+        if step.sibling_on_left {
+            merkle_node_hash(&step.sibling, &node)
+        } else {
+            merkle_node_hash(&node, &step.sibling)
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::commitment::{
+        decode_transaction, merkle_leaf_hash, merkle_proof, merkle_root, merkle_root_from_proof,
+        op_return_script, parse_op_return, principal_commitment, verify_principal_commitment,
+        CommitmentError, MAX_OP_RETURN_PAYLOAD,
+    };
+    use bitcoin::absolute::LockTime;
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::{ScriptBuf, Transaction, TxOut};
+    use candid::Principal;
+
+    fn principal(n: u8) -> Principal {
+        Principal::from_slice(&[n; 29])
+    }
+
+    fn transaction(scripts: Vec<ScriptBuf>) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: scripts
+                .into_iter()
+                .map(|script_pubkey| TxOut {
+                    value: 0,
+                    script_pubkey,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_commitment_round_trip() {
+        let payload = principal_commitment("REPDAO", &principal(1)).unwrap();
+        assert_eq!(payload.len(), 7 + 32);
+        assert!(payload.starts_with(b"REPDAO:"));
+
+        let script = op_return_script(&payload).unwrap();
+        assert!(script.is_op_return());
+        assert_eq!(parse_op_return(&script), Some(payload));
+    }
+
+    #[test]
+    fn test_invalid_tags() {
+        for tag in ["", "REP:DAO", "REP DAO", "A_TAG_LONGER_THAN_16"] {
+            assert_eq!(
+                principal_commitment(tag, &principal(1)),
+                Err(CommitmentError::InvalidTag)
+            );
+        }
+    }
+
+    #[test]
+    fn test_payload_too_large() {
+        assert_eq!(
+            op_return_script(&[0u8; MAX_OP_RETURN_PAYLOAD + 1]),
+            Err(CommitmentError::PayloadTooLarge(MAX_OP_RETURN_PAYLOAD + 1))
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_other_scripts() {
+        let p2wpkh = ScriptBuf::from_hex("0014841c0ced434be44ff4afaa1aac333658f31505").unwrap();
+        assert_eq!(parse_op_return(&p2wpkh), None);
+
+        // OP_RETURN followed by two pushes
+        let split = ScriptBuf::from_hex("6a0201020103").unwrap();
+        assert_eq!(parse_op_return(&split), None);
+    }
+
+    #[test]
+    fn test_verify_commitment_in_transaction() {
+        let payload = principal_commitment("REPDAO", &principal(1)).unwrap();
+        let p2wpkh = ScriptBuf::from_hex("0014841c0ced434be44ff4afaa1aac333658f31505").unwrap();
+        let tx = transaction(vec![p2wpkh, op_return_script(&payload).unwrap()]);
+
+        // Round trip through the wire format, as a canister would receive it
+        let tx = decode_transaction(&serialize(&tx)).unwrap();
+        assert_eq!(
+            verify_principal_commitment(&tx, "REPDAO", &principal(1)),
+            Ok(1)
+        );
+        assert_eq!(
+            verify_principal_commitment(&tx, "REPDAO", &principal(2)),
+            Err(CommitmentError::CommitmentNotFound)
+        );
+        assert_eq!(
+            verify_principal_commitment(&tx, "OTHER", &principal(1)),
+            Err(CommitmentError::CommitmentNotFound)
+        );
+    }
+
+    #[test]
+    fn test_decode_invalid_transaction() {
+        assert!(matches!(
+            decode_transaction(&[0u8; 3]),
+            Err(CommitmentError::TransactionDecodingError(_))
+        ));
+    }
+
+    #[test]
+    fn test_merkle_proofs() {
+        for size in 1..=9u8 {
+            let leaves: Vec<_> = (0..size).map(|n| merkle_leaf_hash(&[n])).collect();
+            let root = merkle_root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = merkle_proof(&leaves, index).unwrap();
+                assert_eq!(merkle_root_from_proof(leaf, &proof), root);
+            }
+            assert!(merkle_proof(&leaves, size as usize).is_none());
+        }
+    }
+
+    #[test]
+    fn test_merkle_root_binds_order_and_content() {
+        let leaves: Vec<_> = (0..4u8).map(|n| merkle_leaf_hash(&[n])).collect();
+        let mut swapped = leaves.clone();
+        swapped.swap(0, 1);
+        assert_ne!(merkle_root(&leaves), merkle_root(&swapped));
+        assert_eq!(merkle_root(&leaves[..1]), leaves[0]);
+
+        // A proof for one leaf does not verify another
+        let proof = merkle_proof(&leaves, 2).unwrap();
+        assert_ne!(
+            merkle_root_from_proof(&leaves[3], &proof),
+            merkle_root(&leaves)
+        );
+    }
+}
@@ -2,6 +2,7 @@ use std::{collections::HashMap, fmt};
 
 use super::hash::{self, Value};
 use crate::{
+    session_epoch,
     settings::{RuntimeFeature, Settings},
     signature_map::SignatureMap,
     with_settings,
@@ -68,6 +69,8 @@ struct CertificateSignature<'a> {
 }
 
 /// Generates a unique seed for delegation, derived from the salt, Bitcoin address, and SIWB message URI.
+/// When the [`RuntimeFeature::IncludeSessionEpochInSeed`] feature is enabled the current session epoch
+/// is appended as well. See [`seed_preimage`] for the exact byte layout.
 ///
 /// # Parameters
 /// * `address`: The Bitcoin address as a string slice.
@@ -76,31 +79,66 @@ struct CertificateSignature<'a> {
 /// A `Hash` value representing the unique seed.
 pub fn generate_seed(address: &Address) -> Hash {
     with_settings!(|settings: &Settings| {
-        let mut seed: Vec<u8> = vec![];
-
-        let salt = settings.salt.as_bytes();
-        seed.push(salt.len() as u8);
-        seed.extend_from_slice(salt);
-
-        let binding = address.to_string();
-        let address_bytes = binding.as_bytes();
-        seed.push(address_bytes.len() as u8);
-        seed.extend(address_bytes);
-
-        // Only include the URI in the seed if the runtime feature is enabled
-        match settings.runtime_features {
-            Some(ref features) if features.contains(&RuntimeFeature::IncludeUriInSeed) => {
-                let uri = settings.uri.as_bytes();
-                seed.push(uri.len() as u8);
-                seed.extend_from_slice(uri);
-            }
-            _ => (),
-        }
+        generate_seed_with(address, &settings.salt, &settings.uri)
+    })
+}
 
-        hash::hash_bytes(seed)
+/// Like [`generate_seed`], but derives the seed from the given salt and URI in place of the configured ones,
+/// e.g. to find the principal an address had before the salt or URI was changed.
+pub fn generate_seed_with(address: &Address, salt: &str, uri: &str) -> Hash {
+    with_settings!(|settings: &Settings| {
+        let has_feature = |feature: RuntimeFeature| {
+            settings
+                .runtime_features
+                .as_ref()
+                .is_some_and(|features| features.contains(&feature))
+        };
+
+        // Only include the URI and session epoch in the seed if the runtime features are enabled
+        let uri = has_feature(RuntimeFeature::IncludeUriInSeed).then_some(uri);
+        let epoch = has_feature(RuntimeFeature::IncludeSessionEpochInSeed).then(session_epoch);
+
+        hash::hash_bytes(seed_preimage(salt, &address.to_string(), uri, epoch))
     })
 }
 
+/// Returns the bytes hashed (SHA-256) by [`generate_seed`]. Each component is prefixed with its length
+/// as a single byte:
+///
+/// ```text
+/// len(salt) || salt || len(address) || address [ || len(uri) || uri ] [ || 0x08 || epoch as u64 big-endian ]
+/// ```
+///
+/// `address` is the textual Bitcoin address. The URI is only present with
+/// [`RuntimeFeature::IncludeUriInSeed`] and the epoch only with [`RuntimeFeature::IncludeSessionEpochInSeed`].
+pub fn seed_preimage(
+    salt: &str,
+    address: &str,
+    uri: Option<&str>,
+    session_epoch: Option<u64>,
+) -> Vec<u8> {
+    let mut seed: Vec<u8> = vec![];
+
+    seed.push(salt.len() as u8);
+    seed.extend_from_slice(salt.as_bytes());
+
+    seed.push(address.len() as u8);
+    seed.extend_from_slice(address.as_bytes());
+
+    if let Some(uri) = uri {
+        seed.push(uri.len() as u8);
+        seed.extend_from_slice(uri.as_bytes());
+    }
+
+    if let Some(epoch) = session_epoch {
+        let epoch = epoch.to_be_bytes();
+        seed.push(epoch.len() as u8);
+        seed.extend_from_slice(&epoch);
+    }
+
+    seed
+}
+
 /// Creates a delegation with the provided session key and expiration, including a list of canisters for identity delegation.
 ///
 /// # Parameters
@@ -183,13 +221,27 @@ pub fn create_certified_signature(
     cbor_serialize(&certificate_signature)
 }
 
+/// Hashes a delegation the way the IC verifies it: the representation-independent hash of the map
+/// `{ pubkey: blob, expiration: nat, targets?: [blob] }`, domain separated with `ic-request-auth-delegation`.
+/// See [`delegation_hash`] for a variant taking the fields directly.
 pub fn create_delegation_hash(delegation: &Delegation) -> Hash {
+    delegation_hash(
+        &delegation.pubkey,
+        delegation.expiration,
+        delegation.targets.as_deref(),
+    )
+}
+
+/// Computes the delegation hash from its fields. The result is
+/// `sha256(0x1a || "ic-request-auth-delegation" || rih(map))` where `rih` is the
+/// representation-independent hash of the delegation map.
+pub fn delegation_hash(pubkey: &[u8], expiration: u64, targets: Option<&[Principal]>) -> Hash {
     let mut delegation_map = HashMap::new();
 
-    delegation_map.insert("pubkey", Value::Bytes(&delegation.pubkey));
-    delegation_map.insert("expiration", Value::U64(delegation.expiration));
+    delegation_map.insert("pubkey", Value::Bytes(pubkey));
+    delegation_map.insert("expiration", Value::U64(expiration));
 
-    if let Some(targets) = delegation.targets.as_ref() {
+    if let Some(targets) = targets {
         let mut arr = Vec::with_capacity(targets.len());
         for t in targets.iter() {
             arr.push(Value::Bytes(t.as_ref()));
@@ -210,7 +262,7 @@ pub fn create_delegation_hash(delegation: &Delegation) -> Hash {
 ///
 /// # Returns
 /// Bytes of the DER-encoded public key.
-pub(crate) fn create_user_canister_pubkey(
+pub fn create_user_canister_pubkey(
     canister_id: &Principal,
     seed: Vec<u8>,
 ) -> Result<Vec<u8>, ASN1EncodeErr> {
@@ -282,6 +334,100 @@ mod tests {
         // Additional assertions can be added here
     }
 
+    #[test]
+    fn test_generate_seed_with() {
+        let address = init();
+        assert_eq!(
+            generate_seed_with(&address, "some_salt", "http://example.com"),
+            generate_seed(&address)
+        );
+        assert_ne!(
+            generate_seed_with(&address, "other_salt", "http://example.com"),
+            generate_seed(&address)
+        );
+    }
+
+    #[test]
+    fn test_seed_preimage_layout() {
+        assert_eq!(
+            hex::encode(seed_preimage("salt", "bc1q", None, None)),
+            "0473616c740462633171"
+        );
+        assert_eq!(
+            hex::encode(seed_preimage("salt", "bc1q", Some("u"), Some(1))),
+            "0473616c7404626331710175080000000000000001"
+        );
+    }
+
+    #[test]
+    fn test_seed_known_answers() {
+        let address = "tb1qshqyem2rf8jyla904gd2cvek2k8nz5z3vc2j3x";
+        assert_eq!(
+            hex::encode(hash::hash_bytes(seed_preimage(
+                "some_salt",
+                address,
+                None,
+                None
+            ))),
+            "b4a5f2b5c46922d4d5c46b1f17575e9090bb79af87ebe93ef6ca3c21d817fe06"
+        );
+        assert_eq!(
+            hex::encode(hash::hash_bytes(seed_preimage(
+                "some_salt",
+                address,
+                Some("http://example.com"),
+                Some(7)
+            ))),
+            "ae14b95582b466026552dd19e44c50ccba97fa1018a9d0e75451a89cac9824cf"
+        );
+    }
+
+    #[test]
+    fn test_delegation_hash_known_answers() {
+        let pubkey: Vec<u8> = (1..=32).collect();
+        assert_eq!(
+            hex::encode(delegation_hash(&pubkey, 1_000_000_000, None)),
+            "78443e08f1e20b7b89ae58e8c4b017c6fe115b9a55c1cc490e59a98a3bd88131"
+        );
+        let targets = [Principal::from_text("aaaaa-aa").unwrap()];
+        assert_eq!(
+            hex::encode(delegation_hash(&pubkey, 1_000_000_000, Some(&targets))),
+            "958afa22844ca8bca3d531328bde440e60194b26345e027b3b51a70aae8d0ba6"
+        );
+    }
+
+    #[test]
+    fn test_create_delegation_hash_matches_fields() {
+        let delegation = Delegation {
+            pubkey: ByteBuf::from(SESSION_KEY.to_vec()),
+            expiration: 42,
+            targets: Some(vec![Principal::from_text("aaaaa-aa").unwrap()]),
+        };
+        assert_eq!(
+            create_delegation_hash(&delegation),
+            delegation_hash(SESSION_KEY, 42, delegation.targets.as_deref())
+        );
+    }
+
+    #[test]
+    fn test_generate_seed_with_session_epoch() {
+        let address = init();
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .runtime_features(vec![RuntimeFeature::IncludeSessionEpochInSeed])
+            .build()
+            .unwrap();
+        SETTINGS.set(Some(settings));
+
+        crate::set_session_epoch(0);
+        let seed_epoch_0 = generate_seed(&address);
+        crate::set_session_epoch(1);
+        let seed_epoch_1 = generate_seed(&address);
+        assert_ne!(
+            seed_epoch_0, seed_epoch_1,
+            "Seed should change with the epoch"
+        );
+    }
+
     #[test]
     fn test_create_delegation() {
         init();
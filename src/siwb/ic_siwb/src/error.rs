@@ -1,14 +1,26 @@
+use bitcoin::AddressType;
 use std::fmt;
 
 #[derive(Debug)]
 pub enum BtcError {
     AddressTypeNotSupported,
+    /// The address type is excluded by `Settings::allowed_address_types`; None if it could not be determined.
+    AddressTypeNotAllowed(Option<AddressType>),
     AddressFormatError(String),
     DecodingError(hex::FromHexError),
     SignatureFormatError(String),
     InvalidSignature,
     InvalidRecoveryId,
     PublicKeyRecoveryFailure,
+    /// The requested locale is not one of `Settings::locales`.
+    UnknownLocale(String),
+    InvalidMessagePhrase(String),
+    /// The requested TTL of the message is zero or exceeds the given maximum in nanoseconds.
+    InvalidExpiresIn(u64),
+    /// A signature or key exceeds the given limit in bytes.
+    InputTooLarge(usize),
+    /// The request ID or a resource of the message is malformed.
+    InvalidMessageField(String),
 }
 
 impl From<hex::FromHexError> for BtcError {
@@ -31,6 +43,19 @@ impl fmt::Display for BtcError {
             BtcError::AddressTypeNotSupported => {
                 write!(f, "Address type not supported")
             }
+            BtcError::AddressTypeNotAllowed(Some(address_type)) => {
+                write!(f, "Address type {} is not allowed", address_type)
+            }
+            BtcError::AddressTypeNotAllowed(None) => {
+                write!(f, "Address type is not allowed")
+            }
+            BtcError::UnknownLocale(locale) => write!(f, "Unknown locale: {}", locale),
+            BtcError::InvalidMessagePhrase(e) => write!(f, "Invalid message phrase: {}", e),
+            BtcError::InvalidExpiresIn(max) => {
+                write!(f, "Sign in expires in must be between 1 and {} ns", max)
+            }
+            BtcError::InputTooLarge(limit) => write!(f, "Input exceeds {} bytes", limit),
+            BtcError::InvalidMessageField(e) => write!(f, "Invalid message field: {}", e),
         }
     }
 }
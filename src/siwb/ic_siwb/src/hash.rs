@@ -53,7 +53,7 @@ pub(crate) fn hash_string(value: &str) -> Hash {
 }
 
 /// Hashes a byte slice.
-pub(crate) fn hash_bytes(value: impl AsRef<[u8]>) -> Hash {
+pub fn hash_bytes(value: impl AsRef<[u8]>) -> Hash {
     let mut hasher = Sha256::new();
     hasher.update(value.as_ref());
     hasher.finalize().into()
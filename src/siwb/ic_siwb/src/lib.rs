@@ -1,20 +1,28 @@
+pub mod bip322;
+pub mod capabilities;
+pub mod challenge;
+pub mod commitment;
 pub mod delegation;
 pub mod error;
 pub mod hash;
 pub mod init;
 pub mod login;
 pub mod macros;
+pub mod metrics;
 pub mod rand;
 pub mod settings;
+pub mod shadow;
 pub mod signature_map;
+pub mod signer;
 pub mod siwb;
 pub mod time;
 pub mod utils;
+pub mod verify;
 pub use bitcoin;
 
 pub use init::init;
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 
 use crate::settings::Settings;
 use crate::siwb::SiwbMessageMap;
@@ -35,4 +43,19 @@ thread_local! {
     // Bitcoin address as a byte array and the value is the SIWB message. After a successful
     // login, the SIWB message is removed from state.
     static SIWB_MESSAGES: RefCell<SiwbMessageMap> = RefCell::new(SiwbMessageMap::new());
+
+    // The session epoch is bumped every time all outstanding sessions are revoked through
+    // [`login::rotate_session_epoch`]. The library does not persist the epoch, providers that
+    // need it to survive upgrades should store it and restore it with [`set_session_epoch`].
+    static SESSION_EPOCH: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Returns the current session epoch.
+pub fn session_epoch() -> u64 {
+    SESSION_EPOCH.get()
+}
+
+/// Restores the session epoch, typically from stable memory after a canister upgrade.
+pub fn set_session_epoch(epoch: u64) {
+    SESSION_EPOCH.set(epoch);
 }
@@ -3,20 +3,18 @@ use std::fmt;
 use std::mem::size_of;
 use std::str::FromStr;
 
-use base64::engine::general_purpose;
-use base64::Engine;
 use bitcoin::absolute::LockTime;
+use bitcoin::consensus::deserialize;
 use bitcoin::hashes::Hash;
 use bitcoin::key::XOnlyPublicKey;
 use bitcoin::psbt::{Prevouts, Psbt};
 use bitcoin::script::Builder;
-use bitcoin::script::Instruction::PushBytes;
+use bitcoin::script::Instruction::{Op, PushBytes};
 use bitcoin::secp256k1::{Message, Secp256k1, ThirtyTwoByteHash};
-use bitcoin::sighash::{EcdsaSighashType, SighashCache, TapSighashType};
-use bitcoin::Network::{Bitcoin, Testnet};
+use bitcoin::sighash::{EcdsaSighashType, SighashCache};
 use bitcoin::{
-    secp256k1, Address, AddressType, Network, OutPoint, PublicKey as BitcoinPublicKey, Script,
-    ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+    Address, AddressType, Network, OutPoint, PublicKey as BitcoinPublicKey, Script, ScriptBuf,
+    Sequence, Transaction, TxIn, TxOut, Txid, Witness,
 };
 use byteorder::{ByteOrder, LittleEndian};
 use candid::{CandidType, Deserialize, Principal};
@@ -27,30 +25,38 @@ use serde::Serialize;
 use serde_bytes::ByteBuf;
 use simple_asn1::ASN1EncodeErr;
 
+use crate::bip322::{verify_bip322_full, verify_witness_spend};
 use crate::error::BtcError;
 use crate::error::BtcError::AddressTypeNotSupported;
 use crate::hash::hash_bytes;
-use crate::utils::{get_script_from_address, AddressInfo};
+use crate::utils::{
+    decode_base64_bounded, decode_hex_bounded, get_script_from_address, max_signature_bytes,
+    parse_address, AddressInfo,
+};
 use crate::{
     delegation::{
         create_delegation, create_delegation_hash, create_user_canister_pubkey, generate_seed,
         DelegationError,
     },
     hash,
+    metrics::{increment, Counter},
     settings::Settings,
+    shadow::compare_with_shadow,
     signature_map::SignatureMap,
-    siwb::{SiwbMessage, SiwbMessageError},
+    siwb::{MessageOptions, SiwbMessage, SiwbMessageError},
     time::get_current_time,
-    with_settings, SIWB_MESSAGES,
+    with_settings, SESSION_EPOCH, SIWB_MESSAGES,
 };
 
 const MAX_SIGS_TO_PRUNE: usize = 10;
 const MAGIC_BYTES: &str = "Bitcoin Signed Message:\n";
 
-#[derive(CandidType, Clone, Serialize, Deserialize)]
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SignMessageType {
     ECDSA,
     Bip322Simple,
+    /// A complete BIP-322 `to_sign` transaction, see [`crate::bip322`].
+    Bip322Full,
 }
 
 pub struct BtcSignature(pub String);
@@ -75,15 +81,73 @@ pub struct BtcSignature(pub String);
 /// let message = prepare_login(&address).unwrap();
 /// ```
 pub fn prepare_login(address: &Address) -> Result<SiwbMessage, BtcError> {
-    let message = SiwbMessage::new(address);
+    prepare_login_with_options(address, None, &MessageOptions::default())
+}
+
+/// Same as [`prepare_login`], but binds the SIWB message to `session_key`. The signed message then contains
+/// the hash of the session key and [`login`] only accepts that session key, so a signature captured in
+/// transit cannot be used to establish a delegation for a different session.
+pub fn prepare_login_with_session_key(
+    address: &Address,
+    session_key: &[u8],
+) -> Result<SiwbMessage, BtcError> {
+    prepare_login_with_options(address, Some(session_key), &MessageOptions::default())
+}
+
+/// Same as [`prepare_login`], optionally binding the message to `session_key` (see
+/// [`prepare_login_with_session_key`]) and wording the header and statement according to `options`, e.g. in
+/// one of `Settings::locales`. The stored message keeps its wording, so [`login`] verifies the signature
+/// against exactly the text that was returned. A statement override without a locale replaces
+/// `Settings::statement` for this message only, e.g. "Approve withdrawal of 0.1 BTC for proposal #42".
+pub fn prepare_login_with_options(
+    address: &Address,
+    session_key: Option<&[u8]>,
+    options: &MessageOptions,
+) -> Result<SiwbMessage, BtcError> {
+    let message = new_message(address, session_key, options)
+        .inspect_err(|_| increment(Counter::PrepareLoginRejected, 1))?;
 
     // Save the SIWB message for use in the login call
     SIWB_MESSAGES.with_borrow_mut(|siwb_messages| {
         siwb_messages.insert(address.script_pubkey().to_bytes(), message.clone());
     });
+    increment(Counter::PrepareLogin, 1);
 
     Ok(message)
 }
+
+fn new_message(
+    address: &Address,
+    session_key: Option<&[u8]>,
+    options: &MessageOptions,
+) -> Result<SiwbMessage, BtcError> {
+    ensure_address_type_allowed(address)?;
+    let mut message = SiwbMessage::new(address).localize(options)?;
+    if let Some(expires_in) = options.expires_in {
+        message = message.expire_in(expires_in)?;
+    }
+    if options.request_id.is_some() || options.resources.is_some() {
+        message = message.with_request(options.request_id.clone(), options.resources.clone())?;
+    }
+    Ok(match session_key {
+        Some(session_key) => message.bind_session_key(session_key),
+        None => message,
+    })
+}
+
+/// Checks the address against `Settings::allowed_address_types`.
+fn ensure_address_type_allowed(address: &Address) -> Result<(), BtcError> {
+    with_settings!(|settings: &Settings| {
+        match &settings.allowed_address_types {
+            None => Ok(()),
+            Some(allowed) => match address.address_type() {
+                Some(address_type) if allowed.contains(&address_type) => Ok(()),
+                address_type => Err(BtcError::AddressTypeNotAllowed(address_type)),
+            },
+        }
+    })
+}
+
 /// Login details are returned after a successful login. They contain the expiration time of the
 /// delegation and the user canister public key.
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -94,12 +158,52 @@ pub struct LoginDetails {
 
     /// The user canister public key. This key is used to derive the user principal.
     pub user_canister_pubkey: ByteBuf,
+
+    /// The request ID of the signed SIWB message, if any, e.g. the action the login is bound to.
+    #[serde(default)]
+    pub request_id: Option<String>,
+
+    /// The resources of the signed SIWB message, e.g. the URI of a proposal.
+    #[serde(default)]
+    pub resources: Vec<String>,
+}
+
+/// How the signature of a login was verified.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum VerificationPath {
+    /// A "Bitcoin Signed Message" ECDSA signature by a compressed key.
+    Ecdsa,
+    /// A "Bitcoin Signed Message" ECDSA signature by an uncompressed key (legacy P2PKH wallets).
+    EcdsaUncompressedKey,
+    /// A BIP-322 simple signature, verified as a spend of the address.
+    Bip322Simple,
+    /// A full BIP-322 proof, whose `to_sign` transaction is verified as a spend of the address.
+    Bip322Full,
+}
+
+/// Describes who signed a successful login, for identity metadata and policy decisions.
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct VerifiedSigner {
+    /// The address type, e.g. "p2tr" or "p2wpkh".
+    pub address_type: String,
+
+    /// The network of the address, e.g. "bitcoin" or "testnet".
+    pub network: String,
+
+    /// The public key recovered from an ECDSA signature, in the encoding the address was derived from.
+    /// None for BIP-322, where the signature proves control of the address without disclosing a key.
+    pub public_key: Option<ByteBuf>,
+
+    pub verification_path: VerificationPath,
 }
 
 pub enum LoginError {
     BtcError(BtcError),
     SiwbMessageError(SiwbMessageError),
     AddressMismatch,
+    SessionKeyMismatch,
+    /// A session duration of zero was requested.
+    InvalidSessionDuration,
     DelegationError(DelegationError),
     ASN1EncodeErr(ASN1EncodeErr),
 }
@@ -134,6 +238,12 @@ impl fmt::Display for LoginError {
             LoginError::BtcError(e) => write!(f, "{}", e),
             LoginError::SiwbMessageError(e) => write!(f, "{}", e),
             LoginError::AddressMismatch => write!(f, "Recovered address does not match"),
+            LoginError::SessionKeyMismatch => {
+                write!(f, "Session key does not match the signed message")
+            }
+            LoginError::InvalidSessionDuration => {
+                write!(f, "Session duration must be greater than 0")
+            }
             LoginError::DelegationError(e) => write!(f, "{}", e),
             LoginError::ASN1EncodeErr(e) => write!(f, "{}", e),
         }
@@ -146,7 +256,8 @@ impl fmt::Display for LoginError {
 /// # Parameters
 /// * `signature`: The SIWB message signature to verify.
 /// * `address`: The Bitcoin address used to sign the SIWB message.
-/// * `public_key`: The ecdsa public key of wallet, can retrieve from wallet provider
+/// * `public_key`: The hex public key reported by the wallet, only checked for ECDSA signatures. May be empty:
+///   the key is recovered from the signature and the address of the type of `address` is derived from it.
 /// * `session_key`: A unique session key to be used for the delegation.
 /// * `signature_map`: A mutable reference to `SignatureMap` to which the delegation hash will be added
 ///   after successful validation.
@@ -164,6 +275,98 @@ pub fn login(
     canister_id: &Principal,
     sign_message_type: SignMessageType,
 ) -> Result<LoginDetails, LoginError> {
+    login_with_signer(
+        signature,
+        address,
+        public_key,
+        session_key,
+        signature_map,
+        canister_id,
+        sign_message_type,
+    )
+    .map(|(details, _)| details)
+}
+
+/// Same as [`login`], but also returns the [`VerifiedSigner`] describing how the signature was verified.
+pub fn login_with_signer(
+    signature: &BtcSignature,
+    address: &Address,
+    public_key: String,
+    session_key: ByteBuf,
+    signature_map: &mut SignatureMap,
+    canister_id: &Principal,
+    sign_message_type: SignMessageType,
+) -> Result<(LoginDetails, VerifiedSigner), LoginError> {
+    login_with_options(
+        signature,
+        address,
+        public_key,
+        session_key,
+        signature_map,
+        canister_id,
+        sign_message_type,
+        &LoginOptions::default(),
+    )
+}
+
+/// Per-login choices of [`login_with_options`].
+#[derive(Clone, Debug, Default)]
+pub struct LoginOptions {
+    /// Verifies the signature against the pending message with this nonce instead of the most recently
+    /// prepared one, so that several devices can sign in to one address at the same time. The other pending
+    /// messages of the address stay valid. Without the `nonce` feature every message has the same nonce, so an
+    /// address has one pending message at a time.
+    pub nonce: Option<String>,
+
+    /// The requested session duration in nanoseconds, e.g. short sessions for admin users. Longer durations
+    /// are capped at `Settings::session_expires_in`. Defaults to None, which uses `session_expires_in`.
+    pub session_expires_in: Option<u64>,
+}
+
+/// Same as [`login_with_signer`], adjusted by `options`.
+#[allow(clippy::too_many_arguments)]
+pub fn login_with_options(
+    signature: &BtcSignature,
+    address: &Address,
+    public_key: String,
+    session_key: ByteBuf,
+    signature_map: &mut SignatureMap,
+    canister_id: &Principal,
+    sign_message_type: SignMessageType,
+    options: &LoginOptions,
+) -> Result<(LoginDetails, VerifiedSigner), LoginError> {
+    let result = verify_and_delegate(
+        signature,
+        address,
+        public_key,
+        session_key,
+        signature_map,
+        canister_id,
+        sign_message_type,
+        options,
+    );
+    match result {
+        Ok(_) => increment(Counter::Login, 1),
+        Err(_) => increment(Counter::LoginFailed, 1),
+    }
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+fn verify_and_delegate(
+    signature: &BtcSignature,
+    address: &Address,
+    public_key: String,
+    session_key: ByteBuf,
+    signature_map: &mut SignatureMap,
+    canister_id: &Principal,
+    sign_message_type: SignMessageType,
+    options: &LoginOptions,
+) -> Result<(LoginDetails, VerifiedSigner), LoginError> {
+    if options.session_expires_in == Some(0) {
+        return Err(LoginError::InvalidSessionDuration);
+    }
+
     // Remove expired SIWB messages from the state before proceeding. The init settings determines
     // the time to live for SIWB messages.
     SIWB_MESSAGES.with_borrow_mut(|siwb_messages| {
@@ -173,67 +376,50 @@ pub fn login(
         // Get the previously created SIWB message for current address. If it has expired or does not
         // exist, return an error.
         let address_bytes = address.script_pubkey().to_bytes();
-        let message = siwb_messages.get(&address_bytes)?;
+        let message = match &options.nonce {
+            Some(nonce) => siwb_messages.get_by_nonce(&address_bytes, nonce)?,
+            None => siwb_messages.get(&address_bytes)?,
+        };
+        if !message.accepts_session_key(&session_key) {
+            return Err(LoginError::SessionKeyMismatch);
+        }
         let message_string: String = message.clone().into();
 
         // Verify the supplied signature against the SIWB message and recover the Bitcoin address
         // used to sign the message.
-
-        match sign_message_type {
-            SignMessageType::ECDSA => {
-                let v = _verify_message(message_string, signature.0.clone(), public_key)
-                    .map_err(|_| LoginError::AddressMismatch)?;
-
-                if let Ok(addr) = verify_address(address.to_string().as_str(), v) {
-                    if address.to_string() != addr {
-                        return Err(LoginError::AddressMismatch);
-                    }
-                } else {
-                    return Err(LoginError::AddressMismatch);
-                }
-            }
-            SignMessageType::Bip322Simple => {
-                let AddressInfo {
-                    network,
-                    address_type,
-                    ..
-                } = match get_script_from_address(address.to_string()) {
-                    Ok(a) => a,
-                    Err(_) => return Err(LoginError::AddressMismatch),
-                };
-                if address_type == AddressType::P2tr {
-                    if !verify_signature_of_bip322_simple_p2tr(
-                        address.to_string().as_str(),
-                        message_string.as_str(),
-                        signature.0.as_str(),
-                        network,
-                    ) {
-                        return Err(LoginError::AddressMismatch);
-                    }
-                } else if address_type == AddressType::P2wpkh {
-                    if !verify_signature_of_bip322_simple_segwitv0(
-                        address.to_string().as_str(),
-                        message_string.as_str(),
-                        signature.0.as_str(),
-                        network,
-                    ) {
-                        return Err(LoginError::AddressMismatch);
-                    }
-                } else {
-                    return Err(LoginError::BtcError(AddressTypeNotSupported));
-                }
-            }
-        }
+        let verified = verify_signature(
+            &message_string,
+            address,
+            &signature.0,
+            &public_key,
+            sign_message_type.clone(),
+        );
+        compare_with_shadow(
+            &message_string,
+            address,
+            &signature.0,
+            &public_key,
+            sign_message_type,
+            &verified,
+        );
+        let signer = verified?;
 
         // At this point, the signature has been verified and the SIWB message has been used. Remove
         // the SIWB message from the state.
-        siwb_messages.remove(&address_bytes);
+        siwb_messages.remove_nonce(&address_bytes, &message.nonce);
 
-        // The delegation is valid for the duration of the session as defined in the settings.
+        // The delegation is valid for the requested duration of the session, at most as long as defined in
+        // the settings, plus the clock skew tolerance.
         let expiration = with_settings!(|settings: &Settings| {
+            let duration = options
+                .session_expires_in
+                .map_or(settings.session_expires_in, |requested| {
+                    requested.min(settings.session_expires_in)
+                });
             message
                 .issued_at
-                .saturating_add(settings.session_expires_in)
+                .saturating_add(duration)
+                .saturating_add(settings.clock_skew_tolerance_ns)
         });
 
         // The seed is what uniquely identifies the delegation. It is derived from the salt, the
@@ -241,7 +427,8 @@ pub fn login(
         let seed = generate_seed(address);
 
         // Before adding the signature to the signature map, prune any expired signatures.
-        signature_map.prune_expired(get_current_time(), MAX_SIGS_TO_PRUNE);
+        let pruned = signature_map.prune_expired(get_current_time(), MAX_SIGS_TO_PRUNE);
+        increment(Counter::SignaturePruned, pruned as u64);
 
         // Create the delegation and add its hash to the signature map. The seed is used as the map key.
         let delegation = create_delegation(session_key, expiration)?;
@@ -252,10 +439,135 @@ pub fn login(
         // user principal.
         let user_canister_pubkey = create_user_canister_pubkey(canister_id, seed.to_vec())?;
 
-        Ok(LoginDetails {
-            expiration,
-            user_canister_pubkey: ByteBuf::from(user_canister_pubkey),
-        })
+        Ok((
+            LoginDetails {
+                expiration,
+                user_canister_pubkey: ByteBuf::from(user_canister_pubkey),
+                request_id: message.request_id,
+                resources: message.resources,
+            },
+            signer,
+        ))
+    })
+}
+
+/// Verifies `signature` over `message` for `address` without touching any canister state. This is the
+/// check [`login`] performs against the stored SIWB message; it is public so wallet output can be
+/// validated offline (see `tests/wallet_vectors`).
+///
+/// `public_key` is the hex key reported by the wallet and is only used for ECDSA signatures, whose key is
+/// recovered from the signature. It may be empty; otherwise it must be the recovered key.
+pub fn verify_signature(
+    message: &str,
+    address: &Address,
+    signature: &str,
+    public_key: &str,
+    sign_message_type: SignMessageType,
+) -> Result<VerifiedSigner, LoginError> {
+    let verified = verify(message, address, signature, public_key, sign_message_type);
+    let counter = match &verified {
+        Ok(signer) => match signer.verification_path {
+            VerificationPath::Ecdsa => Counter::VerifiedEcdsa,
+            VerificationPath::EcdsaUncompressedKey => Counter::VerifiedEcdsaUncompressedKey,
+            VerificationPath::Bip322Simple => Counter::VerifiedBip322Simple,
+            VerificationPath::Bip322Full => Counter::VerifiedBip322Full,
+        },
+        Err(_) => Counter::VerificationFailed,
+    };
+    increment(counter, 1);
+    verified
+}
+
+fn verify(
+    message: &str,
+    address: &Address,
+    signature: &str,
+    public_key: &str,
+    sign_message_type: SignMessageType,
+) -> Result<VerifiedSigner, LoginError> {
+    let AddressInfo {
+        network,
+        address_type,
+        ..
+    } = match get_script_from_address(address.to_string()) {
+        Ok(a) => a,
+        Err(_) => return Err(LoginError::AddressMismatch),
+    };
+
+    let (signer_key, verification_path) = match sign_message_type {
+        SignMessageType::ECDSA => {
+            let (recovered, header) = _verify_message(
+                message.to_string(),
+                signature.to_string(),
+                public_key.to_string(),
+            )
+            .map_err(|_| LoginError::AddressMismatch)?;
+            if header
+                .address_type()
+                .is_some_and(|header_type| header_type != address_type)
+            {
+                return Err(LoginError::AddressMismatch);
+            }
+
+            // The header says how a P2PKH key is encoded; segwit and taproot keys are always compressed.
+            let encoding = if address_type == AddressType::P2pkh {
+                header.key_encoding()
+            } else {
+                P2pkhKeyEncoding::Compressed
+            };
+            let key = encoding.encode(&recovered);
+            let derived = if address_type == AddressType::P2pkh {
+                Ok(Address::p2pkh(&key, network).to_string())
+            } else {
+                verify_address(address.to_string().as_str(), key.to_bytes())
+            };
+            if derived.as_deref() != Ok(address.to_string().as_str()) {
+                return Err(LoginError::AddressMismatch);
+            }
+            let path = match encoding {
+                P2pkhKeyEncoding::Compressed => VerificationPath::Ecdsa,
+                P2pkhKeyEncoding::Uncompressed => VerificationPath::EcdsaUncompressedKey,
+            };
+            (Some(ByteBuf::from(key.to_bytes())), path)
+        }
+        SignMessageType::Bip322Simple => {
+            if address_type == AddressType::P2wsh {
+                if !verify_signature_of_bip322_simple_p2wsh(address, message, signature)? {
+                    return Err(LoginError::AddressMismatch);
+                }
+            } else if address_type == AddressType::P2tr {
+                if !verify_signature_of_bip322_simple_p2tr(
+                    address.to_string().as_str(),
+                    message,
+                    signature,
+                    network,
+                )? {
+                    return Err(LoginError::AddressMismatch);
+                }
+            } else if address_type == AddressType::P2wpkh {
+                if !verify_signature_of_bip322_simple_segwitv0(
+                    address.to_string().as_str(),
+                    message,
+                    signature,
+                    network,
+                )? {
+                    return Err(LoginError::AddressMismatch);
+                }
+            } else {
+                return Err(LoginError::BtcError(AddressTypeNotSupported));
+            }
+            (None, VerificationPath::Bip322Simple)
+        }
+        SignMessageType::Bip322Full => {
+            verify_bip322_full(message, address, signature)?;
+            (None, VerificationPath::Bip322Full)
+        }
+    };
+    Ok(VerifiedSigner {
+        address_type: address_type.to_string(),
+        network: network.to_string(),
+        public_key: signer_key,
+        verification_path,
     })
 }
 
@@ -263,7 +575,21 @@ pub fn prune_all(signature_map: &mut SignatureMap) {
     SIWB_MESSAGES.with_borrow_mut(|siwb_messages| {
         siwb_messages.clear();
         signature_map.prune_all();
-    })
+    });
+    increment(Counter::PruneAll, 1);
+}
+
+/// Revokes every outstanding session in one step: all pending SIWB messages and all delegation
+/// signatures are dropped and the session epoch is incremented. Returns the new epoch.
+///
+/// Delegations that have already been fetched remain valid until they expire, but no new
+/// delegation can be fetched for a login that happened before the rotation. The caller is
+/// responsible for updating the certified data after the signature map has been cleared.
+pub fn rotate_session_epoch(signature_map: &mut SignatureMap) -> u64 {
+    prune_all(signature_map);
+    let epoch = SESSION_EPOCH.get().saturating_add(1);
+    SESSION_EPOCH.set(epoch);
+    epoch
 }
 
 struct BufferWriter {}
@@ -309,44 +635,55 @@ pub fn _msg_hash(message: String) -> Vec<u8> {
     return hash.finalize_fixed().to_vec();
 }
 
+/// Recovers the key behind a "Bitcoin Signed Message" signature, encoded as its BIP-137 header declares. When
+/// the wallet reported a key in `public_key`, it must be the recovered one.
 fn _verify_message(
     message: String,
     signature: String,
     public_key: String,
-) -> Result<Vec<u8>, String> {
+) -> Result<(BitcoinPublicKey, Bip137Header), String> {
     let message_prehashed = _msg_hash(message);
-    let signature_bytes = general_purpose::STANDARD
-        .decode(signature)
-        .map_err(|_| "Invalid b64 signature".to_string())?;
-    let public_key_bytes = hex::decode(public_key).map_err(|_| "Invalid public key".to_string())?;
+    let signature_bytes = decode_base64_bounded(&signature, 65)
+        .map_err(|e| format!("Invalid b64 signature: {}", e))?;
+    let header = signature_bytes
+        .first()
+        .and_then(|byte| Bip137Header::from_byte(*byte))
+        .ok_or("Invalid BIP-137 header byte".to_string())?;
     let recovered_public_key = recover_pub_key_compact(
         signature_bytes.as_slice(),
         message_prehashed.as_slice(),
         None,
     )?;
-
-    return if public_key_bytes.clone() != recovered_public_key.clone() {
-        Err("public_key_bytes != recovered_public_key".to_string())
-    } else {
-        Ok(recovered_public_key.clone())
-    };
+    let recovered = BitcoinPublicKey::from_slice(&recovered_public_key)
+        .map_err(|_| "Invalid recovered public key".to_string())?;
+
+    if !public_key.is_empty() {
+        let public_key_bytes =
+            decode_hex_bounded(&public_key, 65).map_err(|_| "Invalid public key".to_string())?;
+        // Wallets may report the key in either encoding.
+        let same_key = BitcoinPublicKey::from_slice(&public_key_bytes)
+            .is_ok_and(|provided| provided.inner == recovered.inner);
+        if !same_key {
+            return Err("public_key_bytes != recovered_public_key".to_string());
+        }
+    }
+    Ok((header.key_encoding().encode(&recovered), header))
 }
 
+/// Recovers the compressed public key from a 65 byte compact signature, whose first byte holds the recovery
+/// id as a BIP-137 header or, with `chain_id`, in the EIP-155 form.
 pub fn recover_pub_key_compact(
     signature_bytes: &[u8],
     message_hash: &[u8],
     chain_id: Option<u8>,
 ) -> Result<Vec<u8>, String> {
-    let mut v;
+    if signature_bytes.len() != 65 {
+        return Err("Cannot create secp256k1 signature: malformed signature.".to_string());
+    }
+    let mut v = signature_bytes[0];
     let r: Vec<u8> = signature_bytes[1..33].to_vec();
-    let mut s: Vec<u8> = signature_bytes[33..65].to_vec();
+    let s: Vec<u8> = signature_bytes[33..65].to_vec();
 
-    if signature_bytes.len() >= 65 {
-        v = signature_bytes[0];
-    } else {
-        v = signature_bytes[33] >> 7;
-        s[0] &= 0x7f;
-    };
     if v < 27 {
         v = v + 27;
     }
@@ -401,38 +738,19 @@ pub fn calculate_sig_recovery(mut v: u8, chain_id: Option<u8>) -> u8 {
     };
 }
 
+/// Derives the address of `pub_bytes` of the type and network `address` has, for comparison with `address`.
+/// The network is the configured one, see [`parse_address`]. A P2PKH address is derived from the compressed and
+/// the uncompressed encoding of the key, whichever matches, since legacy wallets hash the uncompressed key;
+/// segwit and taproot addresses always use the compressed key.
 pub fn verify_address(address: &str, pub_bytes: Vec<u8>) -> Result<String, String> {
     let public_key =
         BitcoinPublicKey::from_slice(pub_bytes.as_slice()).map_err(|e| e.to_string())?;
     let secp = Secp256k1::verification_only();
-    let mut network = Bitcoin;
-    let mut address_type = AddressType::P2tr;
-
-    if address.starts_with("bc1q") {
-        address_type = AddressType::P2wpkh;
-        network = Bitcoin;
-    } else if address.starts_with("bc1p") {
-        address_type = AddressType::P2tr;
-        network = Bitcoin;
-    } else if address.starts_with('1') {
-        address_type = AddressType::P2pkh;
-        network = Bitcoin;
-    } else if address.starts_with('3') {
-        address_type = AddressType::P2sh;
-        network = Bitcoin;
-    } else if address.starts_with("tb1q") {
-        address_type = AddressType::P2wpkh;
-        network = Testnet;
-    } else if address.starts_with('m') || address.starts_with('n') {
-        address_type = AddressType::P2pkh;
-        network = Testnet;
-    } else if address.starts_with('2') {
-        address_type = AddressType::P2sh;
-        network = Testnet;
-    } else if address.starts_with("tb1p") {
-        address_type = AddressType::P2tr;
-        network = Testnet;
-    }
+    let AddressInfo {
+        network,
+        address_type,
+        ..
+    } = parse_address(address).map_err(|e| e.to_string())?;
     let compressed = if !public_key.compressed {
         BitcoinPublicKey::from_slice(&public_key.inner.serialize())
             .map_err(|e| e.to_string())
@@ -443,8 +761,10 @@ pub fn verify_address(address: &str, pub_bytes: Vec<u8>) -> Result<String, Strin
 
     match address_type {
         AddressType::P2pkh => {
-            let p2pkh_address = Address::p2pkh(&public_key, network);
-            Ok(p2pkh_address.to_string())
+            // Very old wallets use uncompressed keys, which hash to a different P2PKH address
+            let encoding = match_p2pkh_key_encoding(address, &public_key, network)
+                .unwrap_or(P2pkhKeyEncoding::Compressed);
+            Ok(Address::p2pkh(&encoding.encode(&public_key), network).to_string())
         }
         AddressType::P2wpkh => {
             let p2wpkh_address =
@@ -465,12 +785,84 @@ pub fn verify_address(address: &str, pub_bytes: Vec<u8>) -> Result<String, Strin
     }
 }
 
+/// The address type declared by the header byte of a "Bitcoin Signed Message" signature, as defined by
+/// BIP-137. The header also carries the recovery id in its two lowest bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bip137Header {
+    /// 27 to 30: P2PKH with an uncompressed key.
+    P2pkhUncompressed,
+    /// 31 to 34: P2PKH with a compressed key.
+    P2pkhCompressed,
+    /// 35 to 38: P2SH-P2WPKH.
+    P2shP2wpkh,
+    /// 39 to 42: P2WPKH.
+    P2wpkh,
+}
+
+impl Bip137Header {
+    pub fn from_byte(header: u8) -> Option<Self> {
+        match header {
+            27..=30 => Some(Bip137Header::P2pkhUncompressed),
+            31..=34 => Some(Bip137Header::P2pkhCompressed),
+            35..=38 => Some(Bip137Header::P2shP2wpkh),
+            39..=42 => Some(Bip137Header::P2wpkh),
+            _ => None,
+        }
+    }
+
+    /// The address type the header commits to. Many wallets sign for every address type with a P2PKH
+    /// header, so those only determine how a P2PKH key is encoded and commit to no address type.
+    pub fn address_type(self) -> Option<AddressType> {
+        match self {
+            Bip137Header::P2shP2wpkh => Some(AddressType::P2sh),
+            Bip137Header::P2wpkh => Some(AddressType::P2wpkh),
+            Bip137Header::P2pkhUncompressed | Bip137Header::P2pkhCompressed => None,
+        }
+    }
+
+    pub fn key_encoding(self) -> P2pkhKeyEncoding {
+        match self {
+            Bip137Header::P2pkhUncompressed => P2pkhKeyEncoding::Uncompressed,
+            _ => P2pkhKeyEncoding::Compressed,
+        }
+    }
+}
+
+/// The public key encoding a P2PKH address was derived from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum P2pkhKeyEncoding {
+    Compressed,
+    Uncompressed,
+}
+
+impl P2pkhKeyEncoding {
+    fn encode(self, public_key: &BitcoinPublicKey) -> BitcoinPublicKey {
+        match self {
+            P2pkhKeyEncoding::Compressed => BitcoinPublicKey::new(public_key.inner),
+            P2pkhKeyEncoding::Uncompressed => BitcoinPublicKey::new_uncompressed(public_key.inner),
+        }
+    }
+}
+
+/// Tries both encodings of `public_key` against a P2PKH `address` and returns the one that matches, if any.
+pub fn match_p2pkh_key_encoding(
+    address: &str,
+    public_key: &BitcoinPublicKey,
+    network: Network,
+) -> Option<P2pkhKeyEncoding> {
+    [P2pkhKeyEncoding::Compressed, P2pkhKeyEncoding::Uncompressed]
+        .into_iter()
+        .find(|encoding| {
+            Address::p2pkh(&encoding.encode(public_key), network).to_string() == address
+        })
+}
+
 fn get_output_script_from_address(address: &str, network: Network) -> ScriptBuf {
     let _address = Address::from_str(address).unwrap();
     _address.require_network(network).unwrap().script_pubkey()
 }
 
-fn bip0322_hash(message: &str) -> Vec<u8> {
+pub(crate) fn bip0322_hash(message: &str) -> Vec<u8> {
     let tag = "BIP0322-signed-message";
     let tag_hash = hash_bytes(tag.as_bytes());
     let mut hasher = Sha256::new();
@@ -480,7 +872,7 @@ fn bip0322_hash(message: &str) -> Vec<u8> {
     hasher.finalize().to_vec()
 }
 
-fn bip0322_tx(message_slice: &[u8], output_script: ScriptBuf) -> Transaction {
+pub(crate) fn bip0322_tx(message_slice: &[u8], output_script: ScriptBuf) -> Transaction {
     // Prepare the transaction to spend
     let prevout_hash = vec![0u8; 32];
     let prevout_index = 0xffffffff;
@@ -534,42 +926,55 @@ fn bip0322_psbt_unsigned(tx_to_spend: Transaction) -> Transaction {
     }
 }
 
+/// The witness elements of a decoded BIP-322 Simple signature, i.e. without the leading element count. A
+/// signature that decodes but holds no elements is an error rather than a panic.
+fn bip322_witness_elements(data: &[u8]) -> Result<&[u8], LoginError> {
+    match data.split_first() {
+        Some((_, elements)) if !elements.is_empty() => Ok(elements),
+        _ => Err(LoginError::BtcError(BtcError::SignatureFormatError(
+            "Empty BIP-322 witness".to_string(),
+        ))),
+    }
+}
+
 fn verify_signature_of_bip322_simple_p2tr(
     address: &str,
     msg: &str,
     sig: &str,
     network: Network,
-) -> bool {
+) -> Result<bool, LoginError> {
     let secp = Secp256k1::new();
     let output_script = get_output_script_from_address(address.to_string().as_str(), network);
     let _tx = bip0322_tx(bip0322_hash(msg).as_slice(), output_script.clone());
 
     // Decode the signature
-    let data = match general_purpose::STANDARD.decode(sig) {
+    let data = match decode_base64_bounded(sig, max_signature_bytes()) {
         Ok(d) => d,
-        Err(_) => return false,
+        Err(_) => return Ok(false),
     };
 
-    let script_buf = ScriptBuf::from_bytes(data[1..].to_vec());
-
-    let signature = match secp256k1::schnorr::Signature::from_slice(&script_buf.to_bytes()[1..]) {
+    // The single element is the length of the signature followed by the signature itself: 64 bytes for
+    // SIGHASH_DEFAULT; some wallets append an explicit sighash byte
+    let element = bip322_witness_elements(&data)?;
+    let signature = match bitcoin::taproot::Signature::from_slice(&element[1..]) {
         Ok(sig) => sig,
-        Err(_) => return false,
+        Err(_) => return Ok(false),
     };
 
-    let mut b = vec![];
-    b.extend_from_slice(&output_script.to_bytes()[2..]);
-
     // Extract the public key from the address
-    let pubkey = match XOnlyPublicKey::from_slice(b.as_slice()) {
-        Ok(key) => key,
-        Err(_) => return false,
+    let pubkey = match output_script
+        .as_bytes()
+        .get(2..)
+        .and_then(|b| XOnlyPublicKey::from_slice(b).ok())
+    {
+        Some(key) => key,
+        None => return Ok(false),
     };
 
     // Prepare the PSBT to sign
     let mut psbt_to_sign = match Psbt::from_unsigned_tx(_tx) {
         Ok(psbt) => psbt,
-        Err(_) => return false,
+        Err(_) => return Ok(false),
     };
     psbt_to_sign.version = 0;
     psbt_to_sign.inputs[0].tap_internal_key = Some(pubkey);
@@ -580,16 +985,18 @@ fn verify_signature_of_bip322_simple_p2tr(
     let prevouts_all = Prevouts::All(&binding);
 
     let mut cache = SighashCache::new(&mut psbt_to_sign.unsigned_tx);
-    let sighash = cache.taproot_key_spend_signature_hash(0, &prevouts_all, TapSighashType::Default);
+    let sighash = cache.taproot_key_spend_signature_hash(0, &prevouts_all, signature.hash_ty);
     match sighash {
         Ok(sighash) => {
             let message = match Message::from_slice(&sighash.into_32()) {
                 Ok(m) => m,
-                Err(_) => return false,
+                Err(_) => return Ok(false),
             };
-            secp.verify_schnorr(&signature, &message, &pubkey).is_ok()
+            Ok(secp
+                .verify_schnorr(&signature.sig, &message, &pubkey)
+                .is_ok())
         }
-        Err(_) => false,
+        Err(_) => Ok(false),
     }
 }
 
@@ -598,30 +1005,30 @@ fn verify_signature_of_bip322_simple_segwitv0(
     msg: &str,
     sig: &str,
     network: Network,
-) -> bool {
+) -> Result<bool, LoginError> {
     let secp = Secp256k1::new();
     let output_script = get_output_script_from_address(address.to_string().as_str(), network);
     let _tx = bip0322_tx(bip0322_hash(msg).as_slice(), output_script.clone());
 
     // process signature, create partial_sig for segwit_v0
-    let _data = match general_purpose::STANDARD.decode(sig) {
+    let _data = match decode_base64_bounded(sig, max_signature_bytes()) {
         Ok(data) => data,
-        Err(_) => return false,
+        Err(_) => return Ok(false),
     };
 
-    let script_buf = ScriptBuf::from_bytes(_data[1..].to_vec());
+    let script_buf = ScriptBuf::from_bytes(bip322_witness_elements(&_data)?.to_vec());
 
     let _res = match extract_bytes_from_script(&script_buf, 2) {
         Ok(d) => d.clone(),
-        Err(_) => return false,
+        Err(_) => return Ok(false),
     };
     let sig = match bitcoin::ecdsa::Signature::from_slice(&_res[0]) {
         Ok(sig) => sig,
-        Err(_) => return false,
+        Err(_) => return Ok(false),
     };
     let pubkey = match bitcoin::key::PublicKey::from_slice(&_res[1]) {
         Ok(key) => key,
-        Err(_) => return false,
+        Err(_) => return Ok(false),
     };
     let mut partial_sig = BTreeMap::new();
     partial_sig.insert(pubkey, sig);
@@ -629,7 +1036,7 @@ fn verify_signature_of_bip322_simple_segwitv0(
     // Prepare the PSBT to sign
     let mut psbt_to_sign = match Psbt::from_unsigned_tx(_tx) {
         Ok(psbt) => psbt,
-        Err(_) => return false,
+        Err(_) => return Ok(false),
     };
     psbt_to_sign.version = 0;
     psbt_to_sign.inputs[0].partial_sigs = partial_sig;
@@ -658,32 +1065,288 @@ fn verify_signature_of_bip322_simple_segwitv0(
         })
     });
 
-    return ret;
+    Ok(ret)
 }
 
+/// Verifies a BIP-322 Simple signature of a P2WSH address, i.e. the consensus encoded witness spending the
+/// `to_spend` commitment. The last witness element is the witness script, which must hash to the address. A
+/// multisig script needs as many signatures as its threshold, in the order of their keys, after the empty
+/// element OP_CHECKMULTISIG consumes.
+fn verify_signature_of_bip322_simple_p2wsh(
+    address: &Address,
+    msg: &str,
+    sig: &str,
+) -> Result<bool, LoginError> {
+    let data = decode_base64_bounded(sig, max_signature_bytes())?;
+    let witness: Witness = deserialize(&data).map_err(|_| {
+        LoginError::BtcError(BtcError::SignatureFormatError(
+            "Invalid BIP-322 witness".to_string(),
+        ))
+    })?;
+    let output_script = address.script_pubkey();
+    let to_sign = bip0322_tx(&bip0322_hash(msg), output_script.clone());
+    verify_witness_spend(&to_sign, &output_script, &witness)
+}
+
+/// Returns the data of a script made of exactly `expect_size` pushes, e.g. the signature and key of a
+/// segwit v0 witness, in one pass. Any other opcode or a truncated push is an error rather than skipped.
 fn extract_bytes_from_script(script: &Script, expect_size: usize) -> Result<Vec<Vec<u8>>, String> {
-    if script.instructions().count() != expect_size {
+    let mut payload = Vec::with_capacity(expect_size);
+    for instruction in script.instructions() {
+        match instruction.map_err(|e| format!("Malformed script: {}", e))? {
+            PushBytes(_) if payload.len() == expect_size => {
+                return Err("Invalid script size".to_string())
+            }
+            PushBytes(bytes) => payload.push(bytes.as_bytes().to_vec()),
+            Op(opcode) => return Err(format!("Unexpected opcode {}", opcode)),
+        }
+    }
+    if payload.len() != expect_size {
         return Err("Invalid script size".to_string());
     }
-    let mut payload = vec![];
-    let instructions = script.instructions().peekable();
-    instructions
-        .into_iter()
-        .for_each(|instruction| match instruction {
-            Ok(PushBytes(bytes)) => payload.push(bytes.as_bytes().to_vec()),
-            _ => {
-                println!("instruction is {:?}", instruction);
-            }
-        });
     Ok(payload)
 }
 
 #[cfg(test)]
 mod test {
+    use crate::error::BtcError;
     use crate::login::{
-        _verify_message, bip0322_hash, verify_address, verify_signature_of_bip322_simple_p2tr,
-        verify_signature_of_bip322_simple_segwitv0,
+        _msg_hash, _verify_message, bip0322_hash, bip0322_tx, extract_bytes_from_script,
+        login_with_options, match_p2pkh_key_encoding, prepare_login, prepare_login_with_options,
+        rotate_session_epoch, verify_address, verify_signature,
+        verify_signature_of_bip322_simple_p2tr, verify_signature_of_bip322_simple_segwitv0,
+        BtcSignature, LoginError, LoginOptions, P2pkhKeyEncoding, SignMessageType,
+        VerificationPath,
     };
+    use crate::settings::{MessageLocale, SettingsBuilder};
+    use crate::signature_map::SignatureMap;
+    use crate::siwb::MessageOptions;
+    use crate::utils::get_script_from_address;
+    use crate::SETTINGS;
+    use crate::SIWB_MESSAGES;
+    use bitcoin::AddressType;
+    use candid::Principal;
+    use serde_bytes::ByteBuf;
+
+    #[test]
+    fn test_prepare_login_address_type_policy() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .allowed_address_types(vec![AddressType::P2tr])
+            .build()
+            .unwrap();
+        SETTINGS.set(Some(settings));
+
+        let p2tr = get_script_from_address(
+            "bc1pgvdp7lf89d62zadds5jvyjntxmr7v70yv33g7vqaeu2p0cuexveq9hcwdv".to_string(),
+        )
+        .unwrap();
+        assert!(prepare_login(&p2tr.address_raw).is_ok());
+
+        let p2sh =
+            get_script_from_address("3L3aWoYtxUMa7szaGhjuGAcJap9Hb13EEP".to_string()).unwrap();
+        assert!(matches!(
+            prepare_login(&p2sh.address_raw),
+            Err(BtcError::AddressTypeNotAllowed(Some(AddressType::P2sh)))
+        ));
+    }
+
+    #[test]
+    fn test_prepare_login_localized() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .locale(
+                "de",
+                MessageLocale {
+                    header: Some(
+                        "möchte, dass Sie sich mit Ihrem Bitcoin-Konto anmelden:".to_string(),
+                    ),
+                    statement: Some("Bei der App anmelden".to_string()),
+                },
+            )
+            .build()
+            .unwrap();
+        SETTINGS.set(Some(settings));
+        let address = get_script_from_address(
+            "bc1pgvdp7lf89d62zadds5jvyjntxmr7v70yv33g7vqaeu2p0cuexveq9hcwdv".to_string(),
+        )
+        .unwrap();
+
+        let options = MessageOptions {
+            locale: Some("de".to_string()),
+            overrides: Some(MessageLocale {
+                header: None,
+                statement: Some("Willkommen".to_string()),
+            }),
+            ..Default::default()
+        };
+        let message = prepare_login_with_options(&address.address_raw, None, &options).unwrap();
+        let stored = SIWB_MESSAGES
+            .with_borrow(|m| m.get(&address.script_buf.to_bytes()))
+            .unwrap();
+        let text: String = message.into();
+        assert_eq!(String::from(stored), text);
+        assert!(text
+            .starts_with("example.com möchte, dass Sie sich mit Ihrem Bitcoin-Konto anmelden:\n"));
+        assert!(text.contains("\n\nWillkommen\n\nURI: http://example.com\nVersion: 1\n"));
+
+        let unknown = MessageOptions {
+            locale: Some("fr".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            prepare_login_with_options(&address.address_raw, None, &unknown),
+            Err(BtcError::UnknownLocale(_))
+        ));
+    }
+
+    #[test]
+    fn test_prepare_login_statement_override() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .build()
+            .unwrap();
+        SETTINGS.set(Some(settings));
+        let address = get_script_from_address(
+            "bc1pgvdp7lf89d62zadds5jvyjntxmr7v70yv33g7vqaeu2p0cuexveq9hcwdv".to_string(),
+        )
+        .unwrap();
+        let with_statement = |statement: &str| MessageOptions {
+            overrides: Some(MessageLocale {
+                header: None,
+                statement: Some(statement.to_string()),
+            }),
+            ..Default::default()
+        };
+
+        let statement = "Approve withdrawal of 0.1 BTC for proposal #42";
+        prepare_login_with_options(&address.address_raw, None, &with_statement(statement)).unwrap();
+        let stored = SIWB_MESSAGES
+            .with_borrow(|m| m.get(&address.script_buf.to_bytes()))
+            .unwrap();
+        assert_eq!(stored.statement, statement);
+
+        assert!(matches!(
+            prepare_login_with_options(
+                &address.address_raw,
+                None,
+                &with_statement("Approve\nanything")
+            ),
+            Err(BtcError::InvalidMessagePhrase(_))
+        ));
+    }
+
+    #[test]
+    fn test_prepare_login_expires_in() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .sign_in_expires_in(300 * 1_000_000_000)
+            .max_sign_in_expires_in(900 * 1_000_000_000)
+            .build()
+            .unwrap();
+        SETTINGS.set(Some(settings));
+        let address = get_script_from_address(
+            "bc1pgvdp7lf89d62zadds5jvyjntxmr7v70yv33g7vqaeu2p0cuexveq9hcwdv".to_string(),
+        )
+        .unwrap();
+        let expiring_in = |expires_in: u64| MessageOptions {
+            expires_in: Some(expires_in),
+            ..Default::default()
+        };
+
+        for expires_in in [60 * 1_000_000_000, 900 * 1_000_000_000] {
+            let message =
+                prepare_login_with_options(&address.address_raw, None, &expiring_in(expires_in))
+                    .unwrap();
+            assert_eq!(message.expiration_time - message.issued_at, expires_in);
+        }
+        for expires_in in [0, 901 * 1_000_000_000] {
+            assert!(matches!(
+                prepare_login_with_options(&address.address_raw, None, &expiring_in(expires_in)),
+                Err(BtcError::InvalidExpiresIn(900_000_000_000))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_prepare_login_request_and_resources() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .resources(vec!["https://example.com/terms".to_string()])
+            .build()
+            .unwrap();
+        SETTINGS.set(Some(settings));
+        let address = get_script_from_address(
+            "bc1pgvdp7lf89d62zadds5jvyjntxmr7v70yv33g7vqaeu2p0cuexveq9hcwdv".to_string(),
+        )
+        .unwrap();
+
+        let text: String = prepare_login(&address.address_raw).unwrap().into();
+        assert!(text.ends_with("\nResources:\n- https://example.com/terms"));
+
+        let options = MessageOptions {
+            request_id: Some("proposal-42".to_string()),
+            resources: Some(vec!["https://example.com/proposals/42".to_string()]),
+            ..Default::default()
+        };
+        let text: String = prepare_login_with_options(&address.address_raw, None, &options)
+            .unwrap()
+            .into();
+        assert!(text.ends_with(
+            "\nRequest ID: proposal-42\nResources:\n- https://example.com/proposals/42"
+        ));
+
+        let malformed = MessageOptions {
+            resources: Some(vec!["not a uri".to_string()]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            prepare_login_with_options(&address.address_raw, None, &malformed),
+            Err(BtcError::InvalidMessageField(_))
+        ));
+    }
+
+    #[test]
+    fn test_login_rejects_zero_session_duration() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .build()
+            .unwrap();
+        SETTINGS.set(Some(settings));
+        let address = get_script_from_address(
+            "bc1pgvdp7lf89d62zadds5jvyjntxmr7v70yv33g7vqaeu2p0cuexveq9hcwdv".to_string(),
+        )
+        .unwrap();
+        prepare_login(&address.address_raw).unwrap();
+
+        let options = LoginOptions {
+            session_expires_in: Some(0),
+            ..Default::default()
+        };
+        assert!(matches!(
+            login_with_options(
+                &BtcSignature(String::new()),
+                &address.address_raw,
+                String::new(),
+                ByteBuf::from(vec![1u8; 32]),
+                &mut SignatureMap::default(),
+                &Principal::anonymous(),
+                SignMessageType::Bip322Simple,
+                &options,
+            ),
+            Err(LoginError::InvalidSessionDuration)
+        ));
+        // The message was not consumed by the rejected attempt
+        assert!(SIWB_MESSAGES
+            .with_borrow(|m| m.get(&address.script_buf.to_bytes()))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_rotate_session_epoch() {
+        let mut signature_map = SignatureMap::default();
+        signature_map.put([1u8; 32], [2u8; 32]);
+        let epoch = crate::session_epoch();
+
+        let rotated = rotate_session_epoch(&mut signature_map);
+        assert_eq!(rotated, epoch + 1);
+        assert_eq!(crate::session_epoch(), epoch + 1);
+        assert!(signature_map.witness([1u8; 32], [2u8; 32]).is_none());
+    }
 
     #[test]
     fn test_get_address() {
@@ -763,6 +1426,211 @@ mod test {
             "1DW2KKsStJ4QECVfzHM2Qzh2wCBjTe9TH1".to_string()
         );
     }
+    #[test]
+    fn test_p2pkh_key_encodings() {
+        let compressed =
+            hex::decode("03133c85d348d6c0796382966380719397453592e706cd3329119a2d2cb8d2ff7b")
+                .unwrap();
+        let uncompressed = hex::decode("04133c85d348d6c0796382966380719397453592e706cd3329119a2d2cb8d2ff7b77a1646474a6d921d6a46bccc6bdeeddfc4ef82e23e8aeb282f2449399293295").unwrap();
+
+        // Either encoding of the key verifies both addresses
+        for key in [&compressed, &uncompressed] {
+            assert_eq!(
+                verify_address("1DW2KKsStJ4QECVfzHM2Qzh2wCBjTe9TH1", key.clone()).unwrap(),
+                "1DW2KKsStJ4QECVfzHM2Qzh2wCBjTe9TH1"
+            );
+            assert_eq!(
+                verify_address("1G77Dvm33cn8Dwx9gbiWXmokuzjrM6kxQD", key.clone()).unwrap(),
+                "1G77Dvm33cn8Dwx9gbiWXmokuzjrM6kxQD"
+            );
+        }
+
+        let public_key = bitcoin::PublicKey::from_slice(&compressed).unwrap();
+        assert_eq!(
+            match_p2pkh_key_encoding(
+                "1DW2KKsStJ4QECVfzHM2Qzh2wCBjTe9TH1",
+                &public_key,
+                bitcoin::Network::Bitcoin
+            ),
+            Some(P2pkhKeyEncoding::Compressed)
+        );
+        assert_eq!(
+            match_p2pkh_key_encoding(
+                "mvd4Wyr1reDP14RmQAgtMh25mzLZHUBKf4",
+                &public_key,
+                bitcoin::Network::Testnet
+            ),
+            Some(P2pkhKeyEncoding::Uncompressed)
+        );
+        assert_eq!(
+            match_p2pkh_key_encoding(
+                "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2",
+                &public_key,
+                bitcoin::Network::Bitcoin
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_bip137_headers() {
+        use base64::Engine;
+        use bitcoin::{Address, Network, PublicKey};
+        use k256::ecdsa::SigningKey;
+
+        let signing_key = SigningKey::from_slice(&[7; 32]).unwrap();
+        let message = "Sign in";
+        let (signature, recovery_id) = signing_key
+            .sign_prehash_recoverable(&_msg_hash(message.to_string()))
+            .unwrap();
+        let sign = |base: u8| {
+            let mut bytes = vec![base + recovery_id.to_byte()];
+            bytes.extend_from_slice(&signature.to_bytes());
+            base64::engine::general_purpose::STANDARD.encode(bytes)
+        };
+        let compressed = PublicKey::from_slice(
+            &signing_key
+                .verifying_key()
+                .to_encoded_point(true)
+                .to_bytes(),
+        )
+        .unwrap();
+        let uncompressed = PublicKey::new_uncompressed(compressed.inner);
+        let p2pkh = Address::p2pkh(&compressed, Network::Bitcoin);
+        let p2pkh_uncompressed = Address::p2pkh(&uncompressed, Network::Bitcoin);
+        let p2sh = Address::p2shwpkh(&compressed, Network::Bitcoin).unwrap();
+        let p2wpkh = Address::p2wpkh(&compressed, Network::Bitcoin).unwrap();
+        // No public key is needed, it is recovered from the signature
+        let verify = |address: &Address, base: u8| {
+            verify_signature(message, address, &sign(base), "", SignMessageType::ECDSA)
+                .map(|signer| signer.verification_path)
+                .map_err(|e| e.to_string())
+        };
+
+        assert_eq!(verify(&p2pkh, 31), Ok(VerificationPath::Ecdsa));
+        assert_eq!(
+            verify(&p2pkh_uncompressed, 27),
+            Ok(VerificationPath::EcdsaUncompressedKey)
+        );
+        assert!(verify(&p2pkh, 27).is_err());
+        assert!(verify(&p2pkh_uncompressed, 31).is_err());
+        assert_eq!(verify(&p2sh, 35), Ok(VerificationPath::Ecdsa));
+        assert_eq!(verify(&p2wpkh, 39), Ok(VerificationPath::Ecdsa));
+        // Segwit headers commit to their address type
+        assert!(verify(&p2wpkh, 35).is_err());
+        assert!(verify(&p2sh, 39).is_err());
+        // P2PKH headers are used for every address type by many wallets
+        assert_eq!(verify(&p2wpkh, 31), Ok(VerificationPath::Ecdsa));
+        assert!(verify(&p2wpkh, 43).is_err());
+        // Taproot has no header of its own; the key-path address is derived from the recovered key
+        let secp = bitcoin::secp256k1::Secp256k1::verification_only();
+        let p2tr = Address::p2tr(
+            &secp,
+            compressed.inner.x_only_public_key().0,
+            None,
+            Network::Bitcoin,
+        );
+        assert_eq!(verify(&p2tr, 31), Ok(VerificationPath::Ecdsa));
+
+        // A reported key must be the recovered one
+        assert!(verify_signature(
+            message,
+            &p2wpkh,
+            &sign(39),
+            &uncompressed.to_string(),
+            SignMessageType::ECDSA
+        )
+        .is_ok());
+        let other = PublicKey::from_slice(
+            &SigningKey::from_slice(&[8; 32])
+                .unwrap()
+                .verifying_key()
+                .to_encoded_point(true)
+                .to_bytes(),
+        )
+        .unwrap();
+        assert!(verify_signature(
+            message,
+            &p2wpkh,
+            &sign(39),
+            &other.to_string(),
+            SignMessageType::ECDSA
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_verify_address_signet() {
+        use bitcoin::{Address, Network, PublicKey};
+        use std::str::FromStr;
+
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .network(Network::Signet)
+            .build()
+            .unwrap();
+        SETTINGS.set(Some(settings));
+
+        let key = PublicKey::from_str(
+            "03133c85d348d6c0796382966380719397453592e706cd3329119a2d2cb8d2ff7b",
+        )
+        .unwrap();
+        for address in [
+            Address::p2pkh(&key, Network::Signet),
+            Address::p2wpkh(&key, Network::Signet).unwrap(),
+        ] {
+            let address = address.to_string();
+            assert_eq!(
+                verify_address(&address, key.to_bytes()),
+                Ok(address.clone())
+            );
+            assert_eq!(
+                get_script_from_address(address).unwrap().network,
+                Network::Signet
+            );
+        }
+    }
+
+    #[test]
+    fn test_ecdsa_regtest_addresses() {
+        use base64::Engine;
+        use bitcoin::{Address, Network, PublicKey};
+        use k256::ecdsa::SigningKey;
+
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .network(Network::Regtest)
+            .build()
+            .unwrap();
+        SETTINGS.set(Some(settings));
+
+        let signing_key = SigningKey::from_slice(&[7; 32]).unwrap();
+        let message = "Sign in";
+        let (signature, recovery_id) = signing_key
+            .sign_prehash_recoverable(&_msg_hash(message.to_string()))
+            .unwrap();
+        let mut bytes = vec![31 + recovery_id.to_byte()];
+        bytes.extend_from_slice(&signature.to_bytes());
+        let signature = base64::engine::general_purpose::STANDARD.encode(bytes);
+        let key = PublicKey::from_slice(
+            &signing_key
+                .verifying_key()
+                .to_encoded_point(true)
+                .to_bytes(),
+        )
+        .unwrap();
+
+        for address in [
+            Address::p2pkh(&key, Network::Regtest),
+            Address::p2shwpkh(&key, Network::Regtest).unwrap(),
+            Address::p2wpkh(&key, Network::Regtest).unwrap(),
+        ] {
+            let signer =
+                verify_signature(message, &address, &signature, "", SignMessageType::ECDSA)
+                    .map_err(|e| e.to_string())
+                    .unwrap();
+            assert_eq!(signer.network, "regtest");
+        }
+    }
+
     #[test]
     fn test_message() {
         let p = "03133c85d348d6c0796382966380719397453592e706cd3329119a2d2cb8d2ff7b".to_string();
@@ -773,7 +1641,7 @@ mod test {
         let v = _verify_message(m, s, p);
         println!("v is {:?}", v);
 
-        let v2 = verify_address(a.as_str(), v.unwrap());
+        let v2 = verify_address(a.as_str(), v.unwrap().0.to_bytes());
         println!("v2 is {:?}", v2);
     }
 
@@ -800,7 +1668,26 @@ mod test {
             s.as_str(),
             bitcoin::Network::Testnet,
         );
-        assert_eq!(v, true)
+        assert!(matches!(v, Ok(true)))
+    }
+
+    #[test]
+    fn test_bip322_p2tr_empty_witness() {
+        let a = "tb1phy4ay0kvcnelc9trqzk4ksld3qx45gm83274qxp204vzycg7hxaq2m2nrn";
+
+        // Nothing at all, and an element count without elements
+        for s in ["", "AA=="] {
+            let v =
+                verify_signature_of_bip322_simple_p2tr(a, "hello", s, bitcoin::Network::Testnet);
+            assert!(matches!(
+                v,
+                Err(LoginError::BtcError(BtcError::SignatureFormatError(_)))
+            ));
+        }
+        // An empty signature element
+        let v =
+            verify_signature_of_bip322_simple_p2tr(a, "hello", "AQA=", bitcoin::Network::Testnet);
+        assert!(matches!(v, Ok(false)));
     }
 
     #[test]
@@ -815,6 +1702,200 @@ mod test {
             s.as_str(),
             bitcoin::Network::Testnet,
         );
-        assert_eq!(v, true);
+        assert!(matches!(v, Ok(true)));
+    }
+
+    #[test]
+    fn test_bip322_segwitv0_empty_witness() {
+        let a = "tb1qf620ch70a2evf2n2jrmdk85wwpupx8qcszr2s7";
+
+        for s in ["", "AA=="] {
+            let v = verify_signature_of_bip322_simple_segwitv0(
+                a,
+                "hello",
+                s,
+                bitcoin::Network::Testnet,
+            );
+            assert!(matches!(
+                v,
+                Err(LoginError::BtcError(BtcError::SignatureFormatError(_)))
+            ));
+        }
+        let v = verify_signature_of_bip322_simple_segwitv0(
+            a,
+            "hello",
+            "AQA=",
+            bitcoin::Network::Testnet,
+        );
+        assert!(matches!(v, Ok(false)));
+    }
+
+    #[test]
+    fn test_extract_bytes_from_script() {
+        use bitcoin::ScriptBuf;
+
+        // Two pushes, of 2 bytes and of nothing (OP_0).
+        let script = ScriptBuf::from_bytes(vec![0x02, 0xab, 0xcd, 0x00]);
+        assert_eq!(
+            extract_bytes_from_script(&script, 2).unwrap(),
+            vec![vec![0xab, 0xcd], vec![]]
+        );
+        assert_eq!(
+            extract_bytes_from_script(&script, 1).unwrap_err(),
+            "Invalid script size"
+        );
+        assert_eq!(
+            extract_bytes_from_script(&script, 3).unwrap_err(),
+            "Invalid script size"
+        );
+    }
+
+    #[test]
+    fn test_extract_bytes_from_script_rejects_malformed() {
+        use bitcoin::ScriptBuf;
+
+        // A push followed by OP_CHECKSIG.
+        let with_opcode = ScriptBuf::from_bytes(vec![0x01, 0xab, 0xac]);
+        assert!(extract_bytes_from_script(&with_opcode, 2)
+            .unwrap_err()
+            .starts_with("Unexpected opcode"));
+
+        // A push of 3 bytes with only 1 left.
+        let truncated = ScriptBuf::from_bytes(vec![0x01, 0xab, 0x03, 0xcd]);
+        assert!(extract_bytes_from_script(&truncated, 2)
+            .unwrap_err()
+            .starts_with("Malformed script"));
+
+        assert!(
+            extract_bytes_from_script(&ScriptBuf::new(), 2).is_err(),
+            "An empty witness has no signature and key"
+        );
+    }
+
+    /// Times `extract_bytes_from_script` against the two-pass parser it replaced on a P2WPKH witness. Run it
+    /// with `cargo test --release -p ic_siwb --lib bench_extract_bytes_from_script -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_extract_bytes_from_script() {
+        use base64::Engine;
+        use bitcoin::script::Instruction::PushBytes;
+        use bitcoin::{Script, ScriptBuf};
+        use std::hint::black_box;
+        use std::time::Instant;
+
+        // The parser before the rewrite, which counted the instructions before collecting the pushes
+        fn two_pass(script: &Script, expect_size: usize) -> Result<Vec<Vec<u8>>, String> {
+            if script.instructions().count() != expect_size {
+                return Err("Invalid script size".to_string());
+            }
+            let mut payload = vec![];
+            for instruction in script.instructions() {
+                if let Ok(PushBytes(bytes)) = instruction {
+                    payload.push(bytes.as_bytes().to_vec());
+                }
+            }
+            Ok(payload)
+        }
+
+        let witness = base64::engine::general_purpose::STANDARD
+            .decode("AkgwRQIhAOh1XvCVjPhJbc6oELxiRjjavkOW9ebYC5gzepzjWhn0AiAPpoXFwjozO82PYiSGlnc9RoM9JknaFt5OhmrGD/J58AEhA89jkK3c5cXYcnPiBLRTC27FwKz4mzOrZ+rizCQnR/jj")
+            .unwrap();
+        let script = ScriptBuf::from_bytes(witness[1..].to_vec());
+        assert_eq!(
+            two_pass(&script, 2).unwrap(),
+            extract_bytes_from_script(&script, 2).unwrap()
+        );
+
+        type Parser = dyn Fn(&Script, usize) -> Result<Vec<Vec<u8>>, String>;
+        const ROUNDS: u32 = 200_000;
+        let time = |parse: &Parser| {
+            let start = Instant::now();
+            for _ in 0..ROUNDS {
+                black_box(parse(black_box(&script), 2)).unwrap();
+            }
+            start.elapsed() / ROUNDS
+        };
+        // Once unmeasured, to warm up
+        time(&two_pass);
+        let before = time(&two_pass);
+        let after = time(&extract_bytes_from_script);
+        println!(
+            "extract_bytes_from_script: two passes {:?}, one pass {:?}",
+            before, after
+        );
+        assert!(
+            after < before,
+            "the single pass is not faster: {:?} vs {:?}",
+            after,
+            before
+        );
+    }
+
+    #[test]
+    fn test_bip322_verify_p2wsh_multisig() {
+        use base64::Engine;
+        use bitcoin::hashes::Hash;
+        use bitcoin::opcodes::all::{OP_CHECKMULTISIG, OP_PUSHNUM_2, OP_PUSHNUM_3};
+        use bitcoin::secp256k1::{Message, Secp256k1, SecretKey};
+        use bitcoin::sighash::{EcdsaSighashType, SighashCache};
+        use bitcoin::{Address, PublicKey, Witness};
+
+        let secp = Secp256k1::new();
+        let secret = |byte: u8| SecretKey::from_slice(&[byte; 32]).unwrap();
+        let key = |byte: u8| PublicKey::new(secret(byte).public_key(&secp));
+        let witness_script = bitcoin::script::Builder::new()
+            .push_opcode(OP_PUSHNUM_2)
+            .push_key(&key(1))
+            .push_key(&key(2))
+            .push_key(&key(3))
+            .push_opcode(OP_PUSHNUM_3)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script();
+        let address = Address::p2wsh(&witness_script, bitcoin::Network::Testnet);
+        let to_sign = bip0322_tx(&bip0322_hash("hello"), address.script_pubkey());
+        let sign = |byte: u8| {
+            let sighash = SighashCache::new(&to_sign)
+                .segwit_signature_hash(0, &witness_script, 0, EcdsaSighashType::All)
+                .unwrap();
+            let message = Message::from_slice(&sighash.to_byte_array()).unwrap();
+            bitcoin::ecdsa::Signature {
+                sig: secp.sign_ecdsa(&message, &secret(byte)),
+                hash_ty: EcdsaSighashType::All,
+            }
+            .to_vec()
+        };
+        let encode = |stack: &[Vec<u8>]| {
+            base64::engine::general_purpose::STANDARD
+                .encode(bitcoin::consensus::serialize(&Witness::from_slice(stack)))
+        };
+        let verify = |message: &str, signature: &str| {
+            verify_signature(
+                message,
+                &address,
+                signature,
+                "",
+                SignMessageType::Bip322Simple,
+            )
+        };
+
+        let signature = encode(&[vec![], sign(1), sign(3), witness_script.to_bytes()]);
+        let signer = verify("hello", &signature).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(signer.address_type, "p2wsh");
+        assert_eq!(signer.verification_path, VerificationPath::Bip322Simple);
+        assert!(verify("hello!", &signature).is_err());
+
+        // Below the threshold
+        let one = encode(&[vec![], sign(2), witness_script.to_bytes()]);
+        assert!(verify("hello", &one).is_err());
+        // A witness script the address does not commit to
+        let other = bitcoin::script::Builder::new()
+            .push_opcode(OP_PUSHNUM_2)
+            .push_key(&key(1))
+            .push_key(&key(3))
+            .push_opcode(OP_PUSHNUM_2)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script();
+        let wrong_script = encode(&[vec![], sign(1), sign(3), other.to_bytes()]);
+        assert!(verify("hello", &wrong_script).is_err());
     }
 }
@@ -0,0 +1,108 @@
+//! Counters for the login flow, enabled with the `metrics` feature. Without the feature every
+//! [`increment`] compiles to nothing, so the instrumented call sites cost nothing.
+//!
+//! Like the SIWB messages, the counters live on the heap and start from zero after an upgrade.
+
+#[cfg(feature = "metrics")]
+use candid::{CandidType, Deserialize};
+
+/// An event counted by the library.
+#[derive(Clone, Copy)]
+pub(crate) enum Counter {
+    /// A SIWB message was prepared.
+    PrepareLogin,
+    /// `prepare_login` rejected the address.
+    PrepareLoginRejected,
+    /// A login issued a delegation.
+    Login,
+    /// A login was rejected.
+    LoginFailed,
+    /// An expired SIWB message was removed.
+    MessagePruned,
+    /// An expired delegation signature was removed.
+    SignaturePruned,
+    /// All SIWB messages and signatures were dropped by `prune_all`.
+    PruneAll,
+    VerifiedEcdsa,
+    VerifiedEcdsaUncompressedKey,
+    VerifiedBip322Simple,
+    VerifiedBip322Full,
+    /// `verify_signature` rejected the signature.
+    VerificationFailed,
+}
+
+#[cfg(feature = "metrics")]
+const COUNTERS: usize = Counter::VerificationFailed as usize + 1;
+
+#[cfg(feature = "metrics")]
+thread_local! {
+    static COUNTS: std::cell::RefCell<[u64; COUNTERS]> = const { std::cell::RefCell::new([0; COUNTERS]) };
+}
+
+/// Adds `by` to `counter`.
+#[inline(always)]
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn increment(counter: Counter, by: u64) {
+    #[cfg(feature = "metrics")]
+    COUNTS.with_borrow_mut(|counts| {
+        let count = &mut counts[counter as usize];
+        *count = count.saturating_add(by);
+    });
+}
+
+/// The counters at one point in time, see [`metrics_snapshot`].
+#[cfg(feature = "metrics")]
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub prepare_login: u64,
+    pub prepare_login_rejected: u64,
+    pub login: u64,
+    pub login_failed: u64,
+    pub messages_pruned: u64,
+    pub signatures_pruned: u64,
+    pub prune_all: u64,
+    /// Successful verifications per branch of `verify_signature`.
+    pub verified_ecdsa: u64,
+    pub verified_ecdsa_uncompressed_key: u64,
+    pub verified_bip322_simple: u64,
+    pub verified_bip322_full: u64,
+    pub verification_failed: u64,
+}
+
+/// Returns the counters collected since the canister was installed or last upgraded.
+#[cfg(feature = "metrics")]
+pub fn metrics_snapshot() -> MetricsSnapshot {
+    COUNTS.with_borrow(|counts| {
+        let count = |counter: Counter| counts[counter as usize];
+        MetricsSnapshot {
+            prepare_login: count(Counter::PrepareLogin),
+            prepare_login_rejected: count(Counter::PrepareLoginRejected),
+            login: count(Counter::Login),
+            login_failed: count(Counter::LoginFailed),
+            messages_pruned: count(Counter::MessagePruned),
+            signatures_pruned: count(Counter::SignaturePruned),
+            prune_all: count(Counter::PruneAll),
+            verified_ecdsa: count(Counter::VerifiedEcdsa),
+            verified_ecdsa_uncompressed_key: count(Counter::VerifiedEcdsaUncompressedKey),
+            verified_bip322_simple: count(Counter::VerifiedBip322Simple),
+            verified_bip322_full: count(Counter::VerifiedBip322Full),
+            verification_failed: count(Counter::VerificationFailed),
+        }
+    })
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_increment_is_reflected_in_snapshot() {
+        let before = metrics_snapshot();
+        increment(Counter::Login, 1);
+        increment(Counter::SignaturePruned, 3);
+        let after = metrics_snapshot();
+        assert_eq!(after.login, before.login + 1);
+        assert_eq!(after.signatures_pruned, before.signatures_pruned + 3);
+        assert_eq!(after.login_failed, before.login_failed);
+    }
+}
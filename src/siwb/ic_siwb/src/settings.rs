@@ -1,17 +1,44 @@
-use bitcoin::Network;
-use candid::Principal;
+use bitcoin::{AddressType, Network};
+use candid::{CandidType, Deserialize, Principal};
+use std::collections::HashMap;
 use url::Url;
 
+use crate::siwb::CLOCK_SKEW_TOLERANCE_NS;
+
 const DEFAULT_SCHEME: &str = "https";
-const DEFAULT_STATEMENT: &str = "SIWB Fields:";
+/// The statement of messages when none is configured.
+pub const DEFAULT_STATEMENT: &str = "SIWB Fields:";
 // const DEFAULT_CHAIN_ID: u32 = 1; // Bitcoin mainnet
 const DEFAULT_SIGN_IN_EXPIRES_IN: u64 = 60 * 5 * 1_000_000_000; // 5 minutes
 const DEFAULT_SESSION_EXPIRES_IN: u64 = 30 * 60 * 1_000_000_000; // 30 minutes
+/// Room for a full BIP-322 proof of a large multisig; single-key signatures are under 200 bytes.
+pub const DEFAULT_MAX_SIGNATURE_BYTES: usize = 16 * 1024;
+/// The most resources one SIWB message may list.
+pub const MAX_MESSAGE_RESOURCES: usize = 16;
+/// The longest request ID of a SIWB message, in bytes.
+pub const MAX_REQUEST_ID_LENGTH: usize = 128;
+/// The largest accepted `Settings::clock_skew_tolerance_ns`: 5 minutes.
+pub const MAX_CLOCK_SKEW_TOLERANCE_NS: u64 = 5 * 60 * 1_000_000_000;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum RuntimeFeature {
     // Enabling this feature will include the app frontend URI as part of the identity seed.
     IncludeUriInSeed,
+
+    // Enabling this feature will include the session epoch as part of the identity seed. Rotating the
+    // epoch then also changes the principal of every user.
+    IncludeSessionEpochInSeed,
+}
+
+/// Translations of the human-readable parts of the SIWB message for one locale. The field labels ("URI:",
+/// "Nonce:", ...) and their values are parsed by wallets and verifiers and are never translated.
+#[derive(CandidType, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct MessageLocale {
+    /// Replaces "wants you to sign in with your Bitcoin account:" after the domain on the first line.
+    pub header: Option<String>,
+
+    /// Replaces `Settings::statement`.
+    pub statement: Option<String>,
 }
 
 /// Represents the settings for initializing SIWB.
@@ -44,6 +71,11 @@ pub struct Settings {
     /// The TTL for a sign-in message in nanoseconds. After this time, the sign-in message will be pruned.
     pub sign_in_expires_in: u64,
 
+    /// The longest TTL in nanoseconds a single sign-in message may request through
+    /// [`crate::siwb::MessageOptions::expires_in`]. Defaults to None, which caps it at `sign_in_expires_in`, so
+    /// per-call TTLs can only be shorter.
+    pub max_sign_in_expires_in: Option<u64>,
+
     /// The TTL for a session in nanoseconds.
     pub session_expires_in: u64,
 
@@ -55,6 +87,28 @@ pub struct Settings {
     pub runtime_features: Option<Vec<RuntimeFeature>>,
 
     pub network: Network,
+
+    /// The address types that may sign in. Defaults to None, which means that every address type the
+    /// library can verify is accepted. Note that all P2SH ("3…"/"2…") addresses are verified as
+    /// P2SH-P2WPKH, so leaving `P2sh` out is the way to refuse wrapped-segwit logins.
+    pub allowed_address_types: Option<Vec<AddressType>>,
+
+    /// Translations of the SIWB message keyed by locale, e.g. "de". A locale is selected per message, see
+    /// [`crate::siwb::MessageOptions`]. Messages without a locale are rendered in English.
+    pub locales: HashMap<String, MessageLocale>,
+
+    /// The largest signature, once decoded, that is verified. Longer encodings are rejected before they are
+    /// decoded. Defaults to 16 KiB.
+    pub max_signature_bytes: usize,
+
+    /// The URIs every SIWB message lists under "Resources:", e.g. the terms the user accepts by signing in.
+    /// Replaced per message by [`crate::siwb::MessageOptions::resources`]. Defaults to none.
+    pub resources: Vec<String>,
+
+    /// How far, in nanoseconds, the canister clock may be outside the validity window of a SIWB message before
+    /// it counts as expired, e.g. when a hardware wallet takes long to sign. Also added to the expiration of
+    /// delegations. Defaults to [`CLOCK_SKEW_TOLERANCE_NS`].
+    pub clock_skew_tolerance_ns: u64,
 }
 
 /// A builder for creating `Settings` instances.
@@ -106,10 +160,16 @@ impl SettingsBuilder {
                 scheme: DEFAULT_SCHEME.to_string(),
                 statement: DEFAULT_STATEMENT.to_string(),
                 sign_in_expires_in: DEFAULT_SIGN_IN_EXPIRES_IN,
+                max_sign_in_expires_in: None,
                 session_expires_in: DEFAULT_SESSION_EXPIRES_IN,
                 targets: None,
                 runtime_features: None,
                 network: Network::Bitcoin,
+                allowed_address_types: None,
+                locales: HashMap::new(),
+                max_signature_bytes: DEFAULT_MAX_SIGNATURE_BYTES,
+                resources: vec![],
+                clock_skew_tolerance_ns: CLOCK_SKEW_TOLERANCE_NS,
             },
         }
     }
@@ -142,6 +202,13 @@ impl SettingsBuilder {
         self
     }
 
+    /// Lets a sign-in message request a TTL of up to `max_expires_in` nanoseconds, e.g. for slow hardware-wallet
+    /// flows. Must be at least `sign_in_expires_in`.
+    pub fn max_sign_in_expires_in(mut self, max_expires_in: u64) -> Self {
+        self.settings.max_sign_in_expires_in = Some(max_expires_in);
+        self
+    }
+
     /// Sessions (as represented by delegete identities) are valid for a limited time, after which they expire.
     /// The `session_expires_in` value is the time-to-live (TTL) for a session in nanoseconds. Defaults to 30 minutes.
     pub fn session_expires_in(mut self, expires_in: u64) -> Self {
@@ -162,17 +229,101 @@ impl SettingsBuilder {
         self
     }
 
+    /// Restricts sign-in to the given address types, e.g. only `P2tr` and `P2wpkh`. [`crate::login::prepare_login`]
+    /// rejects any other address with [`crate::error::BtcError::AddressTypeNotAllowed`].
+    pub fn allowed_address_types(mut self, address_types: Vec<AddressType>) -> Self {
+        self.settings.allowed_address_types = Some(address_types);
+        self
+    }
+
+    /// Adds the translations of the SIWB message for `locale`, e.g. "de".
+    pub fn locale<S: Into<String>>(mut self, locale: S, translation: MessageLocale) -> Self {
+        self.settings.locales.insert(locale.into(), translation);
+        self
+    }
+
+    /// Limits the size of the signatures that are verified, in decoded bytes. Defaults to 16 KiB, which fits a
+    /// full BIP-322 proof of a 15-of-15 multisig.
+    pub fn max_signature_bytes(mut self, max_bytes: usize) -> Self {
+        self.settings.max_signature_bytes = max_bytes;
+        self
+    }
+
+    /// Lists `resources` in every SIWB message, see [`Settings::resources`]. At most 16 URIs.
+    pub fn resources(mut self, resources: Vec<String>) -> Self {
+        self.settings.resources = resources;
+        self
+    }
+
+    /// Tolerates clocks that are `tolerance_ns` nanoseconds apart, see [`Settings::clock_skew_tolerance_ns`]. At
+    /// most 5 minutes. Defaults to 1 second.
+    pub fn clock_skew_tolerance_ns(mut self, tolerance_ns: u64) -> Self {
+        self.settings.clock_skew_tolerance_ns = tolerance_ns;
+        self
+    }
+
+    /// Checks every setting without building, returning each invalid one by name with the reason it was
+    /// rejected. Where [`Self::build`] stops at the first error, this lists all of them, e.g. for a dry run.
+    pub fn validate(&self) -> Vec<(&'static str, String)> {
+        let settings = &self.settings;
+        let mut errors = vec![];
+        let mut check = |field: &'static str, result: Result<(), String>| {
+            if let Err(e) = result {
+                errors.push((field, e));
+            }
+        };
+        check(
+            "domain",
+            validate_domain(&settings.scheme, &settings.domain).map(drop),
+        );
+        check("uri", validate_uri(&settings.uri).map(drop));
+        check("salt", validate_salt(&settings.salt).map(drop));
+        check("scheme", validate_scheme(&settings.scheme).map(drop));
+        check(
+            "statement",
+            validate_statement(&settings.statement).map(drop),
+        );
+        check(
+            "sign_in_expires_in",
+            validate_sign_in_expires_in(settings.sign_in_expires_in).map(drop),
+        );
+        check(
+            "max_sign_in_expires_in",
+            validate_max_sign_in_expires_in(
+                settings.max_sign_in_expires_in,
+                settings.sign_in_expires_in,
+            ),
+        );
+        check(
+            "clock_skew_tolerance_ns",
+            validate_clock_skew_tolerance(settings.clock_skew_tolerance_ns),
+        );
+        check(
+            "session_expires_in",
+            validate_session_expires_in(settings.session_expires_in).map(drop),
+        );
+        check("targets", validate_targets(&settings.targets).map(drop));
+        check("network", validate_network(settings.network).map(drop));
+        check(
+            "allowed_address_types",
+            validate_allowed_address_types(&settings.allowed_address_types).map(drop),
+        );
+        check(
+            "max_signature_bytes",
+            validate_max_signature_bytes(settings.max_signature_bytes).map(drop),
+        );
+        check("resources", validate_resources(&settings.resources));
+        for translation in settings.locales.values() {
+            check("locales", validate_message_locale(translation));
+        }
+        errors
+    }
+
     pub fn build(self) -> Result<Settings, String> {
-        validate_domain(&self.settings.scheme, &self.settings.domain)?;
-        validate_uri(&self.settings.uri)?;
-        validate_salt(&self.settings.salt)?;
-        validate_scheme(&self.settings.scheme)?;
-        validate_statement(&self.settings.statement)?;
-        validate_sign_in_expires_in(self.settings.sign_in_expires_in)?;
-        validate_session_expires_in(self.settings.session_expires_in)?;
-        validate_targets(&self.settings.targets)?;
-        validate_network(self.settings.network)?;
-        Ok(self.settings)
+        match self.validate().into_iter().next() {
+            Some((_, e)) => Err(e),
+            None => Ok(self.settings),
+        }
     }
 }
 
@@ -220,6 +371,20 @@ fn validate_scheme(scheme: &str) -> Result<String, String> {
     Err(String::from("Invalid scheme"))
 }
 
+/// Translated phrases must keep the message on its fixed lines so that the machine-parsed fields stay where
+/// wallets expect them.
+pub(crate) fn validate_message_locale(translation: &MessageLocale) -> Result<(), String> {
+    if let Some(header) = &translation.header {
+        if header.trim().is_empty() || header.contains('\n') {
+            return Err(String::from("Invalid message header"));
+        }
+    }
+    if let Some(statement) = &translation.statement {
+        validate_statement(statement)?;
+    }
+    Ok(())
+}
+
 fn validate_statement(statement: &str) -> Result<String, String> {
     if statement.contains('\n') {
         return Err(String::from("Invalid statement"));
@@ -227,6 +392,36 @@ fn validate_statement(statement: &str) -> Result<String, String> {
     Ok(statement.to_string())
 }
 
+pub(crate) fn validate_resources(resources: &[String]) -> Result<(), String> {
+    if resources.len() > MAX_MESSAGE_RESOURCES {
+        return Err(format!("At most {} resources", MAX_MESSAGE_RESOURCES));
+    }
+    for resource in resources {
+        if Url::parse(resource).is_err() || resource.contains(char::is_whitespace) {
+            return Err(format!("Invalid resource: {}", resource));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_request_id(request_id: &str) -> Result<(), String> {
+    if request_id.is_empty()
+        || request_id.len() > MAX_REQUEST_ID_LENGTH
+        || request_id.contains(|c: char| c.is_whitespace() || c.is_control())
+    {
+        return Err(String::from("Invalid request ID"));
+    }
+    Ok(())
+}
+
+// A compact ECDSA signature is the smallest signature accepted
+fn validate_max_signature_bytes(max_bytes: usize) -> Result<usize, String> {
+    if max_bytes < 65 {
+        return Err(String::from("Max signature bytes must be at least 65"));
+    }
+    Ok(max_bytes)
+}
+
 fn validate_sign_in_expires_in(expires_in: u64) -> Result<u64, String> {
     if expires_in == 0 {
         return Err(String::from("Sign in expires in must be greater than 0"));
@@ -234,6 +429,27 @@ fn validate_sign_in_expires_in(expires_in: u64) -> Result<u64, String> {
     Ok(expires_in)
 }
 
+fn validate_max_sign_in_expires_in(
+    max_expires_in: Option<u64>,
+    expires_in: u64,
+) -> Result<(), String> {
+    match max_expires_in {
+        Some(max) if max < expires_in => Err(String::from(
+            "Max sign in expires in must be at least sign in expires in",
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn validate_clock_skew_tolerance(tolerance_ns: u64) -> Result<(), String> {
+    if tolerance_ns > MAX_CLOCK_SKEW_TOLERANCE_NS {
+        return Err(String::from(
+            "Clock skew tolerance must be at most 5 minutes",
+        ));
+    }
+    Ok(())
+}
+
 fn validate_session_expires_in(expires_in: u64) -> Result<u64, String> {
     if expires_in == 0 {
         return Err(String::from("Session expires in must be greater than 0"));
@@ -275,6 +491,17 @@ fn validate_network(network: Network) -> Result<Network, String> {
     }
 }
 
+fn validate_allowed_address_types(
+    address_types: &Option<Vec<AddressType>>,
+) -> Result<Option<Vec<AddressType>>, String> {
+    if let Some(address_types) = address_types {
+        if address_types.is_empty() {
+            return Err(String::from("Allowed address types cannot be empty"));
+        }
+    }
+    Ok(address_types.clone())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -368,6 +595,33 @@ mod tests {
         assert!(builder.build().is_err());
     }
 
+    #[test]
+    fn test_max_sign_in_expires_in_below_default() {
+        let builder = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .sign_in_expires_in(10_000_000_000)
+            .max_sign_in_expires_in(5_000_000_000);
+        assert_eq!(
+            builder.build().unwrap_err(),
+            "Max sign in expires in must be at least sign in expires in"
+        );
+    }
+
+    #[test]
+    fn test_clock_skew_tolerance() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .clock_skew_tolerance_ns(30_000_000_000)
+            .build()
+            .unwrap();
+        assert_eq!(settings.clock_skew_tolerance_ns, 30_000_000_000);
+
+        let builder = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .clock_skew_tolerance_ns(MAX_CLOCK_SKEW_TOLERANCE_NS + 1);
+        assert_eq!(
+            builder.build().unwrap_err(),
+            "Clock skew tolerance must be at most 5 minutes"
+        );
+    }
+
     // Test empty targets
     #[test]
     fn test_empty_targets() {
@@ -546,6 +800,29 @@ mod tests {
         assert!(builder.build().is_err());
     }
 
+    #[test]
+    fn test_invalid_locale_phrases() {
+        let builder = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .locale(
+                "de",
+                MessageLocale {
+                    header: Some("möchte, dass Sie sich\nanmelden:".to_string()),
+                    statement: None,
+                },
+            );
+        assert!(builder.build().is_err());
+
+        let builder = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .locale(
+                "de",
+                MessageLocale {
+                    header: None,
+                    statement: Some("Ungültige\nAussage".to_string()),
+                },
+            );
+        assert!(builder.build().is_err());
+    }
+
     // Test Validating an Empty SettingsBuilder
     #[test]
     fn test_validating_an_empty_settingsbuilder() {
@@ -559,4 +836,61 @@ mod tests {
         let builder = SettingsBuilder::new("xn--exmple-cua.com", "http://example.com", "some_salt");
         assert!(builder.build().is_ok());
     }
+
+    #[test]
+    fn test_allowed_address_types() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .allowed_address_types(vec![AddressType::P2tr, AddressType::P2wpkh])
+            .build()
+            .expect("Failed to create settings with allowed address types");
+        assert_eq!(
+            settings.allowed_address_types,
+            Some(vec![AddressType::P2tr, AddressType::P2wpkh])
+        );
+    }
+
+    #[test]
+    fn test_empty_allowed_address_types() {
+        let builder = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .allowed_address_types(vec![]);
+        assert!(builder.build().is_err());
+    }
+
+    #[test]
+    fn test_p2wsh_allowed() {
+        let builder = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .allowed_address_types(vec![AddressType::P2wsh]);
+        assert!(builder.build().is_ok());
+    }
+
+    #[test]
+    fn test_max_signature_bytes() {
+        let builder = || SettingsBuilder::new("example.com", "http://example.com", "some_salt");
+        assert_eq!(
+            builder().build().unwrap().max_signature_bytes,
+            DEFAULT_MAX_SIGNATURE_BYTES
+        );
+        assert_eq!(
+            builder()
+                .max_signature_bytes(1024)
+                .build()
+                .unwrap()
+                .max_signature_bytes,
+            1024
+        );
+        assert!(builder().max_signature_bytes(64).build().is_err());
+    }
+
+    #[test]
+    fn test_validate_lists_every_error() {
+        let builder = SettingsBuilder::new("example.com", "http://example.com", "some_salt");
+        assert!(builder.validate().is_empty());
+
+        let builder = SettingsBuilder::new("example.com", "invalid_uri", "")
+            .scheme("ftp")
+            .max_signature_bytes(64);
+        let fields: Vec<_> = builder.validate().into_iter().map(|(f, _)| f).collect();
+        assert_eq!(fields, vec!["uri", "salt", "scheme", "max_signature_bytes"]);
+        assert_eq!(builder.build().unwrap_err(), "Invalid URI");
+    }
 }
@@ -0,0 +1,322 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::Hash;
+use bitcoin::key::XOnlyPublicKey;
+use bitcoin::secp256k1::{Message, Secp256k1};
+use bitcoin::sighash::{Prevouts, SighashCache};
+use bitcoin::{Address, AddressType, PublicKey, ScriptBuf, TxOut, Witness};
+use candid::{CandidType, Deserialize};
+
+use crate::error::BtcError;
+use crate::login::{
+    bip0322_hash, bip0322_tx, LoginError, SignMessageType, VerificationPath, VerifiedSigner,
+};
+use crate::time::get_current_time;
+use crate::utils::{
+    decode_base64_bounded, get_script_from_address, max_signature_bytes, AddressInfo,
+};
+
+/// The maximum number of divergences kept by [`shadow_report`].
+const MAX_DIVERGENCES_TRACKED: usize = 100;
+
+/// A signature verifier with the signature of [`crate::login::verify_signature`].
+pub type Verifier =
+    fn(&str, &Address, &str, &str, SignMessageType) -> Result<VerifiedSigner, LoginError>;
+
+thread_local! {
+    // The candidate run alongside every login verification, if any.
+    static SHADOW_VERIFIER: Cell<Option<Verifier>> = const { Cell::new(None) };
+
+    static COMPARISONS: Cell<u64> = const { Cell::new(0) };
+
+    // Newest first.
+    static DIVERGENCES: RefCell<VecDeque<Divergence>> = const { RefCell::new(VecDeque::new()) };
+}
+
+/// A login for which the shadow verifier disagreed with the verifier that decided the login.
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct Divergence {
+    pub observed_at: u64,
+    pub address: String,
+    pub sign_message_type: SignMessageType,
+    /// The outcome of the verifier that decided the login.
+    pub primary: String,
+    pub shadow: String,
+}
+
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct ShadowReport {
+    /// The number of logins verified by both verifiers.
+    pub comparisons: u64,
+    /// The most recent divergences, newest first.
+    pub divergences: Vec<Divergence>,
+}
+
+/// Runs `verifier` in shadow of every login verification from now on, or stops shadowing if None. The shadow
+/// result is only compared and recorded, it never changes the outcome of a login, so a refactored verifier
+/// can be validated against production traffic before it replaces the current one. The verifier must not
+/// panic, as a trap would fail the login.
+pub fn set_shadow_verifier(verifier: Option<Verifier>) {
+    SHADOW_VERIFIER.set(verifier);
+}
+
+pub fn shadow_report() -> ShadowReport {
+    ShadowReport {
+        comparisons: COMPARISONS.get(),
+        divergences: DIVERGENCES.with_borrow(|d| d.iter().cloned().collect()),
+    }
+}
+
+pub fn clear_shadow_report() {
+    COMPARISONS.set(0);
+    DIVERGENCES.with_borrow_mut(|d| d.clear());
+}
+
+fn outcome(result: &Result<VerifiedSigner, LoginError>) -> String {
+    match result {
+        Ok(signer) => format!("{:?}", signer),
+        Err(e) => format!("Err({})", e),
+    }
+}
+
+/// Verifies the login with the shadow verifier, if one is set, and records a divergence from `primary`.
+pub(crate) fn compare_with_shadow(
+    message: &str,
+    address: &Address,
+    signature: &str,
+    public_key: &str,
+    sign_message_type: SignMessageType,
+    primary: &Result<VerifiedSigner, LoginError>,
+) {
+    let Some(verifier) = SHADOW_VERIFIER.get() else {
+        return;
+    };
+    let shadow = verifier(
+        message,
+        address,
+        signature,
+        public_key,
+        sign_message_type.clone(),
+    );
+    COMPARISONS.set(COMPARISONS.get() + 1);
+
+    let agree = match (primary, &shadow) {
+        (Ok(primary), Ok(shadow)) => primary == shadow,
+        (Err(_), Err(_)) => true,
+        _ => false,
+    };
+    if agree {
+        return;
+    }
+    let divergence = Divergence {
+        observed_at: get_current_time(),
+        address: address.to_string(),
+        sign_message_type,
+        primary: outcome(primary),
+        shadow: outcome(&shadow),
+    };
+    DIVERGENCES.with_borrow_mut(|d| {
+        d.push_front(divergence);
+        d.truncate(MAX_DIVERGENCES_TRACKED);
+    });
+}
+
+/// A BIP-322 simple verifier that decodes the signature as a consensus encoded witness, instead of slicing
+/// fixed offsets out of it, and takes the sighash type from the signature. ECDSA signatures and full proofs are left
+/// to [`crate::login::verify_signature`].
+pub fn verify_bip322_witness(
+    message: &str,
+    address: &Address,
+    signature: &str,
+    public_key: &str,
+    sign_message_type: SignMessageType,
+) -> Result<VerifiedSigner, LoginError> {
+    if sign_message_type != SignMessageType::Bip322Simple {
+        return crate::login::verify_signature(
+            message,
+            address,
+            signature,
+            public_key,
+            sign_message_type,
+        );
+    }
+
+    let AddressInfo {
+        network,
+        address_type,
+        script_buf,
+        ..
+    } = get_script_from_address(address.to_string()).map_err(|_| LoginError::AddressMismatch)?;
+    let data = decode_base64_bounded(signature, max_signature_bytes())?;
+    let witness: Witness = deserialize(&data).map_err(|_| {
+        LoginError::BtcError(BtcError::SignatureFormatError(
+            "Invalid BIP-322 witness".to_string(),
+        ))
+    })?;
+
+    let to_sign = bip0322_tx(&bip0322_hash(message), script_buf.clone());
+    let verified = match address_type {
+        AddressType::P2tr => verify_p2tr_witness(&witness, &script_buf, to_sign),
+        AddressType::P2wpkh => verify_p2wpkh_witness(&witness, &script_buf, to_sign),
+        _ => return Err(LoginError::BtcError(BtcError::AddressTypeNotSupported)),
+    };
+    if !verified {
+        return Err(LoginError::AddressMismatch);
+    }
+    Ok(VerifiedSigner {
+        address_type: address_type.to_string(),
+        network: network.to_string(),
+        public_key: None,
+        verification_path: VerificationPath::Bip322Simple,
+    })
+}
+
+fn verify_p2tr_witness(
+    witness: &Witness,
+    script: &ScriptBuf,
+    mut to_sign: bitcoin::Transaction,
+) -> bool {
+    let (Some(element), 1) = (witness.nth(0), witness.len()) else {
+        return false;
+    };
+    let (Ok(signature), Ok(key)) = (
+        bitcoin::taproot::Signature::from_slice(element),
+        XOnlyPublicKey::from_slice(&script.as_bytes()[2..]),
+    ) else {
+        return false;
+    };
+    let prevouts = [TxOut {
+        value: 0,
+        script_pubkey: script.clone(),
+    }];
+    let sighash = SighashCache::new(&mut to_sign).taproot_key_spend_signature_hash(
+        0,
+        &Prevouts::All(&prevouts),
+        signature.hash_ty,
+    );
+    match sighash.map(|h| Message::from_slice(h.as_byte_array())) {
+        Ok(Ok(message)) => Secp256k1::verification_only()
+            .verify_schnorr(&signature.sig, &message, &key)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+fn verify_p2wpkh_witness(
+    witness: &Witness,
+    script: &ScriptBuf,
+    mut to_sign: bitcoin::Transaction,
+) -> bool {
+    let (Some(signature), Some(key), 2) = (witness.nth(0), witness.nth(1), witness.len()) else {
+        return false;
+    };
+    let (Ok(signature), Ok(key)) = (
+        bitcoin::ecdsa::Signature::from_slice(signature),
+        PublicKey::from_slice(key),
+    ) else {
+        return false;
+    };
+    // The key must be the one the address commits to, which also rules out uncompressed keys.
+    if key
+        .wpubkey_hash()
+        .map(|hash| ScriptBuf::new_v0_p2wpkh(&hash))
+        != Some(script.clone())
+    {
+        return false;
+    }
+    let Some(script_code) = script.p2wpkh_script_code() else {
+        return false;
+    };
+    let sighash = SighashCache::new(&mut to_sign).segwit_signature_hash(
+        0,
+        &script_code,
+        0,
+        signature.hash_ty,
+    );
+    match sighash.map(|h| Message::from_slice(h.as_byte_array())) {
+        Ok(Ok(message)) => Secp256k1::verification_only()
+            .verify_ecdsa(&message, &signature.sig, &key.inner)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn address(address: &str) -> Address {
+        Address::from_str(address).unwrap().assume_checked()
+    }
+
+    #[test]
+    fn test_witness_verifier_p2tr() {
+        let signer = verify_bip322_witness(
+            "hello",
+            &address("tb1phy4ay0kvcnelc9trqzk4ksld3qx45gm83274qxp204vzycg7hxaq2m2nrn"),
+            "AUBNN/m5COckJE1nj5bR9iAO+Ga5VlJU2xIIGBraFZQNDUtOO0J0tOhoQzvk0o+YwknQ3OGWyWR5VwiG2KzJwjUV",
+            "",
+            SignMessageType::Bip322Simple,
+        )
+        .unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(signer.address_type, "p2tr");
+        assert_eq!(signer.verification_path, VerificationPath::Bip322Simple);
+    }
+
+    #[test]
+    fn test_witness_verifier_p2wpkh() {
+        let a = address("tb1qf620ch70a2evf2n2jrmdk85wwpupx8qcszr2s7");
+        let s = "AkgwRQIhAOh1XvCVjPhJbc6oELxiRjjavkOW9ebYC5gzepzjWhn0AiAPpoXFwjozO82PYiSGlnc9RoM9JknaFt5OhmrGD/J58AEhA89jkK3c5cXYcnPiBLRTC27FwKz4mzOrZ+rizCQnR/jj";
+        assert!(verify_bip322_witness("hello", &a, s, "", SignMessageType::Bip322Simple).is_ok());
+        assert!(verify_bip322_witness("hello!", &a, s, "", SignMessageType::Bip322Simple).is_err());
+    }
+
+    fn reject_all(
+        _: &str,
+        _: &Address,
+        _: &str,
+        _: &str,
+        _: SignMessageType,
+    ) -> Result<VerifiedSigner, LoginError> {
+        Err(LoginError::AddressMismatch)
+    }
+
+    #[test]
+    fn test_divergences_are_recorded() {
+        let a = address("tb1qf620ch70a2evf2n2jrmdk85wwpupx8qcszr2s7");
+        let primary = Ok(VerifiedSigner {
+            address_type: "p2wpkh".to_string(),
+            network: "testnet".to_string(),
+            public_key: None,
+            verification_path: VerificationPath::Bip322Simple,
+        });
+
+        compare_with_shadow("hello", &a, "", "", SignMessageType::Bip322Simple, &primary);
+        assert_eq!(shadow_report().comparisons, 0);
+
+        set_shadow_verifier(Some(reject_all));
+        compare_with_shadow("hello", &a, "", "", SignMessageType::Bip322Simple, &primary);
+        compare_with_shadow(
+            "hello",
+            &a,
+            "",
+            "",
+            SignMessageType::Bip322Simple,
+            &Err(LoginError::AddressMismatch),
+        );
+        let report = shadow_report();
+        assert_eq!(report.comparisons, 2);
+        assert_eq!(report.divergences.len(), 1);
+        assert_eq!(
+            report.divergences[0].shadow,
+            "Err(Recovered address does not match)"
+        );
+
+        clear_shadow_report();
+        assert_eq!(shadow_report().comparisons, 0);
+        set_shadow_verifier(None);
+    }
+}
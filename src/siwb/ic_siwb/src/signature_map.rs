@@ -1,6 +1,6 @@
 use ic_certified_map::{leaf_hash, AsHashTree, Hash, HashTree, RbTree};
 use std::borrow::Cow;
-use std::collections::BinaryHeap;
+use std::collections::{BTreeMap, BTreeSet};
 
 use crate::time::get_current_time;
 
@@ -18,38 +18,138 @@ impl AsHashTree for Unit {
     }
 }
 
-#[derive(PartialEq, Eq)]
-struct SigExpiration {
-    seed_hash: Hash,
-    delegation_hash: Hash,
-    signature_expires_at: u64,
-}
+/// Storage for the signatures behind a [`SignatureMap`]: every (seed hash, delegation hash) pair with the time
+/// its signature expires. The certified tree itself is always kept on the heap, because certification needs it
+/// in memory; the store keeps the expiration index and lets the tree be rebuilt, e.g. after an upgrade.
+pub trait SignatureStore {
+    /// Records a signature, replacing the expiration of an existing entry.
+    fn insert(&mut self, seed_hash: Hash, delegation_hash: Hash, expires_at: u64);
+
+    fn remove(&mut self, seed_hash: Hash, delegation_hash: Hash);
+
+    /// Removes and returns up to `max` entries that expired at or before `now`, earliest first.
+    fn pop_expired(&mut self, now: u64, max: usize) -> Vec<(Hash, Hash)>;
+
+    /// All entries as (seed hash, delegation hash, expires at).
+    fn entries(&self) -> Vec<(Hash, Hash, u64)>;
 
-impl Ord for SigExpiration {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        // BinaryHeap is a max heap, but we want expired entries
-        // first, hence the inversed order.
-        other.signature_expires_at.cmp(&self.signature_expires_at)
+    fn clear(&mut self);
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 }
 
-impl PartialOrd for SigExpiration {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
+/// The default [`SignatureStore`], kept on the heap and lost on upgrade.
+#[derive(Default)]
+pub struct HeapSignatureStore {
+    expirations: BTreeMap<(Hash, Hash), u64>,
+    queue: BTreeSet<(u64, Hash, Hash)>,
+}
+
+impl SignatureStore for HeapSignatureStore {
+    fn insert(&mut self, seed_hash: Hash, delegation_hash: Hash, expires_at: u64) {
+        if let Some(previous) = self
+            .expirations
+            .insert((seed_hash, delegation_hash), expires_at)
+        {
+            self.queue.remove(&(previous, seed_hash, delegation_hash));
+        }
+        self.queue.insert((expires_at, seed_hash, delegation_hash));
+    }
+
+    fn remove(&mut self, seed_hash: Hash, delegation_hash: Hash) {
+        if let Some(expires_at) = self.expirations.remove(&(seed_hash, delegation_hash)) {
+            self.queue.remove(&(expires_at, seed_hash, delegation_hash));
+        }
+    }
+
+    fn pop_expired(&mut self, now: u64, max: usize) -> Vec<(Hash, Hash)> {
+        let mut expired = Vec::new();
+        while expired.len() < max {
+            match self.queue.first() {
+                Some(&(expires_at, seed_hash, delegation_hash)) if expires_at <= now => {
+                    self.remove(seed_hash, delegation_hash);
+                    expired.push((seed_hash, delegation_hash));
+                }
+                _ => break,
+            }
+        }
+        expired
+    }
+
+    fn entries(&self) -> Vec<(Hash, Hash, u64)> {
+        self.expirations
+            .iter()
+            .map(|(&(seed_hash, delegation_hash), &expires_at)| {
+                (seed_hash, delegation_hash, expires_at)
+            })
+            .collect()
+    }
+
+    fn clear(&mut self) {
+        self.expirations.clear();
+        self.queue.clear();
+    }
+
+    fn len(&self) -> usize {
+        self.expirations.len()
     }
 }
 
 /// The SignatureMap maintains the tree of delegation hashes required for authentication.
-#[derive(Default)]
 pub struct SignatureMap {
     certified_map: RbTree<Hash, RbTree<Hash, Unit>>,
-    expiration_queue: BinaryHeap<SigExpiration>,
+    store: Box<dyn SignatureStore>,
+}
+
+impl Default for SignatureMap {
+    fn default() -> Self {
+        Self::with_store(Box::<HeapSignatureStore>::default())
+    }
 }
 
 impl SignatureMap {
-    pub fn put(&mut self, seed_hash: Hash, delegation_hash: Hash) {
-        let signature_expires_at =
-            get_current_time().saturating_add(DELEGATION_SIGNATURE_EXPIRES_AT);
+    /// Creates a map backed by `store`, certifying the signatures already in it.
+    pub fn with_store(store: Box<dyn SignatureStore>) -> Self {
+        let mut map = Self {
+            certified_map: RbTree::new(),
+            store,
+        };
+        map.rebuild();
+        map
+    }
+
+    /// Moves every signature into `store` and makes it the backend of this map. Signatures already in `store`
+    /// are kept, and the old store is cleared.
+    pub fn set_store(&mut self, mut store: Box<dyn SignatureStore>) {
+        for (seed_hash, delegation_hash, expires_at) in self.store.entries() {
+            store.insert(seed_hash, delegation_hash, expires_at);
+        }
+        self.store.clear();
+        self.store = store;
+        self.certified_map = RbTree::new();
+        self.rebuild();
+    }
+
+    fn rebuild(&mut self) {
+        for (seed_hash, delegation_hash, _) in self.store.entries() {
+            self.certify(seed_hash, delegation_hash);
+        }
+    }
+
+    /// The number of signatures, including expired ones that have not been pruned yet.
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+
+    fn certify(&mut self, seed_hash: Hash, delegation_hash: Hash) {
         if self.certified_map.get(&seed_hash[..]).is_none() {
             let mut submap = RbTree::new();
             submap.insert(delegation_hash, Unit);
@@ -59,14 +159,9 @@ impl SignatureMap {
                 submap.insert(delegation_hash, Unit);
             });
         }
-        self.expiration_queue.push(SigExpiration {
-            seed_hash,
-            delegation_hash,
-            signature_expires_at,
-        });
     }
 
-    pub fn delete(&mut self, seed_hash: Hash, delegation_hash: Hash) {
+    fn uncertify(&mut self, seed_hash: Hash, delegation_hash: Hash) {
         let mut is_empty = false;
         self.certified_map.modify(&seed_hash[..], |m| {
             m.delete(&delegation_hash[..]);
@@ -77,30 +172,32 @@ impl SignatureMap {
         }
     }
 
-    pub fn prune_expired(&mut self, now: u64, max_to_prune: usize) -> usize {
-        let mut num_pruned = 0;
+    pub fn put(&mut self, seed_hash: Hash, delegation_hash: Hash) {
+        let signature_expires_at =
+            get_current_time().saturating_add(DELEGATION_SIGNATURE_EXPIRES_AT);
+        self.certify(seed_hash, delegation_hash);
+        self.store
+            .insert(seed_hash, delegation_hash, signature_expires_at);
+    }
 
-        // Never prune more than the size of the expiration queue.
-        let max_to_prune = std::cmp::min(max_to_prune, self.expiration_queue.len());
+    pub fn delete(&mut self, seed_hash: Hash, delegation_hash: Hash) {
+        self.uncertify(seed_hash, delegation_hash);
+        self.store.remove(seed_hash, delegation_hash);
+    }
 
-        for _step in 0..max_to_prune {
-            if let Some(expiration) = self.expiration_queue.peek() {
-                if expiration.signature_expires_at > now {
-                    return num_pruned;
-                }
-            }
-            if let Some(expiration) = self.expiration_queue.pop() {
-                self.delete(expiration.seed_hash, expiration.delegation_hash);
-            }
-            num_pruned += 1;
+    pub fn prune_expired(&mut self, now: u64, max_to_prune: usize) -> usize {
+        let expired = self.store.pop_expired(now, max_to_prune);
+        for &(seed_hash, delegation_hash) in &expired {
+            self.uncertify(seed_hash, delegation_hash);
         }
-
-        num_pruned
+        expired.len()
     }
+
     pub fn prune_all(&mut self) {
-        while let Some(expiration) = self.expiration_queue.pop() {
-            self.delete(expiration.seed_hash, expiration.delegation_hash);
+        for (seed_hash, delegation_hash, _) in self.store.entries() {
+            self.uncertify(seed_hash, delegation_hash);
         }
+        self.store.clear();
     }
 
     pub fn root_hash(&self) -> Hash {
@@ -118,6 +215,165 @@ impl SignatureMap {
     }
 }
 
+#[cfg(feature = "stable-signatures")]
+pub use stable::StableSignatureStore;
+
+#[cfg(feature = "stable-signatures")]
+mod stable {
+    use super::{Hash, SignatureStore};
+    use ic_stable_structures::storable::Bound;
+    use ic_stable_structures::{Memory, StableBTreeMap, Storable};
+    use std::borrow::Cow;
+
+    /// A 64-byte (seed hash, delegation hash) key.
+    #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    struct PairKey([u8; 64]);
+
+    /// An expiration index key. The big-endian time prefix orders entries by expiration.
+    #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    struct ExpiryKey([u8; 72]);
+
+    impl PairKey {
+        fn new(seed_hash: Hash, delegation_hash: Hash) -> Self {
+            let mut key = [0u8; 64];
+            key[..32].copy_from_slice(&seed_hash);
+            key[32..].copy_from_slice(&delegation_hash);
+            Self(key)
+        }
+
+        fn split(&self) -> (Hash, Hash) {
+            let mut seed_hash = [0u8; 32];
+            let mut delegation_hash = [0u8; 32];
+            seed_hash.copy_from_slice(&self.0[..32]);
+            delegation_hash.copy_from_slice(&self.0[32..]);
+            (seed_hash, delegation_hash)
+        }
+    }
+
+    impl ExpiryKey {
+        fn new(expires_at: u64, pair: PairKey) -> Self {
+            let mut key = [0u8; 72];
+            key[..8].copy_from_slice(&expires_at.to_be_bytes());
+            key[8..].copy_from_slice(&pair.0);
+            Self(key)
+        }
+
+        fn expires_at(&self) -> u64 {
+            let mut time = [0u8; 8];
+            time.copy_from_slice(&self.0[..8]);
+            u64::from_be_bytes(time)
+        }
+
+        fn pair(&self) -> PairKey {
+            let mut pair = [0u8; 64];
+            pair.copy_from_slice(&self.0[8..]);
+            PairKey(pair)
+        }
+    }
+
+    macro_rules! fixed_size_storable {
+        ($key:ident, $size:expr) => {
+            impl Storable for $key {
+                fn to_bytes(&self) -> Cow<'_, [u8]> {
+                    Cow::Borrowed(&self.0)
+                }
+
+                fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                    let mut key = [0u8; $size];
+                    key.copy_from_slice(&bytes);
+                    Self(key)
+                }
+
+                const BOUND: Bound = Bound::Bounded {
+                    max_size: $size,
+                    is_fixed_size: true,
+                };
+            }
+        };
+    }
+
+    fixed_size_storable!(PairKey, 64);
+    fixed_size_storable!(ExpiryKey, 72);
+
+    /// A [`SignatureStore`] in stable memory, for providers with many concurrent sessions or that must keep
+    /// issued signatures across upgrades. It needs two memories, which must not be shared with anything else.
+    pub struct StableSignatureStore<M: Memory> {
+        expirations: StableBTreeMap<PairKey, u64, M>,
+        queue: StableBTreeMap<ExpiryKey, (), M>,
+    }
+
+    impl<M: Memory> StableSignatureStore<M> {
+        /// Loads the store from `expirations_memory` and `queue_memory`, or creates it if they are empty.
+        pub fn init(expirations_memory: M, queue_memory: M) -> Self {
+            Self {
+                expirations: StableBTreeMap::init(expirations_memory),
+                queue: StableBTreeMap::init(queue_memory),
+            }
+        }
+    }
+
+    impl<M: Memory> SignatureStore for StableSignatureStore<M> {
+        fn insert(&mut self, seed_hash: Hash, delegation_hash: Hash, expires_at: u64) {
+            let pair = PairKey::new(seed_hash, delegation_hash);
+            if let Some(previous) = self.expirations.insert(pair, expires_at) {
+                self.queue.remove(&ExpiryKey::new(previous, pair));
+            }
+            self.queue.insert(ExpiryKey::new(expires_at, pair), ());
+        }
+
+        fn remove(&mut self, seed_hash: Hash, delegation_hash: Hash) {
+            let pair = PairKey::new(seed_hash, delegation_hash);
+            if let Some(expires_at) = self.expirations.remove(&pair) {
+                self.queue.remove(&ExpiryKey::new(expires_at, pair));
+            }
+        }
+
+        fn pop_expired(&mut self, now: u64, max: usize) -> Vec<(Hash, Hash)> {
+            let expired: Vec<ExpiryKey> = self
+                .queue
+                .iter()
+                .take(max)
+                .map(|(key, _)| key)
+                .take_while(|key| key.expires_at() <= now)
+                .collect();
+            expired
+                .into_iter()
+                .map(|key| {
+                    let pair = key.pair();
+                    self.queue.remove(&key);
+                    self.expirations.remove(&pair);
+                    pair.split()
+                })
+                .collect()
+        }
+
+        fn entries(&self) -> Vec<(Hash, Hash, u64)> {
+            self.expirations
+                .iter()
+                .map(|(pair, expires_at)| {
+                    let (seed_hash, delegation_hash) = pair.split();
+                    (seed_hash, delegation_hash, expires_at)
+                })
+                .collect()
+        }
+
+        fn clear(&mut self) {
+            let pairs: Vec<PairKey> = self.expirations.iter().map(|(pair, _)| pair).collect();
+            for pair in pairs {
+                self.expirations.remove(&pair);
+            }
+            let keys: Vec<ExpiryKey> = self.queue.iter().map(|(key, _)| key).collect();
+            for key in keys {
+                self.queue.remove(&key);
+            }
+        }
+
+        fn len(&self) -> usize {
+            self.expirations.len() as usize
+        }
+    }
+}
+
 #[cfg(test)]
 mod signature_map_tests {
     use super::*;
@@ -225,4 +481,65 @@ mod signature_map_tests {
         );
         assert_eq!(pruned, 10);
     }
+
+    #[test]
+    fn test_deleted_signature_not_restored() {
+        let mut map = SignatureMap::default();
+        let (kept, deleted) = (random_hash(), random_hash());
+        map.put(kept, random_hash());
+        map.put(deleted, random_hash());
+        let delegation_hash = random_hash();
+        map.put(deleted, delegation_hash);
+        map.delete(deleted, delegation_hash);
+
+        let root_hash = map.root_hash();
+        map.set_store(Box::<HeapSignatureStore>::default());
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.root_hash(), root_hash);
+    }
+
+    #[test]
+    fn test_put_again_extends_expiration() {
+        let mut store = HeapSignatureStore::default();
+        let (seed_hash, delegation_hash) = (random_hash(), random_hash());
+        store.insert(seed_hash, delegation_hash, 10);
+        store.insert(seed_hash, delegation_hash, 20);
+        assert_eq!(store.len(), 1);
+        assert!(store.pop_expired(15, 10).is_empty());
+        assert_eq!(
+            store.pop_expired(20, 10),
+            vec![(seed_hash, delegation_hash)]
+        );
+    }
+
+    #[cfg(feature = "stable-signatures")]
+    #[test]
+    fn test_stable_store_survives_reload() {
+        use ic_stable_structures::VectorMemory;
+
+        let (expirations, queue) = (VectorMemory::default(), VectorMemory::default());
+        let mut map = SignatureMap::with_store(Box::new(StableSignatureStore::init(
+            expirations.clone(),
+            queue.clone(),
+        )));
+        let seed_hash = random_hash();
+        let delegation_hash = random_hash();
+        map.put(seed_hash, delegation_hash);
+        map.put(random_hash(), random_hash());
+        let root_hash = map.root_hash();
+
+        // A new map over the same memories, as after an upgrade
+        let mut reloaded =
+            SignatureMap::with_store(Box::new(StableSignatureStore::init(expirations, queue)));
+        assert_eq!(reloaded.root_hash(), root_hash);
+        assert!(reloaded.witness(seed_hash, delegation_hash).is_some());
+
+        let pruned = reloaded.prune_expired(
+            get_current_time() + DELEGATION_SIGNATURE_EXPIRES_AT + 1,
+            100,
+        );
+        assert_eq!(pruned, 2);
+        assert!(reloaded.is_empty());
+        assert_eq!(reloaded.root_hash(), SignatureMap::default().root_hash());
+    }
 }
@@ -0,0 +1,367 @@
+//! Payloads for driving SIWB flows through wallets that implement the IC signer standards. Requests and
+//! responses are the JSON-RPC 2.0 envelopes of ICRC-25, consent info follows ICRC-21 and canister calls use
+//! the params of ICRC-49, so a wallet only needs to support the `siwb_sign_message` method on top of them.
+
+use base64::engine::general_purpose;
+use base64::Engine;
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+
+use crate::login::{BtcSignature, SignMessageType};
+use crate::siwb::SiwbMessage;
+
+pub const JSONRPC_VERSION: &str = "2.0";
+
+/// Asks the wallet to sign a SIWB message with the key of one of its Bitcoin addresses.
+pub const SIWB_SIGN_MESSAGE_METHOD: &str = "siwb_sign_message";
+
+pub const ICRC49_CALL_CANISTER_METHOD: &str = "icrc49_call_canister";
+
+/// Error codes of ICRC-25.
+pub const GENERIC_ERROR: i64 = 1000;
+pub const NOT_SUPPORTED: i64 = 2000;
+pub const PERMISSION_NOT_GRANTED: i64 = 3000;
+pub const ACTION_ABORTED: i64 = 3001;
+pub const NETWORK_ERROR: i64 = 4000;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RequestId {
+    Number(u64),
+    String(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SignerRequest<P> {
+    pub jsonrpc: String,
+    pub id: RequestId,
+    pub method: String,
+    pub params: P,
+}
+
+impl<P> SignerRequest<P> {
+    pub fn new(id: RequestId, method: &str, params: P) -> Self {
+        SignerRequest {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id,
+            method: method.to_string(),
+            params,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SignerError {
+    /// One of the ICRC-25 error codes, e.g. [`ACTION_ABORTED`] if the user declined.
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+
+/// Serialized as the `result` or the `error` member of the response.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignerOutcome<R> {
+    Result(R),
+    Error(SignerError),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SignerResponse<R> {
+    pub jsonrpc: String,
+    pub id: RequestId,
+    #[serde(flatten)]
+    pub outcome: SignerOutcome<R>,
+}
+
+impl<R> SignerResponse<R> {
+    pub fn result(id: RequestId, result: R) -> Self {
+        SignerResponse {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id,
+            outcome: SignerOutcome::Result(result),
+        }
+    }
+
+    pub fn error(id: RequestId, error: SignerError) -> Self {
+        SignerResponse {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id,
+            outcome: SignerOutcome::Error(error),
+        }
+    }
+
+    /// Returns the result of the response to `request_id`. A response to another request is reported as a
+    /// [`GENERIC_ERROR`].
+    pub fn into_result(self, request_id: &RequestId) -> Result<R, SignerError> {
+        if self.id != *request_id {
+            return Err(SignerError {
+                code: GENERIC_ERROR,
+                message: "Response does not match the request".to_string(),
+                data: None,
+            });
+        }
+        match self.outcome {
+            SignerOutcome::Result(result) => Ok(result),
+            SignerOutcome::Error(error) => Err(error),
+        }
+    }
+}
+
+/// What the wallet shows the user before signing, as defined by ICRC-21.
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ConsentMessage {
+    GenericDisplayMessage(String),
+    LineDisplayMessage { pages: Vec<LineDisplayPage> },
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LineDisplayPage {
+    pub lines: Vec<String>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConsentMessageMetadata {
+    /// BCP-47 language tag of the message, e.g. "en".
+    pub language: String,
+    pub utc_offset_minutes: Option<i16>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConsentInfo {
+    pub consent_message: ConsentMessage,
+    pub metadata: ConsentMessageMetadata,
+}
+
+/// Consent info showing the exact text that will be signed, for wallets with a full display.
+pub fn consent_info(message: &SiwbMessage, language: &str) -> ConsentInfo {
+    ConsentInfo {
+        consent_message: ConsentMessage::GenericDisplayMessage(String::from(message.clone())),
+        metadata: ConsentMessageMetadata {
+            language: language.to_string(),
+            utc_offset_minutes: None,
+        },
+    }
+}
+
+/// Same as [`consent_info`], split into pages of at most `lines_per_page` lines for hardware wallets. Empty
+/// lines are dropped, as they only separate sections of the message.
+pub fn line_display_consent_info(
+    message: &SiwbMessage,
+    language: &str,
+    lines_per_page: usize,
+) -> ConsentInfo {
+    let text = String::from(message.clone());
+    let lines: Vec<String> = text
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect();
+    let pages = lines
+        .chunks(lines_per_page.max(1))
+        .map(|chunk| LineDisplayPage {
+            lines: chunk.to_vec(),
+        })
+        .collect();
+    ConsentInfo {
+        consent_message: ConsentMessage::LineDisplayMessage { pages },
+        metadata: ConsentMessageMetadata {
+            language: language.to_string(),
+            utc_offset_minutes: None,
+        },
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SignMessageParams {
+    pub address: String,
+    /// The SIWB message as returned by `prepare_login`, rendered to the text that is signed.
+    pub message: String,
+    pub sign_message_type: SignMessageType,
+    pub consent: ConsentInfo,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SignMessageResult {
+    /// Base64 encoded, in the format expected by [`crate::login::login`] for `sign_message_type`.
+    pub signature: String,
+    /// Hex encoded public key of the address, empty if the signature type does not need it.
+    pub public_key: String,
+    pub sign_message_type: SignMessageType,
+}
+
+impl SignMessageResult {
+    /// The signature, public key and signature type arguments of [`crate::login::login`].
+    pub fn into_login_args(self) -> (BtcSignature, String, SignMessageType) {
+        (
+            BtcSignature(self.signature),
+            self.public_key,
+            self.sign_message_type,
+        )
+    }
+}
+
+/// Asks the wallet to sign `message`, showing it the text through [`consent_info`].
+pub fn sign_message_request(
+    id: RequestId,
+    message: &SiwbMessage,
+    sign_message_type: SignMessageType,
+    language: &str,
+) -> SignerRequest<SignMessageParams> {
+    SignerRequest::new(
+        id,
+        SIWB_SIGN_MESSAGE_METHOD,
+        SignMessageParams {
+            address: message.address.clone(),
+            message: String::from(message.clone()),
+            sign_message_type,
+            consent: consent_info(message, language),
+        },
+    )
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CallCanisterParams {
+    pub canister_id: String,
+    pub sender: String,
+    pub method: String,
+    /// Base64 encoded Candid arguments.
+    pub arg: String,
+}
+
+impl CallCanisterParams {
+    pub fn decode_arg(&self) -> Result<Vec<u8>, String> {
+        general_purpose::STANDARD
+            .decode(&self.arg)
+            .map_err(|e| format!("Invalid call argument: {}", e))
+    }
+}
+
+/// Asks the wallet to call `method` of `canister_id` as `sender`, e.g. `siwb_login` of the provider with the
+/// Candid encoded `arg`.
+pub fn call_canister_request(
+    id: RequestId,
+    canister_id: &Principal,
+    sender: &Principal,
+    method: &str,
+    arg: &[u8],
+) -> SignerRequest<CallCanisterParams> {
+    SignerRequest::new(
+        id,
+        ICRC49_CALL_CANISTER_METHOD,
+        CallCanisterParams {
+            canister_id: canister_id.to_text(),
+            sender: sender.to_text(),
+            method: method.to_string(),
+            arg: general_purpose::STANDARD.encode(arg),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> SiwbMessage {
+        SiwbMessage {
+            scheme: "https".to_string(),
+            domain: "example.com".to_string(),
+            address: "tb1qf620ch70a2evf2n2jrmdk85wwpupx8qcszr2s7".to_string(),
+            statement: "SIWB Fan Club".to_string(),
+            uri: "http://example.com".to_string(),
+            version: 1,
+            network: "testnet".to_string(),
+            nonce: "ee1ee5ead5b55fe8c8e9".to_string(),
+            issued_at: 1_620_328_630_000_000_000,
+            expiration_time: 1_620_328_633_000_000_000,
+            session_key_hash: None,
+            header: None,
+            request_id: None,
+            resources: vec![],
+        }
+    }
+
+    #[test]
+    fn test_sign_message_request_envelope() {
+        let request = sign_message_request(
+            RequestId::Number(7),
+            &message(),
+            SignMessageType::Bip322Simple,
+            "en",
+        );
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["jsonrpc"], "2.0");
+        assert_eq!(json["id"], 7);
+        assert_eq!(json["method"], SIWB_SIGN_MESSAGE_METHOD);
+        assert_eq!(json["params"]["signMessageType"], "Bip322Simple");
+        assert_eq!(json["params"]["message"], String::from(message()));
+        assert_eq!(
+            json["params"]["consent"]["consent_message"]["GenericDisplayMessage"],
+            String::from(message())
+        );
+    }
+
+    #[test]
+    fn test_response_outcomes() {
+        let id = RequestId::String("a".to_string());
+        let json = r#"{"jsonrpc":"2.0","id":"a","result":{"signature":"c2ln","publicKey":"","signMessageType":"ECDSA"}}"#;
+        let response: SignerResponse<SignMessageResult> = serde_json::from_str(json).unwrap();
+        let (signature, public_key, sign_message_type) =
+            response.into_result(&id).unwrap().into_login_args();
+        assert_eq!(signature.0, "c2ln");
+        assert!(public_key.is_empty());
+        assert_eq!(sign_message_type, SignMessageType::ECDSA);
+
+        let json = r#"{"jsonrpc":"2.0","id":"a","error":{"code":3001,"message":"Declined"}}"#;
+        let response: SignerResponse<SignMessageResult> = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            response.clone().into_result(&id).unwrap_err().code,
+            ACTION_ABORTED
+        );
+        assert_eq!(
+            response
+                .into_result(&RequestId::Number(1))
+                .unwrap_err()
+                .code,
+            GENERIC_ERROR
+        );
+    }
+
+    #[test]
+    fn test_line_display_pages() {
+        let info = line_display_consent_info(&message(), "en", 4);
+        let ConsentMessage::LineDisplayMessage { pages } = info.consent_message else {
+            panic!("expected a line display message");
+        };
+        assert_eq!(pages.len(), 3);
+        assert_eq!(
+            pages[0].lines[0],
+            format!("example.com {}", crate::siwb::DEFAULT_HEADER)
+        );
+        assert_eq!(
+            pages[2].lines,
+            vec!["Expiration Time: 2021-05-06T19:17:13Z"]
+        );
+    }
+
+    #[test]
+    fn test_call_canister_arg_roundtrip() {
+        let canister = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let request = call_canister_request(
+            RequestId::Number(1),
+            &canister,
+            &Principal::anonymous(),
+            "siwb_login",
+            &[0x44, 0x49, 0x44, 0x4c],
+        );
+        assert_eq!(request.params.canister_id, "rrkah-fqaaa-aaaaa-aaaaq-cai");
+        assert_eq!(
+            request.params.decode_arg().unwrap(),
+            vec![0x44, 0x49, 0x44, 0x4c]
+        );
+    }
+}
@@ -1,24 +1,76 @@
-use crate::settings::Settings;
-use crate::with_settings;
+use crate::error::BtcError;
+use crate::hash::hash_bytes;
+use crate::metrics::{increment, Counter};
+use crate::settings::{
+    validate_message_locale, validate_request_id, validate_resources, MessageLocale, Settings,
+};
 use crate::{rand::generate_nonce, time::get_current_time};
+use crate::{with_settings, SETTINGS};
 
 use bitcoin::Address;
 use candid::{CandidType, Deserialize};
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
+use std::str::FromStr;
 use time::format_description::well_known::Rfc3339;
 use time::OffsetDateTime;
 
-#[derive(Debug)]
+/// The maximum number of recently expired message keys remembered by [`SiwbMessageMap`] so that
+/// lookups can distinguish an expired message from one that never existed.
+const MAX_EXPIRED_TRACKED: usize = 1000;
+
+/// The maximum number of pending messages of one address, e.g. one per device or tab. Preparing another one
+/// replaces the oldest.
+pub const MAX_PENDING_MESSAGES_PER_ADDRESS: usize = 8;
+
+/// The default tolerance applied to both ends of a message's validity window to absorb small clock
+/// differences, in nanoseconds, see `Settings::clock_skew_tolerance_ns`.
+pub const CLOCK_SKEW_TOLERANCE_NS: u64 = 1_000_000_000;
+
+/// The phrase after the domain on the first line of a message that is not localized.
+pub const DEFAULT_HEADER: &str = "wants you to sign in with your Bitcoin account:";
+
+/// Per-call choices of how a SIWB message is worded. Only the header phrase and the statement can change; the
+/// machine-parsed fields are the same in every locale.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct MessageOptions {
+    /// One of `Settings::locales`, e.g. "de". Defaults to None, which renders the message in English.
+    pub locale: Option<String>,
+
+    /// Overrides the header and statement of the locale for this message only.
+    pub overrides: Option<MessageLocale>,
+
+    /// The TTL of this message in nanoseconds, e.g. shorter for high-security actions. At most
+    /// `Settings::max_sign_in_expires_in`. Defaults to None, which uses `Settings::sign_in_expires_in`.
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+
+    /// The "Request ID" of this message, e.g. the ID of the proposal the login is for. Defaults to None.
+    #[serde(default)]
+    pub request_id: Option<String>,
+
+    /// Replaces `Settings::resources` for this message, e.g. with the URI of a proposal. Defaults to None.
+    #[serde(default)]
+    pub resources: Option<Vec<String>>,
+}
+
+#[derive(Debug, PartialEq)]
 pub enum SiwbMessageError {
     MessageNotFound,
+    /// The message existed but expired at `expired_at` (nanoseconds since the UNIX epoch).
+    MessageExpired {
+        expired_at: u64,
+    },
 }
 
 impl fmt::Display for SiwbMessageError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             SiwbMessageError::MessageNotFound => write!(f, "Message not found"),
+            SiwbMessageError::MessageExpired { expired_at } => {
+                write!(f, "Message expired at {}", expired_at)
+            }
         }
     }
 }
@@ -29,6 +81,37 @@ impl From<SiwbMessageError> for String {
     }
 }
 
+/// Why a text is not a SIWB message, see [`SiwbMessage::from_str`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SiwbMessageParseError {
+    /// A line is missing or out of place. Contains the line, e.g. "Nonce".
+    MissingField(&'static str),
+    /// A line is present but its value cannot be parsed.
+    InvalidField { field: &'static str, value: String },
+    /// There is text after the last field.
+    TrailingText(String),
+}
+
+impl fmt::Display for SiwbMessageParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SiwbMessageParseError::MissingField(field) => write!(f, "Missing field: {}", field),
+            SiwbMessageParseError::InvalidField { field, value } => {
+                write!(f, "Invalid {}: {}", field, value)
+            }
+            SiwbMessageParseError::TrailingText(text) => {
+                write!(f, "Unexpected text after the message: {}", text)
+            }
+        }
+    }
+}
+
+impl From<SiwbMessageParseError> for String {
+    fn from(error: SiwbMessageParseError) -> Self {
+        error.to_string()
+    }
+}
+
 /// Represents a SIWB (Sign-In With Bitcoin) message.
 ///
 /// This struct and its implementation methods support all required fields in the [ERC-4361](https://eips.ethereum.org/EIPS/eip-4361)
@@ -51,7 +134,7 @@ impl From<SiwbMessageError> for String {
 /// Issued At: 2021-05-06T19:17:10Z
 /// Expiration Time: 2021-05-06T19:17:13Z
 /// ```
-#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
 pub struct SiwbMessage {
     pub scheme: String,
     pub domain: String,
@@ -63,6 +146,19 @@ pub struct SiwbMessage {
     pub nonce: String,
     pub issued_at: u64,
     pub expiration_time: u64,
+    /// Hex encoded SHA-256 hash of the session key the message is bound to, if any. A bound message can
+    /// only be used to log in with that session key.
+    #[serde(default)]
+    pub session_key_hash: Option<String>,
+    /// Replaces [`DEFAULT_HEADER`] on the first line of a localized message.
+    #[serde(default)]
+    pub header: Option<String>,
+    /// The ERC-4361 "Request ID", e.g. of the action the login is for.
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// The ERC-4361 "Resources": URIs the user refers to by signing the message, e.g. a proposal.
+    #[serde(default)]
+    pub resources: Vec<String>,
 }
 
 impl SiwbMessage {
@@ -89,23 +185,125 @@ impl SiwbMessage {
                 version: 1,
                 network: settings.network.to_string(),
                 nonce,
-                issued_at: get_current_time(),
+                issued_at: current_time,
                 expiration_time: current_time.saturating_add(settings.sign_in_expires_in),
+                session_key_hash: None,
+                header: None,
+                request_id: None,
+                resources: settings.resources.clone(),
             }
         })
     }
 
-    /// Checks if the SIWB message is currently valid.
+    /// Rewords the header and statement according to `options`. Per-call overrides take precedence over the
+    /// translations of the selected locale.
+    pub fn localize(mut self, options: &MessageOptions) -> Result<SiwbMessage, BtcError> {
+        let overrides = options.overrides.clone().unwrap_or_default();
+        validate_message_locale(&overrides).map_err(BtcError::InvalidMessagePhrase)?;
+        let translation = match &options.locale {
+            Some(locale) => with_settings!(|settings: &Settings| {
+                settings
+                    .locales
+                    .get(locale)
+                    .cloned()
+                    .ok_or_else(|| BtcError::UnknownLocale(locale.clone()))
+            })?,
+            None => MessageLocale::default(),
+        };
+        if let Some(header) = overrides.header.or(translation.header) {
+            self.header = Some(header);
+        }
+        if let Some(statement) = overrides.statement.or(translation.statement) {
+            self.statement = statement;
+        }
+        Ok(self)
+    }
+
+    /// Makes the message expire `expires_in` nanoseconds after it was issued, instead of after
+    /// `Settings::sign_in_expires_in`. Fails if `expires_in` is zero or exceeds `Settings::max_sign_in_expires_in`.
+    pub fn expire_in(mut self, expires_in: u64) -> Result<SiwbMessage, BtcError> {
+        let max = with_settings!(|settings: &Settings| {
+            settings
+                .max_sign_in_expires_in
+                .unwrap_or(settings.sign_in_expires_in)
+        });
+        if expires_in == 0 || expires_in > max {
+            return Err(BtcError::InvalidExpiresIn(max));
+        }
+        self.expiration_time = self.issued_at.saturating_add(expires_in);
+        Ok(self)
+    }
+
+    /// Sets the request ID and, if given, replaces the resources of the message. Fails if the request ID or
+    /// a resource is malformed.
+    pub fn with_request(
+        mut self,
+        request_id: Option<String>,
+        resources: Option<Vec<String>>,
+    ) -> Result<SiwbMessage, BtcError> {
+        if let Some(request_id) = &request_id {
+            validate_request_id(request_id).map_err(BtcError::InvalidMessageField)?;
+        }
+        if let Some(resources) = resources {
+            validate_resources(&resources).map_err(BtcError::InvalidMessageField)?;
+            self.resources = resources;
+        }
+        self.request_id = request_id;
+        Ok(self)
+    }
+
+    /// Binds the message to `session_key` by including the hash of the key in the signed text.
+    pub fn bind_session_key(mut self, session_key: &[u8]) -> SiwbMessage {
+        self.session_key_hash = Some(session_key_hash(session_key));
+        self
+    }
+
+    /// Returns `true` if the message is not bound to a session key or is bound to `session_key`.
+    pub fn accepts_session_key(&self, session_key: &[u8]) -> bool {
+        match &self.session_key_hash {
+            Some(expected) => *expected == session_key_hash(session_key),
+            None => true,
+        }
+    }
+
+    /// Returns the `(issued_at, expiration_time)` window, in nanoseconds since the UNIX epoch, during
+    /// which the message may be used to log in.
+    pub fn validity_window(&self) -> (u64, u64) {
+        (self.issued_at, self.expiration_time)
+    }
+
+    /// Checks if the SIWB message can no longer be used at the current time.
     ///
     /// # Returns
     ///
-    /// `true` if the message is within its valid time period, `false` otherwise.
+    /// `false` if `issued_at <= now <= expiration_time` (allowing for `Settings::clock_skew_tolerance_ns` on
+    /// either side), `true` otherwise.
     pub fn is_expired(&self) -> bool {
-        let current_time = get_current_time();
-        self.issued_at < current_time || current_time > self.expiration_time
+        self.is_expired_at(get_current_time())
+    }
+
+    /// Same as [`SiwbMessage::is_expired`], evaluated at `now` (nanoseconds since the UNIX epoch).
+    pub fn is_expired_at(&self, now: u64) -> bool {
+        let (not_before, not_after) = self.validity_window();
+        let tolerance = clock_skew_tolerance();
+        now.saturating_add(tolerance) < not_before || now > not_after.saturating_add(tolerance)
     }
 }
 
+/// `Settings::clock_skew_tolerance_ns`, or [`CLOCK_SKEW_TOLERANCE_NS`] before the library is initialized.
+fn clock_skew_tolerance() -> u64 {
+    SETTINGS.with_borrow(|s| {
+        s.as_ref().map_or(CLOCK_SKEW_TOLERANCE_NS, |settings| {
+            settings.clock_skew_tolerance_ns
+        })
+    })
+}
+
+/// Hex encoded SHA-256 hash of a session key, as it appears in a bound SIWB message.
+pub fn session_key_hash(session_key: &[u8]) -> String {
+    hex::encode(hash_bytes(session_key))
+}
+
 impl fmt::Display for SiwbMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
@@ -128,8 +326,23 @@ impl From<SiwbMessage> for String {
             OffsetDateTime::from_unix_timestamp_nanos(val.expiration_time as i128).unwrap();
         let expiration_iso_8601 = expiration_datetime.format(&Rfc3339).unwrap();
 
+        let session_key_line = match &val.session_key_hash {
+            Some(hash) => format!("\nSession Key Hash: {}", hash),
+            None => String::new(),
+        };
+        let request_id_line = match &val.request_id {
+            Some(request_id) => format!("\nRequest ID: {}", request_id),
+            None => String::new(),
+        };
+        let resources_lines = if val.resources.is_empty() {
+            String::new()
+        } else {
+            let items: String = val.resources.iter().map(|r| format!("\n- {}", r)).collect();
+            format!("\nResources:{}", items)
+        };
+
         format!(
-            "{domain} wants you to sign in with your Bitcoin account:\n\
+            "{domain} {header}\n\
             {address}\n\n\
             {statement}\n\n\
             URI: {uri}\n\
@@ -137,8 +350,9 @@ impl From<SiwbMessage> for String {
             Network: {network}\n\
             Nonce: {nonce}\n\
             Issued At: {issued_at_iso_8601}\n\
-            Expiration Time: {expiration_iso_8601}",
+            Expiration Time: {expiration_iso_8601}{session_key_line}{request_id_line}{resources_lines}",
             domain = val.domain,
+            header = val.header.as_deref().unwrap_or(DEFAULT_HEADER),
             address = val.address,
             statement = val.statement,
             uri = val.uri,
@@ -149,48 +363,222 @@ impl From<SiwbMessage> for String {
     }
 }
 
-/// The SiwbMessageMap is a map of SIWB messages keyed by the Bitcoin address of the user. SIWB messages
-/// are stored in the map during the course of the login process and are removed once the login process
-/// is complete. The map is also pruned periodically to remove expired SIWB messages.
+impl FromStr for SiwbMessage {
+    type Err = SiwbMessageParseError;
+
+    /// Parses a message in the format produced by `From<SiwbMessage> for String`, e.g. to check client-side
+    /// that a message is well-formed before asking a wallet to sign it. The scheme is not part of the text and
+    /// is taken from the URI.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        use SiwbMessageParseError::*;
+
+        let (first_line, rest) = text.split_once('\n').ok_or(MissingField("Address"))?;
+        let (domain, header) = first_line.split_once(' ').ok_or(MissingField("Header"))?;
+        let (address, rest) = rest.split_once("\n\n").ok_or(MissingField("Statement"))?;
+        let (statement, fields) = rest.rsplit_once("\n\nURI: ").ok_or(MissingField("URI"))?;
+
+        let mut lines = fields.split('\n');
+        let uri = lines.next().ok_or(MissingField("URI"))?;
+        let mut field = |name: &'static str| {
+            lines
+                .next()
+                .and_then(|line| line.strip_prefix(name))
+                .and_then(|line| line.strip_prefix(": "))
+                .ok_or(MissingField(name))
+        };
+        let invalid = |field: &'static str, value: &str| InvalidField {
+            field,
+            value: value.to_string(),
+        };
+        let timestamp = |field: &'static str, value: &str| {
+            OffsetDateTime::parse(value, &Rfc3339)
+                .ok()
+                .and_then(|time| u64::try_from(time.unix_timestamp_nanos()).ok())
+                .ok_or_else(|| invalid(field, value))
+        };
+
+        let version = field("Version")?;
+        let version = version.parse().map_err(|_| invalid("Version", version))?;
+        let network = field("Network")?;
+        let nonce = field("Nonce")?;
+        let issued_at = timestamp("Issued At", field("Issued At")?)?;
+        let expiration_time = timestamp("Expiration Time", field("Expiration Time")?)?;
+
+        // The optional fields follow in a fixed order.
+        let mut next = lines.next();
+        let mut optional = |prefix: &str| {
+            let value = next.and_then(|line| line.strip_prefix(prefix))?;
+            next = lines.next();
+            Some(value.to_string())
+        };
+        let session_key_hash = optional("Session Key Hash: ");
+        let request_id = optional("Request ID: ");
+        let mut resources = vec![];
+        if optional("Resources:").is_some() {
+            while let Some(resource) = optional("- ") {
+                resources.push(resource);
+            }
+        }
+        if let Some(line) = next {
+            return Err(TrailingText(line.to_string()));
+        }
+
+        Ok(SiwbMessage {
+            scheme: uri
+                .split_once("://")
+                .map_or("", |(scheme, _)| scheme)
+                .to_string(),
+            domain: domain.to_string(),
+            address: address.to_string(),
+            statement: statement.to_string(),
+            uri: uri.to_string(),
+            version,
+            network: network.to_string(),
+            nonce: nonce.to_string(),
+            issued_at,
+            expiration_time,
+            session_key_hash,
+            header: (header != DEFAULT_HEADER).then(|| header.to_string()),
+            request_id,
+            resources,
+        })
+    }
+}
+
+/// The SiwbMessageMap is a map of SIWB messages keyed by the Bitcoin address of the user and the nonce of the
+/// message, so several devices can sign in to one address at the same time. SIWB messages are stored in the
+/// map during the course of the login process and are removed once the login process is complete. The map is
+/// also pruned periodically to remove expired SIWB messages.
+///
+/// Keys of pruned messages are remembered for a bounded number of entries so that [`SiwbMessageMap::get`]
+/// can report [`SiwbMessageError::MessageExpired`] instead of [`SiwbMessageError::MessageNotFound`].
 pub struct SiwbMessageMap {
-    map: HashMap<Vec<u8>, SiwbMessage>,
+    // The pending messages of each address, oldest first.
+    map: HashMap<Vec<u8>, Vec<SiwbMessage>>,
+    expired: HashMap<Vec<u8>, u64>,
+    expired_order: VecDeque<Vec<u8>>,
 }
 
 impl SiwbMessageMap {
     pub fn new() -> SiwbMessageMap {
         SiwbMessageMap {
             map: HashMap::new(),
+            expired: HashMap::new(),
+            expired_order: VecDeque::new(),
         }
     }
 
     /// Removes SIWB messages that have exceeded their time to live.
     pub fn prune_expired(&mut self) {
         let current_time = get_current_time();
-        self.map
-            .retain(|_, message| message.expiration_time > current_time);
+        let tolerance = clock_skew_tolerance();
+        let mut expired: Vec<(Vec<u8>, u64)> = vec![];
+        let mut pruned = 0;
+        self.map.retain(|key, messages| {
+            let before = messages.len();
+            let mut expired_at = None;
+            messages.retain(|message| {
+                let live = current_time <= message.expiration_time.saturating_add(tolerance);
+                if !live {
+                    expired_at = expired_at.max(Some(message.expiration_time));
+                }
+                live
+            });
+            pruned += before - messages.len();
+            if let Some(expired_at) = expired_at {
+                expired.push((key.clone(), expired_at));
+            }
+            !messages.is_empty()
+        });
+        increment(Counter::MessagePruned, pruned as u64);
+        for (key, expired_at) in expired {
+            self.track_expired(key, expired_at);
+        }
+    }
+
+    fn track_expired(&mut self, key: Vec<u8>, expired_at: u64) {
+        if self.expired.insert(key.clone(), expired_at).is_none() {
+            self.expired_order.push_back(key);
+        }
+        while self.expired_order.len() > MAX_EXPIRED_TRACKED {
+            if let Some(oldest) = self.expired_order.pop_front() {
+                self.expired.remove(&oldest);
+            }
+        }
     }
 
-    /// Adds a SIWB message to the map.
+    /// Adds a SIWB message to the pending messages of the address, dropping the oldest one beyond
+    /// [`MAX_PENDING_MESSAGES_PER_ADDRESS`].
     pub fn insert(&mut self, address_bytes: Vec<u8>, message: SiwbMessage) {
-        self.map.insert(address_bytes, message);
+        if self.expired.remove(&address_bytes).is_some() {
+            self.expired_order.retain(|key| key != &address_bytes);
+        }
+        let messages = self.map.entry(address_bytes).or_default();
+        messages.retain(|pending| pending.nonce != message.nonce);
+        messages.push(message);
+        if messages.len() > MAX_PENDING_MESSAGES_PER_ADDRESS {
+            messages.remove(0);
+        }
     }
 
-    /// Returns a cloned SIWB message associated with the provided address or an error if the message
-    /// does not exist.
+    /// Returns a clone of the most recently prepared SIWB message of the provided address. Returns
+    /// [`SiwbMessageError::MessageExpired`] if the message expired recently and
+    /// [`SiwbMessageError::MessageNotFound`] if it is unknown.
     pub fn get(&self, address_bytes: &Vec<u8>) -> Result<SiwbMessage, SiwbMessageError> {
-        self.map
-            .get(address_bytes)
-            .cloned()
-            .ok_or(SiwbMessageError::MessageNotFound)
+        self.find(address_bytes, |messages| messages.last())
+    }
+
+    /// Same as [`SiwbMessageMap::get`], but returns the pending message of the address with `nonce`.
+    pub fn get_by_nonce(
+        &self,
+        address_bytes: &Vec<u8>,
+        nonce: &str,
+    ) -> Result<SiwbMessage, SiwbMessageError> {
+        self.find(address_bytes, |messages| {
+            messages.iter().find(|message| message.nonce == nonce)
+        })
+    }
+
+    fn find(
+        &self,
+        address_bytes: &Vec<u8>,
+        select: impl Fn(&[SiwbMessage]) -> Option<&SiwbMessage>,
+    ) -> Result<SiwbMessage, SiwbMessageError> {
+        if let Some(message) = self.map.get(address_bytes).and_then(|m| select(m)) {
+            if message.is_expired() {
+                return Err(SiwbMessageError::MessageExpired {
+                    expired_at: message.expiration_time,
+                });
+            }
+            return Ok(message.clone());
+        }
+        match self.expired.get(address_bytes) {
+            Some(expired_at) => Err(SiwbMessageError::MessageExpired {
+                expired_at: *expired_at,
+            }),
+            None => Err(SiwbMessageError::MessageNotFound),
+        }
     }
 
-    /// Removes the SIWB message associated with the provided address.
+    /// Removes all pending SIWB messages of the provided address.
     pub fn remove(&mut self, address_bytes: &Vec<u8>) {
         self.map.remove(address_bytes);
     }
 
+    /// Removes the pending SIWB message of the provided address with `nonce`, keeping the others.
+    pub fn remove_nonce(&mut self, address_bytes: &Vec<u8>, nonce: &str) {
+        if let Some(messages) = self.map.get_mut(address_bytes) {
+            messages.retain(|message| message.nonce != nonce);
+            if messages.is_empty() {
+                self.map.remove(address_bytes);
+            }
+        }
+    }
+
     pub fn clear(&mut self) {
         self.map.clear();
+        self.expired.clear();
+        self.expired_order.clear();
     }
 }
 
@@ -199,3 +587,219 @@ impl Default for SiwbMessageMap {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::settings::SettingsBuilder;
+    use crate::siwb::{
+        session_key_hash, SiwbMessage, SiwbMessageError, SiwbMessageMap, SiwbMessageParseError,
+        CLOCK_SKEW_TOLERANCE_NS, MAX_EXPIRED_TRACKED, MAX_PENDING_MESSAGES_PER_ADDRESS,
+    };
+    use crate::time::get_current_time;
+    use crate::SETTINGS;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn message(issued_at: u64, expiration_time: u64) -> SiwbMessage {
+        SiwbMessage {
+            scheme: "https".to_string(),
+            domain: "example.com".to_string(),
+            address: "bc1qexample".to_string(),
+            statement: "Login".to_string(),
+            uri: "https://example.com".to_string(),
+            version: 1,
+            network: "bitcoin".to_string(),
+            nonce: "nonce".to_string(),
+            issued_at,
+            expiration_time,
+            session_key_hash: None,
+            header: None,
+            request_id: None,
+            resources: vec![],
+        }
+    }
+
+    #[test]
+    fn test_message_text_round_trip() {
+        let now = get_current_time();
+        let mut bound = message(now, now + 10 * SECOND + 123);
+        bound.session_key_hash = Some("ab".repeat(32));
+        let mut localized = message(now, now + SECOND);
+        localized.header = Some("möchte, dass Sie sich anmelden:".to_string());
+        localized.statement = String::new();
+        let mut request = message(now, now + SECOND);
+        request.request_id = Some("proposal-42".to_string());
+        request.resources = vec![
+            "https://example.com/proposals/42".to_string(),
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi".to_string(),
+        ];
+
+        for original in [message(now, now + SECOND), bound, localized, request] {
+            let text = String::from(original.clone());
+            let parsed: SiwbMessage = text.parse().unwrap();
+            assert_eq!(parsed, original);
+            assert_eq!(String::from(parsed), text);
+        }
+    }
+
+    #[test]
+    fn test_message_text_rejects_malformed() {
+        let now = get_current_time();
+        let text = String::from(message(now, now + SECOND));
+        assert_eq!(
+            text.replace("Nonce: ", "Nonse: ").parse::<SiwbMessage>(),
+            Err(SiwbMessageParseError::MissingField("Nonce"))
+        );
+        assert_eq!(
+            text.replace("Version: 1", "Version: one")
+                .parse::<SiwbMessage>(),
+            Err(SiwbMessageParseError::InvalidField {
+                field: "Version",
+                value: "one".to_string()
+            })
+        );
+        assert_eq!(
+            format!("{}\nExtra: x", text).parse::<SiwbMessage>(),
+            Err(SiwbMessageParseError::TrailingText("Extra: x".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_get_unknown_message() {
+        let map = SiwbMessageMap::new();
+        assert_eq!(
+            map.get(&vec![1]).unwrap_err(),
+            SiwbMessageError::MessageNotFound
+        );
+    }
+
+    #[test]
+    fn test_get_pruned_message_reports_expired() {
+        let mut map = SiwbMessageMap::new();
+        let now = get_current_time();
+        map.insert(vec![1], message(now - 20 * SECOND, now - 10 * SECOND));
+        map.prune_expired();
+        assert_eq!(
+            map.get(&vec![1]).unwrap_err(),
+            SiwbMessageError::MessageExpired {
+                expired_at: now - 10 * SECOND
+            }
+        );
+    }
+
+    #[test]
+    fn test_insert_clears_expired_marker() {
+        let mut map = SiwbMessageMap::new();
+        let now = get_current_time();
+        map.insert(vec![1], message(now - 20 * SECOND, now - 10 * SECOND));
+        map.prune_expired();
+        map.insert(vec![1], message(now, now + 1_000 * SECOND));
+        assert!(map.get(&vec![1]).is_ok());
+    }
+
+    #[test]
+    fn test_pending_messages_by_nonce() {
+        let mut map = SiwbMessageMap::new();
+        let now = get_current_time();
+        let with_nonce = |nonce: &str| SiwbMessage {
+            nonce: nonce.to_string(),
+            ..message(now, now + 1_000 * SECOND)
+        };
+        map.insert(vec![1], with_nonce("laptop"));
+        map.insert(vec![1], with_nonce("phone"));
+
+        assert_eq!(map.get(&vec![1]).unwrap().nonce, "phone");
+        assert_eq!(
+            map.get_by_nonce(&vec![1], "laptop").unwrap().nonce,
+            "laptop"
+        );
+        assert_eq!(
+            map.get_by_nonce(&vec![1], "tablet").unwrap_err(),
+            SiwbMessageError::MessageNotFound
+        );
+
+        map.remove_nonce(&vec![1], "phone");
+        assert_eq!(map.get(&vec![1]).unwrap().nonce, "laptop");
+
+        for i in 0..MAX_PENDING_MESSAGES_PER_ADDRESS {
+            map.insert(vec![1], with_nonce(&i.to_string()));
+        }
+        assert!(map.get_by_nonce(&vec![1], "laptop").is_err());
+        assert!(map.get_by_nonce(&vec![1], "0").is_ok());
+    }
+
+    #[test]
+    fn test_expired_tracking_is_bounded() {
+        let mut map = SiwbMessageMap::new();
+        let now = get_current_time();
+        for i in 0..=MAX_EXPIRED_TRACKED as u32 {
+            map.insert(
+                i.to_be_bytes().to_vec(),
+                message(now - 20 * SECOND, now - 10 * SECOND),
+            );
+        }
+        map.prune_expired();
+        assert_eq!(map.expired.len(), MAX_EXPIRED_TRACKED);
+        assert_eq!(map.expired_order.len(), MAX_EXPIRED_TRACKED);
+    }
+
+    #[test]
+    fn test_session_key_binding() {
+        let unbound = message(0, 1);
+        assert!(unbound.accepts_session_key(b"any key"));
+
+        let bound = unbound.bind_session_key(b"session key");
+        assert!(bound.accepts_session_key(b"session key"));
+        assert!(!bound.accepts_session_key(b"other key"));
+
+        let text: String = bound.into();
+        assert!(text.ends_with(&format!(
+            "Session Key Hash: {}",
+            session_key_hash(b"session key")
+        )));
+    }
+
+    #[test]
+    fn test_validity_window() {
+        let m = message(100, 200);
+        assert_eq!(m.validity_window(), (100, 200));
+    }
+
+    #[test]
+    fn test_is_expired_within_window() {
+        let m = message(100 * SECOND, 200 * SECOND);
+        assert!(!m.is_expired_at(100 * SECOND));
+        assert!(!m.is_expired_at(150 * SECOND));
+        assert!(!m.is_expired_at(200 * SECOND));
+    }
+
+    #[test]
+    fn test_is_expired_skew_boundaries() {
+        let m = message(100 * SECOND, 200 * SECOND);
+        assert!(!m.is_expired_at(100 * SECOND - CLOCK_SKEW_TOLERANCE_NS));
+        assert!(m.is_expired_at(100 * SECOND - CLOCK_SKEW_TOLERANCE_NS - 1));
+        assert!(!m.is_expired_at(200 * SECOND + CLOCK_SKEW_TOLERANCE_NS));
+        assert!(m.is_expired_at(200 * SECOND + CLOCK_SKEW_TOLERANCE_NS + 1));
+    }
+
+    #[test]
+    fn test_is_expired_configured_tolerance() {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .clock_skew_tolerance_ns(30 * SECOND)
+            .build()
+            .unwrap();
+        SETTINGS.set(Some(settings));
+        let m = message(100 * SECOND, 200 * SECOND);
+        assert!(!m.is_expired_at(70 * SECOND));
+        assert!(m.is_expired_at(70 * SECOND - 1));
+        assert!(!m.is_expired_at(230 * SECOND));
+        assert!(m.is_expired_at(230 * SECOND + 1));
+        SETTINGS.set(None);
+    }
+
+    #[test]
+    fn test_fresh_message_is_not_expired() {
+        let now = get_current_time();
+        assert!(!message(now, now + 60 * SECOND).is_expired());
+    }
+}
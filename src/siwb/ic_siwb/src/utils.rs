@@ -1,9 +1,14 @@
+use crate::error::BtcError;
 use crate::hash::hash_with_domain;
-use bitcoin::Network::{Bitcoin, Regtest, Testnet};
+use crate::settings::DEFAULT_MAX_SIGNATURE_BYTES;
+use base64::engine::general_purpose;
+use base64::Engine;
+use bitcoin::Network::{Bitcoin, Regtest, Signet, Testnet};
 use bitcoin::{Address, AddressType, Network, ScriptBuf};
 use candid::Principal;
 use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
 use icrc_ledger_types::icrc1::account::Account;
+use std::fmt;
 use std::str::FromStr;
 
 pub fn derive_account_from_address_and_owner_principal(
@@ -23,9 +28,11 @@ pub fn derive_account_from_address_and_owner_principal(
         }
     };
 
+    // Signet shares the testnet addresses and subaccounts
     let chain_id = match address.network {
         Bitcoin => 0u8,
-        Testnet => 1u8,
+        Testnet | Signet => 1u8,
+        Regtest => 2u8,
         _ => {
             return Err("Invalid network".to_string());
         }
@@ -45,56 +52,102 @@ pub struct AddressInfo {
     pub address_raw: Address,
     pub address: String,
     pub script_buf: ScriptBuf,
+    /// The network the address is used on: the configured one, see [`parse_address`].
     pub network: Network,
+    /// Every network the address is valid on.
+    pub valid_on: Vec<Network>,
     pub address_type: AddressType,
 }
 
-pub fn get_script_from_address(address: String) -> Result<AddressInfo, String> {
-    let mut network = Bitcoin;
-    let mut address_type = AddressType::P2tr;
-
-    if address.starts_with("bc1q") {
-        address_type = AddressType::P2wpkh;
-        network = Bitcoin;
-    } else if address.starts_with("bc1p") {
-        address_type = AddressType::P2tr;
-        network = Bitcoin;
-    } else if address.starts_with('1') {
-        address_type = AddressType::P2pkh;
-        network = Bitcoin;
-    } else if address.starts_with('3') {
-        address_type = AddressType::P2sh;
-        network = Bitcoin;
-    } else if address.starts_with("tb1q") {
-        address_type = AddressType::P2wpkh;
-        network = Testnet;
-    } else if address.starts_with('m') || address.starts_with('n') {
-        address_type = AddressType::P2pkh;
-        network = Testnet;
-    } else if address.starts_with('2') {
-        address_type = AddressType::P2sh;
-        network = Testnet;
-    } else if address.starts_with("tb1p") {
-        address_type = AddressType::P2tr;
-        network = Testnet;
+/// The networks an address is tried on, in the order of preference when no network is configured.
+const NETWORKS: [Network; 4] = [Bitcoin, Testnet, Signet, Regtest];
+
+/// Why [`parse_address`] rejected an address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressError {
+    /// The text is not an address on any network.
+    Invalid(String),
+    /// The address is valid, but not on the network configured in `Settings`.
+    NetworkMismatch {
+        configured: Network,
+        valid_on: Vec<Network>,
+    },
+    /// A script type the library does not verify, e.g. a future segwit version.
+    UnsupportedType,
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressError::Invalid(e) => write!(f, "Invalid address: {}", e),
+            AddressError::NetworkMismatch {
+                configured,
+                valid_on,
+            } => {
+                let valid_on: Vec<String> = valid_on.iter().map(|n| n.to_string()).collect();
+                write!(
+                    f,
+                    "Address is for {}, not the configured network {}",
+                    valid_on.join(" or "),
+                    configured
+                )
+            }
+            AddressError::UnsupportedType => write!(f, "Address type not supported"),
+        }
+    }
+}
+
+impl From<AddressError> for String {
+    fn from(error: AddressError) -> Self {
+        error.to_string()
     }
-    let addr = Address::from_str(address.as_str())
-        .map_err(|e| format!("Cannot gen address {:?}", e).to_string())?;
+}
 
-    let addr_checked = addr
-        .clone()
+/// Parses `address` and checks it against the network configured in `Settings`. Testnet, signet and regtest
+/// share prefixes, so an address can be valid on several networks; `valid_on` lists all of them. Without
+/// settings, as when verifying messages standalone, the first of mainnet, testnet, signet and regtest the
+/// address is valid on is used.
+pub fn parse_address(address: &str) -> Result<AddressInfo, AddressError> {
+    let unchecked = Address::from_str(address).map_err(|e| AddressError::Invalid(e.to_string()))?;
+    let valid_on: Vec<Network> = NETWORKS
+        .into_iter()
+        .filter(|n| unchecked.is_valid_for_network(*n))
+        .collect();
+    let configured = crate::SETTINGS.with_borrow(|s| s.as_ref().map(|s| s.network));
+    let network = match configured {
+        Some(configured) if valid_on.contains(&configured) => configured,
+        Some(configured) => {
+            return Err(AddressError::NetworkMismatch {
+                configured,
+                valid_on,
+            })
+        }
+        None => *valid_on
+            .first()
+            .ok_or_else(|| AddressError::Invalid("Unknown network".to_string()))?,
+    };
+    let checked = unchecked
         .require_network(network)
-        .map_err(|e| format!("Cannot require network {:?}", e).to_string())?;
+        .map_err(|e| AddressError::Invalid(e.to_string()))?;
+    let address_type = checked
+        .address_type()
+        .ok_or(AddressError::UnsupportedType)?;
 
     Ok(AddressInfo {
-        address_raw: addr_checked.clone(),
-        address: addr_checked.clone().to_string(),
-        script_buf: addr_checked.clone().script_pubkey(),
+        address: checked.to_string(),
+        script_buf: checked.script_pubkey(),
+        address_raw: checked,
         network,
+        valid_on,
         address_type,
     })
 }
 
+/// [`parse_address`] with the error as text.
+pub fn get_script_from_address(address: String) -> Result<AddressInfo, String> {
+    Ok(parse_address(&address)?)
+}
+
 pub fn from_bitcoin_network(value: BitcoinNetwork) -> Network {
     match value {
         BitcoinNetwork::Mainnet => Bitcoin,
@@ -102,3 +155,192 @@ pub fn from_bitcoin_network(value: BitcoinNetwork) -> Network {
         BitcoinNetwork::Regtest => Regtest,
     }
 }
+
+/// The limit on decoded signatures: `Settings::max_signature_bytes`, or its default when signatures are verified
+/// without initializing the library.
+pub fn max_signature_bytes() -> usize {
+    crate::SETTINGS.with_borrow(|s| {
+        s.as_ref()
+            .map_or(DEFAULT_MAX_SIGNATURE_BYTES, |s| s.max_signature_bytes)
+    })
+}
+
+/// Decodes canonical, padded base64, rejecting input that could decode to more than `max_bytes` before
+/// anything is allocated.
+pub fn decode_base64_bounded(input: &str, max_bytes: usize) -> Result<Vec<u8>, BtcError> {
+    if base64::encoded_len(max_bytes, true).is_some_and(|max_len| input.len() > max_len) {
+        return Err(BtcError::InputTooLarge(max_bytes));
+    }
+    let data = general_purpose::STANDARD
+        .decode(input)
+        .map_err(|e| BtcError::SignatureFormatError(e.to_string()))?;
+    if data.len() > max_bytes {
+        return Err(BtcError::InputTooLarge(max_bytes));
+    }
+    Ok(data)
+}
+
+/// Decodes hex, rejecting input longer than `max_bytes` before anything is allocated.
+pub fn decode_hex_bounded(input: &str, max_bytes: usize) -> Result<Vec<u8>, BtcError> {
+    if input.len() > max_bytes.saturating_mul(2) {
+        return Err(BtcError::InputTooLarge(max_bytes));
+    }
+    Ok(hex::decode(input)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::SettingsBuilder;
+    use crate::SETTINGS;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::PublicKey;
+
+    fn public_key() -> PublicKey {
+        PublicKey::from_str("03133c85d348d6c0796382966380719397453592e706cd3329119a2d2cb8d2ff7b")
+            .unwrap()
+    }
+
+    fn configure_network(network: Network) {
+        let settings = SettingsBuilder::new("example.com", "http://example.com", "some_salt")
+            .network(network)
+            .build()
+            .unwrap();
+        SETTINGS.set(Some(settings));
+    }
+
+    #[test]
+    fn test_regtest_bech32_addresses() {
+        let key = public_key();
+        let p2wpkh = Address::p2wpkh(&key, Regtest).unwrap().to_string();
+        let p2tr = Address::p2tr(
+            &Secp256k1::new(),
+            key.inner.x_only_public_key().0,
+            None,
+            Regtest,
+        )
+        .to_string();
+        assert!(p2wpkh.starts_with("bcrt1q") && p2tr.starts_with("bcrt1p"));
+
+        let info = get_script_from_address(p2wpkh).unwrap();
+        assert_eq!(
+            (info.network, info.address_type),
+            (Regtest, AddressType::P2wpkh)
+        );
+        let info = get_script_from_address(p2tr).unwrap();
+        assert_eq!(
+            (info.network, info.address_type),
+            (Regtest, AddressType::P2tr)
+        );
+    }
+
+    #[test]
+    fn test_shared_prefixes_follow_settings() {
+        let key = public_key();
+        let p2pkh = Address::p2pkh(&key, Testnet).to_string();
+        let p2wpkh = Address::p2wpkh(&key, Testnet).unwrap().to_string();
+        assert_eq!(
+            get_script_from_address(p2pkh.clone()).unwrap().network,
+            Testnet
+        );
+
+        configure_network(Signet);
+        assert_eq!(
+            get_script_from_address(p2pkh.clone()).unwrap().network,
+            Signet
+        );
+        assert_eq!(
+            get_script_from_address(p2wpkh.clone()).unwrap().network,
+            Signet
+        );
+
+        configure_network(Regtest);
+        assert_eq!(get_script_from_address(p2pkh).unwrap().network, Regtest);
+        // Regtest segwit addresses have their own prefix
+        assert!(get_script_from_address(p2wpkh).is_err());
+    }
+
+    #[test]
+    fn test_parse_address_networks() {
+        let key = public_key();
+        let p2pkh = Address::p2pkh(&key, Testnet).to_string();
+        let p2wpkh = Address::p2wpkh(&key, Testnet).unwrap().to_string();
+        assert_eq!(
+            parse_address(&p2pkh).unwrap().valid_on,
+            vec![Testnet, Signet, Regtest]
+        );
+        assert_eq!(
+            parse_address(&p2wpkh).unwrap().valid_on,
+            vec![Testnet, Signet]
+        );
+        assert!(matches!(
+            parse_address("tb1qnot-an-address"),
+            Err(AddressError::Invalid(_))
+        ));
+
+        configure_network(Bitcoin);
+        assert_eq!(
+            parse_address(&p2wpkh).err(),
+            Some(AddressError::NetworkMismatch {
+                configured: Bitcoin,
+                valid_on: vec![Testnet, Signet]
+            })
+        );
+        let mainnet = Address::p2wpkh(&key, Bitcoin).unwrap().to_string();
+        assert_eq!(parse_address(&mainnet).unwrap().valid_on, vec![Bitcoin]);
+    }
+
+    #[test]
+    fn test_parse_address_types() {
+        let script = ScriptBuf::from_bytes(vec![0x51]);
+        let p2wsh = Address::p2wsh(&script, Bitcoin).to_string();
+        let p2sh = Address::p2sh(&script, Bitcoin).unwrap().to_string();
+        assert_eq!(
+            parse_address(&p2wsh).unwrap().address_type,
+            AddressType::P2wsh
+        );
+        assert_eq!(
+            parse_address(&p2sh).unwrap().address_type,
+            AddressType::P2sh
+        );
+    }
+
+    #[test]
+    fn test_decode_base64_bounded() {
+        assert_eq!(
+            decode_base64_bounded("AAECAw==", 4).unwrap(),
+            vec![0, 1, 2, 3]
+        );
+        assert!(matches!(
+            decode_base64_bounded("AAECAw==", 3),
+            Err(BtcError::InputTooLarge(3))
+        ));
+        // Unpadded and non-canonical encodings are rejected
+        assert!(matches!(
+            decode_base64_bounded("AAECAw", 4),
+            Err(BtcError::SignatureFormatError(_))
+        ));
+        assert!(matches!(
+            decode_base64_bounded("AAECAx==", 4),
+            Err(BtcError::SignatureFormatError(_))
+        ));
+        let huge = "A".repeat(1 << 20);
+        assert!(matches!(
+            decode_base64_bounded(&huge, DEFAULT_MAX_SIGNATURE_BYTES),
+            Err(BtcError::InputTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_hex_bounded() {
+        assert_eq!(decode_hex_bounded("0102", 2).unwrap(), vec![1, 2]);
+        assert!(matches!(
+            decode_hex_bounded("010203", 2),
+            Err(BtcError::InputTooLarge(2))
+        ));
+        assert!(matches!(
+            decode_hex_bounded("0g", 2),
+            Err(BtcError::DecodingError(_))
+        ));
+    }
+}
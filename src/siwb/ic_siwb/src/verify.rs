@@ -0,0 +1,135 @@
+//! Verification of Bitcoin-signed statements outside the login flow.
+//!
+//! [`verify_btc_message`] checks a signature over an arbitrary message, such as an attestation or a vote,
+//! with the rules [`crate::login::login`] applies to the SIWB message. It neither reads nor writes any
+//! library state: no SIWB message has to be prepared and the library does not have to be initialized.
+
+use std::fmt;
+
+use crate::error::BtcError;
+use crate::login::{verify_signature, LoginError, SignMessageType, VerifiedSigner};
+use crate::utils::parse_address;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The address could not be parsed.
+    InvalidAddress(String),
+    /// The scheme cannot sign for this type of address, e.g. a BIP-322 simple signature of a P2PKH address.
+    UnsupportedAddressType,
+    /// The signature could not be decoded.
+    MalformedSignature(String),
+    /// The signature is well-formed but was not made over the message by the address.
+    SignatureMismatch,
+}
+
+impl From<LoginError> for VerifyError {
+    fn from(err: LoginError) -> Self {
+        match err {
+            LoginError::AddressMismatch => VerifyError::SignatureMismatch,
+            LoginError::BtcError(BtcError::AddressTypeNotSupported) => {
+                VerifyError::UnsupportedAddressType
+            }
+            e => VerifyError::MalformedSignature(e.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::InvalidAddress(e) => write!(f, "Invalid address: {}", e),
+            VerifyError::UnsupportedAddressType => {
+                write!(f, "Address type not supported by the signature scheme")
+            }
+            VerifyError::MalformedSignature(e) => write!(f, "Malformed signature: {}", e),
+            VerifyError::SignatureMismatch => {
+                write!(f, "Signature does not match the address and message")
+            }
+        }
+    }
+}
+
+impl From<VerifyError> for String {
+    fn from(error: VerifyError) -> Self {
+        error.to_string()
+    }
+}
+
+/// Verifies that `signature` signs `message` with the key behind `address`.
+///
+/// # Parameters
+/// * `address`: The Bitcoin address that signed the message. Once the library is initialized it must be on
+///   the configured network, see [`parse_address`]; before, it may be on any network.
+/// * `message`: The signed statement, verbatim.
+/// * `signature`: The base64 signature: the compact ECDSA signature, the BIP-322 witness or the full
+///   BIP-322 `to_sign` transaction, depending on `scheme`.
+/// * `scheme`: How the message was signed.
+///
+/// # Returns
+/// The address type, network, signing key and verification path on success. Like [`verify_signature`],
+/// the successful and failed verifications are counted in the library metrics.
+pub fn verify_btc_message(
+    address: &str,
+    message: &str,
+    signature: &str,
+    scheme: SignMessageType,
+) -> Result<VerifiedSigner, VerifyError> {
+    let address = parse_address(address).map_err(|e| VerifyError::InvalidAddress(e.to_string()))?;
+    Ok(verify_signature(
+        message,
+        &address.address_raw,
+        signature,
+        "",
+        scheme,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::login::VerificationPath;
+
+    const P2TR_ADDRESS: &str = "tb1phy4ay0kvcnelc9trqzk4ksld3qx45gm83274qxp204vzycg7hxaq2m2nrn";
+    const P2TR_SIGNATURE: &str =
+        "AUBNN/m5COckJE1nj5bR9iAO+Ga5VlJU2xIIGBraFZQNDUtOO0J0tOhoQzvk0o+YwknQ3OGWyWR5VwiG2KzJwjUV";
+
+    #[test]
+    fn test_verify_btc_message() {
+        let signer = verify_btc_message(
+            P2TR_ADDRESS,
+            "hello",
+            P2TR_SIGNATURE,
+            SignMessageType::Bip322Simple,
+        )
+        .unwrap();
+        assert_eq!(signer.address_type, "p2tr");
+        assert_eq!(signer.network, "testnet");
+        assert_eq!(signer.verification_path, VerificationPath::Bip322Simple);
+    }
+
+    #[test]
+    fn test_verify_btc_message_rejects_other_message() {
+        assert_eq!(
+            verify_btc_message(
+                P2TR_ADDRESS,
+                "vote: yes",
+                P2TR_SIGNATURE,
+                SignMessageType::Bip322Simple,
+            ),
+            Err(VerifyError::SignatureMismatch)
+        );
+    }
+
+    #[test]
+    fn test_verify_btc_message_rejects_invalid_address() {
+        assert!(matches!(
+            verify_btc_message(
+                "not an address",
+                "hello",
+                P2TR_SIGNATURE,
+                SignMessageType::Bip322Simple,
+            ),
+            Err(VerifyError::InvalidAddress(_))
+        ));
+    }
+}
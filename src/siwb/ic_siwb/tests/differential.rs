@@ -0,0 +1,154 @@
+//! Differential suite: ECDSA message signatures made with rust-bitcoin's `sign_message` utilities for
+//! randomized keys and messages are checked with [`verify_signature`] and [`msg_hash`], and every verdict is
+//! compared with rust-bitcoin's own. Needs the `secp-recovery` feature of `bitcoin`, so it only runs with
+//! `cargo test --features differential-tests`.
+#![cfg(feature = "differential-tests")]
+
+use ic_siwb::bitcoin::hashes::Hash;
+use ic_siwb::bitcoin::secp256k1::{Message, Secp256k1, SecretKey};
+use ic_siwb::bitcoin::sign_message::{signed_msg_hash, MessageSignature};
+use ic_siwb::bitcoin::{Address, Network, PrivateKey, PublicKey};
+use ic_siwb::login::{msg_hash, verify_signature, SignMessageType, VerificationPath};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+const CASES: usize = 2000;
+// Fixed so that a divergence is reproducible.
+const SEED: u64 = 0x5157_b0a1;
+
+fn random_message(rng: &mut StdRng) -> String {
+    // Lengths past 252 and 65535 bytes exercise the wider varint length prefixes.
+    let len = match rng.gen_range(0..10) {
+        0 => rng.gen_range(253..70_000),
+        _ => rng.gen_range(0..253),
+    };
+    (0..len)
+        .map(|_| match rng.gen_range(0..20) {
+            0 => 'é',
+            1 => '\n',
+            _ => rng.gen_range(' '..='~'),
+        })
+        .collect()
+}
+
+fn random_key(rng: &mut StdRng) -> SecretKey {
+    loop {
+        if let Ok(key) = SecretKey::from_slice(&rng.gen::<[u8; 32]>()) {
+            return key;
+        }
+    }
+}
+
+fn sign(secret_key: &SecretKey, message: &str, compressed: bool) -> MessageSignature {
+    let secp = Secp256k1::new();
+    let digest = Message::from_slice(signed_msg_hash(message).as_ref()).unwrap();
+    MessageSignature::new(secp.sign_ecdsa_recoverable(&digest, secret_key), compressed)
+}
+
+/// Whether rust-bitcoin recovers the key behind `address` from `signature`.
+fn bitcoin_accepts(signature: &MessageSignature, address: &Address, message: &str) -> bool {
+    let secp = Secp256k1::verification_only();
+    let msg_hash = signed_msg_hash(message);
+    match signature.is_signed_by_address(&secp, address, msg_hash) {
+        Ok(signed) => signed,
+        // rust-bitcoin only checks P2PKH addresses itself, so compare the recovered key for the others.
+        Err(_) => signature
+            .recover_pubkey(&secp, msg_hash)
+            .ok()
+            .and_then(|key| Address::p2wpkh(&key, address.network).ok())
+            .is_some_and(|derived| &derived == address),
+    }
+}
+
+#[test]
+fn msg_hash_matches_signed_msg_hash() {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    for _ in 0..CASES {
+        let message = random_message(&mut rng);
+        assert_eq!(
+            msg_hash(message.clone()),
+            signed_msg_hash(&message).to_byte_array().to_vec(),
+            "message hash diverges for a {} byte message",
+            message.len()
+        );
+    }
+}
+
+#[test]
+fn ecdsa_verdicts_match_rust_bitcoin() {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let secp = Secp256k1::new();
+    for case in 0..CASES {
+        let secret_key = random_key(&mut rng);
+        let message = random_message(&mut rng);
+        let compressed = rng.gen_bool(0.7);
+        let private_key = PrivateKey {
+            compressed,
+            network: Network::Bitcoin,
+            inner: secret_key,
+        };
+        let public_key = PublicKey::from_private_key(&secp, &private_key);
+        let (address, expected_path) = if compressed && rng.gen_bool(0.5) {
+            (
+                Address::p2wpkh(&public_key, Network::Bitcoin).unwrap(),
+                VerificationPath::Ecdsa,
+            )
+        } else if compressed {
+            (
+                Address::p2pkh(&public_key, Network::Bitcoin),
+                VerificationPath::Ecdsa,
+            )
+        } else {
+            (
+                Address::p2pkh(&public_key, Network::Bitcoin),
+                VerificationPath::EcdsaUncompressedKey,
+            )
+        };
+        let signature = sign(&secret_key, &message, compressed);
+        let public_key_hex = public_key.to_string();
+
+        // A signature over the message itself.
+        assert!(bitcoin_accepts(&signature, &address, &message));
+        let signer = verify_signature(
+            &message,
+            &address,
+            &signature.to_base64(),
+            &public_key_hex,
+            SignMessageType::ECDSA,
+        )
+        .unwrap_or_else(|e| panic!("case {}: {} rejected: {}", case, address, e));
+        assert_eq!(signer.verification_path, expected_path, "case {}", case);
+
+        // The same signature over a different message.
+        let tampered = format!("{}.", message);
+        assert_eq!(
+            verify_signature(
+                &tampered,
+                &address,
+                &signature.to_base64(),
+                &public_key_hex,
+                SignMessageType::ECDSA,
+            )
+            .is_ok(),
+            bitcoin_accepts(&signature, &address, &tampered),
+            "case {}: verdicts diverge for a tampered message",
+            case
+        );
+
+        // A signature by another key.
+        let other = sign(&random_key(&mut rng), &message, compressed);
+        assert_eq!(
+            verify_signature(
+                &message,
+                &address,
+                &other.to_base64(),
+                &public_key_hex,
+                SignMessageType::ECDSA,
+            )
+            .is_ok(),
+            bitcoin_accepts(&other, &address, &message),
+            "case {}: verdicts diverge for a foreign signature",
+            case
+        );
+    }
+}
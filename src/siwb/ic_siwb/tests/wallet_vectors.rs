@@ -0,0 +1,186 @@
+//! Harness for a data-driven wallet compatibility suite: every `tests/wallet_vectors/*.json` file is
+//! verified with [`verify_signature`]. Only reference and fixture vectors are checked in so far; the
+//! wallet captures still to add are listed in `tests/wallet_vectors/README.md`, with the format.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use ic_siwb::bitcoin::Address;
+use ic_siwb::login::{verify_signature, SignMessageType, VerificationPath};
+use ic_siwb::shadow::verify_bip322_witness;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct VectorFile {
+    wallet: String,
+    vectors: Vec<Vector>,
+}
+
+#[derive(Deserialize)]
+struct Vector {
+    name: String,
+    address: String,
+    message: String,
+    signature: String,
+    #[serde(default)]
+    public_key: String,
+    sign_message_type: SignMessageType,
+    expect: Expect,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Expect {
+    Valid {
+        address_type: String,
+        verification_path: VerificationPath,
+    },
+    Invalid,
+}
+
+fn vector_files() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/wallet_vectors");
+    let mut files: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("cannot read {}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    files.sort();
+    files
+}
+
+/// Returns a description of the mismatch, or None if the vector behaved as expected.
+fn check(vector: &Vector) -> Option<String> {
+    let address = match Address::from_str(&vector.address) {
+        Ok(address) => address.assume_checked(),
+        Err(e) => return Some(format!("invalid address: {}", e)),
+    };
+    let result = verify_signature(
+        &vector.message,
+        &address,
+        &vector.signature,
+        &vector.public_key,
+        vector.sign_message_type.clone(),
+    );
+    match (&vector.expect, result) {
+        (
+            Expect::Valid {
+                address_type,
+                verification_path,
+            },
+            Ok(signer),
+        ) => {
+            if &signer.address_type != address_type {
+                Some(format!(
+                    "address type {}, expected {}",
+                    signer.address_type, address_type
+                ))
+            } else if &signer.verification_path != verification_path {
+                Some(format!(
+                    "verified via {:?}, expected {:?}",
+                    signer.verification_path, verification_path
+                ))
+            } else {
+                None
+            }
+        }
+        (Expect::Valid { .. }, Err(e)) => Some(format!("rejected: {}", e)),
+        (Expect::Invalid, Ok(_)) => Some("accepted, expected a rejection".to_string()),
+        (Expect::Invalid, Err(_)) => None,
+    }
+}
+
+/// Returns a description of the disagreement if the shadow candidate decides differently than
+/// [`verify_signature`].
+fn check_shadow(vector: &Vector) -> Option<String> {
+    let address = Address::from_str(&vector.address).ok()?.assume_checked();
+    let args = (
+        vector.message.as_str(),
+        &address,
+        vector.signature.as_str(),
+        vector.public_key.as_str(),
+    );
+    let primary = verify_signature(
+        args.0,
+        args.1,
+        args.2,
+        args.3,
+        vector.sign_message_type.clone(),
+    );
+    let shadow = verify_bip322_witness(
+        args.0,
+        args.1,
+        args.2,
+        args.3,
+        vector.sign_message_type.clone(),
+    );
+    match (primary, shadow) {
+        (Ok(primary), Ok(shadow)) if primary == shadow => None,
+        (Err(_), Err(_)) => None,
+        (Ok(_), Err(e)) => Some(format!("shadow rejected: {}", e)),
+        (Err(_), Ok(_)) => Some("shadow accepted, primary rejected".to_string()),
+        (Ok(_), Ok(_)) => Some("shadow reported a different signer".to_string()),
+    }
+}
+
+#[test]
+fn wallet_vectors() {
+    let mut checked = 0;
+    let mut failures = Vec::new();
+    for path in vector_files() {
+        let text = fs::read_to_string(&path).unwrap();
+        let file: VectorFile = serde_json::from_str(&text)
+            .unwrap_or_else(|e| panic!("cannot parse {}: {}", path.display(), e));
+        for vector in &file.vectors {
+            checked += 1;
+            if let Some(problem) = check(vector) {
+                failures.push(format!("{} / {}: {}", file.wallet, vector.name, problem));
+            }
+            if let Some(problem) = check_shadow(vector) {
+                failures.push(format!("{} / {}: {}", file.wallet, vector.name, problem));
+            }
+        }
+    }
+    assert!(checked > 0, "no wallet vectors found");
+    assert!(
+        failures.is_empty(),
+        "{} of {} wallet vectors failed:\n{}",
+        failures.len(),
+        checked,
+        failures.join("\n")
+    );
+}
+
+/// The wallets the suite has to cover, by the name of their vector file.
+const WALLETS: [&str; 6] = ["unisat", "xverse", "leather", "okx", "sparrow", "electrum"];
+
+/// Fails while any wallet lacks a file with at least one signature it accepted. No captures exist yet, so the
+/// test is ignored; `--ignored` lists the wallets still missing.
+#[test]
+#[ignore = "wallet captures are outstanding, see tests/wallet_vectors/README.md"]
+fn wallet_captures() {
+    let files = vector_files();
+    let missing: Vec<&str> = WALLETS
+        .into_iter()
+        .filter(|wallet| {
+            let Some(path) = files
+                .iter()
+                .find(|path| path.file_stem().is_some_and(|stem| stem == *wallet))
+            else {
+                return true;
+            };
+            let file: VectorFile =
+                serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+            !file
+                .vectors
+                .iter()
+                .any(|vector| matches!(vector.expect, Expect::Valid { .. }))
+        })
+        .collect();
+    assert!(
+        missing.is_empty(),
+        "no wallet captures from: {}",
+        missing.join(", ")
+    );
+}
@@ -1,24 +1,49 @@
-use crate::service::types::AddressScriptBuf;
+use crate::service::types::{
+    AddressScriptBuf, AnchorRecord, DelegationAuditRecord, NetworkTag, SignerRecord,
+    TermsAcknowledgmentRecord,
+};
+use candid::Principal;
 use ic_cdk::api::set_certified_data;
 use ic_certified_map::{fork_hash, labeled_hash, AsHashTree, Hash, RbTree};
-use ic_siwb::signature_map::SignatureMap;
+use ic_siwb::bitcoin::Network;
+use ic_siwb::signature_map::{
+    HeapSignatureStore, SignatureMap, SignatureStore, StableSignatureStore,
+};
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     storable::Blob,
-    DefaultMemoryImpl, StableBTreeMap,
+    DefaultMemoryImpl, StableBTreeMap, StableCell,
 };
 use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
 
 pub mod service;
 
 pub const LABEL_ASSETS: &[u8] = b"http_assets";
 pub const LABEL_SIG: &[u8] = b"sig";
+pub const LABEL_AUDIT: &[u8] = b"delegation_audit";
 
 pub(crate) type AssetHashes = RbTree<&'static str, Hash>;
 
+/// The certified leaves of the issued delegations, see `service::delegation_audit`.
+pub(crate) type AuditTree = RbTree<Vec<u8>, Vec<u8>>;
+
+/// The key of the audit expiry index: (expiration, seed hash, delegation hash).
+pub(crate) type AuditExpiryKey = (u64, Blob<32>, Blob<32>);
+
+/// A delegation issued to a principal, tracked until it expires to enforce
+/// `max_sessions_per_principal`.
+pub(crate) struct SessionRecord {
+    pub delegation_hash: Hash,
+    pub expiration: u64,
+}
+
 pub(crate) struct State {
     pub signature_map: RefCell<SignatureMap>,
     pub asset_hashes: RefCell<AssetHashes>,
+    // Keyed by seed hash, which identifies the principal.
+    pub sessions: RefCell<HashMap<Hash, VecDeque<SessionRecord>>>,
+    pub delegation_audit: RefCell<AuditTree>,
 }
 
 impl Default for State {
@@ -26,14 +51,113 @@ impl Default for State {
         Self {
             signature_map: RefCell::new(SignatureMap::default()),
             asset_hashes: RefCell::new(AssetHashes::default()),
+            sessions: RefCell::new(HashMap::new()),
+            delegation_audit: RefCell::new(AuditTree::default()),
         }
     }
 }
 
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SessionLimitPolicy {
+    #[default]
+    RejectNewSession,
+    EvictOldestSession,
+}
+
+/// Where issued delegation signatures are kept.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SignatureStoreKind {
+    #[default]
+    Heap,
+    Stable,
+}
+
+/// Who may call an endpoint that supports `endpoint_access`.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum AccessPolicy {
+    #[default]
+    Public,
+    AuthenticatedOnly,
+    AdminOnly,
+}
+
+/// Periodic anchoring of the mappings into Bitcoin transactions, see `service::anchor`.
+#[derive(Debug, Clone)]
+pub(crate) struct AnchoringSettings {
+    /// The threshold ECDSA key that owns the funding address.
+    pub key_name: String,
+    pub interval_seconds: u64,
+    pub fee_per_vbyte: u64,
+    pub network: Network,
+}
+
+/// Automatic cycle top-ups from the cycles ledger, see `service::top_up`.
+#[derive(Debug, Clone)]
+pub(crate) struct TopUpSettings {
+    pub cycles_ledger: Principal,
+    /// The subaccount of this canister on the cycles ledger that top-ups are withdrawn from.
+    pub funding_subaccount: Option<[u8; 32]>,
+    pub threshold: u128,
+    pub amount: u128,
+    pub min_interval_seconds: u64,
+}
+
+/// Shard-router mode, see `service::shard`.
+#[derive(Debug, Clone)]
+pub(crate) struct ShardingSettings {
+    pub shard_count: u32,
+    pub cycles_per_shard: u128,
+    /// Controllers of new shards besides this canister.
+    pub controllers: Vec<Principal>,
+    /// The candid encoded `SettingsInput` new shards are installed with.
+    pub shard_init_arg: Vec<u8>,
+}
+
+/// The Terms of Service every login acknowledges, see `service::terms_of_service`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TermsOfService {
+    pub version: String,
+    /// The SHA-256 hash of the document.
+    pub hash: [u8; 32],
+}
+
+/// The salt and URI principals were derived from before the current ones, see `service::seed_migration`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SeedMigration {
+    pub previous_salt: String,
+    pub previous_uri: String,
+}
+
+/// The synthetic-monitoring address, see `service::probe`.
+#[derive(Debug, Clone)]
+pub(crate) struct ProbeSettings {
+    pub address: String,
+    pub script: Vec<u8>,
+}
+
 #[derive(Default, Debug, Clone)]
 pub(crate) struct Settings {
     pub disable_btc_to_principal_mapping: bool,
     pub disable_principal_to_btc_mapping: bool,
+    pub login_hook: Option<Principal>,
+    pub max_sessions_per_principal: Option<u32>,
+    pub session_limit_policy: SessionLimitPolicy,
+    pub lookup_cache_ttl: Option<u64>,
+    pub endpoint_access: HashMap<String, AccessPolicy>,
+    pub session_expiry_reminder_window: Option<u64>,
+    pub signature_store: SignatureStoreKind,
+    pub anchoring: Option<AnchoringSettings>,
+    pub sharding: Option<ShardingSettings>,
+    pub top_up: Option<TopUpSettings>,
+    /// Sunset timestamps of deprecated endpoints, see `service::deprecations`.
+    pub api_sunsets: HashMap<String, u64>,
+    pub probe: Option<ProbeSettings>,
+    /// The principal allowed to set attestation flags, see `service::attestation`.
+    pub attestor: Option<Principal>,
+    pub terms_of_service: Option<TermsOfService>,
+    /// The duration of guest sessions in nanoseconds, see `service::siwb_login_guest`. None disables them.
+    pub guest_session_expires_in: Option<u64>,
+    pub seed_migration: Option<SeedMigration>,
 }
 
 thread_local! {
@@ -45,25 +169,193 @@ thread_local! {
     static SETTINGS: RefCell<Settings> = RefCell::new(Settings {
         disable_btc_to_principal_mapping: false,
         disable_principal_to_btc_mapping: false,
+        login_hook: None,
+        max_sessions_per_principal: None,
+        session_limit_policy: SessionLimitPolicy::RejectNewSession,
+        lookup_cache_ttl: None,
+        endpoint_access: HashMap::new(),
+        session_expiry_reminder_window: None,
+        signature_store: SignatureStoreKind::Heap,
+        anchoring: None,
+        sharding: None,
+        top_up: None,
+        api_sunsets: HashMap::new(),
+        probe: None,
+        attestor: None,
+        terms_of_service: None,
+        guest_session_expires_in: None,
+        seed_migration: None,
     });
 
-    static PRINCIPAL_ADDRESS: RefCell<StableBTreeMap<Blob<29>, AddressScriptBuf, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+    static PRINCIPAL_ADDRESS: RefCell<StableBTreeMap<(NetworkTag, Blob<29>), AddressScriptBuf, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
+        )
+    );
+
+    static ADDRESS_PRINCIPAL: RefCell<StableBTreeMap<(NetworkTag, AddressScriptBuf), Blob<29>, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
+        )
+    );
+
+    // Mappings stored before they were keyed by network. They are moved to the configured network on upgrade.
+    static LEGACY_PRINCIPAL_ADDRESS: RefCell<StableBTreeMap<Blob<29>, AddressScriptBuf, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
         StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
         )
     );
 
-    static ADDRESS_PRINCIPAL: RefCell<StableBTreeMap<AddressScriptBuf, Blob<29>, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+    static LEGACY_ADDRESS_PRINCIPAL: RefCell<StableBTreeMap<AddressScriptBuf, Blob<29>, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
         StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
         )
     );
+
+    // Backends allowed to pre-create SIWB messages on behalf of their users.
+    static CUSTODIANS: RefCell<StableBTreeMap<Blob<29>, u8, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+        )
+    );
+
+    // Outstanding custodial challenges keyed by address script, with the custodian to notify and the
+    // expiration time of the SIWB message.
+    static CUSTODIAL_LOGINS: RefCell<HashMap<Vec<u8>, (Principal, u64)>> = RefCell::new(HashMap::new());
+
+    // The Terms of Service embedded in the outstanding SIWB messages keyed by address script, with the
+    // expiration time of the message, so a login records the version that was signed.
+    static PREPARED_TERMS: RefCell<HashMap<Vec<u8>, (TermsOfService, u64)>> = RefCell::new(HashMap::new());
+
+    // How each principal last authenticated, kept alongside the principal to address mapping.
+    static SIGNERS: RefCell<StableBTreeMap<Blob<29>, SignerRecord, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+        )
+    );
+
+    // Anchored commitments of the mappings by sequence number, see `service::anchor`.
+    static ANCHORS: RefCell<StableBTreeMap<u64, AnchorRecord, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+        )
+    );
+
+    // The leaves of the latest anchored snapshot by position, and the position of each mapping, to serve proofs
+    // for it after the live mappings changed.
+    static ANCHORED_LEAVES: RefCell<StableBTreeMap<u64, Blob<32>, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+        )
+    );
+
+    static ANCHORED_INDEX: RefCell<StableBTreeMap<(NetworkTag, Blob<29>), u64, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+        )
+    );
+
+    // The child canisters of the shard-router mode by shard index.
+    static SHARDS: RefCell<StableBTreeMap<u32, Blob<29>, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+        )
+    );
+
+    // The wasm module new shards are installed with.
+    static SHARD_WASM: RefCell<StableCell<Vec<u8>, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+            vec![],
+        )
+        .expect("Failed to initialize shard wasm cell")
+    );
+
+    // The shard count the existing shards were created for. Changing it would move keys to other shards.
+    static SHARD_COUNT: RefCell<StableCell<u32, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+            0,
+        )
+        .expect("Failed to initialize shard count cell")
+    );
+
+    // Every delegation issued by `siwb_login` by (seed hash, delegation hash), see `service::delegation_audit`.
+    static DELEGATION_AUDIT: RefCell<StableBTreeMap<(Blob<32>, Blob<32>), DelegationAuditRecord, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+        )
+    );
+
+    // The records of `DELEGATION_AUDIT` by (expiration, seed hash, delegation hash), so expired records are pruned
+    // oldest first.
+    static DELEGATION_AUDIT_EXPIRY: RefCell<StableBTreeMap<AuditExpiryKey, (), VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+        )
+    );
+
+    // Every Terms of Service acknowledgment by (document hash, principal), see `service::terms_of_service`.
+    static TERMS_ACKNOWLEDGMENTS: RefCell<StableBTreeMap<(Blob<32>, Blob<29>), TermsAcknowledgmentRecord, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))),
+        )
+    );
+
+    // The principal each address was derived under the previous salt and URI, recorded by the logins made while
+    // `seed_migration` is set, see `service::seed_migration`.
+    static PREVIOUS_PRINCIPALS: RefCell<StableBTreeMap<(NetworkTag, AddressScriptBuf), Blob<29>, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+        )
+    );
+
+    // The session epoch survives upgrades so that seeds derived with `IncludeSessionEpochInSeed` stay stable.
+    static SESSION_EPOCH: RefCell<StableCell<u64, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
+            0,
+        )
+        .expect("Failed to initialize session epoch cell")
+    );
+}
+
+/// Moves the signature map onto the `kind` backend and recertifies it. Moving onto the stable backend also
+/// restores the signatures it kept across an upgrade. Memories 7 and 8 hold the stable backend.
+pub(crate) fn set_signature_store(kind: SignatureStoreKind) {
+    let store: Box<dyn SignatureStore> = match kind {
+        SignatureStoreKind::Heap => Box::<HeapSignatureStore>::default(),
+        SignatureStoreKind::Stable => MEMORY_MANAGER.with(|m| {
+            let m = m.borrow();
+            Box::new(StableSignatureStore::init(
+                m.get(MemoryId::new(7)),
+                m.get(MemoryId::new(8)),
+            ))
+        }),
+    };
+    STATE.with(|state| {
+        let signature_map = &mut *state.signature_map.borrow_mut();
+        signature_map.set_store(store);
+        update_root_hash(
+            &state.asset_hashes.borrow(),
+            signature_map,
+            &state.delegation_audit.borrow(),
+        );
+    });
 }
 
-pub(crate) fn update_root_hash(asset_hashes: &AssetHashes, signature_map: &SignatureMap) {
+pub(crate) fn update_root_hash(
+    asset_hashes: &AssetHashes,
+    signature_map: &SignatureMap,
+    delegation_audit: &AuditTree,
+) {
+    // Labels must be in sorted order: "delegation_audit" < "http_assets" < "sig".
     let prefixed_root_hash = fork_hash(
-        &labeled_hash(LABEL_ASSETS, &asset_hashes.root_hash()),
-        &labeled_hash(LABEL_SIG, &signature_map.root_hash()),
+        &labeled_hash(LABEL_AUDIT, &delegation_audit.root_hash()),
+        &fork_hash(
+            &labeled_hash(LABEL_ASSETS, &asset_hashes.root_hash()),
+            &labeled_hash(LABEL_SIG, &signature_map.root_hash()),
+        ),
     );
     set_certified_data(&prefixed_root_hash[..]);
 }
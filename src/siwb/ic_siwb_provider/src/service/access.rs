@@ -0,0 +1,62 @@
+use candid::Principal;
+use ic_cdk::api::is_controller;
+
+use crate::{AccessPolicy, SETTINGS};
+
+/// Applies the access policy configured for `endpoint` in `SettingsInput::endpoint_access` to the caller.
+/// Endpoints without a configured policy are public.
+pub(crate) fn check_access(endpoint: &str) -> Result<(), String> {
+    let policy =
+        SETTINGS.with_borrow(|s| s.endpoint_access.get(endpoint).copied().unwrap_or_default());
+    let caller = ic_cdk::caller();
+    match policy {
+        AccessPolicy::Public => Ok(()),
+        AccessPolicy::AuthenticatedOnly if caller != Principal::anonymous() => Ok(()),
+        AccessPolicy::AuthenticatedOnly => {
+            Err(format!("{} requires an authenticated caller", endpoint))
+        }
+        AccessPolicy::AdminOnly if is_controller(&caller) => Ok(()),
+        AccessPolicy::AdminOnly => Err(format!("Only a controller can call {}", endpoint)),
+    }
+}
+
+/// Defines one guard per endpoint, since canister guards take no arguments, and the list of endpoints whose
+/// access can be configured.
+macro_rules! endpoint_guards {
+    ($($guard:ident => $endpoint:literal),* $(,)?) => {
+        $(
+            pub(crate) fn $guard() -> Result<(), String> {
+                check_access($endpoint)
+            }
+        )*
+
+        /// Endpoints accepted in `endpoint_access`. The login flow is always public because its callers are not
+        /// authenticated yet, and controller-only endpoints are always admin-only.
+        pub(crate) const CONFIGURABLE_ENDPOINTS: &[&str] = &[$($endpoint),*];
+    };
+}
+
+endpoint_guards! {
+    get_address_guard => "get_address",
+    get_caller_address_guard => "get_caller_address",
+    get_principal_guard => "get_principal",
+    get_signer_guard => "get_signer",
+    get_cache_metrics_guard => "get_cache_metrics",
+    get_session_epoch_guard => "get_session_epoch",
+    list_custodians_guard => "list_custodians",
+    get_anchor_status_guard => "get_anchor_status",
+    get_anchor_proof_guard => "get_anchor_proof",
+    list_shards_guard => "list_shards",
+    get_shard_for_principal_guard => "get_shard_for_principal",
+    get_shard_for_address_guard => "get_shard_for_address",
+    supported_capabilities_guard => "supported_capabilities",
+    get_top_up_status_guard => "get_top_up_status",
+    get_shadow_report_guard => "get_shadow_report",
+    deprecations_guard => "deprecations",
+    get_probe_status_guard => "get_probe_status",
+    verify_message_guard => "verify_message",
+    get_delegation_audit_guard => "get_delegation_audit",
+    validate_settings_guard => "validate_settings",
+    list_terms_acknowledgments_guard => "list_terms_acknowledgments",
+    get_migration_status_guard => "get_migration_status",
+}
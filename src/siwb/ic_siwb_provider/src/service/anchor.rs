@@ -0,0 +1,397 @@
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::management_canister::bitcoin::{
+    bitcoin_get_utxos, bitcoin_send_transaction, BitcoinNetwork, GetUtxosRequest,
+    SendTransactionRequest, UtxoFilter,
+};
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument,
+    SignWithEcdsaArgument,
+};
+use ic_cdk::{query, update};
+use ic_cdk_timers::TimerId;
+use ic_certified_map::Hash;
+use ic_siwb::bitcoin::absolute::LockTime;
+use ic_siwb::bitcoin::consensus::encode::serialize;
+use ic_siwb::bitcoin::hashes::Hash as _;
+use ic_siwb::bitcoin::secp256k1::ecdsa::Signature;
+use ic_siwb::bitcoin::sighash::{EcdsaSighashType, SighashCache};
+use ic_siwb::bitcoin::{
+    Address, Network, OutPoint, PublicKey, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid,
+    Witness,
+};
+use ic_siwb::commitment::{
+    merkle_leaf_hash, merkle_proof, merkle_root, op_return_script, tagged_commitment,
+};
+use ic_stable_structures::storable::Blob;
+use serde_bytes::ByteBuf;
+
+use crate::service::access::{get_anchor_proof_guard, get_anchor_status_guard};
+use crate::service::siwb_login::controller_guard;
+use crate::service::types::{network_tag, parse_network, AnchorRecord, NetworkTag};
+use crate::{
+    AnchoringSettings, ANCHORED_INDEX, ANCHORED_LEAVES, ANCHORS, PRINCIPAL_ADDRESS, SETTINGS,
+};
+
+/// Tag of the OP_RETURN commitment: `SIWB:<Merkle root>`.
+const ANCHOR_TAG: &str = "SIWB";
+
+/// Shortest accepted `interval_seconds`; every anchor costs a Bitcoin transaction fee.
+pub(crate) const MIN_ANCHOR_INTERVAL_SECONDS: u64 = 3_600;
+
+pub(crate) const DEFAULT_ANCHOR_FEE_PER_VBYTE: u64 = 2;
+
+/// Virtual size of an anchoring transaction: one P2WPKH input, the OP_RETURN output and a P2WPKH change output.
+const ANCHOR_TX_VSIZE: u64 = 170;
+
+/// Change below this is not relayed, so the anchor would be stuck.
+const DUST_THRESHOLD: u64 = 546;
+
+thread_local! {
+    static ANCHOR_TIMER: Cell<Option<TimerId>> = const { Cell::new(None) };
+
+    static ANCHOR_IN_PROGRESS: Cell<bool> = const { Cell::new(false) };
+
+    // The compressed public key of the anchoring key, derived on first use.
+    static ANCHOR_PUBLIC_KEY: RefCell<Option<Vec<u8>>> = const { RefCell::new(None) };
+
+    static LAST_ANCHOR_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn derivation_path() -> Vec<Vec<u8>> {
+    vec![b"siwb-anchor".to_vec()]
+}
+
+fn key_id(config: &AnchoringSettings) -> EcdsaKeyId {
+    EcdsaKeyId {
+        curve: EcdsaCurve::Secp256k1,
+        name: config.key_name.clone(),
+    }
+}
+
+fn bitcoin_network(network: Network) -> BitcoinNetwork {
+    match network {
+        Network::Bitcoin => BitcoinNetwork::Mainnet,
+        Network::Regtest => BitcoinNetwork::Regtest,
+        _ => BitcoinNetwork::Testnet,
+    }
+}
+
+/// The leaf of a mapping: the network tag, the 29 principal bytes and the address script.
+fn leaf_data(network: NetworkTag, principal: &Blob<29>, script: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(30 + script.len());
+    data.push(network);
+    data.extend_from_slice(principal.as_slice());
+    data.extend_from_slice(script);
+    data
+}
+
+/// The principal to address mappings with their leaf hashes, in key order.
+fn snapshot() -> Vec<((NetworkTag, Blob<29>), Hash)> {
+    PRINCIPAL_ADDRESS.with_borrow(|pa| {
+        pa.iter()
+            .map(|((network, principal), script)| {
+                let leaf = merkle_leaf_hash(&leaf_data(network, &principal, &script.0));
+                ((network, principal), leaf)
+            })
+            .collect()
+    })
+}
+
+fn latest_anchor() -> Option<AnchorRecord> {
+    ANCHORS.with_borrow(|a| a.last_key_value().map(|(_, record)| record))
+}
+
+/// (Re)starts the anchoring timer after the settings changed.
+pub(crate) fn schedule_anchoring() {
+    if let Some(timer) = ANCHOR_TIMER.take() {
+        ic_cdk_timers::clear_timer(timer);
+    }
+    ANCHOR_PUBLIC_KEY.with_borrow_mut(|k| *k = None);
+    let Some(interval) = SETTINGS.with_borrow(|s| s.anchoring.as_ref().map(|a| a.interval_seconds))
+    else {
+        return;
+    };
+    let timer = ic_cdk_timers::set_timer_interval(Duration::from_secs(interval), || {
+        ic_cdk::spawn(async {
+            if let Err(e) = anchor().await {
+                ic_cdk::println!("state anchoring failed: {}", e);
+            }
+        })
+    });
+    ANCHOR_TIMER.set(Some(timer));
+}
+
+async fn anchor_public_key(config: &AnchoringSettings) -> Result<Vec<u8>, String> {
+    if let Some(key) = ANCHOR_PUBLIC_KEY.with_borrow(|k| k.clone()) {
+        return Ok(key);
+    }
+    let (response,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: derivation_path(),
+        key_id: key_id(config),
+    })
+    .await
+    .map_err(|(code, msg)| format!("ecdsa_public_key failed: {:?} {}", code, msg))?;
+    ANCHOR_PUBLIC_KEY.with_borrow_mut(|k| *k = Some(response.public_key.clone()));
+    Ok(response.public_key)
+}
+
+fn funding_address(public_key: &[u8], network: Network) -> Result<Address, String> {
+    let public_key = PublicKey::from_slice(public_key).map_err(|e| e.to_string())?;
+    Address::p2wpkh(&public_key, network).map_err(|e| e.to_string())
+}
+
+/// Anchors the current mappings unless they are unchanged since the last anchor. Returns the new anchor, or
+/// None if there was nothing to anchor.
+async fn anchor() -> Result<Option<AnchorRecord>, String> {
+    if ANCHOR_IN_PROGRESS.replace(true) {
+        return Err("An anchoring transaction is already in progress".to_string());
+    }
+    let result = anchor_snapshot().await;
+    ANCHOR_IN_PROGRESS.set(false);
+    LAST_ANCHOR_ERROR.with_borrow_mut(|e| *e = result.as_ref().err().cloned());
+    result
+}
+
+async fn anchor_snapshot() -> Result<Option<AnchorRecord>, String> {
+    let config = SETTINGS
+        .with_borrow(|s| s.anchoring.clone())
+        .ok_or("Anchoring is disabled")?;
+    let network = config.network;
+
+    let entries = snapshot();
+    let leaves: Vec<Hash> = entries.iter().map(|(_, leaf)| *leaf).collect();
+    let root = merkle_root(&leaves);
+    if latest_anchor().is_some_and(|a| a.root.as_ref() == root) {
+        return Ok(None);
+    }
+
+    let public_key = anchor_public_key(&config).await?;
+    let address = funding_address(&public_key, network)?;
+    let (utxos,) = bitcoin_get_utxos(GetUtxosRequest {
+        address: address.to_string(),
+        network: bitcoin_network(network),
+        filter: Some(UtxoFilter::MinConfirmations(1)),
+    })
+    .await
+    .map_err(|(code, msg)| format!("bitcoin_get_utxos failed: {:?} {}", code, msg))?;
+
+    let fee = config.fee_per_vbyte.saturating_mul(ANCHOR_TX_VSIZE);
+    let utxo = utxos
+        .utxos
+        .into_iter()
+        .max_by_key(|u| u.value)
+        .filter(|u| u.value >= fee.saturating_add(DUST_THRESHOLD))
+        .ok_or(format!(
+            "Insufficient funds: send at least {} sats to {}",
+            fee + DUST_THRESHOLD,
+            address
+        ))?;
+
+    let commitment = op_return_script(&tagged_commitment(ANCHOR_TAG, &root).map_err(String::from)?)
+        .map_err(String::from)?;
+    let txid = Txid::from_slice(&utxo.outpoint.txid).map_err(|e| e.to_string())?;
+    let mut transaction = Transaction {
+        version: 2,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid,
+                vout: utxo.outpoint.vout,
+            },
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::new(),
+        }],
+        output: vec![
+            TxOut {
+                value: 0,
+                script_pubkey: commitment,
+            },
+            TxOut {
+                value: utxo.value - fee,
+                script_pubkey: address.script_pubkey(),
+            },
+        ],
+    };
+
+    let script_code = address
+        .script_pubkey()
+        .p2wpkh_script_code()
+        .ok_or("Funding address is not P2WPKH")?;
+    let sighash = SighashCache::new(&transaction)
+        .segwit_signature_hash(0, &script_code, utxo.value, EcdsaSighashType::All)
+        .map_err(|e| e.to_string())?;
+    let (signed,) = sign_with_ecdsa(SignWithEcdsaArgument {
+        message_hash: sighash.to_byte_array().to_vec(),
+        derivation_path: derivation_path(),
+        key_id: key_id(&config),
+    })
+    .await
+    .map_err(|(code, msg)| format!("sign_with_ecdsa failed: {:?} {}", code, msg))?;
+    let mut signature = Signature::from_compact(&signed.signature).map_err(|e| e.to_string())?;
+    signature.normalize_s();
+    let mut witness = Witness::new();
+    witness.push(
+        ic_siwb::bitcoin::ecdsa::Signature {
+            sig: signature,
+            hash_ty: EcdsaSighashType::All,
+        }
+        .to_vec(),
+    );
+    witness.push(&public_key);
+    transaction.input[0].witness = witness;
+
+    bitcoin_send_transaction(SendTransactionRequest {
+        transaction: serialize(&transaction),
+        network: bitcoin_network(network),
+    })
+    .await
+    .map_err(|(code, msg)| format!("bitcoin_send_transaction failed: {:?} {}", code, msg))?;
+
+    let record = AnchorRecord {
+        sequence: latest_anchor().map_or(0, |a| a.sequence + 1),
+        root: ByteBuf::from(root.to_vec()),
+        txid: transaction.txid().to_string(),
+        mapping_count: entries.len() as u64,
+        fee,
+        anchored_at: ic_cdk::api::time(),
+    };
+    ANCHORS.with_borrow_mut(|a| a.insert(record.sequence, record.clone()));
+    store_anchored_snapshot(&entries);
+    Ok(Some(record))
+}
+
+/// Replaces the stored snapshot with the leaves of the latest anchor, so proofs can be served for it.
+fn store_anchored_snapshot(entries: &[((NetworkTag, Blob<29>), Hash)]) {
+    ANCHORED_LEAVES.with_borrow_mut(|leaves| {
+        let indexes: Vec<u64> = leaves.iter().map(|(index, _)| index).collect();
+        for index in indexes {
+            leaves.remove(&index);
+        }
+        for (index, (_, leaf)) in entries.iter().enumerate() {
+            leaves.insert(index as u64, Blob::try_from(&leaf[..]).unwrap());
+        }
+    });
+    ANCHORED_INDEX.with_borrow_mut(|by_key| {
+        let keys: Vec<(NetworkTag, Blob<29>)> = by_key.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            by_key.remove(&key);
+        }
+        for (index, (key, _)) in entries.iter().enumerate() {
+            by_key.insert(*key, index as u64);
+        }
+    });
+}
+
+/// Anchors the current mappings now instead of waiting for the next interval. Returns None if they are
+/// unchanged since the last anchor.
+#[update(guard = "controller_guard")]
+async fn anchor_now() -> Result<Option<AnchorRecord>, String> {
+    anchor().await
+}
+
+/// Whether anchoring is enabled, the address that pays its fees and the outcome of the last attempt.
+#[derive(CandidType, Deserialize)]
+pub struct AnchorStatus {
+    pub enabled: bool,
+
+    /// The P2WPKH address funding the anchoring transactions. None until the key was derived by the first
+    /// anchoring attempt.
+    pub funding_address: Option<String>,
+
+    pub latest: Option<AnchorRecord>,
+
+    /// The error of the last attempt, None if it succeeded or had nothing to anchor.
+    pub last_error: Option<String>,
+}
+
+#[query(guard = "get_anchor_status_guard")]
+fn get_anchor_status() -> AnchorStatus {
+    let network = SETTINGS.with_borrow(|s| s.anchoring.as_ref().map(|a| a.network));
+    AnchorStatus {
+        enabled: network.is_some(),
+        funding_address: ANCHOR_PUBLIC_KEY
+            .with_borrow(|k| k.clone())
+            .zip(network)
+            .and_then(|(k, network)| funding_address(&k, network).ok())
+            .map(|a| a.to_string()),
+        latest: latest_anchor(),
+        last_error: LAST_ANCHOR_ERROR.with_borrow(|e| e.clone()),
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AnchorProofStep {
+    pub sibling: ByteBuf,
+    pub sibling_on_left: bool,
+}
+
+/// Proves that a mapping was part of an anchored snapshot. The leaf is the SHA-256 hash of 0x00, the network
+/// tag byte, the 29 principal bytes and the address script; each step hashes 0x01, the left and the right node.
+/// Folding the steps into the leaf yields the root committed in transaction `anchor.txid`.
+#[derive(CandidType, Deserialize)]
+pub struct AnchorProof {
+    pub anchor: AnchorRecord,
+    pub network_tag: u8,
+    pub address_script: ByteBuf,
+    pub leaf_index: u64,
+    pub steps: Vec<AnchorProofStep>,
+}
+
+/// Returns the proof that the mapping of `principal` on `network` (defaults to the anchoring network) was
+/// part of the latest anchored snapshot.
+#[query(guard = "get_anchor_proof_guard")]
+fn get_anchor_proof(principal: ByteBuf, network: Option<String>) -> Result<AnchorProof, String> {
+    let anchor = latest_anchor().ok_or("Nothing has been anchored yet")?;
+    let network = match network {
+        Some(n) => network_tag(parse_network(&n)?),
+        None => network_tag(
+            SETTINGS
+                .with_borrow(|s| s.anchoring.as_ref().map(|a| a.network))
+                .unwrap_or(Network::Bitcoin),
+        ),
+    };
+    let principal: Blob<29> = principal
+        .as_ref()
+        .try_into()
+        .map_err(|_| "Failed to convert ByteBuf to Blob<29>")?;
+
+    let leaf_index = ANCHORED_INDEX
+        .with_borrow(|by_key| by_key.get(&(network, principal)))
+        .ok_or("The principal is not part of the latest anchored snapshot")?;
+    let leaves: Vec<Hash> = ANCHORED_LEAVES.with_borrow(|leaves| {
+        leaves
+            .iter()
+            .map(|(_, leaf)| leaf.as_slice().try_into().unwrap())
+            .collect()
+    });
+    let steps = merkle_proof(&leaves, leaf_index as usize)
+        .ok_or("Anchored snapshot is incomplete")?
+        .into_iter()
+        .map(|step| AnchorProofStep {
+            sibling: ByteBuf::from(step.sibling.to_vec()),
+            sibling_on_left: step.sibling_on_left,
+        })
+        .collect();
+
+    // The snapshot keeps only leaf hashes, so the script comes from the live mapping and must still match.
+    let address_script = PRINCIPAL_ADDRESS
+        .with_borrow(|pa| pa.get(&(network, principal)))
+        .filter(|script| {
+            merkle_leaf_hash(&leaf_data(network, &principal, &script.0))
+                == leaves[leaf_index as usize]
+        })
+        .ok_or("The mapping changed after the latest anchor")?;
+
+    Ok(AnchorProof {
+        anchor,
+        network_tag: network,
+        address_script: ByteBuf::from(address_script.0),
+        leaf_index,
+        steps,
+    })
+}
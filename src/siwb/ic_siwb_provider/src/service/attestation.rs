@@ -0,0 +1,71 @@
+use candid::candid_method;
+use ic_cdk::update;
+use ic_stable_structures::storable::Blob;
+use serde_bytes::ByteBuf;
+
+use crate::service::types::Attestation;
+use crate::{SETTINGS, SIGNERS};
+
+const MAX_FLAGS: usize = 16;
+const MAX_FLAG_LENGTH: usize = 64;
+
+/// Trims the flags, drops duplicates and rejects empty, overlong or too many flags.
+fn normalize_flags(flags: Vec<String>) -> Result<Vec<String>, String> {
+    let mut normalized: Vec<String> = vec![];
+    for flag in flags {
+        let flag = flag.trim().to_string();
+        if flag.is_empty() || flag.len() > MAX_FLAG_LENGTH {
+            return Err(format!(
+                "Flags must be between 1 and {} bytes long",
+                MAX_FLAG_LENGTH
+            ));
+        }
+        if !normalized.contains(&flag) {
+            normalized.push(flag);
+        }
+    }
+    if normalized.len() > MAX_FLAGS {
+        return Err(format!("At most {} flags can be set", MAX_FLAGS));
+    }
+    Ok(normalized)
+}
+
+/// Marks the mapping of `principal` with attestation flags such as "verified-builder", replacing the flags it
+/// had. An empty list removes the attestation. The caller must be the configured `attestor`, and the principal
+/// must have signed in, since the flags are stored with its signer record.
+///
+/// # Arguments
+/// * `principal` - A `ByteBuf` containing the principal's bytes, expected to be 29 bytes.
+/// * `flags` - The attestation flags, at most 16 of up to 64 bytes each.
+///
+/// # Returns
+/// * `Ok(())` - The flags were stored and are returned by `get_signer`.
+/// * `Err(String)` - If the caller is not the attestor, the flags are invalid or no login is recorded.
+#[update(name = "set_attestation")]
+#[candid_method(update, rename = "set_attestation")]
+fn set_attestation(principal: ByteBuf, flags: Vec<String>) -> Result<(), String> {
+    let attestor = ic_cdk::caller();
+    if SETTINGS.with_borrow(|s| s.attestor) != Some(attestor) {
+        return Err("Caller is not the attestor".to_string());
+    }
+
+    let principal: Blob<29> = principal
+        .as_ref()
+        .try_into()
+        .map_err(|_| "Failed to convert ByteBuf to Blob<29>")?;
+    let flags = normalize_flags(flags)?;
+
+    SIGNERS.with(|s| {
+        let mut s = s.borrow_mut();
+        let mut record = s
+            .get(&principal)
+            .ok_or("No signer found for the given principal".to_string())?;
+        record.attestation = (!flags.is_empty()).then(|| Attestation {
+            flags,
+            attestor,
+            attested_at: ic_cdk::api::time(),
+        });
+        s.insert(principal, record);
+        Ok(())
+    })
+}
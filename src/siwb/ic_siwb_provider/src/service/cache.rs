@@ -0,0 +1,146 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+use candid::CandidType;
+use ic_cdk::query;
+use ic_stable_structures::storable::Blob;
+use serde::Deserialize;
+
+use crate::service::access::get_cache_metrics_guard;
+use crate::service::types::{AddressScriptBuf, NetworkTag};
+use crate::SETTINGS;
+
+/// Upper bound on the number of entries kept per cache.
+const MAX_CACHE_ENTRIES: usize = 10_000;
+
+/// A bounded in-memory cache with a time to live, layered over the stable identity maps.
+pub(crate) struct LookupCache<K: Ord + Clone, V: Clone> {
+    entries: BTreeMap<K, (V, u64)>,
+}
+
+impl<K: Ord + Clone, V: Clone> LookupCache<K, V> {
+    const fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    fn get(&self, key: &K, now: u64, ttl: u64) -> Option<V> {
+        self.entries
+            .get(key)
+            .filter(|(_, inserted_at)| now.saturating_sub(*inserted_at) < ttl)
+            .map(|(value, _)| value.clone())
+    }
+
+    fn insert(&mut self, key: K, value: V, now: u64, ttl: u64) {
+        if self.entries.len() >= MAX_CACHE_ENTRIES {
+            self.entries
+                .retain(|_, (_, inserted_at)| now.saturating_sub(*inserted_at) < ttl);
+        }
+        if self.entries.len() >= MAX_CACHE_ENTRIES {
+            self.entries.pop_first();
+        }
+        self.entries.insert(key, (value, now));
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+thread_local! {
+    static ADDRESS_CACHE: RefCell<LookupCache<(NetworkTag, Blob<29>), AddressScriptBuf>> = const { RefCell::new(LookupCache::new()) };
+    static PRINCIPAL_CACHE: RefCell<LookupCache<(NetworkTag, AddressScriptBuf), Blob<29>>> = const { RefCell::new(LookupCache::new()) };
+    static HITS: Cell<u64> = const { Cell::new(0) };
+    static MISSES: Cell<u64> = const { Cell::new(0) };
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct CacheMetrics {
+    pub hits: u64,
+    pub misses: u64,
+    pub address_entries: u64,
+    pub principal_entries: u64,
+}
+
+fn cache_ttl() -> Option<u64> {
+    SETTINGS.with_borrow(|s| s.lookup_cache_ttl)
+}
+
+fn record(hit: bool) {
+    if hit {
+        HITS.set(HITS.get().saturating_add(1));
+    } else {
+        MISSES.set(MISSES.get().saturating_add(1));
+    }
+}
+
+/// Looks up the address of `principal` on a network, falling back to `load` on a cache miss.
+///
+/// Entries are only added by update calls (see [`cache_mapping`]) because state changes made during a
+/// query are discarded. For the same reason the hit/miss counters only reflect replicated calls.
+pub(crate) fn cached_address(
+    principal: &(NetworkTag, Blob<29>),
+    load: impl FnOnce() -> Option<AddressScriptBuf>,
+) -> Option<AddressScriptBuf> {
+    let Some(ttl) = cache_ttl() else {
+        return load();
+    };
+    let now = ic_cdk::api::time();
+    let cached = ADDRESS_CACHE.with_borrow(|c| c.get(principal, now, ttl));
+    record(cached.is_some());
+    cached.or_else(|| {
+        let loaded = load();
+        if let Some(address) = &loaded {
+            ADDRESS_CACHE.with_borrow_mut(|c| c.insert(*principal, address.clone(), now, ttl));
+        }
+        loaded
+    })
+}
+
+/// Looks up the principal of `address` on a network, falling back to `load` on a cache miss.
+pub(crate) fn cached_principal(
+    address: &(NetworkTag, AddressScriptBuf),
+    load: impl FnOnce() -> Option<Blob<29>>,
+) -> Option<Blob<29>> {
+    let Some(ttl) = cache_ttl() else {
+        return load();
+    };
+    let now = ic_cdk::api::time();
+    let cached = PRINCIPAL_CACHE.with_borrow(|c| c.get(address, now, ttl));
+    record(cached.is_some());
+    cached.or_else(|| {
+        let loaded = load();
+        if let Some(principal) = &loaded {
+            PRINCIPAL_CACHE.with_borrow_mut(|c| c.insert(address.clone(), *principal, now, ttl));
+        }
+        loaded
+    })
+}
+
+/// Warms both caches after a login stored a new mapping.
+pub(crate) fn cache_mapping(network: NetworkTag, principal: &Blob<29>, address: &AddressScriptBuf) {
+    let Some(ttl) = cache_ttl() else {
+        return;
+    };
+    let now = ic_cdk::api::time();
+    ADDRESS_CACHE.with_borrow_mut(|c| c.insert((network, *principal), address.clone(), now, ttl));
+    PRINCIPAL_CACHE.with_borrow_mut(|c| c.insert((network, address.clone()), *principal, now, ttl));
+}
+
+/// Drops all cached lookups, e.g. after the settings changed.
+pub(crate) fn clear_caches() {
+    ADDRESS_CACHE.with_borrow_mut(|c| c.clear());
+    PRINCIPAL_CACHE.with_borrow_mut(|c| c.clear());
+}
+
+/// Returns the lookup cache counters and sizes.
+#[query(guard = "get_cache_metrics_guard")]
+fn get_cache_metrics() -> CacheMetrics {
+    CacheMetrics {
+        hits: HITS.get(),
+        misses: MISSES.get(),
+        address_entries: ADDRESS_CACHE.with_borrow(|c| c.entries.len() as u64),
+        principal_entries: PRINCIPAL_CACHE.with_borrow(|c| c.entries.len() as u64),
+    }
+}
@@ -0,0 +1,109 @@
+use candid::{candid_method, Principal};
+use ic_cdk::{query, update};
+use ic_siwb::siwb::MessageOptions;
+use ic_siwb::utils::get_script_from_address;
+use ic_stable_structures::storable::Blob;
+use serde_bytes::ByteBuf;
+
+use crate::service::access::list_custodians_guard;
+use crate::service::siwb_login::controller_guard;
+use crate::service::terms_of_service::{remember_prepared_terms, with_terms};
+use crate::{CUSTODIAL_LOGINS, CUSTODIANS};
+
+const CUSTODIAL_LOGIN_METHOD: &str = "siwb_custodial_login_completed";
+
+fn custodian_key(principal: &Principal) -> Blob<29> {
+    Blob::try_from(principal.as_slice()).unwrap_or_else(|_| ic_cdk::trap("Invalid principal"))
+}
+
+fn is_custodian(principal: &Principal) -> bool {
+    CUSTODIANS.with_borrow(|c| c.contains_key(&custodian_key(principal)))
+}
+
+/// Registers a backend that may pre-create SIWB messages for its users with `siwb_prepare_login_for`.
+#[update(name = "register_custodian", guard = "controller_guard")]
+#[candid_method(update, rename = "register_custodian")]
+fn register_custodian(custodian: Principal) {
+    CUSTODIANS.with_borrow_mut(|c| c.insert(custodian_key(&custodian), 1));
+}
+
+/// Removes a custodian. Challenges it already created are no longer reported to it.
+#[update(name = "unregister_custodian", guard = "controller_guard")]
+#[candid_method(update, rename = "unregister_custodian")]
+fn unregister_custodian(custodian: Principal) {
+    CUSTODIANS.with_borrow_mut(|c| c.remove(&custodian_key(&custodian)));
+    CUSTODIAL_LOGINS.with_borrow_mut(|logins| logins.retain(|_, (owner, _)| *owner != custodian));
+}
+
+/// Lists the registered custodians.
+#[query(guard = "list_custodians_guard")]
+fn list_custodians() -> Vec<Principal> {
+    CUSTODIANS.with_borrow(|c| {
+        c.iter()
+            .map(|(key, _)| Principal::from_slice(key.as_slice()))
+            .collect()
+    })
+}
+
+/// Creates a SIWB message on behalf of a user. The caller must be a registered custodian. The message is
+/// returned to the custodian, which has the user sign it; once `siwb_login` completes for the address the
+/// custodian is notified with `siwb_custodial_login_completed(address, principal)`.
+///
+/// # Arguments
+/// * `address` (String): The Bitcoin address of the user.
+/// * `session_key` (Option<ByteBuf>): Optionally binds the message to the session key used at login.
+/// * `options` (Option<MessageOptions>): Optionally words the message in one of the configured locales.
+#[update]
+fn siwb_prepare_login_for(
+    address: String,
+    session_key: Option<ByteBuf>,
+    options: Option<MessageOptions>,
+) -> Result<String, String> {
+    let custodian = ic_cdk::caller();
+    if !is_custodian(&custodian) {
+        return Err("Caller is not a registered custodian".to_string());
+    }
+
+    // Create an BtcAddress from the string. This validates the address.
+    let address = get_script_from_address(address)?;
+
+    let message = ic_siwb::login::prepare_login_with_options(
+        &address.address_raw,
+        session_key.as_ref().map(|key| key.as_slice()),
+        &with_terms(options.unwrap_or_default()),
+    )
+    .map_err(String::from)?;
+    remember_prepared_terms(address.script_buf.as_bytes(), message.expiration_time);
+
+    CUSTODIAL_LOGINS.with_borrow_mut(|logins| {
+        let now = ic_cdk::api::time();
+        logins.retain(|_, (_, expires_at)| *expires_at > now);
+        logins.insert(
+            address.script_buf.to_bytes(),
+            (custodian, message.expiration_time),
+        );
+    });
+
+    Ok(message.into())
+}
+
+/// Drops the custodial challenge for an address, e.g. when the user prepares a login directly.
+pub(crate) fn clear_custodial_login(script: &[u8]) {
+    CUSTODIAL_LOGINS.with_borrow_mut(|logins| {
+        logins.remove(script);
+    });
+}
+
+/// Notifies the custodian that created the challenge for `script`, if any. Delivery is best effort.
+pub(crate) fn notify_custodial_login(script: &[u8], address: String, principal: Principal) {
+    let Some((custodian, expires_at)) = CUSTODIAL_LOGINS.with_borrow_mut(|l| l.remove(script))
+    else {
+        return;
+    };
+    if expires_at < ic_cdk::api::time() || !is_custodian(&custodian) {
+        return;
+    }
+    if let Err(code) = ic_cdk::notify(custodian, CUSTODIAL_LOGIN_METHOD, (address, principal)) {
+        ic_cdk::println!("custodial login notification failed: {:?}", code);
+    }
+}
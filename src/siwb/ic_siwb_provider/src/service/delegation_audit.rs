@@ -0,0 +1,260 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::{api::data_certificate, query};
+use ic_certified_map::{fork, labeled, labeled_hash, AsHashTree, Hash, HashTree};
+use ic_siwb::delegation::{create_certified_signature, generate_seed};
+use ic_siwb::hash::hash_bytes;
+use ic_siwb::utils::get_script_from_address;
+use ic_stable_structures::storable::Blob;
+use serde_bytes::ByteBuf;
+
+use crate::service::access::get_delegation_audit_guard;
+use crate::service::types::DelegationAuditRecord;
+use crate::{
+    update_root_hash, AuditTree, State, DELEGATION_AUDIT, DELEGATION_AUDIT_EXPIRY, LABEL_ASSETS,
+    LABEL_AUDIT, LABEL_SIG, STATE,
+};
+
+/// How long a record is kept after its delegation expired: 30 days.
+const AUDIT_RETENTION_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+/// The most records kept per identity. Issuing another delegation drops the oldest.
+const MAX_AUDIT_RECORDS_PER_SEED: usize = 100;
+
+/// The most records past their retention that one login removes, which bounds the work of a login.
+const MAX_AUDIT_RECORDS_PRUNED_PER_LOGIN: usize = 32;
+
+/// The most delegations returned by one `get_delegation_audit` call.
+const MAX_AUDITED_DELEGATIONS_PER_PAGE: usize = 100;
+
+/// The key of a record in the certified audit tree: the seed hash followed by the delegation hash, so the
+/// records of one identity form a contiguous range.
+fn audit_key(seed_hash: &Hash, delegation_hash: &Hash) -> Vec<u8> {
+    [&seed_hash[..], &delegation_hash[..]].concat()
+}
+
+fn blob(hash: &Hash) -> Blob<32> {
+    Blob::try_from(&hash[..]).unwrap()
+}
+
+fn hash(blob: &Blob<32>) -> Hash {
+    blob.as_slice().try_into().unwrap()
+}
+
+/// Whether a record of a delegation until `expiration` is past its retention at `now`.
+fn is_retired(expiration: u64, now: u64) -> bool {
+    expiration.saturating_add(AUDIT_RETENTION_NS) < now
+}
+
+/// Removes a record from the certified tree, stable memory and the expiry index.
+fn remove_record(tree: &mut AuditTree, seed_hash: Blob<32>, delegation_hash: Blob<32>) {
+    let Some(record) =
+        DELEGATION_AUDIT.with_borrow_mut(|a| a.remove(&(seed_hash, delegation_hash)))
+    else {
+        return;
+    };
+    DELEGATION_AUDIT_EXPIRY
+        .with_borrow_mut(|e| e.remove(&(record.expiration, seed_hash, delegation_hash)));
+    tree.delete(&audit_key(&hash(&seed_hash), &hash(&delegation_hash)));
+}
+
+/// Removes up to `MAX_AUDIT_RECORDS_PRUNED_PER_LOGIN` records past their retention, oldest first.
+fn prune_retired(tree: &mut AuditTree, now: u64) {
+    let retired: Vec<(Blob<32>, Blob<32>)> = DELEGATION_AUDIT_EXPIRY.with_borrow(|e| {
+        e.iter()
+            .take_while(|((expiration, _, _), _)| is_retired(*expiration, now))
+            .take(MAX_AUDIT_RECORDS_PRUNED_PER_LOGIN)
+            .map(|((_, seed_hash, delegation_hash), _)| (seed_hash, delegation_hash))
+            .collect()
+    });
+    for (seed_hash, delegation_hash) in retired {
+        remove_record(tree, seed_hash, delegation_hash);
+    }
+}
+
+/// Drops the oldest records of `seed_hash` until there is room for one more.
+fn enforce_seed_limit(tree: &mut AuditTree, seed_hash: &Hash) {
+    let mut records: Vec<(u64, Blob<32>)> = DELEGATION_AUDIT.with_borrow(|audit| {
+        audit
+            .range((blob(seed_hash), blob(&[0; 32]))..)
+            .take_while(|((seed, _), _)| seed.as_slice() == seed_hash)
+            .map(|((_, delegation_hash), record)| (record.issued_at, delegation_hash))
+            .collect()
+    });
+    if records.len() < MAX_AUDIT_RECORDS_PER_SEED {
+        return;
+    }
+    records.sort();
+    let excess = records.len() + 1 - MAX_AUDIT_RECORDS_PER_SEED;
+    for (_, delegation_hash) in records.into_iter().take(excess) {
+        remove_record(tree, blob(seed_hash), delegation_hash);
+    }
+}
+
+/// Records that a delegation of `session_key` until `expiration` was issued for the identity behind
+/// `seed_hash`, dropping records past their retention and the oldest records of an identity with
+/// `MAX_AUDIT_RECORDS_PER_SEED` of them. The caller is responsible for updating the certified data afterwards.
+pub(crate) fn record_delegation(
+    state: &State,
+    seed_hash: Hash,
+    delegation_hash: Hash,
+    session_key: &[u8],
+    expiration: u64,
+    now: u64,
+) {
+    let mut tree = state.delegation_audit.borrow_mut();
+    prune_retired(&mut tree, now);
+    enforce_seed_limit(&mut tree, &seed_hash);
+
+    let record = DelegationAuditRecord {
+        session_key_hash: ByteBuf::from(hash_bytes(session_key)),
+        expiration,
+        issued_at: now,
+    };
+    tree.insert(audit_key(&seed_hash, &delegation_hash), record.leaf());
+    DELEGATION_AUDIT_EXPIRY.with_borrow_mut(|e| {
+        e.insert((expiration, blob(&seed_hash), blob(&delegation_hash)), ());
+    });
+    DELEGATION_AUDIT.with_borrow_mut(|audit| {
+        audit.insert((blob(&seed_hash), blob(&delegation_hash)), record);
+    });
+}
+
+/// Rebuilds the certified audit tree from stable memory after an upgrade and recertifies it. Records past their
+/// retention are left out of the tree; logins remove them from stable memory. The expiry index of records made
+/// before it existed is filled in on the way.
+pub(crate) fn restore_delegation_audit() {
+    STATE.with(|state| {
+        let mut tree = state.delegation_audit.borrow_mut();
+        if tree.iter().next().is_some() {
+            return;
+        }
+        let now = ic_cdk::api::time();
+        let index_missing = DELEGATION_AUDIT_EXPIRY.with_borrow(|e| e.is_empty());
+        DELEGATION_AUDIT.with_borrow(|audit| {
+            for ((seed_hash, delegation_hash), record) in audit.iter() {
+                if index_missing {
+                    DELEGATION_AUDIT_EXPIRY.with_borrow_mut(|e| {
+                        e.insert((record.expiration, seed_hash, delegation_hash), ())
+                    });
+                }
+                if !is_retired(record.expiration, now) {
+                    tree.insert(
+                        audit_key(&hash(&seed_hash), &hash(&delegation_hash)),
+                        record.leaf(),
+                    );
+                }
+            }
+        });
+        update_root_hash(
+            &state.asset_hashes.borrow(),
+            &state.signature_map.borrow(),
+            &tree,
+        );
+    });
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AuditedDelegation {
+    pub delegation_hash: ByteBuf,
+    pub session_key_hash: ByteBuf,
+    pub expiration: u64,
+    pub issued_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct DelegationAudit {
+    /// Up to 100 delegations issued for the address and still within their retention, ordered by delegation
+    /// hash. Fewer mean the list is complete.
+    pub delegations: Vec<AuditedDelegation>,
+    /// A CBOR map of the canister certificate and a hash tree, in the format of a canister signature. The tree
+    /// reveals every leaf under `delegation_audit/<seed hash>` from `start_after` up to the last returned
+    /// delegation, or to the end of the identity on the last page; each key is the seed hash followed by the
+    /// delegation hash and each leaf is the session key hash followed by the big-endian expiration and issue time.
+    pub proof: ByteBuf,
+}
+
+/// Returns the delegations issued for `address` together with a certified proof that no delegation of the
+/// page was left out, so a user can later show which sessions were authorized for their identity. Records are
+/// kept for 30 days after their delegation expired, at most 100 per identity.
+///
+/// # Arguments
+/// * `address` (String): The Bitcoin address of the user.
+/// * `start_after` (Option<ByteBuf>): The last delegation hash of the previous page, if any.
+///
+/// # Returns
+/// * `Ok(DelegationAudit)`: The delegations and their certified inclusion proof.
+/// * `Err(String)`: If the address or delegation hash is invalid or the endpoint was not called as a query call.
+#[query(guard = "get_delegation_audit_guard")]
+fn get_delegation_audit(
+    address: String,
+    start_after: Option<ByteBuf>,
+) -> Result<DelegationAudit, String> {
+    let certificate = data_certificate()
+        .ok_or("get_delegation_audit must be called using a query call".to_string())?;
+    let address = get_script_from_address(address)?;
+    let seed_hash = hash_bytes(generate_seed(&address.address_raw));
+    let start: Hash = match &start_after {
+        Some(delegation_hash) => delegation_hash
+            .as_slice()
+            .try_into()
+            .map_err(|_| "Invalid delegation hash".to_string())?,
+        None => [0; 32],
+    };
+
+    STATE.with(|s| {
+        let audit = s.delegation_audit.borrow();
+        let delegations: Vec<AuditedDelegation> = DELEGATION_AUDIT.with_borrow(|records| {
+            records
+                .range((blob(&seed_hash), blob(&start))..)
+                .take_while(|((seed, _), _)| seed.as_slice() == seed_hash)
+                .filter(|((_, delegation_hash), _)| {
+                    start_after.is_none() || delegation_hash.as_slice() != start
+                })
+                // Records past their retention are no longer certified.
+                .filter(|((_, delegation_hash), _)| {
+                    audit
+                        .get(&audit_key(&seed_hash, &hash(delegation_hash)))
+                        .is_some()
+                })
+                .take(MAX_AUDITED_DELEGATIONS_PER_PAGE)
+                .map(|((_, delegation_hash), record)| AuditedDelegation {
+                    delegation_hash: ByteBuf::from(delegation_hash.as_slice()),
+                    session_key_hash: record.session_key_hash,
+                    expiration: record.expiration,
+                    issued_at: record.issued_at,
+                })
+                .collect()
+        });
+        let last: Hash = match delegations.last() {
+            Some(last) if delegations.len() == MAX_AUDITED_DELEGATIONS_PER_PAGE => {
+                last.delegation_hash.as_slice().try_into().unwrap()
+            }
+            _ => [u8::MAX; 32],
+        };
+
+        let tree = fork(
+            labeled(
+                LABEL_AUDIT,
+                audit.value_range(
+                    &audit_key(&seed_hash, &start),
+                    &audit_key(&seed_hash, &last),
+                ),
+            ),
+            fork(
+                HashTree::Pruned(labeled_hash(
+                    LABEL_ASSETS,
+                    &s.asset_hashes.borrow().root_hash(),
+                )),
+                HashTree::Pruned(labeled_hash(
+                    LABEL_SIG,
+                    &s.signature_map.borrow().root_hash(),
+                )),
+            ),
+        );
+        let proof = create_certified_signature(certificate, tree).map_err(String::from)?;
+        Ok(DelegationAudit {
+            delegations,
+            proof: ByteBuf::from(proof),
+        })
+    })
+}
@@ -0,0 +1,51 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use candid::{CandidType, Deserialize};
+use ic_cdk::query;
+
+use crate::service::access::deprecations_guard;
+use crate::SETTINGS;
+
+/// Endpoints slated for removal and the endpoints that replace them.
+pub(crate) const DEPRECATED_ENDPOINTS: &[(&str, &str)] = &[("siwb_login", "siwb_login_v2")];
+
+thread_local! {
+    // Calls per deprecated endpoint since the last upgrade, so integrators still on them can be spotted.
+    static DEPRECATED_CALLS: RefCell<HashMap<&'static str, u64>> = RefCell::new(HashMap::new());
+}
+
+/// Counts a call of a deprecated endpoint and logs a warning naming its replacement.
+pub(crate) fn record_deprecated_call(endpoint: &'static str) {
+    DEPRECATED_CALLS.with_borrow_mut(|calls| *calls.entry(endpoint).or_default() += 1);
+    if let Some((_, replacement)) = DEPRECATED_ENDPOINTS.iter().find(|(e, _)| *e == endpoint) {
+        ic_cdk::println!("{} is deprecated, use {} instead", endpoint, replacement);
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Deprecation {
+    pub endpoint: String,
+    pub replacement: String,
+    /// When the endpoint is removed, in nanoseconds since the UNIX epoch. None if no date has been set with
+    /// `api_sunsets`.
+    pub sunset_at: Option<u64>,
+    /// Calls since the last upgrade.
+    pub calls: u64,
+}
+
+/// The endpoints slated for removal, their replacements and sunset timestamps, so integrators can migrate
+/// before an endpoint goes away.
+#[query(guard = "deprecations_guard")]
+fn deprecations() -> Vec<Deprecation> {
+    let sunsets = SETTINGS.with_borrow(|s| s.api_sunsets.clone());
+    DEPRECATED_ENDPOINTS
+        .iter()
+        .map(|(endpoint, replacement)| Deprecation {
+            endpoint: endpoint.to_string(),
+            replacement: replacement.to_string(),
+            sunset_at: sunsets.get(*endpoint).copied(),
+            calls: DEPRECATED_CALLS.with_borrow(|calls| calls.get(endpoint).copied().unwrap_or(0)),
+        })
+        .collect()
+}
@@ -0,0 +1,69 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use candid::Principal;
+use ic_cdk_timers::TimerId;
+
+use crate::SETTINGS;
+
+const SESSION_EXPIRING_METHOD: &str = "siwbSessionExpiring";
+
+/// How often sessions are checked against the reminder window.
+const REMINDER_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+thread_local! {
+    // Sessions awaiting a reminder, ordered by expiration. Like the session records used for
+    // `max_sessions_per_principal` they live on the heap, so sessions issued before an upgrade get no reminder.
+    static PENDING_REMINDERS: RefCell<BTreeSet<(u64, Principal)>> = const { RefCell::new(BTreeSet::new()) };
+
+    static REMINDER_TIMER: Cell<Option<TimerId>> = const { Cell::new(None) };
+}
+
+fn reminders_enabled() -> bool {
+    SETTINGS.with_borrow(|s| s.session_expiry_reminder_window.is_some() && s.login_hook.is_some())
+}
+
+/// Queues a reminder for a session issued at login.
+pub(crate) fn track_session(principal: Principal, expiration: u64) {
+    if reminders_enabled() {
+        PENDING_REMINDERS.with_borrow_mut(|r| r.insert((expiration, principal)));
+    }
+}
+
+/// (Re)starts the reminder timer after the settings changed. Reminders need both
+/// `session_expiry_reminder_window` and a `login_hook` to notify.
+pub(crate) fn schedule_reminders() {
+    if let Some(timer) = REMINDER_TIMER.take() {
+        ic_cdk_timers::clear_timer(timer);
+    }
+    if !reminders_enabled() {
+        PENDING_REMINDERS.with_borrow_mut(|r| r.clear());
+        return;
+    }
+    let timer = ic_cdk_timers::set_timer_interval(REMINDER_CHECK_INTERVAL, send_due_reminders);
+    REMINDER_TIMER.set(Some(timer));
+}
+
+/// Notifies the login hook canister with `siwbSessionExpiring(principal, expiration)` for every session that
+/// expires within the reminder window. Sessions that already expired are dropped without a notification.
+fn send_due_reminders() {
+    let Some((window, hook)) =
+        SETTINGS.with_borrow(|s| s.session_expiry_reminder_window.zip(s.login_hook))
+    else {
+        return;
+    };
+    let now = ic_cdk::api::time();
+    let due = PENDING_REMINDERS.with_borrow_mut(|r| {
+        let later = r.split_off(&(now.saturating_add(window), Principal::management_canister()));
+        std::mem::replace(r, later)
+    });
+    for (expiration, principal) in due {
+        if expiration <= now {
+            continue;
+        }
+        if let Err(code) = ic_cdk::notify(hook, SESSION_EXPIRING_METHOD, (principal, expiration)) {
+            ic_cdk::println!("session expiry reminder failed: {:?}", code);
+        }
+    }
+}
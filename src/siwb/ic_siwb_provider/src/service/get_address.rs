@@ -1,20 +1,25 @@
 use ic_cdk::query;
-use ic_siwb::bitcoin::Network::{Bitcoin, Regtest, Signet, Testnet};
 use ic_siwb::bitcoin::{Address, ScriptBuf};
 use ic_stable_structures::storable::Blob;
 use serde_bytes::ByteBuf;
 
+use crate::service::access::get_address_guard;
+use crate::service::cache::cached_address;
+use crate::service::legacy_mappings::legacy_address;
+use crate::service::shard::routed_miss;
+use crate::service::types::{network_tag, parse_network};
 use crate::{PRINCIPAL_ADDRESS, SETTINGS};
 
 /// Retrieves the Bitcoin address associated with a given IC principal.
 ///
 /// # Arguments
 /// * `principal` - A `ByteBuf` containing the principal's bytes, expected to be 29 bytes.
+/// * `network` - The network the principal signed in on: "bitcoin" (or "mainnet"), "testnet", "signet" or "regtest".
 ///
 /// # Returns
 /// * `Ok(String)` - The EIP-55-compliant Bitcoin address if found.
 /// * `Err(String)` - An error message if the principal cannot be converted or no address is found.
-#[query]
+#[query(guard = "get_address_guard")]
 pub(crate) fn get_address(principal: ByteBuf, network: String) -> Result<String, String> {
     SETTINGS.with_borrow(|s| {
         if s.disable_principal_to_btc_mapping {
@@ -28,27 +33,27 @@ pub(crate) fn get_address(principal: ByteBuf, network: String) -> Result<String,
         .try_into()
         .map_err(|_| "Failed to convert ByteBuf to Blob<29>")?;
 
-    let _network = match network.as_str() {
-        "bitcoin" => Bitcoin,
-        "mainnet" => Bitcoin,
-        "testnet" => Testnet,
-        "regtest" => Regtest,
-        "signet" => Signet,
-        _ => return Err("Invalid network".to_string()),
-    };
+    let network = parse_network(&network)?;
+    let key = (network_tag(network), principal);
 
-    let address = PRINCIPAL_ADDRESS.with(|pa| {
-        pa.borrow().get(&principal).map_or(
-            Err("No address found for the given principal".to_string()),
-            |a| {
-                let s = a.0;
-                let script_buf = ScriptBuf::from(s);
-                Address::from_script(script_buf.as_script(), _network)
-                    .map(|a| a)
-                    .map_err(|e| e.to_string())
-            },
-        )
-    })?;
+    let address = cached_address(&key, || {
+        PRINCIPAL_ADDRESS
+            .with(|pa| pa.borrow().get(&key))
+            .or_else(|| legacy_address(key.0, &principal))
+    })
+    .map_or(
+        Err(routed_miss(
+            "No address found for the given principal",
+            principal.as_slice(),
+        )),
+        |a| {
+            let s = a.0;
+            let script_buf = ScriptBuf::from(s);
+            Address::from_script(script_buf.as_script(), network)
+                .map(|a| a)
+                .map_err(|e| e.to_string())
+        },
+    )?;
 
     Ok(address.to_string())
 }
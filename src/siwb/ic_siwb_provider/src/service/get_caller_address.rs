@@ -1,6 +1,7 @@
 use ic_cdk::query;
 use serde_bytes::ByteBuf;
 
+use crate::service::access::get_caller_address_guard;
 use crate::SETTINGS;
 
 use super::get_address::get_address;
@@ -12,7 +13,7 @@ use super::get_address::get_address;
 /// # Returns
 /// * `Ok(String)` - The Bitcoin address if found.
 /// * `Err(String)` - An error message if the principal cannot be converted or no address is found.
-#[query]
+#[query(guard = "get_caller_address_guard")]
 fn get_caller_address(network: Option<String>) -> Result<String, String> {
     SETTINGS.with_borrow(|s| {
         if s.disable_principal_to_btc_mapping {
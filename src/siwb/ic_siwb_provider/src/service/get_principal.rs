@@ -2,19 +2,25 @@ use ic_cdk::query;
 use ic_siwb::utils::{get_script_from_address, AddressInfo};
 use serde_bytes::ByteBuf;
 
-use crate::service::types::AddressScriptBuf;
+use crate::service::access::get_principal_guard;
+use crate::service::cache::cached_principal;
+use crate::service::legacy_mappings::legacy_principal;
+use crate::service::shard::routed_miss;
+use crate::service::types::{network_tag, parse_network, AddressScriptBuf};
 use crate::{ADDRESS_PRINCIPAL, SETTINGS};
 
 /// Retrieves the principal associated with the given Bitcoin address.
 ///
 /// # Arguments
 /// * `address` - The Bitcoin address.
+/// * `network` - The network to look the address up on. Defaults to the network encoded in the address; an
+///   explicit network that disagrees with the address is rejected.
 ///
 /// # Returns
 /// * `Ok(ByteBuf)` - The principal if found.
 /// * `Err(String)` - An error message if the address cannot be converted or no principal is found.
-#[query]
-fn get_principal(address: String) -> Result<ByteBuf, String> {
+#[query(guard = "get_principal_guard")]
+fn get_principal(address: String, network: Option<String>) -> Result<ByteBuf, String> {
     SETTINGS.with_borrow(|s| {
         if s.disable_btc_to_principal_mapping {
             return Err("Bitcoin address to principal mapping is disabled".to_string());
@@ -23,14 +29,31 @@ fn get_principal(address: String) -> Result<ByteBuf, String> {
     })?;
 
     // Create an BtcAddress from the string. This validates the address.
-    let AddressInfo { script_buf, .. } = get_script_from_address(address)?;
+    let AddressInfo {
+        script_buf,
+        network: address_network,
+        ..
+    } = get_script_from_address(address)?;
 
-    ADDRESS_PRINCIPAL.with(|ap| {
-        ap.borrow()
-            .get(&AddressScriptBuf(script_buf.to_bytes()))
-            .map_or(
-                Err("No principal found for the given address".to_string()),
-                |p| Ok(ByteBuf::from(p.as_ref().to_vec())),
-            )
+    let network = match network {
+        Some(n) => network_tag(parse_network(&n)?),
+        None => network_tag(address_network),
+    };
+    if network != network_tag(address_network) {
+        return Err("The address does not belong to the given network".to_string());
+    }
+
+    let address = (network, AddressScriptBuf(script_buf.to_bytes()));
+    cached_principal(&address, || {
+        ADDRESS_PRINCIPAL
+            .with(|ap| ap.borrow().get(&address))
+            .or_else(|| legacy_principal(address.0, &address.1))
     })
+    .map_or(
+        Err(routed_miss(
+            "No principal found for the given address",
+            &address.1 .0,
+        )),
+        |p| Ok(ByteBuf::from(p.as_ref().to_vec())),
+    )
 }
@@ -0,0 +1,35 @@
+use ic_cdk::query;
+use ic_stable_structures::storable::Blob;
+use serde_bytes::ByteBuf;
+
+use crate::service::access::get_signer_guard;
+use crate::service::types::SignerRecord;
+use crate::{SETTINGS, SIGNERS};
+
+/// Retrieves how a given IC principal last authenticated: the address type and network, the recovered public
+/// key for ECDSA logins, the verification path and the flags set by the `attestor`.
+///
+/// # Arguments
+/// * `principal` - A `ByteBuf` containing the principal's bytes, expected to be 29 bytes.
+///
+/// # Returns
+/// * `Ok(SignerRecord)` - The signer of the principal's most recent login.
+/// * `Err(String)` - An error message if the principal cannot be converted or no login is recorded.
+#[query(guard = "get_signer_guard")]
+pub(crate) fn get_signer(principal: ByteBuf) -> Result<SignerRecord, String> {
+    SETTINGS.with_borrow(|s| {
+        if s.disable_principal_to_btc_mapping {
+            return Err("Principal to Bitcoin address mapping is disabled".to_string());
+        }
+        Ok(())
+    })?;
+
+    let principal: Blob<29> = principal
+        .as_ref()
+        .try_into()
+        .map_err(|_| "Failed to convert ByteBuf to Blob<29>")?;
+
+    SIGNERS
+        .with(|s| s.borrow().get(&principal))
+        .ok_or("No signer found for the given principal".to_string())
+}
@@ -1,19 +1,45 @@
+use crate::service::access::{validate_settings_guard, CONFIGURABLE_ENDPOINTS};
+use crate::service::anchor::{
+    schedule_anchoring, DEFAULT_ANCHOR_FEE_PER_VBYTE, MIN_ANCHOR_INTERVAL_SECONDS,
+};
+use crate::service::cache::clear_caches;
+use crate::service::delegation_audit::restore_delegation_audit;
+use crate::service::deprecations::DEPRECATED_ENDPOINTS;
+use crate::service::expiry_reminder::schedule_reminders;
+use crate::service::legacy_mappings::schedule_legacy_migration;
+use crate::service::shard::{schedule_shard_flush, MAX_SHARD_COUNT};
 use crate::service::siwb_login::controller_guard;
-use candid::{candid_method, CandidType, Principal};
-use ic_cdk::{init, post_upgrade, update};
-use ic_siwb::bitcoin::Network;
+use crate::service::terms_of_service::{terms_statement, MAX_TERMS_VERSION_LENGTH};
+use crate::service::top_up::{
+    schedule_top_ups, CYCLES_LEDGER, DEFAULT_TOP_UP_INTERVAL_SECONDS, MIN_TOP_UP_INTERVAL_SECONDS,
+};
+use candid::{candid_method, CandidType, Encode, Principal};
+use ic_cdk::{init, post_upgrade, query, update};
 use ic_siwb::bitcoin::Network::Bitcoin;
-use ic_siwb::settings::SettingsBuilder;
+use ic_siwb::bitcoin::{Address, AddressType, Network};
+use ic_siwb::settings::{MessageLocale, SettingsBuilder, DEFAULT_STATEMENT};
+use ic_siwb::utils::get_script_from_address;
 use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
 use std::str::FromStr;
 
-use crate::SETTINGS;
+use crate::service::types::parse_network;
+use crate::{
+    set_signature_store, AccessPolicy, AnchoringSettings, ProbeSettings, SeedMigration,
+    SessionLimitPolicy, ShardingSettings, SignatureStoreKind, TermsOfService, TopUpSettings,
+    SESSION_EPOCH, SETTINGS, SHARD_COUNT,
+};
 
 #[derive(CandidType, Debug, Clone, PartialEq, Deserialize)]
 pub enum RuntimeFeature {
     // Include the app frontend URI as part of the identity seed.
     IncludeUriInSeed,
 
+    // Include the session epoch as part of the identity seed. Rotating the epoch with `rotate_session_epoch`
+    // then also changes the principal of every user.
+    IncludeSessionEpochInSeed,
+
     // Disable the mapping of Bitcoin address to principal. This also disables canister endpoints `get_principal`.
     DisableBtcToPrincipalMapping,
 
@@ -21,6 +47,186 @@ pub enum RuntimeFeature {
     DisablePrincipalToBtcMapping,
 }
 
+/// What happens when a login would exceed `max_sessions_per_principal`.
+#[derive(CandidType, Debug, Clone, PartialEq, Deserialize)]
+pub enum SessionLimitPolicyInput {
+    // The login fails and the existing sessions are kept. This is the default.
+    RejectNewSession,
+
+    // The oldest session of the principal is evicted: its delegation can no longer be fetched.
+    EvictOldestSession,
+}
+
+/// Who may call an endpoint listed in `endpoint_access`.
+#[derive(CandidType, Debug, Clone, PartialEq, Deserialize)]
+pub enum AccessPolicyInput {
+    // Anyone, including the anonymous principal. This is the default.
+    Public,
+
+    // Any caller except the anonymous principal.
+    AuthenticatedOnly,
+
+    // Controllers of the canister only.
+    AdminOnly,
+}
+
+impl From<AccessPolicyInput> for AccessPolicy {
+    fn from(value: AccessPolicyInput) -> Self {
+        match value {
+            AccessPolicyInput::Public => AccessPolicy::Public,
+            AccessPolicyInput::AuthenticatedOnly => AccessPolicy::AuthenticatedOnly,
+            AccessPolicyInput::AdminOnly => AccessPolicy::AdminOnly,
+        }
+    }
+}
+
+/// Where issued delegation signatures are kept.
+#[derive(CandidType, Debug, Clone, PartialEq, Deserialize)]
+pub enum SignatureStoreInput {
+    // On the heap. Signatures are lost on upgrade, so a `get_delegation` call racing an upgrade fails. This is the
+    // default.
+    Heap,
+
+    // In stable memory, which keeps heap usage flat with many concurrent logins and keeps signatures across
+    // upgrades. The stable backend must be selected again in the `post_upgrade` arguments to restore them.
+    Stable,
+}
+
+impl From<SignatureStoreInput> for SignatureStoreKind {
+    fn from(value: SignatureStoreInput) -> Self {
+        match value {
+            SignatureStoreInput::Heap => SignatureStoreKind::Heap,
+            SignatureStoreInput::Stable => SignatureStoreKind::Stable,
+        }
+    }
+}
+
+/// Opt-in anchoring of the mappings into Bitcoin, see `get_anchor_proof`.
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub struct AnchoringInput {
+    /// The threshold ECDSA key that signs the anchoring transactions, e.g. "key_1". Its P2WPKH address, shown by
+    /// `get_anchor_status`, pays the fees and must be funded.
+    pub ecdsa_key_name: String,
+
+    /// How often the mappings are anchored, if they changed. At least one hour.
+    pub interval_seconds: u64,
+
+    /// The fee rate in satoshis per vbyte. Defaults to 2.
+    pub fee_per_vbyte: Option<u64>,
+}
+
+/// Automatic cycle top-ups: when the balance falls below `threshold`, `amount` cycles are withdrawn from an
+/// account of this canister on the cycles ledger, which anyone can fund with the ledger's `deposit`.
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub struct TopUpInput {
+    /// Defaults to the mainnet cycles ledger um5iw-rqaaa-aaaaq-qaaba-cai.
+    pub cycles_ledger: Option<String>,
+
+    /// The 32-byte subaccount of the funding account. Defaults to None, the default subaccount.
+    pub funding_subaccount: Option<ByteBuf>,
+
+    pub threshold: u128,
+
+    pub amount: u128,
+
+    /// The least time between two attempts, successful or not. At least ten minutes, defaults to one hour.
+    pub min_interval_seconds: Option<u64>,
+}
+
+/// Verifiers that can run in shadow of the one deciding logins, see `get_shadow_report`.
+#[derive(CandidType, Debug, Clone, PartialEq, Deserialize)]
+pub enum ShadowVerifierInput {
+    /// BIP-322 verification from the decoded witness, `ic_siwb::shadow::verify_bip322_witness`.
+    Bip322Witness,
+}
+
+/// Shard-router mode: mappings are stored on child canisters instead of this canister.
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub struct ShardingInput {
+    /// The number of shards the key space is split into. Cannot change once a shard was created.
+    pub shard_count: u32,
+
+    /// Cycles each new shard is created with.
+    pub cycles_per_shard: u128,
+
+    /// Controllers of new shards besides this canister, e.g. to upgrade them.
+    pub controllers: Option<Vec<String>>,
+}
+
+/// The access policy of one endpoint.
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub struct EndpointAccessInput {
+    /// The endpoint name, e.g. "get_principal".
+    pub endpoint: String,
+
+    pub policy: AccessPolicyInput,
+}
+
+/// The date after which a deprecated endpoint may be removed.
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub struct ApiSunsetInput {
+    /// The endpoint name, e.g. "siwb_login".
+    pub endpoint: String,
+
+    /// In nanoseconds since the UNIX epoch.
+    pub sunset_at: u64,
+}
+
+/// The translations of the SIWB message for one locale. Only the header phrase and the statement are translated.
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub struct MessageLocaleInput {
+    /// The locale the frontend selects with `siwb_prepare_login`, e.g. "de".
+    pub locale: String,
+
+    /// Replaces "wants you to sign in with your Bitcoin account:" after the domain.
+    pub header: Option<String>,
+
+    /// Replaces `statement`.
+    pub statement: Option<String>,
+}
+
+/// A version of the Terms of Service that every login acknowledges, see `list_terms_acknowledgments`.
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub struct TermsOfServiceInput {
+    /// The version shown in the SIWB message, e.g. "2024-06". At most 64 bytes, on one line.
+    pub version: String,
+
+    /// The hex SHA-256 hash of the document, so a signed message commits to its exact text.
+    pub hash: String,
+}
+
+/// The salt and URI principals were derived from before the current ones, see `get_migration_status`.
+#[derive(CandidType, Debug, Clone, Deserialize)]
+pub struct SeedMigrationInput {
+    /// The previous salt. Like `salt`, it can only contain printable ASCII characters.
+    pub previous_salt: String,
+
+    /// The previous URI. Only part of the seed with `IncludeUriInSeed`. Defaults to `uri`.
+    pub previous_uri: Option<String>,
+}
+
+/// Bitcoin address types, as accepted by `allowed_address_types`.
+#[derive(CandidType, Debug, Clone, PartialEq, Deserialize)]
+pub enum AddressTypeInput {
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+}
+
+impl From<AddressTypeInput> for AddressType {
+    fn from(value: AddressTypeInput) -> Self {
+        match value {
+            AddressTypeInput::P2pkh => AddressType::P2pkh,
+            AddressTypeInput::P2sh => AddressType::P2sh,
+            AddressTypeInput::P2wpkh => AddressType::P2wpkh,
+            AddressTypeInput::P2wsh => AddressType::P2wsh,
+            AddressTypeInput::P2tr => AddressType::P2tr,
+        }
+    }
+}
+
 /// Represents the settings that determine the behavior of the SIWB library. It includes settings such as domain, scheme, statement,
 /// and expiration times for sessions and sign-ins.
 #[derive(CandidType, Deserialize, Debug, Clone)]
@@ -37,7 +243,8 @@ pub struct SettingsInput {
     /// printable ASCII characters.
     pub salt: String,
 
-    /// The Bitcoin network ic-siwb, defaults to "bitcoin" (Bitcoin mainnet).
+    /// The Bitcoin network of ic-siwb: "bitcoin" (or "mainnet"), "testnet", "signet" or "regtest". Defaults to
+    /// "bitcoin". Any other name is rejected. Signet addresses look like testnet addresses, see `network_tag`.
     pub network: Option<String>,
 
     // The scheme used to serve the frontend that uses SIWB. Defaults to "https".
@@ -49,6 +256,15 @@ pub struct SettingsInput {
     /// The TTL for a sign-in message in nanoseconds. After this time, the sign-in message will be pruned.
     pub sign_in_expires_in: Option<u64>,
 
+    /// The longest TTL in nanoseconds that `siwb_prepare_login` may request through `MessageOptions.expires_in`.
+    /// Defaults to None, which caps it at `sign_in_expires_in`.
+    pub max_sign_in_expires_in: Option<u64>,
+
+    /// How far the canister clock may be outside the validity window of a SIWB message, in nanoseconds, e.g.
+    /// for wallets that take long to sign. Also added to the expiration of delegations. At most 5 minutes.
+    /// Defaults to 1 second.
+    pub clock_skew_tolerance_ns: Option<u64>,
+
     /// The TTL for a session in nanoseconds.
     pub session_expires_in: Option<u64>,
 
@@ -57,80 +273,629 @@ pub struct SettingsInput {
     pub targets: Option<Vec<String>>,
 
     pub runtime_features: Option<Vec<RuntimeFeature>>,
+
+    /// Canister notified with `siwbLoginHook(principal, address)` after every successful login, e.g. a
+    /// reputation canister granting an onboarding bonus. The notification is one-way and never blocks login.
+    pub login_hook: Option<String>,
+
+    /// The maximum number of unexpired sessions a single principal may hold. Defaults to None, which means no limit.
+    pub max_sessions_per_principal: Option<u32>,
+
+    /// What to do when a login exceeds `max_sessions_per_principal`. Defaults to `RejectNewSession`.
+    pub session_limit_policy: Option<SessionLimitPolicyInput>,
+
+    /// The TTL in nanoseconds of the in-memory cache in front of `get_address` and `get_principal`. Defaults to
+    /// None, which disables the cache.
+    pub lookup_cache_ttl: Option<u64>,
+
+    /// The address types that may sign in, e.g. only `P2tr` and `P2wpkh`. Defaults to None, which means all
+    /// supported types. All P2SH addresses are verified as P2SH-P2WPKH.
+    pub allowed_address_types: Option<Vec<AddressTypeInput>>,
+
+    /// The largest signature, in decoded bytes, that logins and `verify_message` accept. Longer signatures are
+    /// rejected before they are decoded. Defaults to 16 KiB.
+    pub max_signature_bytes: Option<u32>,
+
+    /// URIs every SIWB message lists under "Resources:", at most 16. A `siwb_prepare_login` call can replace them
+    /// through `MessageOptions`, e.g. with the URI of the proposal the login is for. Defaults to none.
+    pub resources: Option<Vec<String>>,
+
+    /// Per-endpoint access policies, e.g. to make `get_principal` authenticated-only. Endpoints not listed are
+    /// public. Supported endpoints are the lookups `get_address`, `get_caller_address`, `get_principal`,
+    /// `get_signer`, `get_cache_metrics`, `get_session_epoch`, `list_custodians`, `get_anchor_status`,
+    /// `get_anchor_proof`, `list_shards`, `get_shard_for_principal`, `get_shard_for_address`,
+    /// `supported_capabilities`, `get_top_up_status`, `get_shadow_report`, `deprecations`, `get_probe_status`,
+    /// `verify_message`, `get_delegation_audit`, `validate_settings`, `list_terms_acknowledgments` and
+    /// `get_migration_status`.
+    pub endpoint_access: Option<Vec<EndpointAccessInput>>,
+
+    /// When set, the `login_hook` canister is also notified with `siwbSessionExpiring(principal, expiration)` once a
+    /// session is within this many nanoseconds of expiring, so frontends can prompt users to renew. Checked every
+    /// minute. Defaults to None, which disables the reminders.
+    pub session_expiry_reminder_window: Option<u64>,
+
+    /// Where issued delegation signatures are kept, `Heap` or `Stable`. Defaults to `Heap`.
+    pub signature_store: Option<SignatureStoreInput>,
+
+    /// Periodically anchors a Merkle root of the mappings into a Bitcoin transaction on the configured network.
+    /// Defaults to None, which disables anchoring.
+    pub anchoring: Option<AnchoringInput>,
+
+    /// Turns this canister into a shard router: new mappings are written to child canisters, spawned on demand
+    /// from the wasm uploaded with `set_shard_wasm`, by the hash of the address script or principal. Lookups that
+    /// miss on the router name the shard to query, see `get_shard_for_address` and `get_shard_for_principal`.
+    /// Defaults to None, which stores all mappings in this canister.
+    pub sharding: Option<ShardingInput>,
+
+    /// Translations of the SIWB message for non-English communities. Defaults to None, which renders every
+    /// message in English.
+    pub locales: Option<Vec<MessageLocaleInput>>,
+
+    /// Tops the canister up from the cycles ledger when its balance runs low, see `get_top_up_status`. Defaults to
+    /// None, which disables top-ups.
+    pub top_up: Option<TopUpInput>,
+
+    /// Runs a candidate verifier alongside every login and records where it disagrees, without affecting the
+    /// result, so a verification refactor can be validated before it is switched over. Defaults to None.
+    pub shadow_verifier: Option<ShadowVerifierInput>,
+
+    /// When deprecated endpoints are removed, as listed by `deprecations`. Defaults to None, which announces no
+    /// dates.
+    pub api_sunsets: Option<Vec<ApiSunsetInput>>,
+
+    /// A dedicated address whose prepare, login and delegation cycle a monitoring job runs against the live
+    /// canister, see `get_probe_status`. Its logins store no mappings or signer, notify no hooks and are not
+    /// tracked for expiry reminders. Defaults to None.
+    pub probe_address: Option<String>,
+
+    /// The principal allowed to mark principals with attestation flags such as "verified-builder" through
+    /// `set_attestation`, e.g. a KYC provider. The flags are returned by `get_signer`. Defaults to None, which
+    /// disables attestations.
+    pub attestor: Option<String>,
+
+    /// Terms of Service that every login acknowledges: their version and hash are appended to the statement,
+    /// including translated and per-call statements, and each login is recorded, see
+    /// `list_terms_acknowledgments`. Defaults to None.
+    pub terms_of_service: Option<TermsOfServiceInput>,
+
+    /// Enables `siwb_login_guest`, which issues sessions of this many nanoseconds, capped at
+    /// `session_expires_in`, without recording the address. Defaults to None, which disables guest sessions.
+    pub guest_session_expires_in: Option<u64>,
+
+    /// Double-write mode for changing `salt` or `uri`: every login also derives the principal the address had
+    /// under the previous salt and URI and keeps the mappings of both, so that the balances of the old principal
+    /// can be merged into the new one before the migration ends, see `get_migration_status`. Defaults to None.
+    pub seed_migration: Option<SeedMigrationInput>,
 }
 
-/// Initialize the SIWB library with the given settings.
-///
-/// Required fields are `domain`, `uri`, and `salt`. All other fields are optional.
-///
-/// ## 🛑 Important: Changing the `salt` or `uri` setting affects how user seeds are generated.
-/// This means that existing users will get a new principal id when they sign in. Tip: Don't change the `salt` or `uri`
-/// settings after users have started using the service!
-fn siwb_init(settings_input: SettingsInput) {
+/// The network set in `settings_input`, Bitcoin mainnet if none is set. Traps on an unrecognized name rather
+/// than silently serving another network.
+fn configured_network(settings_input: &SettingsInput) -> Network {
+    match settings_input.network.as_deref() {
+        Some(name) => parse_network(name).unwrap_or_else(|e| panic!("{}: {}", e, name)),
+        None => Bitcoin,
+    }
+}
+
+/// A setting rejected by `validate_settings`.
+#[derive(CandidType, Debug, Clone, PartialEq, Deserialize)]
+pub struct SettingsIssue {
+    /// The field of `SettingsInput`, e.g. "targets" or "top_up.amount".
+    pub field: String,
+
+    pub message: String,
+}
+
+/// The Terms of Service set in `settings_input`, if valid.
+fn configured_terms(settings_input: &SettingsInput) -> Option<TermsOfService> {
+    let terms = settings_input.terms_of_service.as_ref()?;
+    Some(TermsOfService {
+        version: terms.version.clone(),
+        hash: hex::decode(&terms.hash).ok()?.try_into().ok()?,
+    })
+}
+
+/// The library settings in `settings_input`. Network names and target principals that do not parse are left
+/// out, [`validate_settings_input`] reports them.
+fn library_settings(settings_input: &SettingsInput) -> SettingsBuilder {
     let mut ic_siwb_settings = SettingsBuilder::new(
         &settings_input.domain,
         &settings_input.uri,
         &settings_input.salt,
     );
-
-    // Optional fields
-    if let Some(chain_id) = settings_input.network {
-        if let Ok(n) = Network::from_str(&chain_id) {
-            ic_siwb_settings = ic_siwb_settings.network(n);
-        } else {
-            ic_siwb_settings = ic_siwb_settings.network(Bitcoin);
-        }
+    if let Some(Ok(network)) = settings_input.network.as_deref().map(parse_network) {
+        ic_siwb_settings = ic_siwb_settings.network(network);
     }
-    if let Some(scheme) = settings_input.scheme {
+    if let Some(scheme) = &settings_input.scheme {
         ic_siwb_settings = ic_siwb_settings.scheme(scheme);
     }
-    if let Some(statement) = settings_input.statement {
-        ic_siwb_settings = ic_siwb_settings.statement(statement);
-    }
+    let terms = configured_terms(settings_input);
+    let with_terms = |statement: &str| match &terms {
+        Some(terms) => terms_statement(statement, terms),
+        None => statement.to_string(),
+    };
+    ic_siwb_settings = ic_siwb_settings.statement(with_terms(
+        settings_input
+            .statement
+            .as_deref()
+            .unwrap_or(DEFAULT_STATEMENT),
+    ));
     if let Some(expire_in) = settings_input.sign_in_expires_in {
         ic_siwb_settings = ic_siwb_settings.sign_in_expires_in(expire_in);
     }
+    if let Some(max_expire_in) = settings_input.max_sign_in_expires_in {
+        ic_siwb_settings = ic_siwb_settings.max_sign_in_expires_in(max_expire_in);
+    }
+    if let Some(tolerance) = settings_input.clock_skew_tolerance_ns {
+        ic_siwb_settings = ic_siwb_settings.clock_skew_tolerance_ns(tolerance);
+    }
     if let Some(session_expire_in) = settings_input.session_expires_in {
         ic_siwb_settings = ic_siwb_settings.session_expires_in(session_expire_in);
     }
-    if let Some(targets) = settings_input.targets {
-        let targets: Vec<Principal> = targets
-            .into_iter()
-            .map(|t| Principal::from_text(t).unwrap())
-            .collect();
-        // Make sure the canister id of this canister is in the list of targets
-        let canister_id = ic_cdk::id();
-        if !targets.contains(&canister_id) {
-            panic!(
-                "ic_siwb_provider canister id {} not in the list of targets",
-                canister_id
+    if let Some(targets) = &settings_input.targets {
+        ic_siwb_settings = ic_siwb_settings.targets(
+            targets
+                .iter()
+                .filter_map(|t| Principal::from_text(t).ok())
+                .collect(),
+        );
+    }
+    if let Some(address_types) = &settings_input.allowed_address_types {
+        ic_siwb_settings = ic_siwb_settings.allowed_address_types(
+            address_types
+                .iter()
+                .cloned()
+                .map(AddressType::from)
+                .collect(),
+        );
+    }
+    if let Some(max_bytes) = settings_input.max_signature_bytes {
+        ic_siwb_settings = ic_siwb_settings.max_signature_bytes(max_bytes as usize);
+    }
+    if let Some(resources) = &settings_input.resources {
+        ic_siwb_settings = ic_siwb_settings.resources(resources.clone());
+    }
+    for locale in settings_input.locales.iter().flatten() {
+        ic_siwb_settings = ic_siwb_settings.locale(
+            &locale.locale,
+            MessageLocale {
+                header: locale.header.clone(),
+                statement: locale.statement.as_deref().map(with_terms),
+            },
+        );
+    }
+
+    let library_features: Vec<_> = settings_input
+        .runtime_features
+        .iter()
+        .flatten()
+        .filter_map(|feature| match feature {
+            RuntimeFeature::IncludeUriInSeed => {
+                Some(ic_siwb::settings::RuntimeFeature::IncludeUriInSeed)
+            }
+            RuntimeFeature::IncludeSessionEpochInSeed => {
+                Some(ic_siwb::settings::RuntimeFeature::IncludeSessionEpochInSeed)
+            }
+            RuntimeFeature::DisableBtcToPrincipalMapping
+            | RuntimeFeature::DisablePrincipalToBtcMapping => None,
+        })
+        .collect();
+    if !library_features.is_empty() {
+        ic_siwb_settings = ic_siwb_settings.runtime_features(library_features);
+    }
+    ic_siwb_settings
+}
+
+/// Checks every field of `settings_input` without applying any of it, so that all problems are reported at
+/// once instead of one per failed upgrade.
+fn validate_settings_input(settings_input: &SettingsInput) -> Vec<SettingsIssue> {
+    let mut issues = vec![];
+    let mut issue = |field: &str, message: String| {
+        issues.push(SettingsIssue {
+            field: field.to_string(),
+            message,
+        })
+    };
+    let principal = |text: &str| Principal::from_text(text).map_err(|e| format!("{}: {}", text, e));
+
+    let network = match settings_input.network.as_deref() {
+        Some(name) => parse_network(name)
+            .map_err(|e| issue("network", format!("{}: {}", e, name)))
+            .ok(),
+        None => Some(Bitcoin),
+    };
+
+    for (field, message) in library_settings(settings_input).validate() {
+        issue(field, message);
+    }
+
+    if let Some(targets) = &settings_input.targets {
+        let targets: Result<Vec<Principal>, String> =
+            targets.iter().map(|t| principal(t)).collect();
+        match targets {
+            // Make sure the canister id of this canister is in the list of targets
+            Ok(targets) if !targets.contains(&ic_cdk::id()) => issue(
+                "targets",
+                format!(
+                    "ic_siwb_provider canister id {} not in the list of targets",
+                    ic_cdk::id()
+                ),
+            ),
+            Ok(_) => {}
+            Err(e) => issue("targets", e),
+        }
+    }
+    if let Some(Err(e)) = settings_input.login_hook.as_deref().map(principal) {
+        issue("login_hook", e);
+    }
+    if let Some(Err(e)) = settings_input.attestor.as_deref().map(principal) {
+        issue("attestor", e);
+    }
+
+    if let Some(migration) = &settings_input.seed_migration {
+        if migration.previous_salt.is_empty()
+            || !migration
+                .previous_salt
+                .chars()
+                .all(|c| c.is_ascii_graphic())
+        {
+            issue(
+                "seed_migration.previous_salt",
+                "must be non-empty printable ASCII".to_string(),
+            );
+        }
+        let uri_in_seed = settings_input
+            .runtime_features
+            .iter()
+            .flatten()
+            .any(|feature| matches!(feature, RuntimeFeature::IncludeUriInSeed));
+        let previous_uri = migration
+            .previous_uri
+            .as_deref()
+            .unwrap_or(&settings_input.uri);
+        if migration.previous_salt == settings_input.salt
+            && (!uri_in_seed || previous_uri == settings_input.uri)
+        {
+            issue(
+                "seed_migration",
+                "derives the same principals as the current salt and uri".to_string(),
+            );
+        }
+    }
+
+    if settings_input.guest_session_expires_in == Some(0) {
+        issue(
+            "guest_session_expires_in",
+            "must be greater than 0".to_string(),
+        );
+    }
+
+    if let Some(sharding) = &settings_input.sharding {
+        if sharding.shard_count == 0 || sharding.shard_count > MAX_SHARD_COUNT {
+            issue(
+                "sharding.shard_count",
+                format!("must be between 1 and {}", MAX_SHARD_COUNT),
+            );
+        }
+        let existing_count = SHARD_COUNT.with_borrow(|c| *c.get());
+        if existing_count != 0 && existing_count != sharding.shard_count {
+            issue(
+                "sharding.shard_count",
+                format!("cannot change from {} once shards exist", existing_count),
+            );
+        }
+        for controller in sharding.controllers.iter().flatten() {
+            if let Err(e) = principal(controller) {
+                issue("sharding.controllers", e);
+            }
+        }
+    }
+
+    if let Some(anchoring) = &settings_input.anchoring {
+        if anchoring.interval_seconds < MIN_ANCHOR_INTERVAL_SECONDS {
+            issue(
+                "anchoring.interval_seconds",
+                format!("must be at least {}", MIN_ANCHOR_INTERVAL_SECONDS),
+            );
+        }
+        // The Bitcoin API of the IC serves mainnet, testnet and regtest only
+        if network == Some(Network::Signet) {
+            issue("anchoring", "not available on signet".to_string());
+        }
+    }
+
+    if let Some(top_up) = &settings_input.top_up {
+        let min_interval_seconds = top_up
+            .min_interval_seconds
+            .unwrap_or(DEFAULT_TOP_UP_INTERVAL_SECONDS);
+        if min_interval_seconds < MIN_TOP_UP_INTERVAL_SECONDS {
+            issue(
+                "top_up.min_interval_seconds",
+                format!("must be at least {}", MIN_TOP_UP_INTERVAL_SECONDS),
+            );
+        }
+        if top_up.amount == 0 {
+            issue("top_up.amount", "must be greater than 0".to_string());
+        }
+        if let Some(Err(e)) = top_up.cycles_ledger.as_deref().map(principal) {
+            issue("top_up.cycles_ledger", e);
+        }
+        if top_up
+            .funding_subaccount
+            .as_ref()
+            .is_some_and(|subaccount| subaccount.len() != 32)
+        {
+            issue("top_up.funding_subaccount", "must be 32 bytes".to_string());
+        }
+    }
+
+    for access in settings_input.endpoint_access.iter().flatten() {
+        if !CONFIGURABLE_ENDPOINTS.contains(&access.endpoint.as_str()) {
+            issue(
+                "endpoint_access",
+                format!("access to {} cannot be configured", access.endpoint),
+            );
+        }
+    }
+
+    // Checked against the new network, which the library only switches to once the settings are applied.
+    if let (Some(address), Some(network)) = (&settings_input.probe_address, network) {
+        match Address::from_str(address) {
+            Ok(parsed) => match parsed.require_network(network) {
+                Ok(parsed) if parsed.address_type().is_none() => issue(
+                    "probe_address",
+                    format!("{}: address type not supported", address),
+                ),
+                Ok(_) => {}
+                Err(_) => issue(
+                    "probe_address",
+                    format!("{} is not an address on {}", address, network),
+                ),
+            },
+            Err(e) => issue("probe_address", format!("{}: {}", address, e)),
+        }
+    }
+
+    if let Some(terms) = &settings_input.terms_of_service {
+        if terms.version.trim().is_empty()
+            || terms.version.len() > MAX_TERMS_VERSION_LENGTH
+            || terms.version.chars().any(char::is_control)
+        {
+            issue(
+                "terms_of_service.version",
+                format!(
+                    "must be between 1 and {} bytes on one line",
+                    MAX_TERMS_VERSION_LENGTH
+                ),
+            );
+        }
+        if hex::decode(&terms.hash).map_or(true, |hash| hash.len() != 32) {
+            issue(
+                "terms_of_service.hash",
+                "must be a hex SHA-256 hash".to_string(),
+            );
+        }
+    }
+
+    for sunset in settings_input.api_sunsets.iter().flatten() {
+        if !DEPRECATED_ENDPOINTS
+            .iter()
+            .any(|(endpoint, _)| *endpoint == sunset.endpoint)
+        {
+            issue(
+                "api_sunsets",
+                format!("{} is not deprecated", sunset.endpoint),
             );
         }
-        ic_siwb_settings = ic_siwb_settings.targets(targets);
     }
 
+    issues
+}
+
+/// Checks `settings` as `init`, `post_upgrade` and `update_settings` would, without applying them, so that an
+/// upgrade argument can be tried out before the upgrade.
+///
+/// # Returns
+/// * `Ok(())`: The settings would be accepted.
+/// * `Err(Vec<SettingsIssue>)`: Every invalid field with the reason it is rejected.
+#[query(name = "validate_settings", guard = "validate_settings_guard")]
+#[candid_method(query, rename = "validate_settings")]
+fn validate_settings(settings: SettingsInput) -> Result<(), Vec<SettingsIssue>> {
+    let issues = validate_settings_input(&settings);
+    if issues.is_empty() {
+        Ok(())
+    } else {
+        Err(issues)
+    }
+}
+
+/// Initialize the SIWB library with the given settings, returning the configured network. Traps with every
+/// invalid field, see `validate_settings`, before anything is applied.
+///
+/// Required fields are `domain`, `uri`, and `salt`. All other fields are optional.
+///
+/// ## 🛑 Important: Changing the `salt` or `uri` setting affects how user seeds are generated.
+/// This means that existing users will get a new principal id when they sign in. Tip: Don't change the `salt` or `uri`
+/// settings after users have started using the service!
+fn siwb_init(settings_input: SettingsInput) -> Network {
+    let issues = validate_settings_input(&settings_input);
+    if !issues.is_empty() {
+        let report: Vec<String> = issues
+            .iter()
+            .map(|issue| format!("{}: {}", issue.field, issue.message))
+            .collect();
+        ic_cdk::trap(&format!("Invalid settings: {}", report.join("; ")));
+    }
+
+    let ic_siwb_settings = library_settings(&settings_input);
+    let network = configured_network(&settings_input);
+    let terms_of_service = configured_terms(&settings_input);
+
+    // The fields below were validated above.
+    let sharding = settings_input.sharding.clone().map(|sharding| {
+        // Shards are plain providers with the same settings, so they derive the same principals.
+        let shard_settings = SettingsInput {
+            targets: None,
+            login_hook: None,
+            session_expiry_reminder_window: None,
+            anchoring: None,
+            sharding: None,
+            top_up: None,
+            probe_address: None,
+            attestor: None,
+            ..settings_input.clone()
+        };
+        ShardingSettings {
+            shard_count: sharding.shard_count,
+            cycles_per_shard: sharding.cycles_per_shard,
+            controllers: sharding
+                .controllers
+                .unwrap_or_default()
+                .into_iter()
+                .map(|c| Principal::from_text(c).unwrap())
+                .collect(),
+            shard_init_arg: Encode!(&shard_settings).unwrap(),
+        }
+    });
+
+    let login_hook = settings_input
+        .login_hook
+        .map(|hook| Principal::from_text(hook).unwrap());
+
+    let attestor = settings_input
+        .attestor
+        .map(|attestor| Principal::from_text(attestor).unwrap());
+
+    let signature_store = settings_input
+        .signature_store
+        .map(SignatureStoreKind::from)
+        .unwrap_or_default();
+
+    let anchoring = settings_input.anchoring.map(|anchoring| AnchoringSettings {
+        key_name: anchoring.ecdsa_key_name,
+        interval_seconds: anchoring.interval_seconds,
+        fee_per_vbyte: anchoring
+            .fee_per_vbyte
+            .unwrap_or(DEFAULT_ANCHOR_FEE_PER_VBYTE),
+        network,
+    });
+
+    let top_up = settings_input.top_up.map(|top_up| TopUpSettings {
+        cycles_ledger: Principal::from_text(
+            top_up.cycles_ledger.as_deref().unwrap_or(CYCLES_LEDGER),
+        )
+        .unwrap(),
+        funding_subaccount: top_up
+            .funding_subaccount
+            .map(|subaccount| subaccount.as_slice().try_into().unwrap()),
+        threshold: top_up.threshold,
+        amount: top_up.amount,
+        min_interval_seconds: top_up
+            .min_interval_seconds
+            .unwrap_or(DEFAULT_TOP_UP_INTERVAL_SECONDS),
+    });
+
+    let endpoint_access: HashMap<_, _> = settings_input
+        .endpoint_access
+        .unwrap_or_default()
+        .into_iter()
+        .map(|access| (access.endpoint, AccessPolicy::from(access.policy)))
+        .collect();
+
+    let api_sunsets: HashMap<_, _> = settings_input
+        .api_sunsets
+        .unwrap_or_default()
+        .into_iter()
+        .map(|sunset| (sunset.endpoint, sunset.sunset_at))
+        .collect();
+
+    let previous_signature_store = SETTINGS.with_borrow_mut(|provider_settings| {
+        std::mem::replace(&mut provider_settings.signature_store, signature_store)
+    });
+
     SETTINGS.with_borrow_mut(|provider_settings| {
-        if let Some(runtime_features) = settings_input.runtime_features {
-            for feature in runtime_features {
-                match feature {
-                    RuntimeFeature::IncludeUriInSeed => {
-                        ic_siwb_settings = ic_siwb_settings.runtime_features(vec![
-                            ic_siwb::settings::RuntimeFeature::IncludeUriInSeed,
-                        ]);
-                    }
-                    RuntimeFeature::DisableBtcToPrincipalMapping => {
-                        provider_settings.disable_btc_to_principal_mapping = true;
-                    }
-                    RuntimeFeature::DisablePrincipalToBtcMapping => {
-                        provider_settings.disable_principal_to_btc_mapping = true;
-                    }
+        provider_settings.login_hook = login_hook;
+        provider_settings.max_sessions_per_principal = settings_input.max_sessions_per_principal;
+        provider_settings.lookup_cache_ttl = settings_input.lookup_cache_ttl;
+        provider_settings.endpoint_access = endpoint_access;
+        provider_settings.anchoring = anchoring;
+        provider_settings.sharding = sharding;
+        provider_settings.top_up = top_up;
+        provider_settings.api_sunsets = api_sunsets;
+        provider_settings.attestor = attestor;
+        provider_settings.terms_of_service = terms_of_service;
+        provider_settings.guest_session_expires_in = settings_input.guest_session_expires_in;
+        provider_settings.seed_migration =
+            settings_input
+                .seed_migration
+                .clone()
+                .map(|migration| SeedMigration {
+                    previous_uri: migration
+                        .previous_uri
+                        .unwrap_or_else(|| settings_input.uri.clone()),
+                    previous_salt: migration.previous_salt,
+                });
+        provider_settings.session_expiry_reminder_window =
+            settings_input.session_expiry_reminder_window;
+        provider_settings.session_limit_policy = match settings_input.session_limit_policy {
+            Some(SessionLimitPolicyInput::EvictOldestSession) => {
+                SessionLimitPolicy::EvictOldestSession
+            }
+            Some(SessionLimitPolicyInput::RejectNewSession) | None => {
+                SessionLimitPolicy::RejectNewSession
+            }
+        };
+        for feature in settings_input.runtime_features.unwrap_or_default() {
+            match feature {
+                RuntimeFeature::DisableBtcToPrincipalMapping => {
+                    provider_settings.disable_btc_to_principal_mapping = true;
+                }
+                RuntimeFeature::DisablePrincipalToBtcMapping => {
+                    provider_settings.disable_principal_to_btc_mapping = true;
                 }
+                // Passed to the library by `library_settings`.
+                RuntimeFeature::IncludeUriInSeed | RuntimeFeature::IncludeSessionEpochInSeed => {}
             }
         }
 
         // Build and initialize SIWB
         ic_siwb::init(ic_siwb_settings.build().unwrap()).unwrap();
     });
+
+    // The probe address is parsed once the library serves the configured network.
+    let probe = settings_input.probe_address.map(|address| {
+        let script = get_script_from_address(address.clone())
+            .unwrap_or_else(|e| panic!("probe_address: {}", e))
+            .script_buf
+            .to_bytes();
+        ProbeSettings { address, script }
+    });
+    SETTINGS.with_borrow_mut(|provider_settings| provider_settings.probe = probe);
+
+    // Cached lookups may have been made with different mapping settings.
+    clear_caches();
+
+    ic_siwb::shadow::set_shadow_verifier(match settings_input.shadow_verifier {
+        Some(ShadowVerifierInput::Bip322Witness) => Some(ic_siwb::shadow::verify_bip322_witness),
+        None => None,
+    });
+
+    // After an upgrade the settings start out on the heap backend, so selecting the stable backend again
+    // restores its signatures.
+    if signature_store != previous_signature_store {
+        set_signature_store(signature_store);
+    }
+    restore_delegation_audit();
+
+    schedule_reminders();
+    schedule_anchoring();
+    schedule_shard_flush();
+    schedule_top_ups();
+
+    // Restore the session epoch from stable memory.
+    SESSION_EPOCH.with_borrow(|epoch| ic_siwb::set_session_epoch(*epoch.get()));
+
+    network
 }
 
 /// `init` is called when the canister is created. It initializes the SIWB library with the given settings.
@@ -154,7 +919,8 @@ fn init(settings: SettingsInput) {
 /// settings after users have started using the service!
 #[post_upgrade]
 fn upgrade(settings: SettingsInput) {
-    siwb_init(settings);
+    let network = siwb_init(settings);
+    schedule_legacy_migration(network);
 }
 
 #[update(name = "update_settings", guard = "controller_guard")]
@@ -0,0 +1,104 @@
+use std::cell::Cell;
+use std::time::Duration;
+
+use ic_cdk_timers::TimerId;
+use ic_siwb::bitcoin::Network;
+use ic_stable_structures::storable::Blob;
+
+use crate::service::types::{network_tag, AddressScriptBuf, NetworkTag};
+use crate::{
+    ADDRESS_PRINCIPAL, LEGACY_ADDRESS_PRINCIPAL, LEGACY_PRINCIPAL_ADDRESS, PRINCIPAL_ADDRESS,
+};
+
+/// The most entries of each legacy map moved by one timer tick, which bounds the instructions of a tick.
+const LEGACY_MIGRATION_BATCH_SIZE: usize = 1000;
+
+const LEGACY_MIGRATION_INTERVAL: Duration = Duration::from_secs(1);
+
+thread_local! {
+    static MIGRATION_TIMER: Cell<Option<TimerId>> = const { Cell::new(None) };
+
+    // The network the legacy mappings belong to, set while a migration is pending.
+    static LEGACY_NETWORK: Cell<Option<NetworkTag>> = const { Cell::new(None) };
+}
+
+fn legacy_maps_empty() -> bool {
+    LEGACY_PRINCIPAL_ADDRESS.with_borrow(|legacy| legacy.is_empty())
+        && LEGACY_ADDRESS_PRINCIPAL.with_borrow(|legacy| legacy.is_empty())
+}
+
+/// Starts moving mappings stored before they were keyed by network to `network`, the configured network, which is
+/// the network such deployments were set up for. The entries are moved in batches from a timer so that the upgrade
+/// does not depend on the size of the maps; until then lookups fall back to the legacy maps.
+pub(crate) fn schedule_legacy_migration(network: Network) {
+    if let Some(timer) = MIGRATION_TIMER.take() {
+        ic_cdk_timers::clear_timer(timer);
+    }
+    if legacy_maps_empty() {
+        LEGACY_NETWORK.set(None);
+        return;
+    }
+    LEGACY_NETWORK.set(Some(network_tag(network)));
+    let timer = ic_cdk_timers::set_timer_interval(LEGACY_MIGRATION_INTERVAL, migrate_batch);
+    MIGRATION_TIMER.set(Some(timer));
+}
+
+/// Moves up to `LEGACY_MIGRATION_BATCH_SIZE` entries of each legacy map. A mapping written since the upgrade is
+/// newer than the legacy one and is kept.
+fn migrate_batch() {
+    let Some(network) = LEGACY_NETWORK.get() else {
+        return;
+    };
+
+    LEGACY_PRINCIPAL_ADDRESS.with_borrow_mut(|legacy| {
+        let batch: Vec<_> = legacy.iter().take(LEGACY_MIGRATION_BATCH_SIZE).collect();
+        for (principal, address) in batch {
+            PRINCIPAL_ADDRESS.with_borrow_mut(|pa| {
+                if !pa.contains_key(&(network, principal)) {
+                    pa.insert((network, principal), address);
+                }
+            });
+            legacy.remove(&principal);
+        }
+    });
+    LEGACY_ADDRESS_PRINCIPAL.with_borrow_mut(|legacy| {
+        let batch: Vec<_> = legacy.iter().take(LEGACY_MIGRATION_BATCH_SIZE).collect();
+        for (address, principal) in batch {
+            legacy.remove(&address);
+            ADDRESS_PRINCIPAL.with_borrow_mut(|ap| {
+                if !ap.contains_key(&(network, address.clone())) {
+                    ap.insert((network, address), principal);
+                }
+            });
+        }
+    });
+
+    if legacy_maps_empty() {
+        LEGACY_NETWORK.set(None);
+        if let Some(timer) = MIGRATION_TIMER.take() {
+            ic_cdk_timers::clear_timer(timer);
+        }
+    }
+}
+
+/// The address of `principal` in the legacy map, while it is still being migrated to `network`.
+pub(crate) fn legacy_address(
+    network: NetworkTag,
+    principal: &Blob<29>,
+) -> Option<AddressScriptBuf> {
+    if LEGACY_NETWORK.get() != Some(network) {
+        return None;
+    }
+    LEGACY_PRINCIPAL_ADDRESS.with_borrow(|legacy| legacy.get(principal))
+}
+
+/// The principal of `address` in the legacy map, while it is still being migrated to `network`.
+pub(crate) fn legacy_principal(
+    network: NetworkTag,
+    address: &AddressScriptBuf,
+) -> Option<Blob<29>> {
+    if LEGACY_NETWORK.get() != Some(network) {
+        return None;
+    }
+    LEGACY_ADDRESS_PRINCIPAL.with_borrow(|legacy| legacy.get(address))
+}
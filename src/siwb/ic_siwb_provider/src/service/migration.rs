@@ -0,0 +1,212 @@
+use std::ops::Bound;
+
+use candid::{candid_method, CandidType, Deserialize};
+use ic_cdk::{query, update};
+use ic_stable_structures::storable::Blob;
+use serde_bytes::ByteBuf;
+
+use crate::service::cache::clear_caches;
+use crate::service::siwb_login::{controller_guard, manage_principal_address_mappings};
+use crate::service::types::{AddressScriptBuf, NetworkTag, SignerRecord};
+use crate::{ADDRESS_PRINCIPAL, PRINCIPAL_ADDRESS, SIGNERS};
+
+/// Version of the `MappingBatch` encoding. Bumped whenever the meaning of a field changes, so that a canister
+/// never imports a batch it would misread.
+pub(crate) const MAPPING_BATCH_VERSION: u32 = 1;
+
+/// Maximum number of entries in an exported or imported batch, to stay within the message size limit.
+const MAX_BATCH_ENTRIES: usize = 1_000;
+
+// Cursor phases: first the principal to address mappings, then the address to principal mappings without a
+// counterpart, which exist when `DisablePrincipalToBtcMapping` was set for some logins.
+const PHASE_BY_PRINCIPAL: u8 = 0;
+const PHASE_BY_ADDRESS: u8 = 1;
+
+/// One identity binding: the principal that signed in with the address on the network.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MappingEntry {
+    /// The network tag: 0 for bitcoin, 1 for testnet and signet, 2 for regtest.
+    pub network: NetworkTag,
+    pub principal: ByteBuf,
+    pub address_script: ByteBuf,
+    /// How the principal last signed in. None if no signer was recorded.
+    pub signer: Option<SignerRecord>,
+}
+
+/// A page of mappings as returned by `export_mappings` and accepted by `import_mappings`.
+#[derive(CandidType, Deserialize)]
+pub struct MappingBatch {
+    pub version: u32,
+    pub entries: Vec<MappingEntry>,
+    /// Pass to `export_mappings` to fetch the next page. None on the last page.
+    pub next_cursor: Option<ByteBuf>,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct ImportSummary {
+    pub imported: u64,
+    /// Entries that were already present with the same binding.
+    pub duplicates: u64,
+    /// Entries whose principal or address is already bound differently on this canister. They are not
+    /// imported.
+    pub conflicts: Vec<MappingEntry>,
+}
+
+fn encode_cursor(phase: u8, network: NetworkTag, key: &[u8]) -> ByteBuf {
+    let mut cursor = vec![phase, network];
+    cursor.extend_from_slice(key);
+    ByteBuf::from(cursor)
+}
+
+fn to_principal(bytes: &[u8]) -> Result<Blob<29>, String> {
+    Blob::try_from(bytes).map_err(|_| "Failed to convert ByteBuf to Blob<29>".to_string())
+}
+
+fn to_entry(network: NetworkTag, principal: Blob<29>, address: AddressScriptBuf) -> MappingEntry {
+    MappingEntry {
+        network,
+        principal: ByteBuf::from(principal.as_slice().to_vec()),
+        address_script: ByteBuf::from(address.0),
+        signer: SIGNERS.with_borrow(|s| s.get(&principal)),
+    }
+}
+
+/// Exports a page of the identity mappings, in a stable order, to migrate them to another canister with
+/// `import_mappings`. Start with no cursor and pass `next_cursor` until it is None. Mappings added during the
+/// export are included if they sort after the cursor.
+#[query(guard = "controller_guard")]
+#[candid_method(query, rename = "export_mappings")]
+fn export_mappings(cursor: Option<ByteBuf>) -> Result<MappingBatch, String> {
+    let (phase, network, key) = match cursor.as_ref().map(|c| c.as_slice()) {
+        None => (PHASE_BY_PRINCIPAL, None, &[][..]),
+        Some([phase, network, key @ ..]) if *phase <= PHASE_BY_ADDRESS => {
+            (*phase, Some(*network), key)
+        }
+        Some(_) => return Err("Invalid cursor".to_string()),
+    };
+
+    let mut entries = Vec::new();
+    let mut next_cursor = None;
+
+    if phase == PHASE_BY_PRINCIPAL {
+        let start = match network {
+            Some(network) => Bound::Excluded((network, to_principal(key)?)),
+            None => Bound::Unbounded,
+        };
+        PRINCIPAL_ADDRESS.with_borrow(|pa| {
+            for ((network, principal), address) in pa.range((start, Bound::Unbounded)) {
+                if entries.len() == MAX_BATCH_ENTRIES {
+                    break;
+                }
+                entries.push(to_entry(network, principal, address));
+            }
+        });
+        next_cursor = Some(match entries.last() {
+            Some(last) if entries.len() == MAX_BATCH_ENTRIES => {
+                encode_cursor(PHASE_BY_PRINCIPAL, last.network, &last.principal)
+            }
+            _ => encode_cursor(PHASE_BY_ADDRESS, 0, &[]),
+        });
+    }
+
+    if entries.len() < MAX_BATCH_ENTRIES {
+        let start = match (phase, network) {
+            (PHASE_BY_ADDRESS, Some(network)) if !key.is_empty() => {
+                Bound::Excluded((network, AddressScriptBuf(key.to_vec())))
+            }
+            _ => Bound::Unbounded,
+        };
+        next_cursor = None;
+        ADDRESS_PRINCIPAL.with_borrow(|ap| {
+            for ((network, address), principal) in ap.range((start, Bound::Unbounded)) {
+                if entries.len() == MAX_BATCH_ENTRIES {
+                    next_cursor = entries.last().map(|last| {
+                        encode_cursor(PHASE_BY_ADDRESS, last.network, &last.address_script)
+                    });
+                    break;
+                }
+                if PRINCIPAL_ADDRESS.with_borrow(|pa| pa.contains_key(&(network, principal))) {
+                    continue;
+                }
+                entries.push(to_entry(network, principal, address));
+            }
+        });
+    }
+
+    Ok(MappingBatch {
+        version: MAPPING_BATCH_VERSION,
+        entries,
+        next_cursor,
+    })
+}
+
+/// Imports a batch exported by `export_mappings` on another canister. The mappings are stored subject to the
+/// `DisableBtcToPrincipalMapping` and `DisablePrincipalToBtcMapping` settings of this canister, and signer
+/// records are kept unless this canister already has one for the principal. Importing a batch twice is safe:
+/// the second import only counts duplicates.
+///
+/// The principals are only valid on this canister if it derives the same principals, i.e. it was installed
+/// with the same `salt`, `uri` settings and runtime features as the exporting canister.
+#[update(guard = "controller_guard")]
+#[candid_method(update, rename = "import_mappings")]
+fn import_mappings(batch: MappingBatch) -> Result<ImportSummary, String> {
+    if batch.version != MAPPING_BATCH_VERSION {
+        return Err(format!(
+            "Unsupported mapping batch version {}, expected {}",
+            batch.version, MAPPING_BATCH_VERSION
+        ));
+    }
+    if batch.entries.len() > MAX_BATCH_ENTRIES {
+        return Err(format!(
+            "A batch holds at most {} entries",
+            MAX_BATCH_ENTRIES
+        ));
+    }
+
+    // Validate the whole batch first so that a malformed batch imports nothing.
+    let mut bindings = Vec::with_capacity(batch.entries.len());
+    for entry in &batch.entries {
+        if entry.network > 2 {
+            return Err(format!("Invalid network tag {}", entry.network));
+        }
+        if entry.address_script.is_empty() || entry.address_script.len() > 128 {
+            return Err("Invalid address script".to_string());
+        }
+        let principal = to_principal(&entry.principal)?;
+        bindings.push((principal, AddressScriptBuf(entry.address_script.to_vec())));
+    }
+
+    let mut summary = ImportSummary::default();
+    for (entry, (principal, address)) in batch.entries.into_iter().zip(bindings) {
+        let network = entry.network;
+        let bound_address = PRINCIPAL_ADDRESS.with_borrow(|pa| pa.get(&(network, principal)));
+        let bound_principal =
+            ADDRESS_PRINCIPAL.with_borrow(|ap| ap.get(&(network, address.clone())));
+
+        let conflict = bound_address.as_ref().is_some_and(|a| *a != address)
+            || bound_principal.is_some_and(|p| p != principal);
+        if conflict {
+            summary.conflicts.push(entry);
+            continue;
+        }
+        if bound_address.is_some() || bound_principal.is_some() {
+            summary.duplicates += 1;
+        } else {
+            summary.imported += 1;
+        }
+
+        manage_principal_address_mappings(network, &principal, &address);
+        if let Some(signer) = entry.signer {
+            SIGNERS.with_borrow_mut(|s| {
+                if !s.contains_key(&principal) {
+                    s.insert(principal, signer);
+                }
+            });
+        }
+    }
+
+    // Cached misses may now have a mapping.
+    clear_caches();
+
+    Ok(summary)
+}
@@ -1,8 +1,30 @@
+pub mod access;
+pub mod anchor;
+pub mod attestation;
+pub mod cache;
+pub mod custodial;
+pub mod delegation_audit;
+pub mod deprecations;
+pub mod expiry_reminder;
 pub mod get_address;
 pub mod get_caller_address;
 pub mod get_principal;
+pub mod get_signer;
 pub mod init_upgrade;
+pub mod legacy_mappings;
+pub mod migration;
+pub mod probe;
+pub mod rotate_session_epoch;
+pub mod seed_migration;
+pub mod shadow;
+pub mod shard;
 pub mod siwb_get_delegation;
 pub mod siwb_login;
+pub mod siwb_login_guest;
+pub mod siwb_login_v2;
 pub mod siwb_prepare_login;
+pub mod supported_capabilities;
+pub mod terms_of_service;
+pub mod top_up;
 pub mod types;
+pub mod verify_message;
@@ -0,0 +1,53 @@
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize};
+use ic_cdk::query;
+
+use crate::service::access::get_probe_status_guard;
+use crate::SETTINGS;
+
+thread_local! {
+    // Lives on the heap like the other operational reports, so it starts out empty after an upgrade.
+    static PROBE_STATUS: RefCell<ProbeStatus> = RefCell::new(ProbeStatus::default());
+}
+
+/// The most recent synthetic-monitoring cycle run with the `probe_address`.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct ProbeStatus {
+    pub address: Option<String>,
+    pub last_prepared_at: Option<u64>,
+    pub last_login_at: Option<u64>,
+    /// The expiration of the delegation issued by the last probe login, which `siwb_get_delegation` must
+    /// return for the cycle to pass.
+    pub last_expiration: Option<u64>,
+    pub logins: u64,
+}
+
+/// Whether `script` is the script of the configured probe address. Probe logins issue a delegation like any
+/// other, but store no mappings or signer, notify no hooks and are not tracked as sessions.
+pub(crate) fn is_probe(script: &[u8]) -> bool {
+    SETTINGS.with_borrow(|s| s.probe.as_ref().is_some_and(|p| p.script == script))
+}
+
+pub(crate) fn record_probe_prepare() {
+    PROBE_STATUS.with_borrow_mut(|status| status.last_prepared_at = Some(ic_cdk::api::time()));
+}
+
+pub(crate) fn record_probe_login(expiration: u64) {
+    PROBE_STATUS.with_borrow_mut(|status| {
+        status.last_login_at = Some(ic_cdk::api::time());
+        status.last_expiration = Some(expiration);
+        status.logins += 1;
+    });
+}
+
+/// When the probe address last went through `siwb_prepare_login` and `siwb_login`, so a monitoring job can
+/// confirm that its cycle reached the canister.
+#[query(guard = "get_probe_status_guard")]
+fn get_probe_status() -> ProbeStatus {
+    let address = SETTINGS.with_borrow(|s| s.probe.as_ref().map(|p| p.address.clone()));
+    PROBE_STATUS.with_borrow(|status| ProbeStatus {
+        address,
+        ..status.clone()
+    })
+}
@@ -0,0 +1,43 @@
+use candid::candid_method;
+use ic_cdk::{query, update};
+
+use crate::service::access::get_session_epoch_guard;
+use crate::service::siwb_login::controller_guard;
+use crate::{update_root_hash, SESSION_EPOCH, STATE};
+
+/// Emergency procedure that revokes all outstanding sessions, for example in response to a suspected
+/// wallet-phishing campaign. Pending SIWB messages and delegation signatures are dropped, the session
+/// epoch is bumped and the certified data is updated in the same call.
+///
+/// Delegations that were already fetched stay valid until they expire. If the `IncludeSessionEpochInSeed`
+/// runtime feature is enabled, the new epoch also yields new principals for every user.
+///
+/// # Returns
+/// * `u64` - The new session epoch.
+#[update(name = "rotate_session_epoch", guard = "controller_guard")]
+#[candid_method(update, rename = "rotate_session_epoch")]
+fn rotate_session_epoch() -> u64 {
+    STATE.with(|state| {
+        let signature_map = &mut *state.signature_map.borrow_mut();
+        let epoch = ic_siwb::login::rotate_session_epoch(signature_map);
+
+        // Persist the epoch before certifying the now empty signature map.
+        SESSION_EPOCH.with_borrow_mut(|cell| {
+            cell.set(epoch)
+                .unwrap_or_else(|_| ic_cdk::trap("Failed to persist session epoch"));
+        });
+        update_root_hash(
+            &state.asset_hashes.borrow(),
+            signature_map,
+            &state.delegation_audit.borrow(),
+        );
+
+        epoch
+    })
+}
+
+/// Returns the current session epoch.
+#[query(guard = "get_session_epoch_guard")]
+fn get_session_epoch() -> u64 {
+    ic_siwb::session_epoch()
+}
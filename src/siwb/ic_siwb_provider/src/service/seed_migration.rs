@@ -0,0 +1,95 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::query;
+use ic_certified_map::Hash;
+use ic_siwb::delegation::{create_user_canister_pubkey, generate_seed, generate_seed_with};
+use ic_siwb::utils::{get_script_from_address, AddressInfo};
+use ic_stable_structures::storable::Blob;
+
+use crate::service::access::get_migration_status_guard;
+use crate::service::types::{network_tag, AddressScriptBuf};
+use crate::{PREVIOUS_PRINCIPALS, PRINCIPAL_ADDRESS, SETTINGS};
+
+/// How far the migration of an address from the previous salt and URI has progressed.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MigrationStatus {
+    /// Whether `seed_migration` is set, i.e. logins still record the previous principal.
+    pub active: bool,
+    /// The principal the address signs in as.
+    pub principal: Principal,
+    /// The principal the address had under the previous salt and URI. While the migration is active it is
+    /// derived on the fly; afterwards only a principal recorded during the migration is returned.
+    pub previous_principal: Option<Principal>,
+    /// Whether the address signed in while the migration was active, so that both its principals are mapped to
+    /// it. Balances of the previous principal can then be merged into the new one.
+    pub signed_in_during_migration: bool,
+}
+
+/// The principal that `seed` derives at this canister, as returned by `siwb_login`.
+fn principal_of_seed(seed: Hash) -> Result<Principal, String> {
+    let pubkey = create_user_canister_pubkey(&ic_cdk::api::id(), seed.to_vec())
+        .map_err(|e| format!("Failed to derive principal: {:?}", e))?;
+    Ok(Principal::self_authenticating(pubkey))
+}
+
+/// The principal the address had under the previous salt and URI, if a migration is active.
+fn previous_principal_of(address: &AddressInfo) -> Option<Principal> {
+    let migration = SETTINGS.with_borrow(|s| s.seed_migration.clone())?;
+    principal_of_seed(generate_seed_with(
+        &address.address_raw,
+        &migration.previous_salt,
+        &migration.previous_uri,
+    ))
+    .ok()
+}
+
+/// Records the principal `address` had under the previous salt and URI while a migration is active. The previous
+/// principal keeps resolving to the address through `get_address`, unless principal to address mappings are
+/// disabled or sharded.
+pub(crate) fn record_previous_principal(address: &AddressInfo) {
+    let Some(previous) = previous_principal_of(address) else {
+        return;
+    };
+    let previous: Blob<29> = match previous.as_slice().try_into() {
+        Ok(previous) => previous,
+        Err(_) => return,
+    };
+    let network = network_tag(address.network);
+    let script = AddressScriptBuf(address.script_buf.to_bytes());
+
+    PREVIOUS_PRINCIPALS.with_borrow_mut(|p| p.insert((network, script.clone()), previous));
+    let map_principal =
+        SETTINGS.with_borrow(|s| s.sharding.is_none() && !s.disable_principal_to_btc_mapping);
+    if map_principal {
+        PRINCIPAL_ADDRESS.with_borrow_mut(|pa| pa.insert((network, previous), script));
+    }
+}
+
+/// Reports the principals of an address while `salt` or `uri` changes. With `seed_migration` set, each login
+/// also records the principal the address had before, so that another canister, e.g. the reputation canister,
+/// can merge its balances into the new principal before the previous derivation is retired.
+///
+/// # Arguments
+/// * `address` (String): The Bitcoin address.
+///
+/// # Returns
+/// * `Ok(MigrationStatus)`: The current and previous principal of the address.
+/// * `Err(String)`: If the address is invalid.
+#[query(guard = "get_migration_status_guard")]
+fn get_migration_status(address: String) -> Result<MigrationStatus, String> {
+    let address = get_script_from_address(address)?;
+    let recorded: Option<Principal> = PREVIOUS_PRINCIPALS
+        .with_borrow(|p| {
+            p.get(&(
+                network_tag(address.network),
+                AddressScriptBuf(address.script_buf.to_bytes()),
+            ))
+        })
+        .map(|principal| Principal::from_slice(principal.as_slice()));
+
+    Ok(MigrationStatus {
+        active: SETTINGS.with_borrow(|s| s.seed_migration.is_some()),
+        principal: principal_of_seed(generate_seed(&address.address_raw))?,
+        previous_principal: previous_principal_of(&address).or(recorded),
+        signed_in_during_migration: recorded.is_some(),
+    })
+}
@@ -0,0 +1,19 @@
+use candid::candid_method;
+use ic_cdk::{query, update};
+use ic_siwb::shadow::ShadowReport;
+
+use crate::service::access::get_shadow_report_guard;
+use crate::service::siwb_login::controller_guard;
+
+/// How often the shadow verifier selected with `shadow_verifier` ran and the logins for which it disagreed
+/// with the verifier that decided them. The report lives on the heap and starts out empty after an upgrade.
+#[query(guard = "get_shadow_report_guard")]
+fn get_shadow_report() -> ShadowReport {
+    ic_siwb::shadow::shadow_report()
+}
+
+#[update(guard = "controller_guard")]
+#[candid_method(update, rename = "clear_shadow_report")]
+fn clear_shadow_report() {
+    ic_siwb::shadow::clear_shadow_report();
+}
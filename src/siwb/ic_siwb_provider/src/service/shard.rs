@@ -0,0 +1,287 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use candid::{candid_method, CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::main::{
+    create_canister, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument,
+    InstallCodeArgument,
+};
+use ic_cdk::{query, update};
+use ic_cdk_timers::TimerId;
+use ic_siwb::hash::hash_bytes;
+use ic_siwb::utils::get_script_from_address;
+use ic_stable_structures::storable::Blob;
+use serde_bytes::ByteBuf;
+
+use crate::service::access::{
+    get_shard_for_address_guard, get_shard_for_principal_guard, list_shards_guard,
+};
+use crate::service::migration::{ImportSummary, MappingBatch, MappingEntry, MAPPING_BATCH_VERSION};
+use crate::service::siwb_login::controller_guard;
+use crate::service::types::{AddressScriptBuf, NetworkTag};
+use crate::{SETTINGS, SHARDS, SHARD_COUNT, SHARD_WASM};
+
+/// Upper bound of `shard_count`. Routing uses the first two bytes of the key hash.
+pub(crate) const MAX_SHARD_COUNT: u32 = 1_024;
+
+/// Entries sent to a shard in one `import_mappings` call.
+const MAX_FLUSH_ENTRIES: usize = 1_000;
+
+/// How often writes that could not be delivered are retried.
+const FLUSH_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+thread_local! {
+    // Mappings waiting to be written to their shard. Like the session records they live on the heap, so writes
+    // still pending during an upgrade are lost; `list_shards` shows whether any are pending.
+    static PENDING_WRITES: RefCell<BTreeMap<u32, Vec<MappingEntry>>> = const { RefCell::new(BTreeMap::new()) };
+
+    // Shards being created or written to, so that each has at most one call in flight.
+    static BUSY_SHARDS: RefCell<BTreeSet<u32>> = const { RefCell::new(BTreeSet::new()) };
+
+    // Shards that were created but whose install failed, so that the install is retried on the same canister.
+    static UNINSTALLED_SHARDS: RefCell<BTreeMap<u32, Principal>> = const { RefCell::new(BTreeMap::new()) };
+
+    static FLUSH_TIMER: Cell<Option<TimerId>> = const { Cell::new(None) };
+}
+
+/// The shard responsible for `key`. Shard `i` of `n` owns the keys whose SHA-256 hash starts with a 16-bit
+/// prefix in `[i * 65536 / n, (i + 1) * 65536 / n)`.
+pub(crate) fn shard_index(key: &[u8], shard_count: u32) -> u32 {
+    let hash = hash_bytes(key);
+    let prefix = u16::from_be_bytes([hash[0], hash[1]]) as u32;
+    (prefix * shard_count) >> 16
+}
+
+fn shard_count() -> Option<u32> {
+    SETTINGS.with_borrow(|s| s.sharding.as_ref().map(|sh| sh.shard_count))
+}
+
+fn shard_canister(index: u32) -> Option<Principal> {
+    SHARDS.with_borrow(|s| s.get(&index).map(|id| Principal::from_slice(id.as_slice())))
+}
+
+/// Writes a new mapping to its shards instead of the local maps. Address lookups are routed by the hash of
+/// the address script and principal lookups by the hash of the principal, so a mapping is written to up to two
+/// shards. The writes happen after the login returns and are retried until they succeed.
+pub(crate) fn route_mapping(network: NetworkTag, principal: &Blob<29>, address: &AddressScriptBuf) {
+    let Some(count) = shard_count() else {
+        return;
+    };
+    let entry = MappingEntry {
+        network,
+        principal: ByteBuf::from(principal.as_slice().to_vec()),
+        address_script: ByteBuf::from(address.0.clone()),
+        signer: None,
+    };
+    let shards = BTreeSet::from([
+        shard_index(&address.0, count),
+        shard_index(principal.as_slice(), count),
+    ]);
+    for index in shards {
+        PENDING_WRITES.with_borrow_mut(|p| p.entry(index).or_default().push(entry.clone()));
+        ic_cdk::spawn(flush_shard(index));
+    }
+}
+
+/// Extends the error of a lookup that missed on the router with the shard to query instead.
+pub(crate) fn routed_miss(message: &str, routing_key: &[u8]) -> String {
+    let shard = shard_count().and_then(|count| shard_canister(shard_index(routing_key, count)));
+    match shard {
+        Some(shard) => format!("{}, the mapping may be stored on shard {}", message, shard),
+        None => message.to_string(),
+    }
+}
+
+/// (Re)starts the retry timer after the settings changed.
+pub(crate) fn schedule_shard_flush() {
+    if let Some(timer) = FLUSH_TIMER.take() {
+        ic_cdk_timers::clear_timer(timer);
+    }
+    if shard_count().is_none() {
+        return;
+    }
+    let timer = ic_cdk_timers::set_timer_interval(FLUSH_RETRY_INTERVAL, || {
+        let pending: Vec<u32> = PENDING_WRITES.with_borrow(|p| p.keys().copied().collect());
+        for index in pending {
+            ic_cdk::spawn(flush_shard(index));
+        }
+    });
+    FLUSH_TIMER.set(Some(timer));
+}
+
+/// Delivers the pending writes of shard `index`, creating the shard first if it does not exist yet.
+async fn flush_shard(index: u32) {
+    if !BUSY_SHARDS.with_borrow_mut(|b| b.insert(index)) {
+        return;
+    }
+    if let Err(e) = deliver_pending(index).await {
+        ic_cdk::println!("writing to shard {} failed: {}", index, e);
+    }
+    BUSY_SHARDS.with_borrow_mut(|b| b.remove(&index));
+}
+
+async fn deliver_pending(index: u32) -> Result<(), String> {
+    let shard = match shard_canister(index) {
+        Some(shard) => shard,
+        None => spawn_shard(index).await?,
+    };
+    loop {
+        let batch: Vec<MappingEntry> = PENDING_WRITES.with_borrow_mut(|p| {
+            let Some(entries) = p.get_mut(&index) else {
+                return vec![];
+            };
+            let batch = entries
+                .drain(..entries.len().min(MAX_FLUSH_ENTRIES))
+                .collect();
+            if entries.is_empty() {
+                p.remove(&index);
+            }
+            batch
+        });
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let result: Result<(Result<ImportSummary, String>,), _> = ic_cdk::call(
+            shard,
+            "import_mappings",
+            (MappingBatch {
+                version: MAPPING_BATCH_VERSION,
+                entries: batch.clone(),
+                next_cursor: None,
+            },),
+        )
+        .await;
+        let summary = match result {
+            Ok((Ok(summary),)) => summary,
+            Ok((Err(e),)) => {
+                requeue(index, batch);
+                return Err(e);
+            }
+            Err((code, msg)) => {
+                requeue(index, batch);
+                return Err(format!("import_mappings failed: {:?} {}", code, msg));
+            }
+        };
+        if !summary.conflicts.is_empty() {
+            ic_cdk::println!(
+                "shard {} rejected {} conflicting mappings",
+                index,
+                summary.conflicts.len()
+            );
+        }
+    }
+}
+
+fn requeue(index: u32, mut batch: Vec<MappingEntry>) {
+    PENDING_WRITES.with_borrow_mut(|p| {
+        let entries = p.entry(index).or_default();
+        batch.append(entries);
+        *entries = batch;
+    });
+}
+
+/// Creates shard `index` from the uploaded shard wasm and installs it with the router settings.
+async fn spawn_shard(index: u32) -> Result<Principal, String> {
+    let config = SETTINGS
+        .with_borrow(|s| s.sharding.clone())
+        .ok_or("Sharding is disabled")?;
+    let wasm_module = SHARD_WASM.with_borrow(|w| w.get().clone());
+    if wasm_module.is_empty() {
+        return Err("No shard wasm has been uploaded with set_shard_wasm".to_string());
+    }
+
+    let shard = match UNINSTALLED_SHARDS.with_borrow(|u| u.get(&index).copied()) {
+        Some(shard) => shard,
+        None => {
+            let mut controllers = vec![ic_cdk::id()];
+            controllers.extend(config.controllers);
+            let (record,) = create_canister(
+                CreateCanisterArgument {
+                    settings: Some(CanisterSettings {
+                        controllers: Some(controllers),
+                        ..Default::default()
+                    }),
+                },
+                config.cycles_per_shard,
+            )
+            .await
+            .map_err(|(code, msg)| format!("create_canister failed: {:?} {}", code, msg))?;
+            UNINSTALLED_SHARDS.with_borrow_mut(|u| u.insert(index, record.canister_id));
+            record.canister_id
+        }
+    };
+
+    install_code(InstallCodeArgument {
+        mode: CanisterInstallMode::Install,
+        canister_id: shard,
+        wasm_module,
+        arg: config.shard_init_arg,
+    })
+    .await
+    .map_err(|(code, msg)| format!("install_code failed: {:?} {}", code, msg))?;
+
+    UNINSTALLED_SHARDS.with_borrow_mut(|u| u.remove(&index));
+    SHARDS.with_borrow_mut(|s| s.insert(index, Blob::try_from(shard.as_slice()).unwrap()));
+    SHARD_COUNT.with_borrow_mut(|c| {
+        c.set(config.shard_count)
+            .unwrap_or_else(|_| ic_cdk::trap("Failed to persist shard count"));
+    });
+    Ok(shard)
+}
+
+/// Uploads the wasm module that new shards are installed with, normally the wasm of this canister.
+#[update(guard = "controller_guard")]
+#[candid_method(update, rename = "set_shard_wasm")]
+fn set_shard_wasm(wasm: ByteBuf) {
+    SHARD_WASM.with_borrow_mut(|w| {
+        w.set(wasm.into_vec())
+            .unwrap_or_else(|_| ic_cdk::trap("Failed to store shard wasm"));
+    });
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ShardInfo {
+    pub index: u32,
+    /// None until the first mapping routed to the shard created it.
+    pub canister_id: Option<Principal>,
+    pub pending_writes: u64,
+}
+
+/// Lists the shards of the router and the number of mappings waiting to be written to each.
+#[query(guard = "list_shards_guard")]
+fn list_shards() -> Vec<ShardInfo> {
+    let count = shard_count().unwrap_or_else(|| SHARD_COUNT.with_borrow(|c| *c.get()));
+    (0..count)
+        .map(|index| ShardInfo {
+            index,
+            canister_id: shard_canister(index),
+            pending_writes: PENDING_WRITES
+                .with_borrow(|p| p.get(&index).map_or(0, |e| e.len() as u64)),
+        })
+        .collect()
+}
+
+fn shard_for_key(key: &[u8]) -> Result<Principal, String> {
+    let count = shard_count().ok_or("Sharding is disabled")?;
+    shard_canister(shard_index(key, count))
+        .ok_or("No shard has been created for the key yet".to_string())
+}
+
+/// Returns the shard to call `get_address` and `get_signer` on for `principal`.
+#[query(guard = "get_shard_for_principal_guard")]
+fn get_shard_for_principal(principal: ByteBuf) -> Result<Principal, String> {
+    let principal: Blob<29> = principal
+        .as_ref()
+        .try_into()
+        .map_err(|_| "Failed to convert ByteBuf to Blob<29>")?;
+    shard_for_key(principal.as_slice())
+}
+
+/// Returns the shard to call `get_principal` on for `address`.
+#[query(guard = "get_shard_for_address_guard")]
+fn get_shard_for_address(address: String) -> Result<Principal, String> {
+    let address = get_script_from_address(address)?;
+    shard_for_key(address.script_buf.as_bytes())
+}
@@ -7,7 +7,7 @@ use ic_siwb::delegation::{
 use ic_siwb::utils::{get_script_from_address, AddressInfo};
 use serde_bytes::ByteBuf;
 
-use crate::{LABEL_ASSETS, LABEL_SIG, STATE};
+use crate::{LABEL_ASSETS, LABEL_AUDIT, LABEL_SIG, STATE};
 
 /// Retrieves a signed delegation for a user to authenticate further actions.
 ///
@@ -50,13 +50,20 @@ fn siwb_get_delegation(
         // Create a witness of the signature, confirming the delegation's presence in the signature map.
         let signature_witness = witness(&signature_map, seed, delegation_hash)?;
 
-        // Create a forked version of the state tree with the signature witness and the pruned asset hashes.
+        // Create a forked version of the state tree with the signature witness and the pruned asset hashes and
+        // delegation audit.
         let tree = fork(
             HashTree::Pruned(labeled_hash(
-                LABEL_ASSETS,
-                &s.asset_hashes.borrow().root_hash(),
+                LABEL_AUDIT,
+                &s.delegation_audit.borrow().root_hash(),
             )),
-            ic_certified_map::labeled(LABEL_SIG, signature_witness),
+            fork(
+                HashTree::Pruned(labeled_hash(
+                    LABEL_ASSETS,
+                    &s.asset_hashes.borrow().root_hash(),
+                )),
+                ic_certified_map::labeled(LABEL_SIG, signature_witness),
+            ),
         );
 
         // Certify that the delegation is valid by creating a signature.
@@ -2,13 +2,33 @@ use candid::{candid_method, Principal};
 use ic_cdk::api::is_controller;
 use ic_cdk::update;
 
-use ic_siwb::login::{BtcSignature, LoginDetails, SignMessageType};
+use ic_certified_map::Hash;
+use ic_siwb::delegation::{create_delegation, create_delegation_hash, generate_seed};
+use ic_siwb::hash::hash_bytes;
+use ic_siwb::login::{
+    BtcSignature, LoginDetails, LoginError, LoginOptions, SignMessageType, VerifiedSigner,
+};
+use ic_siwb::signature_map::SignatureMap;
 use ic_siwb::utils::get_script_from_address;
 use ic_stable_structures::storable::Blob;
 use serde_bytes::ByteBuf;
 
-use crate::service::types::AddressScriptBuf;
-use crate::{update_root_hash, ADDRESS_PRINCIPAL, PRINCIPAL_ADDRESS, SETTINGS, STATE};
+use crate::service::cache::cache_mapping;
+use crate::service::custodial::notify_custodial_login;
+use crate::service::delegation_audit::record_delegation;
+use crate::service::deprecations::record_deprecated_call;
+use crate::service::expiry_reminder::track_session;
+use crate::service::probe::{is_probe, record_probe_login};
+use crate::service::seed_migration::record_previous_principal;
+use crate::service::shard::route_mapping;
+use crate::service::terms_of_service::record_terms_acknowledgment;
+use crate::service::types::{network_tag, AddressScriptBuf, NetworkTag, SignerRecord};
+use crate::{
+    update_root_hash, SessionLimitPolicy, SessionRecord, State, ADDRESS_PRINCIPAL,
+    PRINCIPAL_ADDRESS, SETTINGS, SIGNERS, STATE,
+};
+
+const LOGIN_HOOK_METHOD: &str = "siwbLoginHook";
 
 /// Authenticates the user by verifying the signature of the SIWB message. This function also
 /// prepares the delegation to be fetched in the next step, the `siwb_get_delegation` function.
@@ -16,7 +36,11 @@ use crate::{update_root_hash, ADDRESS_PRINCIPAL, PRINCIPAL_ADDRESS, SETTINGS, ST
 /// # Arguments
 /// * `signature` (String): The signature of the SIWB message.
 /// * `address` (String): The Bitcoin address of the user.
+/// * `public_key` (String): The hex public key reported by the wallet, only used for ECDSA signatures. May be
+///   empty, since the key is recovered from the signature.
 /// * `session_key` (ByteBuf): A unique key that identifies the session.
+/// * `session_expires_in` (Option<u64>): The requested session duration in nanoseconds, e.g. shorter for admin
+///   users. Capped at the configured `session_expires_in`, which is also the default.
 ///
 /// # Returns
 /// * `Ok(LoginOkResponse)`: Contains the user canister public key and other login response data if the login is successful.
@@ -28,48 +52,229 @@ fn siwb_login(
     public_key: String,
     session_key: ByteBuf,
     sign_message_type: SignMessageType,
+    session_expires_in: Option<u64>,
 ) -> Result<LoginDetails, String> {
+    record_deprecated_call("siwb_login");
+    login(
+        signature,
+        address,
+        public_key,
+        session_key,
+        sign_message_type,
+        LoginOptions {
+            nonce: None,
+            session_expires_in,
+        },
+        false,
+    )
+    .map(|login| login.details)
+    .map_err(String::from)
+}
+
+/// Why a login failed. Converts to the error text of `siwb_login`.
+pub(crate) enum LoginFailure {
+    InvalidAddress(String),
+    Verification(LoginError),
+    SessionLimitReached(String),
+    Internal(String),
+}
+
+impl From<LoginFailure> for String {
+    fn from(failure: LoginFailure) -> Self {
+        match failure {
+            LoginFailure::InvalidAddress(e)
+            | LoginFailure::SessionLimitReached(e)
+            | LoginFailure::Internal(e) => e,
+            LoginFailure::Verification(e) => e.to_string(),
+        }
+    }
+}
+
+pub(crate) struct SuccessfulLogin {
+    pub details: LoginDetails,
+    pub signer: VerifiedSigner,
+    pub user: Principal,
+}
+
+/// The login flow shared by all versions of the login endpoint. `options` select the signed message among the
+/// pending messages of the address and the session duration. A `guest` login, like a probe login, stores no
+/// mappings or signer, notifies no hooks and is not tracked for expiry reminders.
+pub(crate) fn login(
+    signature: String,
+    address: String,
+    public_key: String,
+    session_key: ByteBuf,
+    sign_message_type: SignMessageType,
+    options: LoginOptions,
+    guest: bool,
+) -> Result<SuccessfulLogin, LoginFailure> {
     STATE.with(|state| {
         let signature_map = &mut *state.signature_map.borrow_mut();
 
         // Create an BtcAddress from the string. This validates the address.
-        let address = get_script_from_address(address)?;
+        let address_text = address.clone();
+        let address = get_script_from_address(address).map_err(LoginFailure::InvalidAddress)?;
 
         // Create an BtcSignature from the string. This validates the signature.
         let signature = BtcSignature(signature);
 
         // Attempt to log in with the provided signature, address, and session key.
 
-        let login_response = ic_siwb::login::login(
+        let (login_response, signer) = ic_siwb::login::login_with_options(
             &signature,
             &address.address_raw,
             public_key,
-            session_key,
+            session_key.clone(),
             &mut *signature_map,
             &ic_cdk::api::id(),
             sign_message_type,
+            &options,
         )
-        .map_err(|e| e.to_string())?;
-
-        // Update the certified data of the canister due to changes in the signature map.
-        update_root_hash(&state.asset_hashes.borrow(), signature_map);
+        .map_err(LoginFailure::Verification)?;
 
         // Convert the user canister public key to a principal.
         let principal: Blob<29> =
             Principal::self_authenticating(&login_response.user_canister_pubkey).as_slice()[..29]
                 .try_into()
-                .map_err(|_| format!("Invalid principal: {:?}", login_response))?;
+                .map_err(|_| {
+                    LoginFailure::Internal(format!("Invalid principal: {:?}", login_response))
+                })?;
+
+        // Track the new session and apply the per principal session limit.
+        let seed_hash = hash_bytes(generate_seed(&address.address_raw));
+        let delegation_hash = create_delegation_hash(
+            &create_delegation(session_key.clone(), login_response.expiration)
+                .map_err(|e| LoginFailure::Internal(e.into()))?,
+        );
+        let session = SessionRecord {
+            delegation_hash,
+            expiration: login_response.expiration,
+        };
+        let limit_result = enforce_session_limit(state, signature_map, seed_hash, session);
+        let ephemeral = guest || is_probe(address.script_buf.as_bytes());
+        if limit_result.is_ok() {
+            audit_login(
+                state,
+                ephemeral,
+                seed_hash,
+                delegation_hash,
+                &session_key,
+                login_response.expiration,
+                ic_cdk::api::time(),
+            );
+        }
+
+        // Update the certified data of the canister due to changes in the signature map and delegation audit.
+        update_root_hash(
+            &state.asset_hashes.borrow(),
+            signature_map,
+            &state.delegation_audit.borrow(),
+        );
+        limit_result.map_err(LoginFailure::SessionLimitReached)?;
+
+        let user = Principal::self_authenticating(&login_response.user_canister_pubkey);
+        if is_probe(address.script_buf.as_bytes()) {
+            record_probe_login(login_response.expiration);
+        }
+        if ephemeral {
+            return Ok(SuccessfulLogin {
+                details: login_response,
+                signer,
+                user,
+            });
+        }
 
         // Store the mapping of principal to Bitcoin address and vice versa if the settings allow it.
         manage_principal_address_mappings(
+            network_tag(address.network),
             &principal,
             &AddressScriptBuf(address.script_buf.to_bytes()),
         );
+        record_previous_principal(&address);
+        record_signer(&principal, signer.clone());
+        record_terms_acknowledgment(address.script_buf.as_bytes(), &principal);
+
+        notify_custodial_login(address.script_buf.as_bytes(), address_text.clone(), user);
+        notify_login_hook(user, address_text);
+        track_session(user, login_response.expiration);
 
-        Ok(login_response)
+        Ok(SuccessfulLogin {
+            details: login_response,
+            signer,
+            user,
+        })
     })
 }
 
+/// Records the delegation a login issued in the delegation audit. Guest and probe sessions are not audited, as
+/// they leave no lasting trace of the address.
+fn audit_login(
+    state: &State,
+    ephemeral: bool,
+    seed_hash: Hash,
+    delegation_hash: Hash,
+    session_key: &[u8],
+    expiration: u64,
+    now: u64,
+) {
+    if ephemeral {
+        return;
+    }
+    record_delegation(
+        state,
+        seed_hash,
+        delegation_hash,
+        session_key,
+        expiration,
+        now,
+    );
+}
+
+/// Records `session` for the principal identified by `seed_hash`. If the principal already holds
+/// `max_sessions_per_principal` unexpired sessions, either the new session is rejected and its signature
+/// removed again, or the oldest session is evicted, depending on the configured [`SessionLimitPolicy`].
+fn enforce_session_limit(
+    state: &State,
+    signature_map: &mut SignatureMap,
+    seed_hash: Hash,
+    session: SessionRecord,
+) -> Result<(), String> {
+    let (max_sessions, policy) = SETTINGS.with(|s| {
+        let s = s.borrow();
+        (s.max_sessions_per_principal, s.session_limit_policy)
+    });
+    let Some(max_sessions) = max_sessions else {
+        return Ok(());
+    };
+    // A limit of zero would make every login fail, treat it as one session.
+    let max_sessions = max_sessions.max(1);
+
+    let now = ic_cdk::api::time();
+    let mut sessions = state.sessions.borrow_mut();
+    let records = sessions.entry(seed_hash).or_default();
+    records.retain(|r| r.expiration > now && r.delegation_hash != session.delegation_hash);
+
+    while records.len() >= max_sessions as usize {
+        match policy {
+            SessionLimitPolicy::RejectNewSession => {
+                signature_map.delete(seed_hash, session.delegation_hash);
+                return Err(format!(
+                    "Maximum number of sessions ({}) reached for this principal",
+                    max_sessions
+                ));
+            }
+            SessionLimitPolicy::EvictOldestSession => {
+                if let Some(oldest) = records.pop_front() {
+                    signature_map.delete(seed_hash, oldest.delegation_hash);
+                }
+            }
+        }
+    }
+
+    records.push_back(session);
+    Ok(())
+}
+
 #[update(name = "prune_sigs", guard = "controller_guard")]
 #[candid_method(update, rename = "prune_sigs")]
 fn prune_sigs() {
@@ -79,21 +284,60 @@ fn prune_sigs() {
     })
 }
 
-fn manage_principal_address_mappings(principal: &Blob<29>, address: &AddressScriptBuf) {
+pub(crate) fn manage_principal_address_mappings(
+    network: NetworkTag,
+    principal: &Blob<29>,
+    address: &AddressScriptBuf,
+) {
+    cache_mapping(network, principal, address);
+    if SETTINGS.with_borrow(|s| s.sharding.is_some()) {
+        route_mapping(network, principal, address);
+        return;
+    }
     SETTINGS.with(|s| {
         if !s.borrow().disable_principal_to_btc_mapping {
             PRINCIPAL_ADDRESS.with(|pa| {
-                pa.borrow_mut().insert(*principal, address.clone());
+                pa.borrow_mut()
+                    .insert((network, *principal), address.clone());
             });
         }
         if !s.borrow().disable_btc_to_principal_mapping {
             ADDRESS_PRINCIPAL.with(|ap| {
-                ap.borrow_mut().insert(address.clone(), *principal);
+                ap.borrow_mut()
+                    .insert((network, address.clone()), *principal);
             });
         }
     });
 }
 
+/// Stores how the principal authenticated. Like the principal to address mapping, this identifies the
+/// address owner, so it is skipped when `disable_principal_to_btc_mapping` is set.
+fn record_signer(principal: &Blob<29>, signer: VerifiedSigner) {
+    if SETTINGS.with_borrow(|s| s.disable_principal_to_btc_mapping) {
+        return;
+    }
+    SIGNERS.with(|s| {
+        let mut s = s.borrow_mut();
+        let attestation = s.get(principal).and_then(|record| record.attestation);
+        let record = SignerRecord {
+            signer,
+            authenticated_at: ic_cdk::api::time(),
+            attestation,
+        };
+        s.insert(*principal, record);
+    });
+}
+
+/// Notifies the configured login hook canister, if any. Delivery is best effort: a failed notification is
+/// only logged so that login never depends on the hook canister being available.
+fn notify_login_hook(principal: Principal, address: String) {
+    if let Some(hook) = SETTINGS.with(|s| s.borrow().login_hook) {
+        if let Err(code) = ic_cdk::notify(hook, LOGIN_HOOK_METHOD, (principal, address)) {
+            ic_cdk::println!("login hook notification failed: {:?}", code);
+        }
+    }
+}
+
 #[inline]
 pub fn controller_guard() -> Result<(), String> {
     match is_controller(&ic_cdk::caller()) {
@@ -103,3 +347,36 @@ pub fn controller_guard() -> Result<(), String> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ic_certified_map::AsHashTree;
+
+    use super::audit_login;
+    use crate::{AuditTree, State, DELEGATION_AUDIT};
+
+    fn audited(state: &State) -> (u64, bool) {
+        let records = DELEGATION_AUDIT.with_borrow(|a| a.len());
+        let empty_tree =
+            state.delegation_audit.borrow().root_hash() == AuditTree::default().root_hash();
+        (records, empty_tree)
+    }
+
+    #[test]
+    fn test_guest_login_is_not_audited() {
+        let state = State::default();
+        audit_login(&state, true, [1; 32], [2; 32], b"session key", 2_000, 1_000);
+        assert_eq!(audited(&state), (0, true));
+
+        audit_login(
+            &state,
+            false,
+            [1; 32],
+            [3; 32],
+            b"session key",
+            2_000,
+            1_000,
+        );
+        assert_eq!(audited(&state), (1, false));
+    }
+}
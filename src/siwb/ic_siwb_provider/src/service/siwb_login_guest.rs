@@ -0,0 +1,54 @@
+use ic_cdk::update;
+use ic_siwb::login::{LoginDetails, LoginOptions, SignMessageType};
+use serde_bytes::ByteBuf;
+
+use crate::service::siwb_login::login;
+use crate::SETTINGS;
+
+/// Authenticates like `siwb_login`, but issues a guest session for browsing without registering the address.
+/// The session lasts `guest_session_expires_in` and the login writes no principal to address mappings, signer
+/// record or Terms of Service acknowledgment, notifies no login hook and schedules no expiry reminder. The delegation is fetched with
+/// `siwb_get_delegation` as usual.
+///
+/// The principal is the one the address gets on a regular login, so only the lookups are skipped: a later
+/// regular login links the principal to the address.
+///
+/// # Arguments
+/// * `signature` (String): The signature of the SIWB message.
+/// * `address` (String): The Bitcoin address of the user.
+/// * `public_key` (String): The hex public key reported by the wallet, only used for ECDSA signatures. May be
+///   empty, since the key is recovered from the signature.
+/// * `session_key` (ByteBuf): A unique key that identifies the session.
+/// * `nonce` (Option<String>): Selects which of the pending SIWB messages of the address was signed, the most
+///   recent one if None.
+///
+/// # Returns
+/// * `Ok(LoginDetails)`: The delegation expiration and user canister public key.
+/// * `Err(String)`: If guest sessions are disabled or the login fails.
+#[update]
+fn siwb_login_guest(
+    signature: String,
+    address: String,
+    public_key: String,
+    session_key: ByteBuf,
+    sign_message_type: SignMessageType,
+    nonce: Option<String>,
+) -> Result<LoginDetails, String> {
+    let Some(session_expires_in) = SETTINGS.with_borrow(|s| s.guest_session_expires_in) else {
+        return Err("Guest sessions are disabled".to_string());
+    };
+    login(
+        signature,
+        address,
+        public_key,
+        session_key,
+        sign_message_type,
+        LoginOptions {
+            nonce,
+            session_expires_in: Some(session_expires_in),
+        },
+        true,
+    )
+    .map(|login| login.details)
+    .map_err(String::from)
+}
@@ -0,0 +1,130 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::update;
+use ic_siwb::error::BtcError;
+use ic_siwb::login::{LoginError, LoginOptions, SignMessageType, VerifiedSigner};
+use ic_siwb::siwb::SiwbMessageError;
+use serde_bytes::ByteBuf;
+
+use crate::service::siwb_login::{login, LoginFailure};
+
+#[derive(CandidType, Deserialize)]
+pub struct LoginDetailsV2 {
+    /// The session expiration time in nanoseconds since the UNIX epoch.
+    pub expiration: u64,
+    pub user_canister_pubkey: ByteBuf,
+    /// The principal the delegation is issued for, derived from `user_canister_pubkey`.
+    pub principal: Principal,
+    /// How the signature was verified.
+    pub signer: VerifiedSigner,
+    /// The request ID of the signed message, if any.
+    pub request_id: Option<String>,
+    /// The resources of the signed message.
+    pub resources: Vec<String>,
+}
+
+/// What went wrong during a login, for frontends that want to react to the cause rather than show a message.
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+pub enum LoginErrorCode {
+    /// The address could not be parsed, or its type is not supported or not allowed.
+    InvalidAddress,
+    InvalidSignature,
+    /// The signature is valid but was not made by the key of the address.
+    AddressMismatch,
+    /// The session key differs from the one the SIWB message was prepared for.
+    SessionKeyMismatch,
+    /// No SIWB message was prepared for the address, or it was already used.
+    MessageNotFound,
+    MessageExpired {
+        expired_at: u64,
+    },
+    /// The principal already has `max_sessions_per_principal` sessions.
+    SessionLimitReached,
+    /// A session duration of zero was requested.
+    InvalidSessionDuration,
+    Internal,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct LoginErrorV2 {
+    pub code: LoginErrorCode,
+    /// The error text `siwb_login` returns.
+    pub message: String,
+}
+
+fn error_code(failure: &LoginFailure) -> LoginErrorCode {
+    match failure {
+        LoginFailure::InvalidAddress(_) => LoginErrorCode::InvalidAddress,
+        LoginFailure::SessionLimitReached(_) => LoginErrorCode::SessionLimitReached,
+        LoginFailure::Internal(_) => LoginErrorCode::Internal,
+        LoginFailure::Verification(e) => match e {
+            LoginError::BtcError(
+                BtcError::AddressTypeNotSupported
+                | BtcError::AddressTypeNotAllowed(_)
+                | BtcError::AddressFormatError(_),
+            ) => LoginErrorCode::InvalidAddress,
+            LoginError::BtcError(_) => LoginErrorCode::InvalidSignature,
+            LoginError::SiwbMessageError(SiwbMessageError::MessageNotFound) => {
+                LoginErrorCode::MessageNotFound
+            }
+            LoginError::SiwbMessageError(SiwbMessageError::MessageExpired { expired_at }) => {
+                LoginErrorCode::MessageExpired {
+                    expired_at: *expired_at,
+                }
+            }
+            LoginError::AddressMismatch => LoginErrorCode::AddressMismatch,
+            LoginError::SessionKeyMismatch => LoginErrorCode::SessionKeyMismatch,
+            LoginError::InvalidSessionDuration => LoginErrorCode::InvalidSessionDuration,
+            LoginError::DelegationError(_) | LoginError::ASN1EncodeErr(_) => {
+                LoginErrorCode::Internal
+            }
+        },
+    }
+}
+
+impl From<LoginFailure> for LoginErrorV2 {
+    fn from(failure: LoginFailure) -> Self {
+        LoginErrorV2 {
+            code: error_code(&failure),
+            message: failure.into(),
+        }
+    }
+}
+
+/// Same as `siwb_login`, but returns the principal and verified signer along with the delegation details, and
+/// errors with a machine readable code.
+///
+/// `nonce` selects which of the address's pending SIWB messages was signed, so that several devices can sign
+/// in with the same address concurrently. Without it the most recently prepared message is used.
+/// `session_expires_in` requests a session duration in nanoseconds, capped at the configured
+/// `session_expires_in`.
+#[update]
+fn siwb_login_v2(
+    signature: String,
+    address: String,
+    public_key: String,
+    session_key: ByteBuf,
+    sign_message_type: SignMessageType,
+    nonce: Option<String>,
+    session_expires_in: Option<u64>,
+) -> Result<LoginDetailsV2, LoginErrorV2> {
+    let login = login(
+        signature,
+        address,
+        public_key,
+        session_key,
+        sign_message_type,
+        LoginOptions {
+            nonce,
+            session_expires_in,
+        },
+        false,
+    )?;
+    Ok(LoginDetailsV2 {
+        expiration: login.details.expiration,
+        user_canister_pubkey: login.details.user_canister_pubkey,
+        principal: login.user,
+        signer: login.signer,
+        request_id: login.details.request_id,
+        resources: login.details.resources,
+    })
+}
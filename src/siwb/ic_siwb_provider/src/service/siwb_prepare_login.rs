@@ -1,14 +1,42 @@
 use ic_cdk::update;
+use ic_siwb::siwb::MessageOptions;
 use ic_siwb::utils::get_script_from_address;
+use serde_bytes::ByteBuf;
+
+use crate::service::custodial::clear_custodial_login;
+use crate::service::probe::{is_probe, record_probe_prepare};
+use crate::service::terms_of_service::{remember_prepared_terms, with_terms};
 
 // Prepare the login by generating a challenge (the SIWB message) and returning it to the caller.
+// When a session key is supplied the message is bound to it and `siwb_login` only accepts that key. The
+// optional message options select one of the configured locales, override the statement or shorten or extend
+// the TTL of this message, up to `max_sign_in_expires_in`.
 #[update]
-fn siwb_prepare_login(address: String) -> Result<String, String> {
+fn siwb_prepare_login(
+    address: String,
+    session_key: Option<ByteBuf>,
+    options: Option<MessageOptions>,
+) -> Result<String, String> {
     // Create an BtcAddress from the string. This validates the address.
     let address = get_script_from_address(address)?;
 
-    match ic_siwb::login::prepare_login(&address.address_raw) {
-        Ok(m) => Ok(m.into()),   // Converts SiwbMessage to String
+    // A direct login replaces any challenge a custodian created for this address.
+    clear_custodial_login(address.script_buf.as_bytes());
+    if is_probe(address.script_buf.as_bytes()) {
+        record_probe_prepare();
+    }
+
+    let prepared = ic_siwb::login::prepare_login_with_options(
+        &address.address_raw,
+        session_key.as_ref().map(|key| key.as_slice()),
+        &with_terms(options.unwrap_or_default()),
+    );
+
+    match prepared {
+        Ok(m) => {
+            remember_prepared_terms(address.script_buf.as_bytes(), m.expiration_time);
+            Ok(m.into()) // Converts SiwbMessage to String
+        }
         Err(e) => Err(e.into()), // Converts BtcError to String
     }
 }
@@ -0,0 +1,35 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::query;
+use ic_siwb::capabilities::SupportedCapabilities;
+
+use crate::service::access::supported_capabilities_guard;
+use crate::SETTINGS;
+
+#[derive(CandidType, Deserialize)]
+pub struct ProviderCapabilities {
+    /// What the SIWB library accepts: address types and their signature types, network and runtime features.
+    pub siwb: SupportedCapabilities,
+    pub btc_to_principal_mapping: bool,
+    pub principal_to_btc_mapping: bool,
+    pub anchoring: bool,
+    pub sharding: bool,
+    /// Whether `siwb_login_guest` is enabled.
+    pub guest_sessions: bool,
+    /// Whether logins also record the principal of the previous salt and URI, see `get_migration_status`.
+    pub seed_migration: bool,
+}
+
+/// Describes what this deployment supports, so that frontends can offer exactly the wallets and address types
+/// that can sign in and hide lookups that are disabled.
+#[query(guard = "supported_capabilities_guard")]
+fn supported_capabilities() -> ProviderCapabilities {
+    SETTINGS.with_borrow(|s| ProviderCapabilities {
+        siwb: ic_siwb::capabilities::supported_capabilities(),
+        btc_to_principal_mapping: !s.disable_btc_to_principal_mapping,
+        principal_to_btc_mapping: !s.disable_principal_to_btc_mapping,
+        anchoring: s.anchoring.is_some(),
+        sharding: s.sharding.is_some(),
+        guest_sessions: s.guest_session_expires_in.is_some(),
+        seed_migration: s.seed_migration.is_some(),
+    })
+}
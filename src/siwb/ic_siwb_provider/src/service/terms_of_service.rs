@@ -0,0 +1,128 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::query;
+use ic_siwb::settings::MessageLocale;
+use ic_siwb::siwb::MessageOptions;
+use ic_stable_structures::storable::Blob;
+use serde_bytes::ByteBuf;
+
+use crate::service::access::list_terms_acknowledgments_guard;
+use crate::service::types::TermsAcknowledgmentRecord;
+use crate::{TermsOfService, PREPARED_TERMS, SETTINGS, TERMS_ACKNOWLEDGMENTS};
+
+/// The most acknowledgments returned by one `list_terms_acknowledgments` call.
+const MAX_ACKNOWLEDGMENTS_PER_PAGE: usize = 1000;
+
+/// The longest accepted Terms of Service version, in bytes.
+pub(crate) const MAX_TERMS_VERSION_LENGTH: usize = 64;
+
+/// A principal that acknowledged a version of the Terms of Service by signing in.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TermsAcknowledgment {
+    pub principal: Principal,
+    pub version: String,
+    /// Time of the login in nanoseconds since the UNIX epoch.
+    pub acknowledged_at: u64,
+}
+
+/// Appends the acknowledgment of `terms` to a statement. The sentence is the same in every locale so that
+/// the version and hash can be read back from any signed message.
+pub(crate) fn terms_statement(statement: &str, terms: &TermsOfService) -> String {
+    format!(
+        "{} By signing in you accept the Terms of Service version {} with SHA-256 hash {}.",
+        statement,
+        terms.version,
+        hex::encode(terms.hash)
+    )
+}
+
+/// Adds the acknowledgment of the configured Terms of Service to a per-call statement override, which would
+/// otherwise replace the statement that carries it.
+pub(crate) fn with_terms(mut options: MessageOptions) -> MessageOptions {
+    let Some(terms) = SETTINGS.with_borrow(|s| s.terms_of_service.clone()) else {
+        return options;
+    };
+    if let Some(MessageLocale {
+        statement: Some(statement),
+        ..
+    }) = &mut options.overrides
+    {
+        *statement = terms_statement(statement, &terms);
+    }
+    options
+}
+
+/// Remembers the Terms of Service embedded in the message prepared for `script` until `expires_at`.
+pub(crate) fn remember_prepared_terms(script: &[u8], expires_at: u64) {
+    let terms = SETTINGS.with_borrow(|s| s.terms_of_service.clone());
+    PREPARED_TERMS.with_borrow_mut(|prepared| {
+        let now = ic_cdk::api::time();
+        prepared.retain(|_, (_, expires_at)| *expires_at > now);
+        match terms {
+            Some(terms) => prepared.insert(script.to_vec(), (terms, expires_at)),
+            None => prepared.remove(script),
+        };
+    });
+}
+
+/// Records that `principal` acknowledged the Terms of Service of the message it signed for `script`, if the
+/// message carried any. The entry is kept until it expires, since other messages prepared for the same address
+/// may still be signed.
+pub(crate) fn record_terms_acknowledgment(script: &[u8], principal: &Blob<29>) {
+    let Some((terms, _)) = PREPARED_TERMS.with_borrow(|prepared| prepared.get(script).cloned())
+    else {
+        return;
+    };
+    let record = TermsAcknowledgmentRecord {
+        version: terms.version,
+        acknowledged_at: ic_cdk::api::time(),
+    };
+    TERMS_ACKNOWLEDGMENTS.with_borrow_mut(|acknowledgments| {
+        acknowledgments.insert(
+            (Blob::try_from(&terms.hash[..]).unwrap(), *principal),
+            record,
+        )
+    });
+}
+
+/// Lists the principals that acknowledged the Terms of Service with the given document hash, with the time of
+/// their most recent acknowledgment, ordered by principal.
+///
+/// # Arguments
+/// * `terms_hash` (String): The hex SHA-256 hash of the document, as configured in `terms_of_service`.
+/// * `start_after` (Option<ByteBuf>): The last principal of the previous page, if any.
+///
+/// # Returns
+/// * `Ok(Vec<TermsAcknowledgment>)`: Up to 1000 acknowledgments. Fewer mean the list is complete.
+/// * `Err(String)`: If the hash or principal is malformed.
+#[query(guard = "list_terms_acknowledgments_guard")]
+fn list_terms_acknowledgments(
+    terms_hash: String,
+    start_after: Option<ByteBuf>,
+) -> Result<Vec<TermsAcknowledgment>, String> {
+    let hash: Blob<32> = hex::decode(&terms_hash)
+        .ok()
+        .filter(|hash| hash.len() == 32)
+        .and_then(|hash| Blob::try_from(hash.as_slice()).ok())
+        .ok_or("Invalid terms hash".to_string())?;
+    let start: Blob<29> = match &start_after {
+        Some(principal) => principal
+            .as_slice()
+            .try_into()
+            .map_err(|_| "Failed to convert ByteBuf to Blob<29>")?,
+        None => Blob::default(),
+    };
+
+    Ok(TERMS_ACKNOWLEDGMENTS.with_borrow(|acknowledgments| {
+        acknowledgments
+            .range((hash, start)..)
+            .take_while(|((key_hash, _), _)| *key_hash == hash)
+            .filter(|((_, principal), _)| start_after.is_none() || *principal != start)
+            .take(MAX_ACKNOWLEDGMENTS_PER_PAGE)
+            .map(|((_, principal), record)| TermsAcknowledgment {
+                principal: Principal::from_slice(principal.as_slice()),
+                version: record.version,
+                acknowledged_at: record.acknowledged_at,
+            })
+            .collect()
+    }))
+}
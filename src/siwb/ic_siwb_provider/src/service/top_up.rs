@@ -0,0 +1,210 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use candid::{candid_method, CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::canister_balance128;
+use ic_cdk::{query, update};
+use ic_cdk_timers::TimerId;
+use serde_bytes::ByteBuf;
+
+use crate::service::access::get_top_up_status_guard;
+use crate::service::siwb_login::controller_guard;
+use crate::{TopUpSettings, SETTINGS};
+
+/// The cycles ledger on the IC mainnet.
+pub(crate) const CYCLES_LEDGER: &str = "um5iw-rqaaa-aaaaq-qaaba-cai";
+
+/// Shortest accepted `min_interval_seconds`.
+pub(crate) const MIN_TOP_UP_INTERVAL_SECONDS: u64 = 600;
+
+pub(crate) const DEFAULT_TOP_UP_INTERVAL_SECONDS: u64 = 3_600;
+
+/// How often the balance is compared against the threshold.
+const CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Attempts kept for `get_top_up_status`.
+const MAX_LOGGED_ATTEMPTS: usize = 100;
+
+thread_local! {
+    static TOP_UP_TIMER: Cell<Option<TimerId>> = const { Cell::new(None) };
+
+    static TOP_UP_IN_PROGRESS: Cell<bool> = const { Cell::new(false) };
+
+    // Newest first. Like the anchoring errors the log lives on the heap, so it starts out empty after an upgrade.
+    static TOP_UP_LOG: RefCell<VecDeque<TopUpAttempt>> = const { RefCell::new(VecDeque::new()) };
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TopUpAttempt {
+    pub attempted_at: u64,
+    /// The balance of this canister when the attempt was made.
+    pub balance: u128,
+    pub amount: u128,
+    /// The cycles ledger block of the withdrawal, None if it failed.
+    pub block_index: Option<Nat>,
+    pub error: Option<String>,
+}
+
+#[derive(CandidType)]
+struct WithdrawArgs {
+    amount: Nat,
+    from_subaccount: Option<ByteBuf>,
+    to: Principal,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum RejectionCode {
+    NoError,
+    CanisterError,
+    SysTransient,
+    DestinationInvalid,
+    Unknown,
+    SysFatal,
+    CanisterReject,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum WithdrawError {
+    BadFee {
+        expected_fee: Nat,
+    },
+    InsufficientFunds {
+        balance: Nat,
+    },
+    TooOld,
+    CreatedInFuture {
+        ledger_time: u64,
+    },
+    TemporarilyUnavailable,
+    Duplicate {
+        duplicate_of: Nat,
+    },
+    FailedToWithdraw {
+        fee_block: Option<Nat>,
+        rejection_code: RejectionCode,
+        rejection_reason: String,
+    },
+    GenericError {
+        error_code: Nat,
+        message: String,
+    },
+    InvalidReceiver {
+        receiver: Principal,
+    },
+}
+
+/// (Re)starts the balance check after the settings changed.
+pub(crate) fn schedule_top_ups() {
+    if let Some(timer) = TOP_UP_TIMER.take() {
+        ic_cdk_timers::clear_timer(timer);
+    }
+    if SETTINGS.with_borrow(|s| s.top_up.is_none()) {
+        return;
+    }
+    let timer = ic_cdk_timers::set_timer_interval(CHECK_INTERVAL, || {
+        ic_cdk::spawn(async {
+            if let Err(e) = top_up(false).await {
+                ic_cdk::println!("cycle top-up skipped: {}", e);
+            }
+        })
+    });
+    TOP_UP_TIMER.set(Some(timer));
+}
+
+/// Withdraws `amount` cycles from the funding account into this canister if the balance is below the
+/// threshold, or regardless of the balance if `force` is set. At most one attempt is made per
+/// `min_interval_seconds`, whether the previous one succeeded or not, so a drained funding account or an
+/// unavailable ledger is not hammered.
+async fn top_up(force: bool) -> Result<Option<TopUpAttempt>, String> {
+    let config = SETTINGS
+        .with_borrow(|s| s.top_up.clone())
+        .ok_or("Cycle top-ups are disabled")?;
+    let balance = canister_balance128();
+    if !force && balance >= config.threshold {
+        return Ok(None);
+    }
+
+    let now = ic_cdk::api::time();
+    let last_attempt = TOP_UP_LOG.with_borrow(|l| l.front().map(|a| a.attempted_at));
+    if let Some(last_attempt) = last_attempt {
+        let next_attempt =
+            last_attempt.saturating_add(config.min_interval_seconds.saturating_mul(1_000_000_000));
+        if now < next_attempt {
+            return Err(format!(
+                "The next top-up may be attempted at {}",
+                next_attempt
+            ));
+        }
+    }
+    if TOP_UP_IN_PROGRESS.replace(true) {
+        return Err("A top-up is already in progress".to_string());
+    }
+
+    let result = withdraw(&config, now).await;
+    TOP_UP_IN_PROGRESS.set(false);
+
+    let attempt = TopUpAttempt {
+        attempted_at: now,
+        balance,
+        amount: config.amount,
+        block_index: result.as_ref().ok().cloned(),
+        error: result.err(),
+    };
+    if let Some(e) = &attempt.error {
+        ic_cdk::println!("cycle top-up of {} failed: {}", config.amount, e);
+    }
+    TOP_UP_LOG.with_borrow_mut(|l| {
+        l.push_front(attempt.clone());
+        l.truncate(MAX_LOGGED_ATTEMPTS);
+    });
+    Ok(Some(attempt))
+}
+
+async fn withdraw(config: &TopUpSettings, now: u64) -> Result<Nat, String> {
+    let args = WithdrawArgs {
+        amount: Nat::from(config.amount),
+        from_subaccount: config
+            .funding_subaccount
+            .map(|subaccount| ByteBuf::from(subaccount.to_vec())),
+        to: ic_cdk::id(),
+        // Lets the ledger deduplicate a withdrawal that is retried after a lost response.
+        created_at_time: Some(now),
+    };
+    let result: Result<(Result<Nat, WithdrawError>,), _> =
+        ic_cdk::call(config.cycles_ledger, "withdraw", (args,)).await;
+    match result {
+        Ok((Ok(block_index),)) => Ok(block_index),
+        Ok((Err(e),)) => Err(format!("withdraw failed: {:?}", e)),
+        Err((code, msg)) => Err(format!("withdraw failed: {:?} {}", code, msg)),
+    }
+}
+
+/// Tops up the canister right away, regardless of its balance. Subject to the same rate limit as the
+/// automatic top-ups.
+#[update(guard = "controller_guard")]
+#[candid_method(update, rename = "top_up_now")]
+async fn top_up_now() -> Result<TopUpAttempt, String> {
+    top_up(true).await?.ok_or("Nothing to top up".to_string())
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TopUpStatus {
+    pub enabled: bool,
+    pub balance: u128,
+    pub threshold: Option<u128>,
+    /// The most recent attempts, newest first.
+    pub attempts: Vec<TopUpAttempt>,
+}
+
+#[query(guard = "get_top_up_status_guard")]
+fn get_top_up_status() -> TopUpStatus {
+    let threshold = SETTINGS.with_borrow(|s| s.top_up.as_ref().map(|t| t.threshold));
+    TopUpStatus {
+        enabled: threshold.is_some(),
+        balance: canister_balance128(),
+        threshold,
+        attempts: TOP_UP_LOG.with_borrow(|l| l.iter().cloned().collect()),
+    }
+}
@@ -1,7 +1,37 @@
 use std::borrow::Cow;
 
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_siwb::bitcoin::Network;
+use ic_siwb::login::VerifiedSigner;
 use ic_stable_structures::storable::Bound;
 use ic_stable_structures::Storable;
+use serde_bytes::ByteBuf;
+
+/// Identifies the Bitcoin network of a mapping. A script is the same on every network, so the mappings are keyed
+/// by (network, script) and (network, principal) to keep the networks of a multi-network deployment apart.
+pub type NetworkTag = u8;
+
+/// Returns the tag of `network`. Testnet and signet share a tag because their addresses have the same
+/// encoding, so a login cannot tell them apart.
+pub fn network_tag(network: Network) -> NetworkTag {
+    match network {
+        Network::Bitcoin => 0,
+        Network::Testnet | Network::Signet => 1,
+        Network::Regtest => 2,
+        _ => u8::MAX,
+    }
+}
+
+/// Parses the network name accepted by the lookup endpoints.
+pub fn parse_network(network: &str) -> Result<Network, String> {
+    match network {
+        "bitcoin" | "mainnet" => Ok(Network::Bitcoin),
+        "testnet" => Ok(Network::Testnet),
+        "regtest" => Ok(Network::Regtest),
+        "signet" => Ok(Network::Signet),
+        _ => Err("Invalid network".to_string()),
+    }
+}
 
 #[derive(Ord, Eq, PartialEq, PartialOrd, Clone)]
 pub struct AddressScriptBuf(pub Vec<u8>);
@@ -21,6 +51,119 @@ impl Storable for AddressScriptBuf {
     };
 }
 
+/// The signer of the most recent login of a principal.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SignerRecord {
+    pub signer: VerifiedSigner,
+    /// Time of the login in nanoseconds since the UNIX epoch.
+    pub authenticated_at: u64,
+    /// The flags the `attestor` marked the principal with, kept across logins.
+    pub attestation: Option<Attestation>,
+}
+
+/// Flags such as "verified-builder" that the configured `attestor` set for a principal, see
+/// `service::attestation`.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Attestation {
+    pub flags: Vec<String>,
+    pub attestor: Principal,
+    /// Time of the attestation in nanoseconds since the UNIX epoch.
+    pub attested_at: u64,
+}
+
+impl Storable for SignerRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode SignerRecord"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode SignerRecord")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A commitment to the mappings anchored in a Bitcoin transaction.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AnchorRecord {
+    pub sequence: u64,
+    /// The Merkle root over the mappings, committed as `SIWB:<root>` in an OP_RETURN output.
+    pub root: ByteBuf,
+    pub txid: String,
+    pub mapping_count: u64,
+    /// The fee paid in satoshis.
+    pub fee: u64,
+    /// Time of the broadcast in nanoseconds since the UNIX epoch.
+    pub anchored_at: u64,
+}
+
+impl Storable for AnchorRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode AnchorRecord"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode AnchorRecord")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A login that acknowledged a version of the Terms of Service, see `service::terms_of_service`.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TermsAcknowledgmentRecord {
+    pub version: String,
+    /// Time of the login in nanoseconds since the UNIX epoch.
+    pub acknowledged_at: u64,
+}
+
+impl Storable for TermsAcknowledgmentRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode TermsAcknowledgmentRecord"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode TermsAcknowledgmentRecord")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A delegation issued by `siwb_login`, see `service::delegation_audit`.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DelegationAuditRecord {
+    pub session_key_hash: ByteBuf,
+    /// The expiration of the delegation in nanoseconds since the UNIX epoch.
+    pub expiration: u64,
+    /// Time of the login in nanoseconds since the UNIX epoch.
+    pub issued_at: u64,
+}
+
+impl DelegationAuditRecord {
+    /// The certified leaf of the record: the session key hash followed by the big-endian expiration and issue
+    /// time.
+    pub fn leaf(&self) -> Vec<u8> {
+        [
+            self.session_key_hash.as_slice(),
+            &self.expiration.to_be_bytes(),
+            &self.issued_at.to_be_bytes(),
+        ]
+        .concat()
+    }
+}
+
+impl Storable for DelegationAuditRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode DelegationAuditRecord"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode DelegationAuditRecord")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 // #[derive(CandidType, Serialize, Deserialize)]
 // pub struct SiwbLoginParams {
 //     pub signature: String,
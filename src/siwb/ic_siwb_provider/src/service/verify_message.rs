@@ -0,0 +1,38 @@
+use ic_cdk::query;
+use ic_siwb::login::{SignMessageType, VerifiedSigner};
+use ic_siwb::utils::get_script_from_address;
+
+use crate::service::access::verify_message_guard;
+
+/// Verifies that `signature` signs `message` with the key behind `address`, exactly as `siwb_login` verifies
+/// the SIWB message, but without touching any state. Lets other canisters accept instructions signed by a
+/// Bitcoin address, e.g. break-glass recovery actions.
+///
+/// # Arguments
+/// * `address` (String): The Bitcoin address that signed the message.
+/// * `message` (String): The signed message.
+/// * `signature` (String): The signature in the format of `sign_message_type`.
+/// * `public_key` (String): The hex public key reported by the wallet, only used for ECDSA signatures. May be
+///   empty, since the key is recovered from the signature.
+///
+/// # Returns
+/// * `Ok(VerifiedSigner)`: How the signature was verified.
+/// * `Err(String)`: Why the address or signature was rejected.
+#[query(guard = "verify_message_guard")]
+fn verify_message(
+    address: String,
+    message: String,
+    signature: String,
+    public_key: String,
+    sign_message_type: SignMessageType,
+) -> Result<VerifiedSigner, String> {
+    let address = get_script_from_address(address)?;
+    ic_siwb::login::verify_signature(
+        &message,
+        &address.address_raw,
+        &signature,
+        &public_key,
+        sign_message_type,
+    )
+    .map_err(|e| e.to_string())
+}